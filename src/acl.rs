@@ -0,0 +1,553 @@
+//! Users, and what each of them may do, as managed by `ACL` and checked before every command.
+//!
+//! Like Redis, a user is built up from rules: whether they're enabled, their passwords, which
+//! commands they may run (by name, subcommand or [`Category`]), and which keys and channels those
+//! commands may touch.  The `default` user, which clients start out as, may do anything without a
+//! password unless `requirepass` is set.
+//!
+//! Users can be loaded from and saved to `aclfile`, which holds a line per user in the same format
+//! as `ACL LIST`, e.g. `user alice on #<sha256> ~cached:* &* -@all +get`.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt::{self, Display},
+    sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+use anyhow::{bail, Context};
+use strum::IntoEnumIterator;
+
+use crate::{
+    bytes::Bytes,
+    command::{sort, Category, Command, Flags},
+    config::Config,
+    glob,
+};
+
+/// Why a user may not run a command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Denied {
+    Command,
+    Key,
+    Channel,
+    /// `SORT` reading keys by pattern with `option`, e.g. `BY`, without access to every key
+    SortPattern {
+        option: &'static str,
+    },
+}
+
+impl Denied {
+    /// The error for `user` being denied `command`, which is named as `CLIENT LIST` names it
+    pub fn error(self, user: &str, command: &str) -> String {
+        match self {
+            Self::Command => {
+                format!("NOPERM User {user} has no permissions to run the '{command}' command")
+            }
+            Self::Key => "NOPERM No permissions to access a key".into(),
+            Self::Channel => "NOPERM No permissions to access a channel".into(),
+            Self::SortPattern { option } => {
+                format!("ERR {option} option of SORT denied due to insufficient ACL permissions.")
+            }
+        }
+    }
+}
+
+/// The keys matching a glob which a user may read, write, or both
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct KeyPattern {
+    pattern: Bytes,
+    read: bool,
+    write: bool,
+}
+
+impl KeyPattern {
+    fn all() -> Self {
+        Self {
+            pattern: Bytes::from("*"),
+            read: true,
+            write: true,
+        }
+    }
+
+    fn allows(&self, key: &[u8], write: bool) -> bool {
+        (if write { self.write } else { self.read }) && glob::matches(&self.pattern, key)
+    }
+}
+
+/// Shown as the rule which adds it, e.g. `~*` or `%R~cached:*`
+impl Display for KeyPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.read, self.write) {
+            (true, false) => write!(f, "%R~{}", self.pattern),
+            (false, true) => write!(f, "%W~{}", self.pattern),
+            _ => write!(f, "~{}", self.pattern),
+        }
+    }
+}
+
+const UNKNOWN_COMMAND: &str = "Unknown command or category name in ACL";
+
+/// The SHA-256 of a password in hex, which is how passwords are kept and shown
+fn hash_password(password: &[u8]) -> String {
+    ring::digest::digest(&ring::digest::SHA256, password)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct User {
+    enabled: bool,
+    /// Whether any password is accepted
+    nopass: bool,
+    /// The hashes of the user's passwords
+    passwords: BTreeSet<String>,
+    /// The commands the user may run in full
+    commands: HashSet<Command>,
+    /// The subcommands, in lowercase, that the user may run of commands they can't run in full
+    subcommands: HashMap<Command, BTreeSet<String>>,
+    /// The command rules applied since the user's commands were last reset, which describe them
+    command_rules: Vec<String>,
+    keys: Vec<KeyPattern>,
+    /// Globs of the channels the user may publish and subscribe to
+    channels: Vec<Bytes>,
+}
+
+impl User {
+    /// The `default` user as it starts out, which can do anything
+    fn default_user() -> Self {
+        let mut user = Self::default();
+        for rule in ["on", "nopass", "allkeys", "allchannels", "allcommands"] {
+            user.apply(rule).expect("default user rules are valid");
+        }
+        user
+    }
+
+    pub fn is_nopass(&self) -> bool {
+        self.nopass
+    }
+
+    /// Apply an `ACL SETUSER` rule, e.g. `on`, `>password`, `~key:*` or `+@read`
+    pub fn apply(&mut self, rule: &str) -> Result<(), String> {
+        match &*rule.to_lowercase() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            }
+            "allkeys" => self.keys = vec![KeyPattern::all()],
+            "resetkeys" => self.keys.clear(),
+            "allchannels" => self.channels = vec![Bytes::from("*")],
+            "resetchannels" => self.channels.clear(),
+            "allcommands" => self.set_all_commands(true),
+            "nocommands" => self.set_all_commands(false),
+            "reset" => *self = Self::default(),
+            _ => return self.apply_with_arg(rule),
+        }
+        Ok(())
+    }
+
+    /// Apply one of the rules which start with a symbol followed by an argument
+    fn apply_with_arg(&mut self, rule: &str) -> Result<(), String> {
+        let Some(symbol) = rule.chars().next() else {
+            return Err("Syntax error".into());
+        };
+        let arg = &rule[symbol.len_utf8()..];
+        match symbol {
+            '>' => {
+                self.passwords.insert(hash_password(arg.as_bytes()));
+                self.nopass = false;
+            }
+            '#' => {
+                if arg.len() != 64 || !arg.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+                    return Err("The password hash must be exactly 64 characters and contain only lowercase hexadecimal characters".into());
+                }
+                self.passwords.insert(arg.into());
+                self.nopass = false;
+            }
+            '<' | '!' => {
+                let hash = match symbol {
+                    '<' => hash_password(arg.as_bytes()),
+                    _ => arg.into(),
+                };
+                if !self.passwords.remove(&hash) {
+                    return Err(
+                        "The password you are trying to remove from the user does not exist".into(),
+                    );
+                }
+            }
+            '~' => self.add_key_pattern(arg, true, true)?,
+            '%' => {
+                let Some((access, pattern)) = arg.split_once('~') else {
+                    return Err("Syntax error".into());
+                };
+                let access = access.to_uppercase();
+                if access.is_empty() || !access.chars().all(|c| c == 'R' || c == 'W') {
+                    return Err("Syntax error".into());
+                }
+                self.add_key_pattern(pattern, access.contains('R'), access.contains('W'))?;
+            }
+            '&' => {
+                if self.channels.iter().any(|channel| *channel == "*") {
+                    return Err("Adding a pattern after the * pattern (or the 'allchannels' flag) is not valid and does not have any effect. Try 'resetchannels' to start with an empty list of channels".into());
+                }
+                self.channels.push(Bytes::from(arg));
+            }
+            '+' | '-' => self.apply_command_rule(symbol == '+', &arg.to_lowercase())?,
+            _ => return Err("Syntax error".into()),
+        }
+        Ok(())
+    }
+
+    fn add_key_pattern(&mut self, pattern: &str, read: bool, write: bool) -> Result<(), String> {
+        if self.keys.contains(&KeyPattern::all()) {
+            return Err("Adding a pattern after the * pattern (or the 'allkeys' flag) is not valid and does not have any effect. Try 'resetkeys' to start with an empty list of patterns".into());
+        }
+        self.keys.push(KeyPattern {
+            pattern: Bytes::from(pattern),
+            read,
+            write,
+        });
+        Ok(())
+    }
+
+    /// Allow or deny `name`, which is a command, a subcommand like `config|get`, or a category
+    /// like `@read`
+    fn apply_command_rule(&mut self, allow: bool, name: &str) -> Result<(), String> {
+        if name == "@all" {
+            self.set_all_commands(allow);
+            return Ok(());
+        }
+
+        if let Some(category) = name.strip_prefix('@') {
+            let category: Category = category.parse().map_err(|_| UNKNOWN_COMMAND)?;
+            for command in Command::iter().filter(|c| c.categories().contains(&category)) {
+                self.set_command(command, allow);
+            }
+        } else if let Some((command, subcommand)) = name.split_once('|') {
+            let command: Command = command
+                .to_uppercase()
+                .parse()
+                .map_err(|_| UNKNOWN_COMMAND)?;
+            if command.info().help.is_none() || subcommand.is_empty() {
+                return Err(UNKNOWN_COMMAND.into());
+            }
+            if allow {
+                if !self.commands.contains(&command) {
+                    self.subcommands
+                        .entry(command)
+                        .or_default()
+                        .insert(subcommand.into());
+                }
+            } else if self.commands.contains(&command) {
+                return Err(
+                    "Denying a subcommand of a command which is allowed in full isn't supported"
+                        .into(),
+                );
+            } else if let Some(subcommands) = self.subcommands.get_mut(&command) {
+                subcommands.remove(subcommand);
+            }
+        } else {
+            let command: Command = name.to_uppercase().parse().map_err(|_| UNKNOWN_COMMAND)?;
+            self.set_command(command, allow);
+        }
+
+        let sign = if allow { '+' } else { '-' };
+        self.command_rules.push(format!("{sign}{name}"));
+        Ok(())
+    }
+
+    fn set_command(&mut self, command: Command, allow: bool) {
+        self.subcommands.remove(&command);
+        if allow {
+            self.commands.insert(command);
+        } else {
+            self.commands.remove(&command);
+        }
+    }
+
+    fn set_all_commands(&mut self, allow: bool) {
+        self.commands = if allow {
+            Command::iter().collect()
+        } else {
+            HashSet::new()
+        };
+        self.subcommands.clear();
+        self.command_rules = if allow {
+            vec!["+@all".into()]
+        } else {
+            Vec::new()
+        };
+    }
+
+    /// Whether `password` is one of the user's, which any is with `nopass`
+    fn check_password(&self, password: &[u8]) -> bool {
+        self.nopass || self.passwords.contains(&hash_password(password))
+    }
+
+    /// Whether the user may run `args`, which start with the name of `command`
+    fn check(&self, command: Command, args: &[Bytes]) -> Result<(), Denied> {
+        let allowed = self.commands.contains(&command)
+            || args.get(1).is_some_and(|subcommand| {
+                self.subcommands
+                    .get(&command)
+                    .is_some_and(|allowed| allowed.contains(&subcommand.to_lowercase()))
+            });
+        if !allowed {
+            return Err(Denied::Command);
+        }
+
+        // Writes need write access to their keys, and everything else read access, since we don't
+        // keep track of what each key of a command is used for
+        let info = command.info();
+        let write = info.flags.contains(Flags::WRITE);
+        let keys = info.keys.positions(args).unwrap_or_default();
+        if !keys.into_iter().all(|i| {
+            self.keys
+                .iter()
+                .any(|pattern| pattern.allows(&args[i], write))
+        }) {
+            return Err(Denied::Key);
+        }
+        // The keys read by pattern can't be known up front
+        if matches!(command, Command::Sort | Command::SortRo)
+            && !self.keys.contains(&KeyPattern::all())
+        {
+            if let Some(option) = sort::pattern_option(args) {
+                return Err(Denied::SortPattern { option });
+            }
+        }
+
+        // Patterns can only be subscribed to if they're allowed as they are
+        let allowed = match command {
+            Command::Publish => self.allows_channel(&args[1]),
+            Command::Subscribe => args[1..].iter().all(|channel| self.allows_channel(channel)),
+            Command::PSubscribe => args[1..].iter().all(|pattern| {
+                self.channels
+                    .iter()
+                    .any(|allowed| *allowed == "*" || allowed == pattern)
+            }),
+            _ => true,
+        };
+        if !allowed {
+            return Err(Denied::Channel);
+        }
+
+        Ok(())
+    }
+
+    fn allows_channel(&self, channel: &[u8]) -> bool {
+        self.channels
+            .iter()
+            .any(|pattern| glob::matches(pattern, channel))
+    }
+
+    /// The user's flags, as `ACL GETUSER` shows them
+    pub fn flags(&self) -> Vec<&'static str> {
+        let mut flags = vec![if self.enabled { "on" } else { "off" }];
+        if self.nopass {
+            flags.push("nopass");
+        }
+        flags
+    }
+
+    pub fn password_hashes(&self) -> impl Iterator<Item = &str> {
+        self.passwords.iter().map(String::as_str)
+    }
+
+    /// The rules which give the user their commands, e.g. `+@all -@dangerous`
+    pub fn command_rules(&self) -> String {
+        match self.command_rules.first() {
+            Some(first) if first == "+@all" => self.command_rules.join(" "),
+            _ => std::iter::once("-@all")
+                .chain(self.command_rules.iter().map(String::as_str))
+                .collect::<Vec<_>>()
+                .join(" "),
+        }
+    }
+
+    /// The rules which give the user their keys, e.g. `~* %R~cached:*`
+    pub fn key_rules(&self) -> String {
+        self.keys
+            .iter()
+            .map(KeyPattern::to_string)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// The rules which give the user their channels, e.g. `&news.*`
+    pub fn channel_rules(&self) -> String {
+        self.channels
+            .iter()
+            .map(|pattern| format!("&{pattern}"))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// Every rule needed to recreate the user, as `ACL LIST` and `aclfile` give them
+impl Display for User {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.flags().join(" "))?;
+        for hash in &self.passwords {
+            write!(f, " #{hash}")?;
+        }
+        if !self.keys.is_empty() {
+            write!(f, " {}", self.key_rules())?;
+        }
+        if self.channels.is_empty() {
+            write!(f, " resetchannels")?;
+        } else {
+            write!(f, " {}", self.channel_rules())?;
+        }
+        write!(f, " {}", self.command_rules())
+    }
+}
+
+/// Every user, by name
+#[derive(Debug)]
+pub struct Acl(RwLock<BTreeMap<String, User>>);
+
+impl Acl {
+    pub fn new(config: &Config) -> Self {
+        let acl = Self(RwLock::new(BTreeMap::from([(
+            "default".into(),
+            User::default_user(),
+        )])));
+        acl.set_requirepass(config.requirepass.as_deref());
+        acl
+    }
+
+    fn users(&self) -> RwLockReadGuard<'_, BTreeMap<String, User>> {
+        self.0.read().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn users_mut(&self) -> RwLockWriteGuard<'_, BTreeMap<String, User>> {
+        self.0.write().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Make `password` the only password of the `default` user, or let it in without one, which
+    /// is what `requirepass` does
+    pub fn set_requirepass(&self, password: Option<&str>) {
+        let mut users = self.users_mut();
+        let default = users.entry("default".into()).or_default();
+        default.passwords.clear();
+        default.nopass = password.is_none();
+        if let Some(password) = password {
+            default.passwords.insert(hash_password(password.as_bytes()));
+        }
+    }
+
+    pub fn user(&self, name: &str) -> Option<User> {
+        self.users().get(name).cloned()
+    }
+
+    pub fn exists(&self, name: &str) -> bool {
+        self.users().contains_key(name)
+    }
+
+    /// Whether clients can use `name` without giving a password
+    pub fn is_open(&self, name: &str) -> bool {
+        self.users()
+            .get(name)
+            .is_some_and(|user| user.enabled && user.nopass)
+    }
+
+    /// Whether `name` is an enabled user with `password`
+    pub fn authenticate(&self, name: &[u8], password: &[u8]) -> bool {
+        let Ok(name) = std::str::from_utf8(name) else {
+            return false;
+        };
+        self.users()
+            .get(name)
+            .is_some_and(|user| user.enabled && user.check_password(password))
+    }
+
+    /// Whether the user `name` may run `args`, which start with the name of `command`
+    pub fn check(&self, name: &str, command: Command, args: &[Bytes]) -> Result<(), Denied> {
+        match self.users().get(name) {
+            Some(user) => user.check(command, args),
+            None => Err(Denied::Command),
+        }
+    }
+
+    /// Apply `rules` to the user `name`, creating them if they don't exist.  Either every rule is
+    /// applied or, if any is invalid, none are.
+    pub fn set_user(&self, name: &str, rules: &[impl AsRef<str>]) -> Result<(), String> {
+        if name.is_empty() || name.contains([' ', '\0']) {
+            return Err("Usernames can't contain spaces or null characters".into());
+        }
+        let mut users = self.users_mut();
+        let mut user = users.get(name).cloned().unwrap_or_default();
+        for rule in rules {
+            let rule = rule.as_ref();
+            user.apply(rule)
+                .map_err(|reason| format!("Error in ACL SETUSER modifier '{rule}': {reason}"))?;
+        }
+        users.insert(name.into(), user);
+        Ok(())
+    }
+
+    /// Delete the users in `names`, returning how many there were
+    pub fn delete_users(&self, names: &[&str]) -> Result<usize, String> {
+        if names.contains(&"default") {
+            return Err("The 'default' user cannot be removed".into());
+        }
+        let mut users = self.users_mut();
+        Ok(names
+            .iter()
+            .filter(|name| users.remove(**name).is_some())
+            .count())
+    }
+
+    pub fn usernames(&self) -> Vec<String> {
+        self.users().keys().cloned().collect()
+    }
+
+    /// Every user as a line of rules, e.g. `user default on nopass ~* &* +@all`, which is the
+    /// format of `aclfile` too
+    pub fn list(&self) -> Vec<String> {
+        self.users()
+            .iter()
+            .map(|(name, user)| format!("user {name} {user}"))
+            .collect()
+    }
+
+    /// Replace every user with those in `contents`, in the format of [`Self::list`], or keep the
+    /// current ones if any line is invalid.  Without a `default` user, it's as it starts out.
+    pub fn load(&self, contents: &str) -> anyhow::Result<()> {
+        let mut users = BTreeMap::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            let (Some("user"), Some(name)) = (words.next(), words.next()) else {
+                bail!("line {} should start with 'user <username>'", i + 1);
+            };
+            if users.contains_key(name) {
+                bail!("line {}: duplicate user '{name}'", i + 1);
+            }
+            let mut user = User::default();
+            for rule in words {
+                user.apply(rule)
+                    .map_err(|reason| anyhow::anyhow!("'{rule}': {reason}"))
+                    .with_context(|| format!("line {}", i + 1))?;
+            }
+            users.insert(name.to_string(), user);
+        }
+        users
+            .entry("default".into())
+            .or_insert_with(User::default_user);
+
+        *self.users_mut() = users;
+        Ok(())
+    }
+}
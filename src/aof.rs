@@ -0,0 +1,609 @@
+//! Multi-part append-only file using the Redis 7 layout: a manifest, an optional base file with a
+//! snapshot of the dataset and incremental files with the writes made since.  A rewrite only
+//! creates new files and then atomically swaps the manifest.
+
+use std::{
+    fmt::Write as _,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, ensure, Context};
+use bytes::BytesMut;
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncWriteExt, BufWriter},
+    sync::mpsc,
+};
+use tracing::{info, warn};
+
+use crate::{
+    bytes::Bytes,
+    command::{Command, ExecContext},
+    resp::{self, Value},
+    ConnectionState, MapValue, MapValueContent, State,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FileKind {
+    Base,
+    Incr,
+}
+
+impl FileKind {
+    fn symbol(self) -> char {
+        match self {
+            FileKind::Base => 'b',
+            FileKind::Incr => 'i',
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ManifestEntry {
+    name: String,
+    seq: u64,
+    kind: FileKind,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Manifest {
+    entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    fn parse(s: &str) -> anyhow::Result<Self> {
+        let mut entries = Vec::new();
+        for line in s.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            let mut name = None;
+            let mut seq = None;
+            let mut kind = None;
+
+            let mut words = line.split(' ');
+            while let Some(field) = words.next() {
+                let value = words
+                    .next()
+                    .with_context(|| format!("missing value for '{field}' in manifest"))?;
+                match field {
+                    "file" => name = Some(value.to_string()),
+                    "seq" => seq = Some(value.parse().context("parsing manifest seq")?),
+                    "type" => {
+                        kind = Some(match value {
+                            "b" => FileKind::Base,
+                            "i" => FileKind::Incr,
+                            _ => bail!("unknown aof file type '{value}' in manifest"),
+                        })
+                    }
+                    // Unknown fields are ignored for forwards compatibility
+                    _ => {}
+                }
+            }
+
+            entries.push(ManifestEntry {
+                name: name.context("manifest line is missing 'file'")?,
+                seq: seq.context("manifest line is missing 'seq'")?,
+                kind: kind.context("manifest line is missing 'type'")?,
+            });
+        }
+
+        ensure!(
+            entries.iter().filter(|e| e.kind == FileKind::Base).count() <= 1,
+            "manifest contains more than one base file"
+        );
+
+        Ok(Self { entries })
+    }
+
+    fn encode(&self) -> String {
+        let mut s = String::new();
+        for e in self.base().into_iter().chain(self.incrs()) {
+            writeln!(s, "file {} seq {} type {}", e.name, e.seq, e.kind.symbol())
+                .expect("write to string does not fail");
+        }
+        s
+    }
+
+    fn base(&self) -> Option<&ManifestEntry> {
+        self.entries.iter().find(|e| e.kind == FileKind::Base)
+    }
+
+    fn incrs(&self) -> impl Iterator<Item = &ManifestEntry> {
+        self.entries.iter().filter(|e| e.kind == FileKind::Incr)
+    }
+
+    fn last_incr(&self) -> Option<&ManifestEntry> {
+        self.incrs().max_by_key(|e| e.seq)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    Always,
+    EverySec,
+    No,
+}
+
+impl std::str::FromStr for FsyncPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(Self::Always),
+            "everysec" => Ok(Self::EverySec),
+            "no" => Ok(Self::No),
+            _ => bail!("appendfsync must be one of 'always', 'everysec' or 'no'"),
+        }
+    }
+}
+
+impl std::fmt::Display for FsyncPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Always => "always",
+            Self::EverySec => "everysec",
+            Self::No => "no",
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Aof {
+    dir: PathBuf,
+    filename: String,
+    fsync: FsyncPolicy,
+    manifest: Manifest,
+    incr: File,
+    rewriting: bool,
+}
+
+impl Aof {
+    /// Open (or create) the append directory, making sure there is an incremental file ready to
+    /// be appended to.
+    pub async fn open(dir: PathBuf, filename: String, fsync: FsyncPolicy) -> anyhow::Result<Self> {
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .with_context(|| format!("creating append directory {}", dir.display()))?;
+
+        let manifest_path = dir.join(format!("{filename}.manifest"));
+        let mut manifest = if tokio::fs::try_exists(&manifest_path)
+            .await
+            .with_context(|| format!("checking whether {} exists", manifest_path.display()))?
+        {
+            let s = tokio::fs::read_to_string(&manifest_path)
+                .await
+                .context("reading aof manifest")?;
+            Manifest::parse(&s).context("parsing aof manifest")?
+        } else {
+            Manifest::default()
+        };
+
+        let incr_name = if let Some(incr) = manifest.last_incr() {
+            incr.name.clone()
+        } else {
+            let name = format!("{filename}.1.incr.aof");
+            manifest.entries.push(ManifestEntry {
+                name: name.clone(),
+                seq: 1,
+                kind: FileKind::Incr,
+            });
+            write_manifest(&dir, &filename, &manifest).await?;
+            name
+        };
+
+        let incr = open_append(&dir.join(incr_name)).await?;
+
+        Ok(Self {
+            dir,
+            filename,
+            fsync,
+            manifest,
+            incr,
+            rewriting: false,
+        })
+    }
+
+    pub fn fsync_policy(&self) -> FsyncPolicy {
+        self.fsync
+    }
+
+    /// Append a command to the current incremental file
+    pub async fn append(&mut self, command: &Value) -> anyhow::Result<()> {
+        let mut buf = BytesMut::new();
+        command
+            .encode_into(&mut buf, 2)
+            .context("encoding command for aof")?;
+        self.incr
+            .write_all(&buf)
+            .await
+            .context("appending to aof")?;
+
+        if self.fsync == FsyncPolicy::Always {
+            self.incr.sync_data().await.context("syncing aof")?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn sync(&mut self) -> anyhow::Result<()> {
+        self.incr.sync_data().await.context("syncing aof")
+    }
+
+    fn files(&self) -> Vec<PathBuf> {
+        self.manifest
+            .base()
+            .into_iter()
+            .chain(self.manifest.incrs())
+            .map(|e| self.dir.join(&e.name))
+            .collect()
+    }
+
+    /// Start appending to a fresh incremental file, returning its sequence number.  Everything
+    /// written before this point will be covered by the base file that the rewrite produces.
+    async fn rotate_incr(&mut self) -> anyhow::Result<u64> {
+        let seq = self.manifest.last_incr().map_or(1, |e| e.seq + 1);
+        let name = format!("{}.{seq}.incr.aof", self.filename);
+
+        let incr = open_append(&self.dir.join(&name)).await?;
+        self.manifest.entries.push(ManifestEntry {
+            name,
+            seq,
+            kind: FileKind::Incr,
+        });
+        write_manifest(&self.dir, &self.filename, &self.manifest).await?;
+
+        self.incr.sync_data().await.context("syncing old aof")?;
+        self.incr = incr;
+
+        Ok(seq)
+    }
+
+    /// Swap in a freshly written base file, dropping every file that it supersedes
+    async fn finish_rewrite(&mut self, base_name: String, first_incr: u64) -> anyhow::Result<()> {
+        let base_seq = self.manifest.base().map_or(1, |e| e.seq + 1);
+
+        let old = std::mem::take(&mut self.manifest.entries);
+        let (keep, remove): (Vec<_>, Vec<_>) = old
+            .into_iter()
+            .partition(|e| e.kind == FileKind::Incr && e.seq >= first_incr);
+
+        self.manifest.entries.push(ManifestEntry {
+            name: base_name,
+            seq: base_seq,
+            kind: FileKind::Base,
+        });
+        self.manifest.entries.extend(keep);
+
+        write_manifest(&self.dir, &self.filename, &self.manifest).await?;
+
+        for e in remove {
+            let path = self.dir.join(&e.name);
+            if let Err(err) = tokio::fs::remove_file(&path).await {
+                warn!("failed to remove old aof file {}: {err}", path.display());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+async fn open_append(path: &Path) -> anyhow::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .with_context(|| format!("opening aof file {}", path.display()))
+}
+
+async fn write_manifest(dir: &Path, filename: &str, manifest: &Manifest) -> anyhow::Result<()> {
+    let path = dir.join(format!("{filename}.manifest"));
+    let tmp = dir.join(format!("temp-{filename}.manifest"));
+
+    let mut file = File::create(&tmp)
+        .await
+        .context("creating temp aof manifest")?;
+    file.write_all(manifest.encode().as_bytes())
+        .await
+        .context("writing temp aof manifest")?;
+    file.sync_all().await.context("syncing temp aof manifest")?;
+
+    tokio::fs::rename(&tmp, &path)
+        .await
+        .context("replacing aof manifest")
+}
+
+/// Replay every file listed in the manifest into `state`
+pub async fn load(state: &Arc<State>) -> anyhow::Result<()> {
+    let Some(ref aof) = state.aof else {
+        return Ok(());
+    };
+    let files = aof.lock().await.files();
+
+    let mut conn = ConnectionState::new(None, Arc::clone(state));
+    let (tx, _rx) = mpsc::unbounded_channel();
+    conn.tx = Some(tx);
+
+    for path in files {
+        if !tokio::fs::try_exists(&path)
+            .await
+            .with_context(|| format!("checking whether {} exists", path.display()))?
+        {
+            continue;
+        }
+
+        let file = File::open(&path)
+            .await
+            .with_context(|| format!("opening aof file {}", path.display()))?;
+        let mut file = resp::Reader::new(file);
+
+        let mut count = 0;
+        loop {
+            let parsed = file
+                .parse_limited(&resp::Limits::NONE)
+                .await
+                .with_context(|| format!("parsing command {count} in {}", path.display()))?;
+            let Some((value, _)) = parsed else {
+                break;
+            };
+            let full_command = value.into_args().context("parsing aof command")?;
+            let (command, args) = full_command.split_first().context("empty command in aof")?;
+            let command: Command = command
+                .to_uppercase()
+                .parse()
+                .context("parsing aof command")?;
+
+            // Like in a transaction, blocking commands mustn't wait, since nothing else will run
+            // until loading is done
+            command
+                .execute(&mut conn, args, ExecContext::Transaction)
+                .await
+                .with_context(|| format!("replaying {command} from aof"))?;
+            count += 1;
+        }
+
+        info!("loaded {count} commands from {}", path.display());
+    }
+
+    Ok(())
+}
+
+/// Commands which recreate `value` under `key`
+fn rebuild_commands(key: &Bytes, value: &MapValue) -> Vec<Value> {
+    let mut commands = Vec::new();
+    match value.value {
+        MapValueContent::Integer(n) => {
+            commands.push(Value::from_iter([
+                Value::from("SET"),
+                Value::from(key),
+                Value::from(n.to_string()),
+            ]));
+        }
+        MapValueContent::String(ref s) => {
+            commands.push(Value::from_iter([
+                Value::from("SET"),
+                Value::from(key),
+                Value::bulk_bytes(s),
+            ]));
+        }
+        MapValueContent::List(ref items) => {
+            if !items.is_empty() {
+                commands.push(
+                    [Value::from("RPUSH"), Value::from(key)]
+                        .into_iter()
+                        .chain(items.iter().map(Value::from))
+                        .collect(),
+                );
+            }
+        }
+        MapValueContent::Stream(ref entries) => {
+            for (id, kv_pairs) in entries.iter() {
+                commands.push(
+                    [Value::from("XADD"), Value::from(key)]
+                        .into_iter()
+                        .chain([Value::from(format!("{}-{}", id.0, id.1))])
+                        .chain(kv_pairs.iter().map(Value::from))
+                        .collect(),
+                );
+            }
+            // Trimming leaves the last ID ahead of the entries that remain, and
+            // can leave the stream empty, so it's recreated and set explicitly. A stream that never
+            // had any entries has nothing to set, and is only kept by its groups' `MKSTREAM`
+            let (ms, seq) = entries.last_id();
+            if (ms, seq) != (0, 0) {
+                let last_id = format!("{ms}-{seq}");
+                if entries.is_empty() {
+                    commands.push(Value::from_iter(
+                        [Value::from("XADD"), Value::from(key)]
+                            .into_iter()
+                            .chain(["MAXLEN", "0", &last_id, "x", "y"].map(Value::from)),
+                    ));
+                }
+                commands.push(Value::from_iter([
+                    Value::from("XSETID"),
+                    Value::from(key),
+                    Value::from(last_id),
+                ]));
+            }
+            for (name, group) in &entries.groups {
+                let last = format!("{}-{}", group.last_delivered.0, group.last_delivered.1);
+                commands.push(Value::from_iter(
+                    [
+                        Value::from("XGROUP"),
+                        Value::from("CREATE"),
+                        Value::from(key),
+                    ]
+                    .into_iter()
+                    .chain([name, &last, "MKSTREAM"].map(Value::from)),
+                ));
+                for consumer in group.consumers.keys() {
+                    commands.push(Value::from_iter(
+                        [Value::from("XGROUP"), Value::from("CREATECONSUMER")]
+                            .into_iter()
+                            .chain([Value::from(key)])
+                            .chain([name, consumer].map(Value::from)),
+                    ));
+                }
+                for (id, pending) in &group.pending {
+                    commands.push(Value::from_iter(
+                        [Value::from("XCLAIM"), Value::from(key)].into_iter().chain(
+                            [
+                                name,
+                                &pending.consumer,
+                                "0",
+                                &format!("{}-{}", id.0, id.1),
+                                "TIME",
+                                &pending.delivered_at.to_string(),
+                                "RETRYCOUNT",
+                                &pending.delivery_count.to_string(),
+                                "FORCE",
+                                "JUSTID",
+                            ]
+                            .map(Value::from),
+                        ),
+                    ));
+                }
+            }
+        }
+        MapValueContent::SortedSet(ref set) => {
+            for entry in set {
+                commands.push(Value::from_iter([
+                    Value::from("ZADD"),
+                    Value::from(key),
+                    Value::from(entry.score.to_string()),
+                    Value::from(&entry.value),
+                ]));
+            }
+        }
+        MapValueContent::Set(ref set) => {
+            if !set.is_empty() {
+                commands.push(
+                    [Value::from("SADD"), Value::from(key)]
+                        .into_iter()
+                        .chain(set.iter().map(Value::from))
+                        .collect(),
+                );
+            }
+        }
+        MapValueContent::Hash(ref hash) => {
+            if !hash.is_empty() {
+                commands.push(
+                    [Value::from("HSET"), Value::from(key)]
+                        .into_iter()
+                        .chain(
+                            hash.iter()
+                                .flat_map(|(f, v)| [Value::from(f), Value::from(&v.value)]),
+                        )
+                        .collect(),
+                );
+            }
+            for (field, value) in hash {
+                if let Some(expires_at) = value.expires_at {
+                    let ms = expires_at
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_millis();
+                    commands.push(Value::from_iter(
+                        [Value::from("HPEXPIREAT"), Value::from(key)]
+                            .into_iter()
+                            .chain([&ms.to_string(), "FIELDS", "1", field].map(Value::from)),
+                    ));
+                }
+            }
+        }
+    }
+
+    if let Some(expires_at) = value.expires_at {
+        let ms = expires_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        commands.push(Value::from_iter([
+            Value::from("PEXPIREAT"),
+            Value::from(key),
+            Value::from(ms.to_string()),
+        ]));
+    }
+
+    commands
+}
+
+async fn write_base(path: &Path, snapshot: &[(Bytes, MapValue)]) -> anyhow::Result<()> {
+    let file = File::create(path)
+        .await
+        .with_context(|| format!("creating aof base file {}", path.display()))?;
+    let mut file = BufWriter::new(file);
+
+    let now = SystemTime::now();
+    for (key, value) in snapshot {
+        if value.expires_at.is_some_and(|e| e <= now) {
+            continue;
+        }
+        for command in rebuild_commands(key, value) {
+            command
+                .write_to(&mut file)
+                .await
+                .context("writing aof base file")?;
+        }
+    }
+
+    file.flush().await.context("flushing aof base file")?;
+    file.get_mut()
+        .sync_all()
+        .await
+        .context("syncing aof base file")
+}
+
+/// Begin rewriting the AOF in the background.  Returns `false` if a rewrite is already running.
+pub async fn start_rewrite(state: Arc<State>) -> anyhow::Result<bool> {
+    let Some(ref aof) = state.aof else {
+        bail!("append only file is not enabled");
+    };
+
+    let mut guard = aof.lock().await;
+    if guard.rewriting {
+        return Ok(false);
+    }
+
+    // Writes hold this lock while they execute, so the snapshot contains exactly the writes
+    // made to the incremental files we're about to replace.
+    let first_incr = guard.rotate_incr().await?;
+    let snapshot: Vec<(Bytes, MapValue)> = state
+        .map
+        .iter()
+        .map(|e| (e.key().clone(), e.value().clone()))
+        .collect();
+    guard.rewriting = true;
+
+    let base_seq = guard.manifest.base().map_or(1, |e| e.seq + 1);
+    let base_name = format!("{}.{base_seq}.base.aof", guard.filename);
+    let tmp_path = guard.dir.join(format!("temp-rewriteaof-{base_seq}.aof"));
+    let base_path = guard.dir.join(&base_name);
+    drop(guard);
+
+    tokio::spawn(async move {
+        let Some(ref aof) = state.aof else {
+            unreachable!("aof was enabled when the rewrite started");
+        };
+
+        let res: anyhow::Result<()> = async {
+            write_base(&tmp_path, &snapshot).await?;
+            tokio::fs::rename(&tmp_path, &base_path)
+                .await
+                .context("moving rewritten aof base file into place")?;
+
+            let mut aof = aof.lock().await;
+            aof.finish_rewrite(base_name, first_incr).await
+        }
+        .await;
+
+        let mut aof = aof.lock().await;
+        aof.rewriting = false;
+        match res {
+            Ok(()) => info!("background AOF rewrite finished successfully"),
+            Err(err) => warn!("background AOF rewrite failed: {err:?}"),
+        }
+    });
+
+    Ok(true)
+}
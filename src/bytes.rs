@@ -0,0 +1,148 @@
+//! Binary-safe strings, as used for keys, string values and command arguments.
+//!
+//! These can hold anything a client sends, not just UTF-8.  Where a command needs text (numbers,
+//! options, or the members of collections, which aren't binary-safe yet), the bytes are
+//! interpreted as UTF-8, and members which aren't valid UTF-8 are rejected.
+//!
+//! They're backed by [`bytes::Bytes`], so the arguments of a command can be slices of the buffer
+//! that it was read into, and cloning one doesn't copy it.
+
+use std::{borrow::Borrow, borrow::Cow, fmt, ops::Deref, str::FromStr};
+
+use anyhow::Context;
+
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Bytes(::bytes::Bytes);
+
+impl Bytes {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn into_vec(self) -> Vec<u8> {
+        self.0.into()
+    }
+
+    /// A copy which doesn't share its memory with anything else, for keeping around after the
+    /// buffer it was sliced out of is done with, since a small slice keeps the whole buffer alive
+    pub fn compact(&self) -> Self {
+        Self(::bytes::Bytes::copy_from_slice(&self.0))
+    }
+
+    /// The contents as a string, if they're valid UTF-8
+    pub fn to_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.0).ok()
+    }
+
+    /// The contents as a string, with anything which isn't valid UTF-8 replaced
+    pub fn to_string_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.0)
+    }
+
+    /// Parse the contents as text, like [`str::parse`], failing if they aren't valid UTF-8
+    pub fn parse<F>(&self) -> anyhow::Result<F>
+    where
+        F: FromStr,
+        F::Err: std::error::Error + Send + Sync + 'static,
+    {
+        let s = std::str::from_utf8(&self.0).context("expected a UTF-8 string")?;
+        Ok(s.parse()?)
+    }
+
+    /// An upper-case copy, for comparing against command names and options
+    pub fn to_uppercase(&self) -> String {
+        self.to_string_lossy().to_uppercase()
+    }
+
+    /// A lower-case copy, for comparing against command names and options
+    pub fn to_lowercase(&self) -> String {
+        self.to_string_lossy().to_lowercase()
+    }
+}
+
+impl Deref for Bytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Borrow<[u8]> for Bytes {
+    fn borrow(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl AsRef<[u8]> for Bytes {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{}\"", self.0.escape_ascii())
+    }
+}
+
+/// Shown as text, with anything which isn't valid UTF-8 replaced
+impl fmt::Display for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_string_lossy())
+    }
+}
+
+impl From<::bytes::Bytes> for Bytes {
+    fn from(value: ::bytes::Bytes) -> Self {
+        Self(value)
+    }
+}
+
+impl From<Vec<u8>> for Bytes {
+    fn from(value: Vec<u8>) -> Self {
+        Self(value.into())
+    }
+}
+
+impl From<&[u8]> for Bytes {
+    fn from(value: &[u8]) -> Self {
+        Self(::bytes::Bytes::copy_from_slice(value))
+    }
+}
+
+impl From<String> for Bytes {
+    fn from(value: String) -> Self {
+        Self(value.into())
+    }
+}
+
+impl From<&str> for Bytes {
+    fn from(value: &str) -> Self {
+        Self(::bytes::Bytes::copy_from_slice(value.as_bytes()))
+    }
+}
+
+impl From<&Bytes> for Bytes {
+    fn from(value: &Bytes) -> Self {
+        value.clone()
+    }
+}
+
+impl From<&String> for Bytes {
+    fn from(value: &String) -> Self {
+        Self(::bytes::Bytes::copy_from_slice(value.as_bytes()))
+    }
+}
+
+impl PartialEq<str> for Bytes {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other.as_bytes()
+    }
+}
+
+impl PartialEq<&str> for Bytes {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == other.as_bytes()
+    }
+}
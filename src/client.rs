@@ -0,0 +1,208 @@
+//! The registry of connected clients, which `CLIENT LIST` and `CLIENT INFO` report on.
+//!
+//! Each connection keeps its own details in its [`ConnectionState`](crate::ConnectionState), and
+//! publishes a copy of them here as each command starts and finishes.  Connections can be asked to
+//! close through here too, which is how idle clients are closed once they reach `timeout`.
+
+use std::{
+    fmt::Display,
+    net::SocketAddr,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use dashmap::{mapref::entry::Entry, DashMap};
+use tokio::sync::Notify;
+
+use crate::{
+    bytes::Bytes,
+    command::{help, Command},
+};
+
+/// What a connection is, which `CLIENT LIST TYPE` filters on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientType {
+    Normal,
+    /// Our master, when we're a replica
+    Master,
+    /// One of our replicas, once it has synced with us
+    Replica,
+    /// A client subscribed to any channels or patterns
+    PubSub,
+}
+
+impl FromStr for ClientType {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.to_lowercase() {
+            "normal" => Ok(Self::Normal),
+            "master" => Ok(Self::Master),
+            "replica" | "slave" => Ok(Self::Replica),
+            "pubsub" => Ok(Self::PubSub),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The name that `CLIENT LIST` shows for `command`, which includes the subcommand of those made
+/// up of subcommands, e.g. `client|list`, given `args` starting with the command name
+pub fn command_name(command: Command, args: &[Bytes]) -> String {
+    let name = command.to_str().to_lowercase();
+    match args.get(1) {
+        Some(subcommand) if help::subcommands(command).is_some() => {
+            format!("{name}|{}", subcommand.to_string_lossy().to_lowercase())
+        }
+        _ => name,
+    }
+}
+
+/// A snapshot of a connection's details
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    pub id: u64,
+    /// `None` for our master
+    pub addr: Option<SocketAddr>,
+    pub name: Option<String>,
+    pub connected: Instant,
+    pub last_interaction: Instant,
+    /// The command being run, or which was run last, with its subcommand, e.g. `client|list`
+    pub command: Option<String>,
+    pub channels: usize,
+    pub patterns: usize,
+    /// The number of commands queued by `MULTI`, if there's a transaction
+    pub queued: Option<usize>,
+    pub replica: bool,
+    /// Whether the client is running a blocking command, e.g. `BLPOP`
+    pub blocked: bool,
+    /// The user the client is authenticated as, if it has
+    pub user: Option<String>,
+    pub protocol: u8,
+}
+
+impl ClientInfo {
+    /// Whether the client has been idle long enough to be closed, given `timeout`.  Like Redis,
+    /// replicas, our master, and clients which are subscribed or blocked are never idle, since
+    /// they're waiting on us rather than the other way round.
+    pub fn is_idle(&self, timeout: Duration) -> bool {
+        !self.blocked
+            && self.client_type() == ClientType::Normal
+            && self.last_interaction.elapsed() >= timeout
+    }
+
+    pub fn client_type(&self) -> ClientType {
+        if self.addr.is_none() {
+            ClientType::Master
+        } else if self.replica {
+            ClientType::Replica
+        } else if self.channels + self.patterns > 0 {
+            ClientType::PubSub
+        } else {
+            ClientType::Normal
+        }
+    }
+
+    /// The single-letter flags that Redis shows for a client
+    fn flags(&self) -> String {
+        let mut flags = String::new();
+        match self.client_type() {
+            ClientType::Master => flags.push('M'),
+            ClientType::Replica => flags.push('S'),
+            ClientType::PubSub => flags.push('P'),
+            ClientType::Normal => {}
+        }
+        if self.blocked {
+            flags.push('b');
+        }
+        if self.queued.is_some() {
+            flags.push('x');
+        }
+        if flags.is_empty() {
+            flags.push('N');
+        }
+        flags
+    }
+}
+
+/// The line for a client in `CLIENT LIST`, in the same `field=value` format as Redis
+impl Display for ClientInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let now = Instant::now();
+        write!(f, "id={}", self.id)?;
+        match self.addr {
+            Some(addr) => write!(f, " addr={addr}")?,
+            None => write!(f, " addr=")?,
+        }
+        write!(f, " name={}", self.name.as_deref().unwrap_or(""))?;
+        write!(
+            f,
+            " age={} idle={}",
+            now.duration_since(self.connected).as_secs(),
+            now.duration_since(self.last_interaction).as_secs()
+        )?;
+        write!(f, " flags={} db=0", self.flags())?;
+        write!(f, " sub={} psub={}", self.channels, self.patterns)?;
+        write!(
+            f,
+            " multi={}",
+            self.queued.map_or(-1, |queued| queued as i64)
+        )?;
+        write!(
+            f,
+            " cmd={} user={} resp={}",
+            self.command.as_deref().unwrap_or("NULL"),
+            self.user.as_deref().unwrap_or("default"),
+            self.protocol
+        )
+    }
+}
+
+#[derive(Debug)]
+struct Client {
+    info: ClientInfo,
+    /// Notified when the connection should close
+    close: Arc<Notify>,
+}
+
+/// Every connected client, by ID
+#[derive(Debug, Default)]
+pub struct Clients(DashMap<u64, Client>);
+
+impl Clients {
+    /// Add a client, or replace its details with newer ones
+    pub fn update(&self, info: ClientInfo) {
+        match self.0.entry(info.id) {
+            Entry::Occupied(mut entry) => entry.get_mut().info = info,
+            Entry::Vacant(entry) => {
+                entry.insert(Client {
+                    info,
+                    close: Default::default(),
+                });
+            }
+        }
+    }
+
+    pub fn remove(&self, id: u64) {
+        self.0.remove(&id);
+    }
+
+    /// What's notified when the client `id` should close, once it has been added
+    pub fn closer(&self, id: u64) -> Option<Arc<Notify>> {
+        self.0.get(&id).map(|client| Arc::clone(&client.close))
+    }
+
+    /// Ask the client `id` to close, which it does once it's waiting for its next command
+    pub fn close(&self, id: u64) {
+        if let Some(client) = self.0.get(&id) {
+            client.close.notify_one();
+        }
+    }
+
+    /// Every client, oldest first
+    pub fn list(&self) -> Vec<ClientInfo> {
+        let mut clients: Vec<_> = self.0.iter().map(|entry| entry.info.clone()).collect();
+        clients.sort_by_key(|client| client.id);
+        clients
+    }
+}
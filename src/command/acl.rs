@@ -0,0 +1,133 @@
+use std::sync::Arc;
+
+use strum::IntoEnumIterator;
+
+use super::{Category, Command};
+use crate::{bytes::Bytes, error::RedisError, resp::Value, ConnectionState, State};
+
+const NO_ACLFILE: &str = "ERR This Redis instance is not configured to use an ACL file. You may want to specify users via the ACL SETUSER command and then issue a CONFIG REWRITE (assuming you have a Redis configuration file set) in order to store users in the Redis configuration.";
+
+/// `ACL <subcommand> [arg ...]`, which manages users and what they may do
+pub async fn acl(
+    state: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let (subcommand, args) = args.split_first().expect("arity is checked");
+
+    let ret = match (&*subcommand.to_uppercase(), args) {
+        ("SETUSER", [username, rules @ ..]) => {
+            let rules: Vec<String> = rules.iter().map(Bytes::to_string).collect();
+            state
+                .acl
+                .set_user(&username.to_string(), &rules)
+                .map_err(|err| RedisError::custom(format!("ERR {err}")))?;
+            Value::simple_string("OK")
+        }
+        ("GETUSER", [username]) => {
+            let Some(user) = state.acl.user(&username.to_string()) else {
+                return Ok(Value::Null);
+            };
+            Value::Map(vec![
+                (
+                    Value::from("flags"),
+                    user.flags().into_iter().map(Value::from).collect(),
+                ),
+                (
+                    Value::from("passwords"),
+                    user.password_hashes().map(Value::from).collect(),
+                ),
+                (Value::from("commands"), Value::from(user.command_rules())),
+                (Value::from("keys"), Value::from(user.key_rules())),
+                (Value::from("channels"), Value::from(user.channel_rules())),
+                (Value::from("selectors"), Value::Array(Vec::new())),
+            ])
+        }
+        ("DELUSER", usernames @ [_, ..]) => {
+            let usernames: Vec<String> = usernames.iter().map(Bytes::to_string).collect();
+            let usernames: Vec<&str> = usernames.iter().map(String::as_str).collect();
+            let deleted = state
+                .acl
+                .delete_users(&usernames)
+                .map_err(|err| RedisError::custom(format!("ERR {err}")))?;
+            // Like Redis, clients authenticated as a deleted user are closed
+            for client in state.clients.list() {
+                if client
+                    .user
+                    .as_deref()
+                    .is_some_and(|user| usernames.contains(&user))
+                {
+                    state.clients.close(client.id);
+                }
+            }
+            Value::Integer(deleted as i64)
+        }
+        ("LIST", []) => state.acl.list().into_iter().map(Value::from).collect(),
+        ("USERS", []) => state.acl.usernames().into_iter().map(Value::from).collect(),
+        ("WHOAMI", []) => Value::from(conn_state.user.as_deref().unwrap_or("default")),
+        ("CAT", []) => Category::iter().map(|c| Value::from(c.to_str())).collect(),
+        ("CAT", [category]) => {
+            let Ok(category) = category.to_string().parse::<Category>() else {
+                return Err(RedisError::custom(format!(
+                    "ERR Unknown category '{category}'"
+                )));
+            };
+            Command::iter()
+                .filter(|command| command.categories().contains(&category))
+                .map(|command| Value::from(command.to_str().to_lowercase()))
+                .collect()
+        }
+        ("LOAD", []) => {
+            let Some(path) = state.config().aclfile.clone() else {
+                return Err(RedisError::custom(NO_ACLFILE));
+            };
+            let contents = tokio::fs::read_to_string(&path).await.map_err(|err| {
+                RedisError::custom(format!("ERR Error loading ACLs, opening file: {err}"))
+            })?;
+            state.acl.load(&contents).map_err(|err| {
+                RedisError::custom(format!(
+                    "ERR Error loading ACLs from {}: {err:#}",
+                    path.display()
+                ))
+            })?;
+            Value::simple_string("OK")
+        }
+        ("SAVE", []) => {
+            let Some(path) = state.config().aclfile.clone() else {
+                return Err(RedisError::custom(NO_ACLFILE));
+            };
+            // Written aside first, so that the file is never left half-written
+            let mut contents = state.acl.list().join("\n");
+            contents.push('\n');
+            let mut temp = path.clone().into_os_string();
+            temp.push(".tmp");
+            let saved = match tokio::fs::write(&temp, contents).await {
+                Ok(()) => tokio::fs::rename(&temp, &path).await,
+                Err(err) => Err(err),
+            };
+            saved.map_err(|err| {
+                RedisError::custom(format!(
+                    "ERR There was an error trying to save the ACLs: {err}"
+                ))
+            })?;
+            Value::simple_string("OK")
+        }
+        (
+            subcommand @ ("SETUSER" | "GETUSER" | "DELUSER" | "LIST" | "USERS" | "WHOAMI" | "CAT"
+            | "LOAD" | "SAVE"),
+            _,
+        ) => {
+            return Err(super::wrong_arity(&format!(
+                "acl|{}",
+                subcommand.to_lowercase()
+            )))
+        }
+        _ => {
+            return Err(RedisError::custom(format!(
+                "ERR unknown subcommand '{subcommand}'. Try ACL HELP."
+            )))
+        }
+    };
+
+    Ok(ret)
+}
@@ -0,0 +1,89 @@
+//! A small parser for the keyword options that follow a command's positional arguments, e.g.
+//! `SET key value NX PX 100` or `SCAN 0 MATCH foo* COUNT 10`.
+//!
+//! Keywords are matched case-insensitively, and running out of arguments, or finding one which
+//! doesn't parse, gives the same errors that Redis replies with.
+
+use std::str::FromStr;
+
+use crate::{bytes::Bytes, error::RedisError};
+
+/// The arguments left to parse, which each method takes from the front of
+#[derive(Debug, Clone)]
+pub struct ArgParser<'a> {
+    args: &'a [Bytes],
+}
+
+impl<'a> ArgParser<'a> {
+    pub fn new(args: &'a [Bytes]) -> Self {
+        Self { args }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.args.is_empty()
+    }
+
+    /// The arguments which haven't been parsed yet
+    pub fn rest(&self) -> &'a [Bytes] {
+        self.args
+    }
+
+    pub fn next_arg(&mut self) -> Option<&'a Bytes> {
+        let (first, rest) = self.args.split_first()?;
+        self.args = rest;
+        Some(first)
+    }
+
+    /// The next argument as an uppercase keyword, or `None` once they've all been parsed
+    pub fn keyword(&mut self) -> Option<String> {
+        self.next_arg().map(Bytes::to_uppercase)
+    }
+
+    /// The next argument as an uppercase keyword, without parsing it
+    pub fn peek_keyword(&self) -> Option<String> {
+        self.args.first().map(Bytes::to_uppercase)
+    }
+
+    /// Parse the next argument if it's the keyword `flag`, returning whether it was
+    pub fn flag(&mut self, flag: &str) -> bool {
+        let found = self
+            .args
+            .first()
+            .is_some_and(|arg| arg.eq_ignore_ascii_case(flag.as_bytes()));
+        if found {
+            self.args = &self.args[1..];
+        }
+        found
+    }
+
+    /// The value which follows an option, which is a syntax error if it's missing
+    pub fn value(&mut self) -> Result<&'a Bytes, RedisError> {
+        self.next_arg().ok_or(RedisError::Syntax)
+    }
+
+    /// The value which follows an option, as an integer
+    pub fn int<T>(&mut self) -> Result<T, RedisError>
+    where
+        T: FromStr,
+        T::Err: std::error::Error + Send + Sync + 'static,
+    {
+        self.value()?.parse().map_err(|_| RedisError::NotAnInteger)
+    }
+
+    /// The value which follows an option, as a float, which can't be NaN
+    pub fn float(&mut self) -> Result<f64, RedisError> {
+        match self.value()?.parse::<f64>() {
+            Ok(n) if !n.is_nan() => Ok(n),
+            _ => Err(RedisError::NotAFloat),
+        }
+    }
+
+    /// Check that every argument has been parsed, as any left over are a syntax error
+    pub fn finish(&self) -> Result<(), RedisError> {
+        if self.is_empty() {
+            Ok(())
+        } else {
+            Err(RedisError::Syntax)
+        }
+    }
+}
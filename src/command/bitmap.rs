@@ -0,0 +1,340 @@
+use std::sync::Arc;
+
+use crate::{
+    bytes::Bytes, error::RedisError, resp::Value, ConnectionState, MapValue, MapValueContent, State,
+};
+
+use super::string::string_value;
+
+/// The largest bit offset that can be addressed, which keeps strings within 512MB
+const MAX_BIT_OFFSET: u64 = 512 * 1024 * 1024 * 8 - 1;
+
+fn parse_offset(offset: &Bytes) -> Result<usize, RedisError> {
+    match offset.parse::<u64>() {
+        Ok(offset) if offset <= MAX_BIT_OFFSET => Ok(offset as usize),
+        _ => Err(RedisError::custom(
+            "ERR bit offset is not an integer or out of range",
+        )),
+    }
+}
+
+/// Call `f` with the bytes of a string value, or return a WRONGTYPE error if it isn't a string
+fn with_bytes<T>(content: &MapValueContent, f: impl FnOnce(&[u8]) -> T) -> Result<T, RedisError> {
+    match content {
+        MapValueContent::String(bytes) => Ok(f(bytes)),
+        content => string_value(content).map(|bytes| f(&bytes)),
+    }
+}
+
+/// The mask for bit `offset` within its byte, where bit 0 is the most significant
+fn bit_mask(offset: usize) -> u8 {
+    0x80 >> (offset % 8)
+}
+
+pub async fn setbit(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, offset, bit] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let offset = parse_offset(offset)?;
+    let bit = match bit.as_bytes() {
+        b"0" => false,
+        b"1" => true,
+        _ => {
+            return Err(RedisError::custom(
+                "ERR bit is not an integer or out of range",
+            ))
+        }
+    };
+
+    let mut value = state.map.get_or_insert_with(key.clone(), || MapValue {
+        value: MapValueContent::String(Vec::new()),
+        expires_at: None,
+    });
+
+    // Integers are stored as such, so they need turning back into their bytes first
+    if let MapValueContent::Integer(_) = value.value {
+        let bytes = string_value(&value.value).expect("integers are strings");
+        value.value = MapValueContent::String(bytes);
+    }
+    let MapValueContent::String(ref mut bytes) = value.value else {
+        return Err(RedisError::WrongType);
+    };
+
+    let byte = offset / 8;
+    if byte >= bytes.len() {
+        bytes.resize(byte + 1, 0);
+    }
+
+    let mask = bit_mask(offset);
+    let old = bytes[byte] & mask != 0;
+    if bit {
+        bytes[byte] |= mask;
+    } else {
+        bytes[byte] &= !mask;
+    }
+
+    Ok(Value::from(old as i64))
+}
+
+pub async fn getbit(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, offset] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let offset = parse_offset(offset)?;
+
+    let Some(value) = state.map.get(key) else {
+        return Ok(Value::from(0));
+    };
+    let bit = with_bytes(&value.value, |bytes| {
+        bytes
+            .get(offset / 8)
+            .is_some_and(|byte| byte & bit_mask(offset) != 0)
+    })?;
+
+    Ok(Value::from(bit as i64))
+}
+
+/// A range of a string, as given to `BITCOUNT` and `BITPOS`, in bytes or in bits
+#[derive(Debug, Clone, Copy)]
+struct BitRange {
+    start: i64,
+    end: Option<i64>,
+    bits: bool,
+}
+
+impl BitRange {
+    /// Parse `[start [end [BYTE|BIT]]]`
+    fn parse(args: &[Bytes]) -> Result<Option<Self>, RedisError> {
+        let parse_index =
+            |index: &Bytes| index.parse::<i64>().map_err(|_| RedisError::NotAnInteger);
+
+        let (start, end, unit) = match args {
+            [] => return Ok(None),
+            [start] => (start, None, None),
+            [start, end] => (start, Some(end), None),
+            [start, end, unit] => (start, Some(end), Some(unit)),
+            _ => return Err(RedisError::Syntax),
+        };
+
+        let bits = match unit.map(|unit| unit.to_uppercase()).as_deref() {
+            None | Some("BYTE") => false,
+            Some("BIT") => true,
+            Some(_) => return Err(RedisError::Syntax),
+        };
+
+        Ok(Some(Self {
+            start: parse_index(start)?,
+            end: end.map(parse_index).transpose()?,
+            bits,
+        }))
+    }
+
+    /// The first and last bits that this range covers in a string of `len` bytes, or `None` if
+    /// it's empty
+    fn resolve(&self, len: usize) -> Option<(usize, usize)> {
+        let len = if self.bits { len * 8 } else { len } as i64;
+        let resolve = |index: i64| if index < 0 { len + index } else { index };
+        let start = resolve(self.start).max(0);
+        let end = resolve(self.end.unwrap_or(-1)).max(0).min(len - 1);
+
+        if start > end {
+            return None;
+        }
+        let (start, end) = (start as usize, end as usize);
+        Some(if self.bits {
+            (start, end)
+        } else {
+            (start * 8, end * 8 + 7)
+        })
+    }
+}
+
+/// The number of set bits from `first` to `last` inclusive
+fn count_bits(bytes: &[u8], first: usize, last: usize) -> usize {
+    let (first_byte, last_byte) = (first / 8, last / 8);
+    let all: u32 = bytes[first_byte..=last_byte]
+        .iter()
+        .map(|byte| byte.count_ones())
+        .sum();
+
+    // Take off the bits before `first` and after `last` within the bytes at either end
+    let before = bytes[first_byte] & !(0xff >> (first % 8));
+    let after = bytes[last_byte] & 0xffu8.checked_shr(last as u32 % 8 + 1).unwrap_or(0);
+    (all - before.count_ones() - after.count_ones()) as usize
+}
+
+/// The position of the first bit from `first` to `last` inclusive which is `bit`
+fn find_bit(bytes: &[u8], bit: bool, first: usize, last: usize) -> Option<usize> {
+    // Whole bytes like this can be skipped over
+    let skip = if bit { 0x00 } else { 0xff };
+
+    let mut i = first;
+    while i <= last {
+        let byte = bytes[i / 8];
+        if i.is_multiple_of(8) && i + 7 <= last && byte == skip {
+            i += 8;
+            continue;
+        }
+        if (byte & bit_mask(i) != 0) == bit {
+            return Some(i);
+        }
+        i += 1;
+    }
+
+    None
+}
+
+pub async fn bitcount(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, args @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let range = match BitRange::parse(args)? {
+        // `BITCOUNT` takes either both ends or neither
+        Some(BitRange { end: None, .. }) => {
+            return Err(RedisError::Syntax);
+        }
+        range => range,
+    };
+
+    let Some(value) = state.map.get(key) else {
+        return Ok(Value::from(0));
+    };
+
+    let count = with_bytes(&value.value, |bytes| {
+        let range = match range {
+            Some(range) => range.resolve(bytes.len()),
+            None => (!bytes.is_empty()).then(|| (0, bytes.len() * 8 - 1)),
+        };
+        range.map_or(0, |(first, last)| count_bits(bytes, first, last))
+    })?;
+
+    Ok(Value::from(count))
+}
+
+pub async fn bitpos(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, bit, args @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let bit = match bit.as_bytes() {
+        b"0" => false,
+        b"1" => true,
+        _ => return Err(RedisError::custom("ERR The bit argument must be 1 or 0.")),
+    };
+    let range = BitRange::parse(args)?;
+
+    // A missing key is an empty string, which is all clear bits
+    let Some(value) = state.map.get(key) else {
+        return Ok(Value::from(if bit { -1 } else { 0 }));
+    };
+
+    let pos = with_bytes(&value.value, |bytes| {
+        let range = range.unwrap_or(BitRange {
+            start: 0,
+            end: None,
+            bits: false,
+        });
+        let Some((first, last)) = range.resolve(bytes.len()) else {
+            return -1;
+        };
+
+        match find_bit(bytes, bit, first, last) {
+            Some(pos) => pos as i64,
+            // Past the end of the string is all clear bits, so if we weren't given an end then
+            // the first clear bit is the one just after the string
+            None if !bit && range.end.is_none() => bytes.len() as i64 * 8,
+            None => -1,
+        }
+    })?;
+
+    Ok(Value::from(pos))
+}
+
+#[derive(Debug, Clone, Copy)]
+enum BitOp {
+    And,
+    Or,
+    Xor,
+    Not,
+}
+
+pub async fn bitop(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [op, dest, keys @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let op = match &*op.to_uppercase() {
+        "AND" => BitOp::And,
+        "OR" => BitOp::Or,
+        "XOR" => BitOp::Xor,
+        "NOT" => BitOp::Not,
+        _ => return Err(RedisError::Syntax),
+    };
+    if matches!(op, BitOp::Not) && keys.len() != 1 {
+        return Err(RedisError::custom(
+            "ERR BITOP NOT must be called with a single source key.",
+        ));
+    }
+
+    // Missing keys are empty strings
+    let mut sources = Vec::with_capacity(keys.len());
+    for key in keys {
+        let bytes = match state.map.get(key) {
+            Some(value) => string_value(&value.value)?,
+            None => Vec::new(),
+        };
+        sources.push(bytes);
+    }
+
+    // Shorter strings are treated as if they were padded with zero bytes
+    let len = sources.iter().map(Vec::len).max().unwrap_or(0);
+    let byte = |source: &Vec<u8>, i: usize| source.get(i).copied().unwrap_or(0);
+    let result: Vec<u8> = (0..len)
+        .map(|i| {
+            let mut bytes = sources.iter().map(|source| byte(source, i));
+            match op {
+                BitOp::And => bytes.fold(0xff, |a, b| a & b),
+                BitOp::Or => bytes.fold(0, |a, b| a | b),
+                BitOp::Xor => bytes.fold(0, |a, b| a ^ b),
+                BitOp::Not => !bytes.next().expect("NOT has a single source"),
+            }
+        })
+        .collect();
+
+    if result.is_empty() {
+        state.map.remove(dest);
+    } else {
+        state.map.insert(
+            dest.clone(),
+            MapValue {
+                value: MapValueContent::String(result),
+                expires_at: None,
+            },
+        );
+    }
+
+    Ok(Value::from(len))
+}
@@ -0,0 +1,368 @@
+//! The machinery shared by the blocking commands (`BLPOP`, `BZPOPMIN`, `XREAD BLOCK` and friends).
+//!
+//! A blocked client registers itself in [`BlockedClients`] on every key it's waiting for.  Writes
+//! to those keys signal the clients in the order that they blocked, so a client that has been
+//! waiting longer is always served first.  Pops are handed their items directly by the write, so
+//! items are never popped by two clients at once, while anything else is woken to try again.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use dashmap::DashMap;
+use tokio::{sync::oneshot, time::Instant};
+
+use crate::{
+    bytes::Bytes, error::RedisError, resp::Value, ConnectionState, MapValueContent, State,
+};
+
+use super::ExecContext;
+
+/// What a blocked client makes of a write to one of the keys it's waiting on
+pub(crate) enum Wake {
+    /// The value isn't what the client is waiting for, so it stays blocked
+    NotReady,
+    /// The client was served, and replicas need to apply these writes to stay in sync, since the
+    /// blocked client's command isn't propagated itself
+    Served(Vec<Value>),
+    /// The client was already served through another key, or gave up waiting
+    Gone,
+}
+
+/// A blocked client, registered on every key it's waiting for
+pub(crate) trait Blocked: Send + Sync {
+    /// Serve the client from `value`, which was just written to `key`, if it's ready for them
+    fn wake(&self, key: &Bytes, value: &mut MapValueContent) -> Wake;
+}
+
+/// The clients blocked on each key, in the order that they blocked
+#[derive(Default)]
+pub struct BlockedClients(DashMap<Bytes, VecDeque<Arc<dyn Blocked>>>);
+
+impl std::fmt::Debug for BlockedClients {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BlockedClients")
+            .field("keys", &self.0.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl BlockedClients {
+    fn register(&self, keys: &[Bytes], client: &Arc<dyn Blocked>) {
+        for key in keys {
+            self.0
+                .entry(key.clone())
+                .or_default()
+                .push_back(Arc::clone(client));
+        }
+    }
+
+    fn unregister(&self, keys: &[Bytes], client: &Arc<dyn Blocked>) {
+        for key in keys {
+            if let Some(mut queue) = self.0.get_mut(key) {
+                queue.retain(|c| !std::ptr::addr_eq(Arc::as_ptr(c), Arc::as_ptr(client)));
+            }
+            self.0.remove_if(key, |_, queue| queue.is_empty());
+        }
+    }
+
+    /// Let the clients blocked on `key` know that `value` was just written there, in the order
+    /// that they blocked.  Returns the writes that replicas need to apply for the clients which
+    /// were served.
+    pub(crate) fn signal(&self, key: &Bytes, value: &mut MapValueContent) -> Vec<Value> {
+        let mut propagate = Vec::new();
+        let Some(mut queue) = self.0.get_mut(key) else {
+            return propagate;
+        };
+
+        queue.retain(|client| match client.wake(key, value) {
+            Wake::NotReady => true,
+            Wake::Served(writes) => {
+                propagate.extend(writes);
+                false
+            }
+            Wake::Gone => false,
+        });
+
+        propagate
+    }
+}
+
+/// Wait for `rx` until `deadline`, if there is one, returning what was received and whether we
+/// stopped because the client hung up
+async fn wait<T>(
+    conn_state: &ConnectionState,
+    rx: &mut oneshot::Receiver<T>,
+    deadline: Option<Instant>,
+) -> (Option<T>, bool) {
+    let mut closed = conn_state.closed.clone();
+    let hung_up = async {
+        match closed {
+            Some(ref mut closed) => {
+                let _ = closed.wait_for(|closed| *closed).await;
+            }
+            None => std::future::pending().await,
+        }
+    };
+    let timed_out = async {
+        match deadline {
+            Some(deadline) => tokio::time::sleep_until(deadline).await,
+            None => std::future::pending().await,
+        }
+    };
+
+    tokio::select! {
+        received = rx => (received.ok(), false),
+        _ = timed_out => (None, false),
+        _ = hung_up => (None, true),
+    }
+}
+
+/// The time that a command blocking for `timeout` (or forever) from now should give up
+pub(crate) fn deadline(timeout: Option<Duration>) -> Option<Instant> {
+    timeout.map(|timeout| Instant::now() + timeout)
+}
+
+/// A client which is woken once a write leaves one of its keys `ready`, so that it can try its
+/// command again
+struct Wakeup<F> {
+    ready: F,
+    tx: Mutex<Option<oneshot::Sender<()>>>,
+}
+
+impl<F> Blocked for Wakeup<F>
+where
+    F: Fn(&Bytes, &mut MapValueContent) -> bool + Send + Sync,
+{
+    fn wake(&self, key: &Bytes, value: &mut MapValueContent) -> Wake {
+        if !(self.ready)(key, value) {
+            return Wake::NotReady;
+        }
+        match self.tx.lock().expect("waiter lock poisoned").take() {
+            Some(tx) => {
+                let _ = tx.send(());
+                Wake::Served(Vec::new())
+            }
+            None => Wake::Gone,
+        }
+    }
+}
+
+/// Wait until one of `keys` is `ready`, either already or after being written to, giving up at
+/// `deadline` or if the client hangs up.  Returns whether any of them became ready.
+pub(crate) async fn wait_until(
+    state: &State,
+    conn_state: &ConnectionState,
+    keys: &[Bytes],
+    deadline: Option<Instant>,
+    ready: impl Fn(&Bytes, &mut MapValueContent) -> bool + Send + Sync + 'static,
+) -> bool {
+    let (tx, mut rx) = oneshot::channel();
+    let client: Arc<dyn Blocked> = Arc::new(Wakeup {
+        ready,
+        tx: Mutex::new(Some(tx)),
+    });
+    state.blocked.register(keys, &client);
+
+    // A write may have landed before we registered
+    let already = keys.iter().any(|key| {
+        state
+            .map
+            .get_mut(key)
+            .is_some_and(|mut value| matches!(client.wake(key, &mut value.value), Wake::Served(_)))
+    });
+    let woken = already || wait(conn_state, &mut rx, deadline).await.0.is_some();
+
+    state.blocked.unregister(keys, &client);
+    woken
+}
+
+/// A key, and whatever was popped from it
+pub(crate) type Popped<B> = (Bytes, <B as BlockingPop>::Popped);
+
+/// A kind of blocking pop, which also holds the options that the client blocked with (e.g. which
+/// end of a list to pop from)
+pub(crate) trait BlockingPop: Sized + Send + Sync + 'static {
+    /// The collection that this pops from
+    type Items;
+    /// What's popped from a single key for a single client
+    type Popped: Send + std::fmt::Debug;
+
+    /// The items held in `value`, or the `WRONGTYPE` error if it's of another type
+    fn items(value: &mut MapValueContent) -> Result<&mut Self::Items, RedisError>;
+
+    fn is_empty(items: &Self::Items) -> bool;
+
+    /// Pop from `items` for a client, returning `None` if there's nothing to pop
+    fn pop(&self, items: &mut Self::Items) -> Option<Self::Popped>;
+
+    /// Put back something which was taken by [`Self::pop`]
+    fn restore(&self, items: &mut Self::Items, popped: Self::Popped);
+
+    /// The command which replicas can use to repeat a pop
+    fn pop_command(&self, key: &Bytes, popped: &Self::Popped) -> Value;
+
+    /// Write something which was popped for a client that has since gone away back to `key`,
+    /// setting up propagation of the write as usual
+    fn give_back(
+        &self,
+        state: &State,
+        conn_state: &mut ConnectionState,
+        key: Bytes,
+        popped: Self::Popped,
+    ) -> anyhow::Result<()>;
+}
+
+/// A client blocked on a pop.  The first write to any of its keys takes the sender, so it's only
+/// ever served once.
+struct Waiter<B: BlockingPop> {
+    how: B,
+    tx: Mutex<Option<oneshot::Sender<Popped<B>>>>,
+}
+
+impl<B: BlockingPop> Waiter<B> {
+    fn take_tx(&self) -> Option<oneshot::Sender<Popped<B>>> {
+        self.tx.lock().expect("waiter lock poisoned").take()
+    }
+}
+
+impl<B: BlockingPop> Blocked for Waiter<B> {
+    fn wake(&self, key: &Bytes, value: &mut MapValueContent) -> Wake {
+        let Ok(items) = B::items(value) else {
+            return Wake::NotReady;
+        };
+        if B::is_empty(items) {
+            return Wake::NotReady;
+        }
+        let Some(tx) = self.take_tx() else {
+            return Wake::Gone;
+        };
+        let Some(popped) = self.how.pop(items) else {
+            *self.tx.lock().expect("waiter lock poisoned") = Some(tx);
+            return Wake::NotReady;
+        };
+
+        let command = self.how.pop_command(key, &popped);
+        match tx.send((key.clone(), popped)) {
+            Ok(()) => Wake::Served(vec![command]),
+            Err((_, popped)) => {
+                self.how.restore(items, popped);
+                Wake::Gone
+            }
+        }
+    }
+}
+
+/// Pop from the first key in `keys` which has anything to pop
+pub(crate) fn pop_first<B: BlockingPop>(
+    state: &State,
+    keys: &[Bytes],
+    how: &B,
+) -> Result<Option<Popped<B>>, RedisError> {
+    for key in keys {
+        let Some(mut value) = state.map.get_mut(key) else {
+            continue;
+        };
+        let items = B::items(&mut value.value)?;
+
+        let popped = how.pop(items);
+        if B::is_empty(items) {
+            drop(value);
+            state.map.remove(key);
+        }
+        if let Some(popped) = popped {
+            return Ok(Some((key.clone(), popped)));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Whether any of `keys` has something to pop
+fn any_ready<B: BlockingPop>(state: &State, keys: &[Bytes]) -> bool {
+    keys.iter().any(|key| {
+        state
+            .map
+            .get_mut(key)
+            .is_some_and(|mut value| B::items(&mut value.value).is_ok_and(|i| !B::is_empty(i)))
+    })
+}
+
+/// Pop from the first key in `keys` which has anything to pop, waiting for up to `timeout` (or
+/// forever) for one of them to be written to if there's nothing there yet.  Gives up early if
+/// the client hangs up, and doesn't wait at all if the context can't block.
+pub(crate) async fn block<B: BlockingPop>(
+    state: &State,
+    conn_state: &mut ConnectionState,
+    keys: &[Bytes],
+    how: B,
+    timeout: Option<Duration>,
+    context: ExecContext,
+) -> Result<Option<Popped<B>>, RedisError> {
+    let deadline = deadline(timeout);
+    let waiter = Arc::new(Waiter {
+        how,
+        tx: Mutex::new(None),
+    });
+    let client: Arc<dyn Blocked> = waiter.clone();
+
+    loop {
+        match pop_first(state, keys, &waiter.how)? {
+            Some((key, popped)) => {
+                conn_state.propagate_as = Some(vec![waiter.how.pop_command(&key, &popped)]);
+                return Ok(Some((key, popped)));
+            }
+            None if !context.can_block() => return Ok(None),
+            None => {}
+        }
+
+        let (tx, mut rx) = oneshot::channel();
+        *waiter.tx.lock().expect("waiter lock poisoned") = Some(tx);
+        state.blocked.register(keys, &client);
+
+        // A write may have landed between our pop and registering.  If so, withdraw and retry,
+        // unless that write has already served us.
+        if any_ready::<B>(state, keys) && waiter.take_tx().is_some() {
+            state.blocked.unregister(keys, &client);
+            continue;
+        }
+
+        // Whoever serves us propagates the pop
+        conn_state.propagate_as = Some(Vec::new());
+
+        let (received, gave_up) = wait(conn_state, &mut rx, deadline).await;
+
+        // We may have been served just as we gave up
+        let received = received.or_else(|| match waiter.take_tx() {
+            Some(_) => None,
+            None => rx.try_recv().ok(),
+        });
+        state.blocked.unregister(keys, &client);
+
+        // Anything we were handed as the client left goes back where it came from, and on to the
+        // next client in line
+        if gave_up {
+            if let Some((key, popped)) = received {
+                waiter.how.give_back(state, conn_state, key, popped)?;
+            }
+            return Ok(None);
+        }
+
+        return Ok(received);
+    }
+}
+
+/// Parse a blocking timeout in seconds, where 0 means forever
+pub fn parse_timeout(timeout: &Bytes) -> Result<Option<Duration>, RedisError> {
+    let Ok(timeout) = timeout.parse::<f64>() else {
+        return Err(RedisError::custom(
+            "ERR timeout is not a float or out of range",
+        ));
+    };
+    if timeout < 0. {
+        return Err(RedisError::custom("ERR timeout is negative"));
+    }
+    Ok((timeout > 0.).then(|| Duration::from_secs_f64(timeout)))
+}
@@ -0,0 +1,72 @@
+use std::sync::Arc;
+
+use crate::{
+    bytes::Bytes,
+    error::RedisError,
+    keyspace::{self, CLUSTER_SLOTS},
+    resp::Value,
+    ConnectionState, State,
+};
+
+fn parse_slot(slot: &Bytes) -> Option<u16> {
+    slot.parse().ok().filter(|&s| s < CLUSTER_SLOTS)
+}
+
+pub async fn cluster(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [subcmd, args @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    if !state.config().cluster_enabled {
+        return Err(RedisError::custom(
+            "ERR This instance has cluster support disabled",
+        ));
+    }
+
+    let ret = match (&*subcmd.to_uppercase(), args) {
+        ("KEYSLOT", [key]) => Value::from(keyspace::key_slot(key)),
+        ("COUNTKEYSINSLOT", [slot]) => {
+            let Some(slot) = parse_slot(slot) else {
+                return Err(RedisError::custom("ERR Invalid slot"));
+            };
+            Value::from(
+                state
+                    .map
+                    .count_keys_in_slot(slot)
+                    .expect("cluster mode is enabled"),
+            )
+        }
+        ("GETKEYSINSLOT", [slot, count]) => {
+            let Some(slot) = parse_slot(slot) else {
+                return Err(RedisError::custom("ERR Invalid slot"));
+            };
+            let Ok(count) = count.parse() else {
+                return Err(RedisError::custom("ERR Invalid number of keys"));
+            };
+            state
+                .map
+                .keys_in_slot(slot, count)
+                .expect("cluster mode is enabled")
+                .into_iter()
+                .map(Value::from)
+                .collect()
+        }
+        (subcmd @ ("KEYSLOT" | "COUNTKEYSINSLOT" | "GETKEYSINSLOT"), _) => {
+            return Err(super::wrong_arity(&format!(
+                "cluster|{}",
+                subcmd.to_lowercase()
+            )))
+        }
+        _ => {
+            return Err(RedisError::custom(format!(
+                "ERR unknown subcommand '{subcmd}'. Try CLUSTER HELP."
+            )))
+        }
+    };
+
+    Ok(ret)
+}
@@ -0,0 +1,224 @@
+use std::sync::Arc;
+
+use crate::{
+    bytes::Bytes, client::ClientType, error::RedisError, resp::Value, ConnectionMode,
+    ConnectionState, State,
+};
+
+const WRONGPASS: &str = "WRONGPASS invalid username-password pair or user is disabled.";
+
+/// Whether `name` can be given to a client, which rules out spaces, newlines and other special
+/// characters so that it reads well in `CLIENT LIST`
+fn valid_client_name(name: &Bytes) -> bool {
+    name.iter().all(|&b| (b'!'..=b'~').contains(&b))
+}
+
+/// `PING [message]`, which replies like a message when a RESP2 client is subscribed, since that's
+/// all it can read then
+pub async fn ping(
+    _: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let message = match args {
+        [] => None,
+        [message] => Some(message),
+        _ => return Err(super::wrong_arity("ping")),
+    };
+
+    Ok(match (conn_state.command_mode(), message) {
+        (ConnectionMode::Normal, None) => Value::simple_string("PONG"),
+        (ConnectionMode::Normal, Some(message)) => Value::bulk_string(message),
+        (ConnectionMode::Subscribed, message) => Value::Array(vec![
+            Value::from("pong"),
+            message.map_or_else(|| Value::from(""), Value::bulk_string),
+        ]),
+    })
+}
+
+/// `ECHO message`
+pub async fn echo(
+    _: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [message] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    Ok(Value::bulk_string(message))
+}
+
+/// `AUTH [username] password`
+pub async fn auth(
+    state: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let default = Bytes::from("default");
+    let (username, password) = match args {
+        [password] => {
+            if state
+                .acl
+                .user("default")
+                .is_none_or(|user| user.is_nopass())
+            {
+                return Err(RedisError::custom("ERR AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?"));
+            }
+            (&default, password)
+        }
+        [username, password] => (username, password),
+        _ => return Err(RedisError::Syntax),
+    };
+
+    if !state.acl.authenticate(username, password) {
+        return Err(RedisError::custom(WRONGPASS));
+    }
+
+    conn_state.user = Some(username.to_string());
+    Ok(Value::simple_string("OK"))
+}
+
+/// `HELLO [protover [AUTH username password] [SETNAME clientname]]`, which switches the
+/// connection between RESP2 and RESP3 and replies with some details about the server
+pub async fn hello(
+    state: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let (protocol, options) = match args {
+        [] => (None, &[][..]),
+        [protover, options @ ..] => match protover.parse::<u8>() {
+            Ok(protocol @ (2 | 3)) => (Some(protocol), options),
+            Ok(_) => return Err(RedisError::custom("NOPROTO unsupported protocol version")),
+            Err(_) => {
+                return Err(RedisError::custom(
+                    "ERR Protocol version is not an integer or out of range",
+                ))
+            }
+        },
+    };
+
+    let mut credentials = None;
+    let mut name = None;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match &*option.to_uppercase() {
+            "AUTH" => match (options.next(), options.next()) {
+                (Some(username), Some(password)) => credentials = Some((username, password)),
+                _ => return Err(RedisError::Syntax),
+            },
+            "SETNAME" => match options.next() {
+                Some(clientname) => name = Some(clientname),
+                None => return Err(RedisError::Syntax),
+            },
+            _ => return Err(RedisError::Syntax),
+        }
+    }
+
+    // Nothing changes unless every option is good
+    match credentials {
+        Some((username, password)) if !state.acl.authenticate(username, password) => {
+            return Err(RedisError::custom(WRONGPASS));
+        }
+        Some(_) => {}
+        None if conn_state.user.is_none() => {
+            return Err(RedisError::custom("NOAUTH HELLO must be called with the client already authenticated, otherwise the HELLO <proto> AUTH <user> <pass> option can be used to authenticate the client and select the RESP protocol version at the same time"));
+        }
+        None => {}
+    }
+    if name.is_some_and(|name| !valid_client_name(name)) {
+        return Err(RedisError::custom(
+            "ERR Client names cannot contain spaces, newlines or special characters.",
+        ));
+    }
+
+    if let Some((username, _)) = credentials {
+        conn_state.user = Some(username.to_string());
+    }
+    if let Some(name) = name {
+        conn_state.name = Some(name.to_string());
+    }
+    if let Some(protocol) = protocol {
+        conn_state.set_protocol(protocol);
+    }
+
+    Ok(Value::Map(vec![
+        (Value::from("server"), Value::from("redis")),
+        (
+            Value::from("version"),
+            Value::from(env!("CARGO_PKG_VERSION")),
+        ),
+        (Value::from("proto"), Value::from(conn_state.protocol())),
+        (Value::from("mode"), Value::from("standalone")),
+        (Value::from("role"), Value::from(state.role.to_string())),
+        (Value::from("modules"), Value::empty_array()),
+    ]))
+}
+
+/// `CLIENT ID|GETNAME|SETNAME|INFO` about the connection that it's run on, and `CLIENT LIST` about
+/// every connection
+pub async fn client(
+    state: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let (subcommand, args) = args.split_first().expect("arity is checked");
+
+    let ret = match (&*subcommand.to_uppercase(), args) {
+        ("ID", []) => Value::Integer(conn_state.id as i64),
+        ("GETNAME", []) => conn_state
+            .name
+            .as_deref()
+            .map(Value::from)
+            .unwrap_or_default(),
+        ("INFO", []) => Value::from(format!("{}\n", conn_state.client_info())),
+        ("LIST", filter) => {
+            let client_type = match filter {
+                [] => None,
+                [option, client_type] if option.eq_ignore_ascii_case(b"TYPE") => {
+                    match client_type.to_string_lossy().parse::<ClientType>() {
+                        Ok(client_type) => Some(client_type),
+                        Err(()) => {
+                            return Err(RedisError::custom(format!(
+                                "ERR Unknown client type '{client_type}'"
+                            )))
+                        }
+                    }
+                }
+                _ => return Err(RedisError::Syntax),
+            };
+            let list: String = state
+                .clients
+                .list()
+                .into_iter()
+                .filter(|client| client_type.is_none_or(|t| client.client_type() == t))
+                .map(|client| format!("{client}\n"))
+                .collect();
+            Value::from(list)
+        }
+        ("SETNAME", [name]) => {
+            if !valid_client_name(name) {
+                return Err(RedisError::custom(
+                    "ERR Client names cannot contain spaces, newlines or special characters.",
+                ));
+            }
+            // Like Redis, an empty name removes it
+            conn_state.name = (!name.is_empty()).then(|| name.to_string());
+            Value::simple_string("OK")
+        }
+        (subcommand @ ("ID" | "GETNAME" | "INFO" | "SETNAME"), _) => {
+            return Err(super::wrong_arity(&format!(
+                "client|{}",
+                subcommand.to_lowercase()
+            )))
+        }
+        _ => {
+            return Err(RedisError::custom(format!(
+                "ERR unknown subcommand '{subcommand}'. Try CLIENT HELP."
+            )))
+        }
+    };
+
+    Ok(ret)
+}
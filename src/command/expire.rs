@@ -0,0 +1,257 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{bytes::Bytes, error::RedisError, resp::Value, ConnectionState, State};
+
+/// Milliseconds since the unix epoch
+pub fn unix_millis(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_millis() as i64,
+        Err(e) => -(e.duration().as_millis() as i64),
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ExpireCondition {
+    nx: bool,
+    xx: bool,
+    gt: bool,
+    lt: bool,
+}
+
+impl ExpireCondition {
+    pub fn parse(args: &[Bytes]) -> Result<Self, RedisError> {
+        let mut cond = Self::default();
+        for arg in args {
+            match &*arg.to_uppercase() {
+                "NX" => cond.nx = true,
+                "XX" => cond.xx = true,
+                "GT" => cond.gt = true,
+                "LT" => cond.lt = true,
+                _ => return Err(RedisError::custom(format!("ERR Unsupported option {arg}"))),
+            }
+        }
+
+        if cond.nx && (cond.xx || cond.gt || cond.lt) {
+            return Err(RedisError::custom(
+                "ERR NX and XX, GT or LT options at the same time are not compatible",
+            ));
+        }
+        if cond.gt && cond.lt {
+            return Err(RedisError::custom(
+                "ERR GT and LT options at the same time are not compatible",
+            ));
+        }
+
+        Ok(cond)
+    }
+
+    /// Whether a key whose current expiry is `current` may be given the expiry `new`.  Keys
+    /// without an expiry are treated as having an infinite TTL.
+    pub fn allows(self, current: Option<i64>, new: i64) -> bool {
+        match current {
+            None => !self.xx && !self.gt,
+            Some(_) if self.nx => false,
+            Some(current) if self.gt => new > current,
+            Some(current) if self.lt => new < current,
+            Some(_) => true,
+        }
+    }
+}
+
+/// Set the expiry of `key` to `at` (unix millis), following the NX/XX/GT/LT rules in `args`.
+/// An expiry in the past deletes the key.
+///
+/// It's propagated as `PEXPIREAT`, or `DEL` if the key was deleted, so that replicas and the AOF
+/// end up with the same deadline as we have, however long it takes them to get it.
+fn expire_at(
+    state: &State,
+    conn_state: &mut ConnectionState,
+    key: &Bytes,
+    at: i64,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let cond = ExpireCondition::parse(args)?;
+
+    // Nothing changes unless the key exists and the condition allows it
+    conn_state.propagate_as = Some(Vec::new());
+
+    let Some(mut value) = state.map.get_mut(key) else {
+        return Ok(Value::from(0));
+    };
+
+    if !cond.allows(value.expires_at.map(unix_millis), at) {
+        return Ok(Value::from(0));
+    }
+
+    let propagated = if at <= unix_millis(SystemTime::now()) {
+        drop(value);
+        state.map.remove(key);
+        Value::from_iter([Value::from("DEL"), Value::from(key)])
+    } else {
+        value.expires_at = Some(UNIX_EPOCH + Duration::from_millis(at as u64));
+        Value::from_iter([
+            Value::from("PEXPIREAT"),
+            Value::from(key),
+            Value::from(at.to_string()),
+        ])
+    };
+    conn_state.propagate_as = Some(vec![propagated]);
+
+    Ok(Value::from(1))
+}
+
+/// Parse a relative expire time in `unit_ms` milliseconds into an absolute unix-millis time
+pub fn relative_expire(time: &Bytes, unit_ms: i64, command: &str) -> Result<i64, RedisError> {
+    let time: i64 = time.parse().map_err(|_| RedisError::NotAnInteger)?;
+
+    time.checked_mul(unit_ms)
+        .and_then(|ms| ms.checked_add(unix_millis(SystemTime::now())))
+        .ok_or_else(|| {
+            RedisError::custom(format!("ERR invalid expire time in '{command}' command"))
+        })
+}
+
+pub async fn expire(
+    state: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, seconds, args @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let at = relative_expire(seconds, 1000, "expire")?;
+    expire_at(&state, conn_state, key, at, args)
+}
+
+pub async fn pexpire(
+    state: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, millis, args @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let at = relative_expire(millis, 1, "pexpire")?;
+    expire_at(&state, conn_state, key, at, args)
+}
+
+/// Parse an absolute unix time in `unit_ms` milliseconds into unix millis
+pub fn absolute_expire(time: &Bytes, unit_ms: i64, command: &str) -> Result<i64, RedisError> {
+    let time: i64 = time.parse().map_err(|_| RedisError::NotAnInteger)?;
+
+    time.checked_mul(unit_ms).ok_or_else(|| {
+        RedisError::custom(format!("ERR invalid expire time in '{command}' command"))
+    })
+}
+
+pub async fn expireat(
+    state: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, timestamp, args @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let at = absolute_expire(timestamp, 1000, "expireat")?;
+    expire_at(&state, conn_state, key, at, args)
+}
+
+pub async fn pexpireat(
+    state: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, timestamp, args @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let at = absolute_expire(timestamp, 1, "pexpireat")?;
+    expire_at(&state, conn_state, key, at, args)
+}
+
+/// The expiry of `key` in unix millis, or the -1/-2 sentinels for no expiry/no key
+fn expire_time(state: &State, key: &Bytes) -> Result<i64, i64> {
+    let value = state.map.get(key).ok_or(-2)?;
+    value.expires_at.map(unix_millis).ok_or(-1)
+}
+
+pub async fn expiretime(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let at = expire_time(&state, key).map_or_else(|e| e, |ms| ms / 1000);
+    Ok(Value::from(at))
+}
+
+pub async fn pexpiretime(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let at = expire_time(&state, key).unwrap_or_else(|e| e);
+    Ok(Value::from(at))
+}
+
+/// The remaining time-to-live of `key` in millis, or the -1/-2 sentinels for no expiry/no key
+fn ttl_millis(state: &State, key: &Bytes) -> Result<i64, i64> {
+    let at = expire_time(state, key)?;
+    Ok((at - unix_millis(SystemTime::now())).max(0))
+}
+
+pub async fn ttl(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    // Round to the nearest second, like Redis does
+    let ttl = ttl_millis(&state, key).map_or_else(|e| e, |ms| (ms + 500) / 1000);
+    Ok(Value::from(ttl))
+}
+
+pub async fn pttl(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let ttl = ttl_millis(&state, key).unwrap_or_else(|e| e);
+    Ok(Value::from(ttl))
+}
+
+pub async fn persist(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let Some(mut value) = state.map.get_mut(key) else {
+        return Ok(Value::from(0));
+    };
+
+    Ok(Value::from(value.expires_at.take().is_some() as i64))
+}
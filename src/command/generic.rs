@@ -0,0 +1,264 @@
+use std::sync::Arc;
+
+use super::args::ArgParser;
+use crate::{
+    bytes::Bytes, error::RedisError, glob, keyspace::free_lazily, resp::Value, ConnectionState,
+    State,
+};
+
+/// Remove each of `keys`, returning how many existed.  If `lazy` is set, large values are freed
+/// in the background.
+fn remove_keys(state: &State, keys: &[Bytes], lazy: bool) -> usize {
+    let mut removed = 0;
+    for key in keys {
+        if let Some((_, value)) = state.map.remove(key) {
+            if lazy {
+                free_lazily(value);
+            }
+            removed += 1;
+        }
+    }
+    removed
+}
+
+pub async fn del(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    if args.is_empty() {
+        return Err(RedisError::WrongArity);
+    }
+
+    let lazy = state.config().lazyfree_lazy_user_del;
+    Ok(Value::from(remove_keys(&state, args, lazy)))
+}
+
+pub async fn unlink(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    if args.is_empty() {
+        return Err(RedisError::WrongArity);
+    }
+
+    Ok(Value::from(remove_keys(&state, args, true)))
+}
+
+/// Move the value at `src` to `dst`, keeping its TTL.  Returns `Err` with the reply if `src`
+/// doesn't exist, and `Ok(false)` if `nx` is set and `dst` already exists.
+fn rename_inner(state: &State, src: &Bytes, dst: &Bytes, nx: bool) -> Result<bool, RedisError> {
+    if state.map.get(src).is_none() {
+        return Err(RedisError::NoSuchKey);
+    }
+
+    if src == dst {
+        return Ok(!nx);
+    }
+
+    if nx && state.map.get(dst).is_some() {
+        return Ok(false);
+    }
+
+    let Some((_, value)) = state.map.remove(src) else {
+        return Err(RedisError::NoSuchKey);
+    };
+    state.map.insert(dst.clone(), value);
+    Ok(true)
+}
+
+pub async fn rename(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [src, dst] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let _ = rename_inner(&state, src, dst, false)?;
+    Ok(Value::simple_string("OK"))
+}
+
+pub async fn renamenx(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [src, dst] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let renamed = rename_inner(&state, src, dst, true)?;
+    Ok(Value::from(renamed as i64))
+}
+
+pub async fn copy(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [src, dst, args @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let mut replace = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match &*arg.to_uppercase() {
+            "REPLACE" => replace = true,
+            "DB" => {
+                let Some(db) = args.next() else {
+                    return Err(RedisError::Syntax);
+                };
+                let Ok(db) = db.parse::<i64>() else {
+                    return Err(RedisError::NotAnInteger);
+                };
+                // There's only a single database
+                if db != 0 {
+                    return Err(RedisError::custom("ERR DB index is out of range"));
+                }
+            }
+            _ => return Err(RedisError::Syntax),
+        }
+    }
+
+    if src == dst {
+        return Err(RedisError::custom(
+            "ERR source and destination objects are the same",
+        ));
+    }
+
+    let Some(value) = state.map.get(src).map(|v| v.clone()) else {
+        return Ok(Value::from(0));
+    };
+
+    if !replace && state.map.get(dst).is_some() {
+        return Ok(Value::from(0));
+    }
+
+    state.map.insert(dst.clone(), value);
+    Ok(Value::from(1))
+}
+
+pub async fn dbsize(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    Ok(Value::from(state.map.key_count()))
+}
+
+pub async fn object(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [subcommand, args @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    match (&*subcommand.to_uppercase(), args) {
+        ("ENCODING", [key]) => Ok(state
+            .map
+            .get(key)
+            .map(|value| Value::from(value.value.encoding()))
+            .unwrap_or_default()),
+        _ => Err(RedisError::custom(format!(
+            "ERR unknown subcommand or wrong number of arguments for '{subcommand}'. Try OBJECT HELP."
+        ))),
+    }
+}
+
+/// Shared by `FLUSHDB` and `FLUSHALL`, since there's only a single database
+fn flush(state: &State, args: &[Bytes]) -> Result<Value, RedisError> {
+    let lazy = match args {
+        [] => false,
+        [mode] if mode.eq_ignore_ascii_case(b"sync") => false,
+        [mode] if mode.eq_ignore_ascii_case(b"async") => true,
+        _ => return Err(RedisError::Syntax),
+    };
+
+    let values = state.map.take_all();
+    if lazy {
+        // Freeing a large dataset can take a while, so do it off of the connection's task
+        tokio::task::spawn_blocking(move || drop(values));
+    }
+
+    Ok(Value::simple_string("OK"))
+}
+
+pub async fn flushdb(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    flush(&state, args)
+}
+
+pub async fn flushall(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    flush(&state, args)
+}
+
+pub async fn scan(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [cursor, args @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let Ok(cursor) = cursor.parse::<u64>() else {
+        return Err(RedisError::custom("ERR invalid cursor"));
+    };
+
+    let mut pattern = None;
+    let mut count = 10;
+    let mut ty = None;
+
+    let mut args = ArgParser::new(args);
+    while let Some(arg) = args.keyword() {
+        match &*arg {
+            "MATCH" => pattern = Some(args.value()?),
+            "TYPE" => ty = Some(args.value()?.to_lowercase()),
+            "COUNT" => match args.int::<usize>()? {
+                0 => return Err(RedisError::Syntax),
+                n => count = n,
+            },
+            _ => return Err(RedisError::Syntax),
+        }
+    }
+
+    let (cursor, keys) = state.map.scan(cursor, count);
+
+    // Like Redis, filters are applied after the keys are collected, so a call may return fewer
+    // than `COUNT` keys (or none) without the scan being finished
+    let keys: Vec<Value> = keys
+        .into_iter()
+        .filter(|key| pattern.is_none_or(|p| glob::matches(p, key)))
+        .filter(|key| {
+            ty.as_deref().is_none_or(|ty| {
+                state
+                    .map
+                    .get(key)
+                    .is_some_and(|v| v.value.type_name() == ty)
+            })
+        })
+        .map(Value::from)
+        .collect();
+
+    Ok(Value::from_iter([
+        Value::bulk_string(cursor.to_string()),
+        Value::from(keys),
+    ]))
+}
@@ -0,0 +1,233 @@
+//! The geo commands, which are built on sorted sets.
+//!
+//! Coordinates are stored as 52-bit geohashes in the scores of a sorted set: 26 bits each of
+//! longitude and latitude, interleaved so that nearby points have nearby scores.
+
+use std::sync::Arc;
+
+use crate::{bytes::Bytes, error::RedisError, resp::Value, ConnectionState, State};
+
+use super::sorted_set::{self, with_zset};
+
+const STEP: u32 = 26;
+
+const LON_RANGE: (f64, f64) = (-180., 180.);
+/// Latitudes are limited to what can be shown on a Web Mercator map
+const LAT_RANGE: (f64, f64) = (-85.05112878, 85.05112878);
+/// The standard geohash latitude range, used for the strings returned by `GEOHASH`
+const STANDARD_LAT_RANGE: (f64, f64) = (-90., 90.);
+
+/// The radius of the Earth in metres, as used by Redis
+const EARTH_RADIUS: f64 = 6372797.560856;
+
+const GEOALPHABET: &[u8; 32] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+/// Spread the bits of `x` out so that they occupy the even bits of the result
+fn spread(x: u32) -> u64 {
+    let mut x = x as u64;
+    x = (x | (x << 16)) & 0x0000ffff0000ffff;
+    x = (x | (x << 8)) & 0x00ff00ff00ff00ff;
+    x = (x | (x << 4)) & 0x0f0f0f0f0f0f0f0f;
+    x = (x | (x << 2)) & 0x3333333333333333;
+    (x | (x << 1)) & 0x5555555555555555
+}
+
+/// The inverse of [`spread`]
+fn squash(x: u64) -> u32 {
+    let mut x = x & 0x5555555555555555;
+    x = (x | (x >> 1)) & 0x3333333333333333;
+    x = (x | (x >> 2)) & 0x0f0f0f0f0f0f0f0f;
+    x = (x | (x >> 4)) & 0x00ff00ff00ff00ff;
+    x = (x | (x >> 8)) & 0x0000ffff0000ffff;
+    ((x | (x >> 16)) & 0x00000000ffffffff) as u32
+}
+
+/// Which cell `x` falls into when `range` is split into `2^STEP` cells
+fn cell(x: f64, (min, max): (f64, f64)) -> u32 {
+    (((x - min) / (max - min)) * (1u64 << STEP) as f64) as u32
+}
+
+/// The centre of `cell` when `range` is split into `2^STEP` cells
+fn cell_centre(cell: u32, (min, max): (f64, f64)) -> f64 {
+    let size = (max - min) / (1u64 << STEP) as f64;
+    (min + size * (cell as f64 + 0.5)).clamp(min, max)
+}
+
+/// The geohash of a point, with latitude in the even bits and longitude in the odd bits
+fn encode(lon: f64, lat: f64, lat_range: (f64, f64)) -> u64 {
+    spread(cell(lat, lat_range)) | (spread(cell(lon, LON_RANGE)) << 1)
+}
+
+/// The longitude and latitude at the centre of the cell that `hash` refers to
+fn decode(hash: u64) -> (f64, f64) {
+    let lat = cell_centre(squash(hash), LAT_RANGE);
+    let lon = cell_centre(squash(hash >> 1), LON_RANGE);
+    (lon, lat)
+}
+
+/// The great-circle distance between two points in metres, using the haversine formula
+fn distance((lon1, lat1): (f64, f64), (lon2, lat2): (f64, f64)) -> f64 {
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let u = ((lat2 - lat1) / 2.).sin();
+    let v = ((lon2 - lon1).to_radians() / 2.).sin();
+    2. * EARTH_RADIUS * (u * u + lat1.cos() * lat2.cos() * v * v).sqrt().asin()
+}
+
+/// The number of metres in `unit`
+fn unit_to_metres(unit: &str) -> Option<f64> {
+    Some(match &*unit.to_lowercase() {
+        "m" => 1.,
+        "km" => 1000.,
+        "mi" => 1609.34,
+        "ft" => 0.3048,
+        _ => return None,
+    })
+}
+
+/// Format a coordinate like Redis does, with up to 17 decimal places
+fn format_coordinate(x: f64) -> String {
+    let s = format!("{x:.17}");
+    s.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+/// The position of `member`, decoded from its score
+fn position(state: &State, key: &Bytes, member: &Bytes) -> Result<Option<(f64, f64)>, RedisError> {
+    with_zset(state, key, |set| {
+        member
+            .to_str()
+            .and_then(|member| set.score(member))
+            .map(|score| decode(score as u64))
+    })
+}
+
+pub async fn geoadd(
+    state: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, args @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let opts_len = args
+        .iter()
+        .take_while(|arg| matches!(&*arg.to_uppercase(), "NX" | "XX" | "CH"))
+        .count();
+    let (opts, triples) = args.split_at(opts_len);
+
+    if triples.is_empty() || triples.len() % 3 != 0 {
+        return Err(RedisError::Syntax);
+    }
+
+    // Rewrite the command as a `ZADD` with each point's geohash as its score
+    let mut zadd_args = vec![key.clone()];
+    zadd_args.extend(opts.iter().cloned());
+    for triple in triples.chunks_exact(3) {
+        let [lon, lat, member] = triple else {
+            unreachable!("chunks are of length 3");
+        };
+        let (Ok(lon), Ok(lat)) = (lon.parse::<f64>(), lat.parse::<f64>()) else {
+            return Err(RedisError::NotAFloat);
+        };
+        if !(LON_RANGE.0..=LON_RANGE.1).contains(&lon)
+            || !(LAT_RANGE.0..=LAT_RANGE.1).contains(&lat)
+        {
+            return Err(RedisError::custom(format!(
+                "ERR invalid longitude,latitude pair {lon:.6},{lat:.6}"
+            )));
+        }
+
+        zadd_args.push(Bytes::from(encode(lon, lat, LAT_RANGE).to_string()));
+        zadd_args.push(member.clone());
+    }
+
+    sorted_set::zadd(state, conn_state, &zadd_args).await
+}
+
+pub async fn geopos(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, members @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let positions = members
+        .iter()
+        .map(|member| {
+            Ok(match position(&state, key, member)? {
+                Some((lon, lat)) => Value::from(vec![
+                    Value::from(format_coordinate(lon)),
+                    Value::from(format_coordinate(lat)),
+                ]),
+                None => Value::Null,
+            })
+        })
+        .collect::<Result<Vec<_>, RedisError>>();
+
+    positions.map(Value::from)
+}
+
+pub async fn geodist(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let (key, from, to, unit) = match args {
+        [key, from, to] => (key, from, to, "m"),
+        [key, from, to, unit] => (key, from, to, &*unit.to_string_lossy()),
+        _ => return Err(RedisError::Syntax),
+    };
+
+    let Some(unit) = unit_to_metres(unit) else {
+        return Err(RedisError::custom(
+            "ERR unsupported unit provided. please use M, KM, FT, MI",
+        ));
+    };
+
+    let (from, to) = match (position(&state, key, from), position(&state, key, to)) {
+        (Ok(Some(from)), Ok(Some(to))) => (from, to),
+        (Err(e), _) | (_, Err(e)) => return Err(e),
+        _ => return Ok(Value::Null),
+    };
+
+    Ok(Value::from(format!("{:.4}", distance(from, to) / unit)))
+}
+
+pub async fn geohash(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, members @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let hashes = members
+        .iter()
+        .map(|member| {
+            let Some((lon, lat)) = position(&state, key, member)? else {
+                return Ok(Value::Null);
+            };
+
+            // Re-encode against the standard latitude range, so that the string matches what
+            // other geohash implementations produce.  We only have 52 bits, so the eleventh
+            // character is always the first in the alphabet.
+            let hash = encode(lon, lat, STANDARD_LAT_RANGE);
+            let s: String = (0..11)
+                .map(|i| {
+                    let index = if i == 10 {
+                        0
+                    } else {
+                        (hash >> (52 - (i + 1) * 5)) & 0x1f
+                    };
+                    GEOALPHABET[index as usize] as char
+                })
+                .collect();
+            Ok(Value::from(s))
+        })
+        .collect::<Result<Vec<_>, RedisError>>();
+
+    hashes.map(Value::from)
+}
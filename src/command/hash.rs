@@ -0,0 +1,580 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use rand::seq::{IndexedRandom, IteratorRandom, SliceRandom};
+
+use super::expire::{absolute_expire, relative_expire, unix_millis, ExpireCondition};
+use crate::{
+    bytes::Bytes, error::RedisError, resp::Value, ConnectionState, HashField, MapValue,
+    MapValueContent, State,
+};
+
+/// Run `f` on the hash at `key`, treating a missing key as an empty hash.  Fields which have
+/// expired but not yet been removed are still in the map, so readers should go through
+/// [`get_field`] and [`live_fields`].
+fn with_hash<T>(
+    state: &State,
+    key: &Bytes,
+    f: impl FnOnce(&HashMap<String, HashField>) -> T,
+) -> Result<T, RedisError> {
+    match state.map.get(key) {
+        Some(value) => value.value.as_hash().map(f),
+        None => Ok(f(&HashMap::new())),
+    }
+}
+
+/// The value of `field`, unless it doesn't exist or has expired
+fn get_field<'a>(hash: &'a HashMap<String, HashField>, field: &Bytes) -> Option<&'a String> {
+    hash.get(field.to_str()?)
+        .filter(|f| !f.is_expired())
+        .map(|f| &f.value)
+}
+
+/// Every field which hasn't expired, along with its value
+fn live_fields(hash: &HashMap<String, HashField>) -> impl Iterator<Item = (&String, &String)> {
+    hash.iter()
+        .filter(|(_, f)| !f.is_expired())
+        .map(|(name, f)| (name, &f.value))
+}
+
+/// Whether a hash has no fields left, so the key holding it should be removed
+fn is_drained(hash: &HashMap<String, HashField>) -> bool {
+    hash.values().all(HashField::is_expired)
+}
+
+pub async fn hset(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, pairs @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    if pairs.is_empty() || pairs.len() % 2 != 0 {
+        return Err(super::wrong_arity("hset"));
+    }
+    let pairs = pairs
+        .iter()
+        .map(super::member)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut value = state.map.get_or_insert_with(key.clone(), || MapValue {
+        value: MapValueContent::Hash(HashMap::new()),
+        expires_at: None,
+    });
+    let hash = value.value.as_hash_mut()?;
+
+    let added = pairs
+        .chunks_exact(2)
+        .filter(|pair| {
+            hash.insert(pair[0].clone(), HashField::new(pair[1].clone()))
+                .is_none_or(|old| old.is_expired())
+        })
+        .count();
+
+    Ok(Value::from(added))
+}
+
+pub async fn hget(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, field] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    with_hash(&state, key, |hash| {
+        get_field(hash, field).map(Value::from).unwrap_or_default()
+    })
+}
+
+pub async fn hmget(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, fields @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    with_hash(&state, key, |hash| {
+        fields
+            .iter()
+            .map(|f| get_field(hash, f).map(Value::from).unwrap_or_default())
+            .collect()
+    })
+}
+
+pub async fn hgetall(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    with_hash(&state, key, |hash| {
+        live_fields(hash)
+            .flat_map(|(f, v)| [Value::from(f), Value::from(v)])
+            .collect()
+    })
+}
+
+pub async fn hdel(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, fields @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let Some(mut value) = state.map.get_mut(key) else {
+        return Ok(Value::from(0));
+    };
+    let hash = value.value.as_hash_mut()?;
+
+    let removed = fields
+        .iter()
+        .filter(|f| {
+            f.to_str()
+                .and_then(|f| hash.remove(f))
+                .is_some_and(|f| !f.is_expired())
+        })
+        .count();
+
+    // Empty hashes don't exist
+    if is_drained(hash) {
+        drop(value);
+        state.map.remove(key);
+    }
+
+    Ok(Value::from(removed))
+}
+
+pub async fn hlen(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    with_hash(&state, key, |hash| Value::from(live_fields(hash).count()))
+}
+
+pub async fn hexists(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, field] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    with_hash(&state, key, |hash| {
+        Value::from(get_field(hash, field).is_some() as i64)
+    })
+}
+
+pub async fn hkeys(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    with_hash(&state, key, |hash| {
+        live_fields(hash).map(|(f, _)| Value::from(f)).collect()
+    })
+}
+
+pub async fn hvals(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    with_hash(&state, key, |hash| {
+        live_fields(hash).map(|(_, v)| Value::from(v)).collect()
+    })
+}
+
+pub async fn hstrlen(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, field] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    with_hash(&state, key, |hash| {
+        Value::from(get_field(hash, field).map_or(0, String::len))
+    })
+}
+
+pub async fn hsetnx(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, field, new] = args else {
+        return Err(RedisError::WrongArity);
+    };
+    let (name, new) = (super::member(field)?, super::member(new)?);
+
+    let mut value = state.map.get_or_insert_with(key.clone(), || MapValue {
+        value: MapValueContent::Hash(HashMap::new()),
+        expires_at: None,
+    });
+    let hash = value.value.as_hash_mut()?;
+
+    if get_field(hash, field).is_some() {
+        return Ok(Value::from(0));
+    }
+
+    hash.insert(name, HashField::new(new));
+    Ok(Value::from(1))
+}
+
+pub async fn hrandfield(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let (key, count, with_values) = match args {
+        [key] => (key, None, false),
+        [key, count] => (key, Some(count), false),
+        [key, count, opt] if opt.eq_ignore_ascii_case(b"withvalues") => (key, Some(count), true),
+        _ => return Err(RedisError::Syntax),
+    };
+
+    let Some(count) = count else {
+        return with_hash(&state, key, |hash| {
+            live_fields(hash)
+                .map(|(f, _)| f)
+                .choose(&mut rand::rng())
+                .map(Value::from)
+                .unwrap_or_default()
+        });
+    };
+
+    let Ok(count) = count.parse::<i64>() else {
+        return Err(RedisError::NotAnInteger);
+    };
+    if count == i64::MIN {
+        return Err(RedisError::OutOfRange);
+    }
+
+    with_hash(&state, key, |hash| {
+        random_fields(hash, count)
+            .into_iter()
+            .flat_map(|(f, v)| {
+                std::iter::once(Value::from(f)).chain(with_values.then(|| Value::from(v)))
+            })
+            .collect()
+    })
+}
+
+/// Pick `count` random fields and their values.  A positive count asks for distinct fields, a
+/// negative one allows repeats.
+fn random_fields(hash: &HashMap<String, HashField>, count: i64) -> Vec<(&String, &String)> {
+    let mut rng = rand::rng();
+    if count >= 0 {
+        // Room for `count` fields is allocated up front, and there can't be more than all of them
+        let count = (count as usize).min(hash.len());
+        let mut picked = live_fields(hash).choose_multiple(&mut rng, count);
+        picked.shuffle(&mut rng);
+        picked
+    } else {
+        let entries: Vec<_> = live_fields(hash).collect();
+        (0..count.unsigned_abs())
+            .map_while(|_| entries.choose(&mut rng).copied())
+            .collect()
+    }
+}
+
+/// Parse the `FIELDS numfields field...` arguments shared by the hash field expiry commands
+fn parse_fields(args: &[Bytes]) -> Result<&[Bytes], RedisError> {
+    let [fields_arg, numfields, fields @ ..] = args else {
+        return Err(RedisError::custom(
+            "ERR Mandatory argument FIELDS is missing or not at the right position",
+        ));
+    };
+    if !fields_arg.eq_ignore_ascii_case(b"fields") {
+        return Err(RedisError::custom(
+            "ERR Mandatory argument FIELDS is missing or not at the right position",
+        ));
+    }
+
+    match numfields.parse::<usize>() {
+        Ok(0) | Err(_) => Err(RedisError::custom(
+            "ERR Parameter `numFields` should be greater than 0",
+        )),
+        Ok(n) if n != fields.len() => Err(RedisError::custom(
+            "ERR The `numfields` parameter must match the number of arguments",
+        )),
+        Ok(_) => Ok(fields),
+    }
+}
+
+/// Set the expiry of each field to `at` (unix millis), replying with a code per field: -2 if the
+/// field doesn't exist, 0 if the NX/XX/GT/LT condition wasn't met, 1 if the expiry was set and
+/// 2 if the field was deleted because `at` is in the past.
+fn hexpire_at(
+    state: &State,
+    conn_state: &mut ConnectionState,
+    key: &Bytes,
+    at: i64,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let (cond, rest) = match args {
+        [cond, rest @ ..] if !cond.eq_ignore_ascii_case(b"fields") => {
+            (ExpireCondition::parse(std::slice::from_ref(cond)), rest)
+        }
+        _ => (Ok(ExpireCondition::default()), args),
+    };
+    let cond = cond?;
+    let fields = parse_fields(rest)?;
+
+    // Relative expiries are sent to replicas and the AOF as absolute ones
+    conn_state.propagate_as = Some(vec![std::iter::once(Value::from("HPEXPIREAT"))
+        .chain([Value::from(key), Value::from(at.to_string())])
+        .chain(args.iter().map(Value::from))
+        .collect()]);
+
+    let Some(mut value) = state.map.get_mut(key) else {
+        return Ok(fields.iter().map(|_| Value::from(-2)).collect());
+    };
+    let hash = value.value.as_hash_mut()?;
+
+    let expired = at <= unix_millis(SystemTime::now());
+    let codes: Vec<Value> = fields
+        .iter()
+        .map(|name| {
+            let Some(name) = name.to_str() else {
+                return Value::from(-2);
+            };
+            let Some(field) = hash.get_mut(name).filter(|f| !f.is_expired()) else {
+                return Value::from(-2);
+            };
+            if !cond.allows(field.expires_at.map(unix_millis), at) {
+                return Value::from(0);
+            }
+            if expired {
+                hash.remove(name);
+                return Value::from(2);
+            }
+            let old = field.expires_at;
+            field.expires_at = Some(UNIX_EPOCH + Duration::from_millis(at as u64));
+            state.map.index_expiry(key, old, field.expires_at);
+            Value::from(1)
+        })
+        .collect();
+
+    if is_drained(hash) {
+        drop(value);
+        state.map.remove(key);
+    }
+
+    Ok(Value::from(codes))
+}
+
+pub async fn hexpire(
+    state: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, seconds, args @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let at = relative_expire(seconds, 1000, "hexpire")?;
+    hexpire_at(&state, conn_state, key, at, args)
+}
+
+pub async fn hpexpire(
+    state: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, millis, args @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let at = relative_expire(millis, 1, "hpexpire")?;
+    hexpire_at(&state, conn_state, key, at, args)
+}
+
+pub async fn hexpireat(
+    state: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, timestamp, args @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let at = absolute_expire(timestamp, 1000, "hexpireat")?;
+    hexpire_at(&state, conn_state, key, at, args)
+}
+
+pub async fn hpexpireat(
+    state: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, timestamp, args @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let at = absolute_expire(timestamp, 1, "hpexpireat")?;
+    hexpire_at(&state, conn_state, key, at, args)
+}
+
+/// The remaining TTL of each field in millis, or -2 if the field doesn't exist and -1 if it has
+/// no expiry
+fn field_ttls(state: &State, args: &[Bytes]) -> Result<Vec<i64>, RedisError> {
+    let [key, args @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let fields = parse_fields(args)?;
+
+    let now = unix_millis(SystemTime::now());
+    with_hash(state, key, |hash| {
+        fields
+            .iter()
+            .map(|name| {
+                match name
+                    .to_str()
+                    .and_then(|name| hash.get(name))
+                    .filter(|f| !f.is_expired())
+                {
+                    None => -2,
+                    Some(HashField {
+                        expires_at: None, ..
+                    }) => -1,
+                    Some(HashField {
+                        expires_at: Some(at),
+                        ..
+                    }) => (unix_millis(*at) - now).max(0),
+                }
+            })
+            .collect()
+    })
+}
+
+pub async fn httl(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    Ok(field_ttls(&state, args)?
+        .into_iter()
+        .map(|ttl| Value::from(if ttl < 0 { ttl } else { (ttl + 500) / 1000 }))
+        .collect())
+}
+
+pub async fn hpttl(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let ttls = field_ttls(&state, args)?;
+    Ok(ttls.into_iter().map(Value::from).collect())
+}
+
+pub async fn hpersist(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, args @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let fields = parse_fields(args)?;
+
+    let Some(mut value) = state.map.get_mut(key) else {
+        return Ok(fields.iter().map(|_| Value::from(-2)).collect());
+    };
+    let hash = value.value.as_hash_mut()?;
+
+    Ok(fields
+        .iter()
+        .map(|name| {
+            match name
+                .to_str()
+                .and_then(|name| hash.get_mut(name))
+                .filter(|f| !f.is_expired())
+            {
+                None => Value::from(-2),
+                Some(field) => match field.expires_at.take() {
+                    Some(_) => Value::from(1),
+                    None => Value::from(-1),
+                },
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(fields: &[&str]) -> HashMap<String, HashField> {
+        fields
+            .iter()
+            .map(|f| (f.to_string(), HashField::new(format!("{f}-value"))))
+            .collect()
+    }
+
+    #[test]
+    fn random_fields_are_distinct_for_positive_counts() {
+        let hash = hash(&["a", "b", "c"]);
+        let mut picked: Vec<_> = random_fields(&hash, 2)
+            .into_iter()
+            .map(|(f, _)| f)
+            .collect();
+        picked.sort();
+        picked.dedup();
+        assert_eq!(picked.len(), 2);
+        assert!(picked.iter().all(|f| hash.contains_key(*f)));
+    }
+
+    #[test]
+    fn random_fields_with_a_huge_count_returns_every_field() {
+        let hash = hash(&["a", "b", "c"]);
+        let mut picked: Vec<_> = random_fields(&hash, 100_000_000_000_000)
+            .into_iter()
+            .map(|(f, v)| (f.as_str(), v.as_str()))
+            .collect();
+        picked.sort();
+        assert_eq!(
+            picked,
+            [("a", "a-value"), ("b", "b-value"), ("c", "c-value")]
+        );
+        assert!(random_fields(&HashMap::new(), i64::MAX).is_empty());
+    }
+
+    #[test]
+    fn random_fields_repeat_for_negative_counts() {
+        let hash = hash(&["a"]);
+        assert_eq!(random_fields(&hash, -3).len(), 3);
+        assert!(random_fields(&HashMap::new(), -3).is_empty());
+    }
+}
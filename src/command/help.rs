@@ -0,0 +1,119 @@
+//! `HELP` for the commands made up of subcommands, e.g. `CONFIG HELP`, which is answered before
+//! the command's handler is run so that none of them need to handle it themselves.
+
+use super::Command;
+use crate::resp::Value;
+
+/// The usage lines for each subcommand of `command`, or `None` if it doesn't have subcommands
+pub fn subcommands(command: Command) -> Option<&'static [&'static str]> {
+    let lines: &[&str] = match command {
+        Command::Client => &[
+            "GETNAME",
+            "    Return the name of the current connection.",
+            "ID",
+            "    Return the ID of the current connection.",
+            "INFO",
+            "    Return information about the current client connection.",
+            "LIST [TYPE (NORMAL|MASTER|REPLICA|PUBSUB)]",
+            "    Return information about client connections. Options:",
+            "    * TYPE (NORMAL|MASTER|REPLICA|PUBSUB)",
+            "      Return clients of specified type.",
+            "SETNAME <name>",
+            "    Assign the name <name> to the current connection.",
+        ],
+        Command::Command => &[
+            "(no subcommand)",
+            "    Return details about all Redis commands.",
+            "COUNT",
+            "    Return the total number of commands in this Redis server.",
+            "DOCS [<command-name> ...]",
+            "    Return documentation details about multiple Redis commands.",
+            "    If no command names are given, documentation details for all",
+            "    commands are returned.",
+            "GETKEYS <full-command>",
+            "    Return the keys from a full Redis command.",
+            "INFO [<command-name> ...]",
+            "    Return details about multiple Redis commands.",
+            "    If no command names are given, documentation details for all",
+            "    commands are returned.",
+        ],
+        Command::Config => &[
+            "GET <pattern>",
+            "    Return parameters matching the glob-like <pattern> and their values.",
+            "SET <directive> <value>",
+            "    Set the configuration <directive> to <value>.",
+            "RESETSTAT",
+            "    Reset statistics reported by the INFO command.",
+        ],
+        Command::Object => &[
+            "ENCODING <key>",
+            "    Return the kind of internal representation used in order to store the value",
+            "    associated with a <key>.",
+        ],
+        Command::XGroup => &[
+            "CREATE <key> <groupname> <id|$> [MKSTREAM]",
+            "    Create a new consumer group. Options are:",
+            "    * MKSTREAM",
+            "      Create the empty stream if it does not exist.",
+            "CREATECONSUMER <key> <groupname> <consumer>",
+            "    Create a new consumer in the specified group.",
+            "DELCONSUMER <key> <groupname> <consumer>",
+            "    Remove the specified consumer.",
+            "DESTROY <key> <groupname>",
+            "    Remove the specified group.",
+            "SETID <key> <groupname> <id|$>",
+            "    Set the current group ID.",
+        ],
+        Command::PubSub => &[
+            "CHANNELS [<pattern>]",
+            "    Return the currently active channels matching a <pattern> (default: '*').",
+            "NUMPAT",
+            "    Return number of subscriptions to patterns.",
+            "NUMSUB [<channel> ...]",
+            "    Return the number of subscribers for the specified channels, excluding",
+            "    pattern subscriptions(default: no channels).",
+        ],
+        Command::Cluster => &[
+            "COUNTKEYSINSLOT <slot>",
+            "    Return the number of keys in <slot>.",
+            "GETKEYSINSLOT <slot> <count>",
+            "    Return key names stored by current node in a slot.",
+            "KEYSLOT <key>",
+            "    Return the hash slot for <key>.",
+        ],
+        Command::Acl => &[
+            "CAT [<category>]",
+            "    List all commands that belong to <category>, or all command categories",
+            "    when no category is specified.",
+            "DELUSER <username> [<username> ...]",
+            "    Delete a list of users.",
+            "GETUSER <username>",
+            "    Get the user's details.",
+            "LIST",
+            "    Show users details in config file format.",
+            "LOAD",
+            "    Reload users from the ACL file.",
+            "SAVE",
+            "    Save the current config to the ACL file.",
+            "SETUSER <username> <attribute> [<attribute> ...]",
+            "    Create or modify a user with the specified attributes.",
+            "USERS",
+            "    List all the registered usernames.",
+            "WHOAMI",
+            "    Return the current connection username.",
+        ],
+        _ => return None,
+    };
+    Some(lines)
+}
+
+/// The reply to `<command> HELP`, given the usage `lines` of its subcommands.  Like Redis, they're
+/// between a heading and the usage of `HELP` itself.
+pub fn reply(command: Command, lines: &[&str]) -> Value {
+    let heading = format!("{command} <subcommand> [<arg> [value] [opt] ...]. Subcommands are:");
+    std::iter::once(heading.as_str())
+        .chain(lines.iter().copied())
+        .chain(["HELP", "    Print this help."])
+        .map(Value::simple_string)
+        .collect()
+}
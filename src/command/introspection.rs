@@ -0,0 +1,132 @@
+use std::sync::Arc;
+
+use super::{Command, CommandInfo, CommandTable, Flags};
+use crate::{bytes::Bytes, error::RedisError, resp::Value, ConnectionState, State};
+
+/// The flags that `COMMAND` reports, named as Redis names them.  The rest are only used
+/// internally.
+const FLAG_NAMES: &[(Flags, &str)] = &[
+    (Flags::WRITE, "write"),
+    (Flags::READONLY, "readonly"),
+    (Flags::BLOCKING, "blocking"),
+    (Flags::PUBSUB_ALLOWED, "pubsub"),
+    (Flags::NO_AUTH, "no_auth"),
+];
+
+/// A command's entry in the `COMMAND` reply, under the name clients run it by
+fn info_value(commands: &CommandTable, command: Command) -> Value {
+    let CommandInfo {
+        arity, flags, keys, ..
+    } = command.info();
+
+    let mut flag_names: Vec<Value> = FLAG_NAMES
+        .iter()
+        .filter(|(flag, _)| flags.contains(*flag))
+        .map(|(_, name)| Value::simple_string(*name))
+        .collect();
+    if keys.is_movable() {
+        flag_names.push(Value::simple_string("movablekeys"));
+    }
+    let categories: Vec<Value> = command
+        .categories()
+        .into_iter()
+        .map(|category| Value::simple_string(format!("@{}", category.to_str())))
+        .collect();
+
+    let (first, last, step) = keys.spec();
+    Value::from_iter([
+        Value::from(commands.name(command).unwrap_or_default()),
+        Value::from(arity as i64),
+        Value::Set(flag_names),
+        Value::from(first),
+        Value::from(last),
+        Value::from(step),
+        Value::Set(categories),
+        // Tips, key specifications and subcommands, which we don't keep track of
+        Value::empty_array(),
+        Value::empty_array(),
+        Value::empty_array(),
+    ])
+}
+
+pub async fn command(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    // Disabled commands are left out, and renamed ones go by their new names
+    let commands = &state.commands;
+    let info_value = |command| info_value(commands, command);
+    let Some((subcommand, args)) = args.split_first() else {
+        return Ok(commands.iter().map(info_value).collect());
+    };
+
+    let ret = match (&*subcommand.to_uppercase(), args) {
+        ("COUNT", []) => Value::from(commands.iter().count()),
+        ("INFO", []) => commands.iter().map(info_value).collect(),
+        ("INFO", names) => names
+            .iter()
+            .map(|name| commands.lookup(name).map(info_value).unwrap_or_default())
+            .collect(),
+        // We have no documentation to give, but clients (including `redis-cli`) ask for it on
+        // connecting, and do without it if each command's docs are empty
+        ("DOCS", names) => {
+            let documented: Vec<Command> = if names.is_empty() {
+                commands.iter().collect()
+            } else {
+                names
+                    .iter()
+                    .filter_map(|name| commands.lookup(name))
+                    .collect()
+            };
+            Value::Map(
+                documented
+                    .into_iter()
+                    .map(|command| {
+                        (
+                            Value::from(commands.name(command).unwrap_or_default()),
+                            Value::Map(Vec::new()),
+                        )
+                    })
+                    .collect(),
+            )
+        }
+        // `args` starts with the command name, which the key positions count as argument 0
+        ("GETKEYS", [name, ..]) => {
+            let Some(command) = commands.lookup(name) else {
+                return Err(RedisError::custom("ERR Invalid command specified"));
+            };
+            let info = command.info();
+            if !info.accepts(args.len()) {
+                return Err(RedisError::custom(
+                    "ERR Invalid number of arguments specified for command",
+                ));
+            }
+            let Some(positions) = info.keys.positions(args) else {
+                return Err(RedisError::custom(
+                    "ERR Invalid arguments specified for command",
+                ));
+            };
+            if positions.is_empty() {
+                return Err(RedisError::custom("ERR The command has no key arguments"));
+            }
+            positions
+                .into_iter()
+                .map(|i| Value::from(&args[i]))
+                .collect()
+        }
+        (subcommand @ ("COUNT" | "GETKEYS"), _) => {
+            return Err(super::wrong_arity(&format!(
+                "command|{}",
+                subcommand.to_lowercase()
+            )))
+        }
+        _ => {
+            return Err(RedisError::custom(format!(
+                "ERR unknown subcommand '{subcommand}'. Try COMMAND HELP."
+            )))
+        }
+    };
+
+    Ok(ret)
+}
@@ -1,195 +1,223 @@
-use std::{collections::VecDeque, sync::Arc, time::Duration};
+use std::sync::Arc;
+
+use tracing::warn;
+
+use super::{
+    blocking::{self, parse_timeout, BlockingPop, Popped},
+    ExecContext,
+};
+use crate::{
+    bytes::Bytes, error::RedisError, listpack::List, resp::Value, ConnectionState, MapValue,
+    MapValueContent, State,
+};
+
+/// An end of a list
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListEnd {
+    Left,
+    Right,
+}
 
-use anyhow::Context;
+impl ListEnd {
+    fn parse(s: &Bytes) -> Option<Self> {
+        match &*s.to_uppercase() {
+            "LEFT" => Some(Self::Left),
+            "RIGHT" => Some(Self::Right),
+            _ => None,
+        }
+    }
 
-use crate::{resp::Value, ConnectionState, MapValue, MapValueContent, State};
+    fn push(self, items: &mut List, item: String) {
+        match self {
+            Self::Left => items.push_front(item),
+            Self::Right => items.push_back(item),
+        }
+    }
 
-pub async fn rpush(
-    state: Arc<State>,
-    _: &mut ConnectionState,
-    args: &[String],
-) -> anyhow::Result<Value> {
-    let (key, values) = args.split_first().expect("TODO: args.len() < 2");
-
-    assert!(!values.is_empty());
-
-    let len = if let Some(mut list) = state.map.get_mut(key) {
-        match list.value {
-            MapValueContent::String(_) | MapValueContent::Integer(_) => todo!(),
-            MapValueContent::List(ref mut items) => {
-                items.extend(values.iter().map(String::clone));
-                let len = items.len();
-
-                if let Some(mut waiting) = state.waiting_on_list.get_mut(key) {
-                    loop {
-                        let Some(tx) = waiting.pop_front() else {
-                            break;
-                        };
-                        let Some(item) = items.pop_front() else {
-                            waiting.push_front(tx);
-                            break;
-                        };
-
-                        if let Err(e) = tx.send(item) {
-                            items.push_front(e);
-                        }
-                    }
-                }
-
-                len
-            }
-            MapValueContent::Stream(_) => todo!(),
-            MapValueContent::SortedSet(_) => todo!(),
+    fn pop(self, items: &mut List) -> Option<String> {
+        match self {
+            Self::Left => items.pop_front(),
+            Self::Right => items.pop_back(),
         }
-    } else {
-        let mut values = values;
-
-        let og_len = values.len();
-
-        if let Some(mut waiting) = state.waiting_on_list.get_mut(key) {
-            loop {
-                let Some(tx) = waiting.pop_front() else {
-                    break;
-                };
-                let Some((item, new_values)) = values.split_first() else {
-                    waiting.push_front(tx);
-                    break;
-                };
-
-                if tx.send(item.clone()).is_ok() {
-                    values = new_values;
-                }
-            }
+    }
+
+    /// Pop up to `count` items
+    fn pop_many(self, items: &mut List, count: usize) -> Vec<String> {
+        (0..count).map_while(|_| self.pop(items)).collect()
+    }
+
+    /// Put back items which were taken by [`Self::pop_many`]
+    fn restore(self, items: &mut List, popped: Vec<String>) {
+        for item in popped.into_iter().rev() {
+            self.push(items, item);
         }
+    }
 
-        state.map.insert(
-            key.clone(),
-            MapValue {
-                value: MapValueContent::List(values.iter().map(String::clone).collect()),
-                expires_at: None,
-            },
-        );
-        og_len
-    };
+    /// The command which replicas can use to repeat a pop from this end
+    fn pop_command(self, key: &Bytes, count: usize) -> Value {
+        let command = match self {
+            Self::Left => "LPOP",
+            Self::Right => "RPOP",
+        };
+        Value::from_iter([
+            Value::from(command),
+            Value::from(key),
+            Value::from(count.to_string()),
+        ])
+    }
+}
 
-    Ok(Value::from(len))
+/// A blocking pop from the lists in `BLPOP`/`BLMPOP`
+#[derive(Debug)]
+pub struct ListPop {
+    end: ListEnd,
+    count: usize,
 }
 
-pub async fn lpush(
-    state: Arc<State>,
-    _: &mut ConnectionState,
-    args: &[String],
-) -> anyhow::Result<Value> {
-    let (key, mut values) = args.split_first().expect("TODO: args.len() < 2");
-
-    assert!(!values.is_empty());
-
-    let og_len = values.len();
-
-    if let Some(mut waiting) = state.waiting_on_list.get_mut(key) {
-        loop {
-            let Some(tx) = waiting.pop_front() else {
-                break;
-            };
-            let Some((item, new_values)) = values.split_last() else {
-                waiting.push_front(tx);
-                break;
-            };
-
-            if tx.send(item.clone()).is_ok() {
-                values = new_values;
-            }
-        }
+impl BlockingPop for ListPop {
+    type Items = List;
+    type Popped = Vec<String>;
+
+    fn items(value: &mut MapValueContent) -> Result<&mut List, RedisError> {
+        value.as_list_mut()
+    }
+
+    fn is_empty(items: &List) -> bool {
+        items.is_empty()
+    }
+
+    fn pop(&self, items: &mut List) -> Option<Vec<String>> {
+        let popped = self.end.pop_many(items, self.count);
+        (!popped.is_empty()).then_some(popped)
+    }
+
+    fn restore(&self, items: &mut List, popped: Vec<String>) {
+        self.end.restore(items, popped);
+    }
+
+    fn pop_command(&self, key: &Bytes, popped: &Vec<String>) -> Value {
+        self.end.pop_command(key, popped.len())
     }
 
-    let len = if let Some(mut list) = state.map.get_mut(key) {
-        match list.value {
-            MapValueContent::String(_) | MapValueContent::Integer(_) => todo!(),
-            MapValueContent::List(ref mut items) => {
-                let len = items.len() + og_len;
-
-                items.reserve(values.len());
-                values
-                    .iter()
-                    .map(String::clone)
-                    .for_each(|v| items.push_front(v));
-
-                if let Some(mut waiting) = state.waiting_on_list.get_mut(key) {
-                    loop {
-                        let Some(tx) = waiting.pop_front() else {
-                            break;
-                        };
-                        let Some(item) = items.pop_front() else {
-                            waiting.push_front(tx);
-                            break;
-                        };
-
-                        if let Err(e) = tx.send(item) {
-                            items.push_front(e);
-                        }
-                    }
-                }
-
-                len
-            }
-            MapValueContent::Stream(_) => todo!(),
-            MapValueContent::SortedSet(_) => todo!(),
+    fn give_back(
+        &self,
+        state: &State,
+        conn_state: &mut ConnectionState,
+        key: Bytes,
+        popped: Vec<String>,
+    ) -> anyhow::Result<()> {
+        let args: Vec<Bytes> = std::iter::once(key)
+            .chain(popped.into_iter().rev().map(Bytes::from))
+            .collect();
+        if let Err(e) = push(state, conn_state, &args, self.end) {
+            warn!("dropping items popped for a disconnected client: {e}");
+            conn_state.propagate_as = Some(Vec::new());
         }
+        Ok(())
+    }
+}
+
+/// Shared by `LPUSH` and `RPUSH`
+fn push(
+    state: &State,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+    end: ListEnd,
+) -> Result<Value, RedisError> {
+    let [key, values @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+    let values = values
+        .iter()
+        .map(super::member)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut list = state.map.get_or_insert_with(key.clone(), || MapValue {
+        value: MapValueContent::List(List::default()),
+        expires_at: None,
+    });
+    let items = list.value.as_list_mut()?;
+
+    for value in values {
+        end.push(items, value);
+    }
+    items.convert_if_needed(state.config().list_max_listpack_size);
+    let len = items.len();
+
+    // Replicas need to see the push, followed by any pops for clients we just served
+    let pops = state.blocked.signal(key, &mut list.value);
+    let command = if end == ListEnd::Left {
+        "LPUSH"
     } else {
-        state.map.insert(
-            key.clone(),
-            MapValue {
-                value: MapValueContent::List(values.iter().rev().map(String::clone).collect()),
-                expires_at: None,
-            },
-        );
-        og_len
+        "RPUSH"
     };
+    let push = std::iter::once(Value::from(command))
+        .chain(args.iter().map(Value::from))
+        .collect();
+    conn_state.propagate_as = Some(std::iter::once(push).chain(pops).collect());
+
+    // Empty lists don't exist
+    if list.value.as_list().is_ok_and(List::is_empty) {
+        drop(list);
+        state.map.remove(key);
+    }
 
     Ok(Value::from(len))
 }
 
+pub async fn rpush(
+    state: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    push(&state, conn_state, args, ListEnd::Right)
+}
+
+pub async fn lpush(
+    state: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    push(&state, conn_state, args, ListEnd::Left)
+}
+
 pub async fn lrange(
     state: Arc<State>,
     _: &mut ConnectionState,
-    args: &[String],
-) -> anyhow::Result<Value> {
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
     let [key, start_index, end_index, ..] = args else {
-        todo!("args.len() < 3");
+        return Err(RedisError::WrongArity);
     };
 
-    let start_index: isize = start_index.parse().context("Invalid start index")?;
-    let end_index: isize = end_index.parse().context("Invalid end index")?;
+    let start_index: isize = start_index.parse().map_err(|_| RedisError::NotAnInteger)?;
+    let end_index: isize = end_index.parse().map_err(|_| RedisError::NotAnInteger)?;
 
     let ret = if let Some(list) = state.map.get(key) {
-        match list.value {
-            MapValueContent::String(_) | MapValueContent::Integer(_) => todo!(),
-            MapValueContent::List(ref items) => {
-                let start_index = if start_index < 0 {
-                    items.len().saturating_add_signed(start_index)
-                } else {
-                    start_index as usize
-                };
-
-                let end_index = if end_index < 0 {
-                    items.len().saturating_add_signed(end_index)
-                } else if end_index as usize >= items.len() {
-                    items.len() - 1
-                } else {
-                    end_index as usize
-                };
-
-                if start_index > end_index || start_index >= items.len() {
-                    Value::Array(Vec::new())
-                } else {
-                    items
-                        .range(start_index..=end_index)
-                        .map(Value::bulk_string)
-                        .collect()
-                }
-            }
-            MapValueContent::Stream(_) => todo!(),
-            MapValueContent::SortedSet(_) => todo!(),
+        let items = list.value.as_list()?;
+        let start_index = if start_index < 0 {
+            items.len().saturating_add_signed(start_index)
+        } else {
+            start_index as usize
+        };
+
+        let end_index = if end_index < 0 {
+            items.len().saturating_add_signed(end_index)
+        } else if end_index as usize >= items.len() {
+            items.len() - 1
+        } else {
+            end_index as usize
+        };
+
+        if start_index > end_index || start_index >= items.len() {
+            Value::Array(Vec::new())
+        } else {
+            items
+                .iter()
+                .skip(start_index)
+                .take(end_index - start_index + 1)
+                .map(Value::from)
+                .collect()
         }
     } else {
         Value::Array(Vec::new())
@@ -201,117 +229,334 @@ pub async fn lrange(
 pub async fn llen(
     state: Arc<State>,
     _: &mut ConnectionState,
-    args: &[String],
-) -> anyhow::Result<Value> {
-    let (key, values) = args.split_first().expect("TODO: args.len() < 2");
-    assert_eq!(values.len(), 0);
-
-    let len = if let Some(list) = state.map.get(key) {
-        match list.value {
-            MapValueContent::String(_) | MapValueContent::Integer(_) => todo!(),
-            MapValueContent::List(ref items) => items.len(),
-            MapValueContent::Stream(_) => todo!(),
-            MapValueContent::SortedSet(_) => todo!(),
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let len = match state.map.get(key) {
+        Some(list) => list.value.as_list()?.len(),
+        None => 0,
+    };
+
+    Ok(Value::from(len))
+}
+
+/// Shared by `LPOP` and `RPOP`, popping from the front or back of the list
+fn pop(state: &State, args: &[Bytes], front: bool) -> Result<Value, RedisError> {
+    let (key, count) = match args {
+        [key] => (key, None),
+        [key, count] => (key, Some(count)),
+        _ => return Err(RedisError::WrongArity),
+    };
+
+    let count: Option<usize> = match count.map(|v| v.parse()) {
+        Some(Ok(count)) => Some(count),
+        Some(Err(_)) => {
+            return Err(RedisError::custom(
+                "ERR value is out of range, must be positive",
+            ))
+        }
+        None => None,
+    };
+
+    let Some(mut list) = state.map.get_mut(key) else {
+        return Ok(Value::Null);
+    };
+    let items = list.value.as_list_mut()?;
+
+    let mut pop_one = || {
+        if front {
+            items.pop_front()
+        } else {
+            items.pop_back()
         }
+    };
+
+    let ret = if let Some(count) = count {
+        (0..count)
+            .map_while(|_| pop_one())
+            .map(Value::bulk_string)
+            .collect()
+    } else if let Some(v) = pop_one() {
+        Value::bulk_string(v)
     } else {
-        0
+        Value::Null
     };
 
-    Ok(Value::from(len))
+    // Empty lists don't exist
+    if items.is_empty() {
+        drop(list);
+        state.map.remove(key);
+    }
+
+    Ok(ret)
 }
 
 pub async fn lpop(
     state: Arc<State>,
     _: &mut ConnectionState,
-    args: &[String],
-) -> anyhow::Result<Value> {
-    let (key, values) = args.split_first().expect("TODO: args.len() < 2");
-
-    let count: Option<usize> = values
-        .first()
-        .map(|v| v.parse().expect("invalid lpop count"));
-
-    let ret = if let Some(mut list) = state.map.get_mut(key) {
-        match list.value {
-            MapValueContent::String(_) | MapValueContent::Integer(_) => todo!(),
-            MapValueContent::List(ref mut items) => {
-                if let Some(count) = count {
-                    (0..count)
-                        .flat_map(|_| items.pop_front())
-                        .map(Value::bulk_string)
-                        .collect()
-                } else if let Some(v) = items.pop_front() {
-                    Value::bulk_string(v)
-                } else {
-                    Value::Null
-                }
-            }
-            MapValueContent::Stream(_) => todo!(),
-            MapValueContent::SortedSet(_) => todo!(),
-        }
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    pop(&state, args, true)
+}
+
+pub async fn rpop(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    pop(&state, args, false)
+}
+
+/// Resolve a possibly-negative list index, returning `None` if it's out of range
+fn resolve_index(index: i64, len: usize) -> Option<usize> {
+    let index = if index < 0 {
+        len.checked_sub(index.unsigned_abs() as usize)?
     } else {
-        Value::Null
+        index as usize
     };
+    (index < len).then_some(index)
+}
 
-    Ok(ret)
+pub async fn linsert(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, position, pivot, element] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let after = match &*position.to_uppercase() {
+        "BEFORE" => false,
+        "AFTER" => true,
+        _ => return Err(RedisError::Syntax),
+    };
+    let element = super::member(element)?;
+
+    let Some(mut list) = state.map.get_mut(key) else {
+        return Ok(Value::from(0));
+    };
+    let items = list.value.as_list_mut()?;
+
+    let Some(index) = items.iter().position(|item| *pivot == item) else {
+        return Ok(Value::from(-1));
+    };
+
+    items.insert(index + after as usize, element);
+    items.convert_if_needed(state.config().list_max_listpack_size);
+    Ok(Value::from(items.len()))
 }
 
-pub async fn blpop(
+pub async fn lset(
     state: Arc<State>,
     _: &mut ConnectionState,
-    args: &[String],
-) -> anyhow::Result<Value> {
-    let (key, values) = args.split_first().expect("TODO: args.len() < 2");
-
-    let timeout = values
-        .first()
-        .map(|v| v.parse::<f64>().expect("invalid lpop count"))
-        .and_then(|n| (n > 0.).then_some(n))
-        .map(Duration::from_secs_f64);
-
-    let wait = || async {
-        let ret: anyhow::Result<Value> = {
-            let (tx, rx) = tokio::sync::oneshot::channel();
-            if let Some(mut waiting) = state.waiting_on_list.get_mut(key) {
-                waiting.push_back(tx);
-            } else {
-                let mut vd = VecDeque::with_capacity(1);
-                vd.push_back(tx);
-                state.waiting_on_list.insert(key.into(), vd);
-            }
-
-            let val = if let Some(timeout) = timeout {
-                match tokio::time::timeout(timeout, rx).await {
-                    Ok(val) => val,
-                    Err(_) => return Ok(Value::Null),
-                }
-            } else {
-                rx.await
-            }
-            .with_context(|| format!("Waiting for blpop on key '{key}'"))?;
-
-            Ok(Value::from_iter([key.clone(), val]))
-        };
-        ret
-    };
-
-    let ret = if let Some(mut list) = state.map.get_mut(key) {
-        match list.value {
-            MapValueContent::String(_) | MapValueContent::Integer(_) => todo!(),
-            MapValueContent::List(ref mut items) => {
-                if let Some(v) = items.pop_front() {
-                    Value::from_iter([key.clone(), v])
-                } else {
-                    drop(list);
-                    wait().await?
-                }
-            }
-            MapValueContent::Stream(_) => todo!(),
-            MapValueContent::SortedSet(_) => todo!(),
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, index, element] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let Ok(index) = index.parse::<i64>() else {
+        return Err(RedisError::NotAnInteger);
+    };
+    let element = super::member(element)?;
+
+    let Some(mut list) = state.map.get_mut(key) else {
+        return Err(RedisError::NoSuchKey);
+    };
+    let items = list.value.as_list_mut()?;
+
+    let Some(index) = resolve_index(index, items.len()) else {
+        return Err(RedisError::custom("ERR index out of range"));
+    };
+
+    items.set(index, element);
+    items.convert_if_needed(state.config().list_max_listpack_size);
+    Ok(Value::simple_string("OK"))
+}
+
+pub async fn lrem(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, count, element] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let Ok(count) = count.parse::<i64>() else {
+        return Err(RedisError::NotAnInteger);
+    };
+
+    let Some(mut list) = state.map.get_mut(key) else {
+        return Ok(Value::from(0));
+    };
+    let items = list.value.as_list_mut()?;
+
+    // A positive count removes from the head, a negative one from the tail, and 0 removes all
+    let limit = match count {
+        0 => usize::MAX,
+        n => n.unsigned_abs() as usize,
+    };
+    let matching = items.iter().filter(|item| *element == *item).count();
+    let removed = matching.min(limit);
+    let first_removed = if count >= 0 { 0 } else { matching - removed };
+
+    let mut seen = 0;
+    items.retain(|item| {
+        if *element != item {
+            return true;
         }
-    } else {
-        wait().await?
+        seen += 1;
+        !(first_removed..first_removed + removed).contains(&(seen - 1))
+    });
+
+    // Empty lists don't exist
+    if items.is_empty() {
+        drop(list);
+        state.map.remove(key);
+    }
+
+    Ok(Value::from(removed))
+}
+
+pub async fn lindex(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, index] = args else {
+        return Err(RedisError::WrongArity);
     };
 
-    Ok(ret)
+    let Ok(index) = index.parse::<i64>() else {
+        return Err(RedisError::NotAnInteger);
+    };
+
+    let Some(list) = state.map.get(key) else {
+        return Ok(Value::Null);
+    };
+    let items = list.value.as_list()?;
+
+    Ok(resolve_index(index, items.len())
+        .and_then(|i| items.get(i))
+        .map(Value::from)
+        .unwrap_or_default())
+}
+
+pub async fn blpop(
+    state: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+    context: ExecContext,
+) -> Result<Value, RedisError> {
+    let [keys @ .., timeout] = args else {
+        return Err(RedisError::WrongArity);
+    };
+    if keys.is_empty() {
+        return Err(RedisError::WrongArity);
+    }
+
+    let timeout = parse_timeout(timeout)?;
+
+    Ok(
+        match blocking::block(
+            &state,
+            conn_state,
+            keys,
+            ListPop {
+                end: ListEnd::Left,
+                count: 1,
+            },
+            timeout,
+            context,
+        )
+        .await?
+        {
+            Some((key, popped)) => std::iter::once(key)
+                .chain(popped.into_iter().map(Bytes::from))
+                .map(Value::bulk_string)
+                .collect(),
+            None => Value::Null,
+        },
+    )
+}
+
+/// Parse the `numkeys key... LEFT|RIGHT [COUNT count]` arguments of `LMPOP` and `BLMPOP`
+fn parse_mpop_args(args: &[Bytes]) -> Result<(&[Bytes], ListEnd, usize), RedisError> {
+    let Some((numkeys, rest)) = args.split_first() else {
+        return Err(RedisError::Syntax);
+    };
+    let numkeys = match numkeys.parse::<usize>() {
+        Ok(0) | Err(_) => return Err(RedisError::custom("ERR numkeys should be greater than 0")),
+        Ok(n) => n,
+    };
+    if rest.len() <= numkeys {
+        return Err(RedisError::Syntax);
+    }
+
+    let (keys, rest) = rest.split_at(numkeys);
+    let Some(end) = ListEnd::parse(&rest[0]) else {
+        return Err(RedisError::Syntax);
+    };
+
+    let count = match &rest[1..] {
+        [] => 1,
+        [opt, count] if opt.eq_ignore_ascii_case(b"count") => match count.parse::<usize>() {
+            Ok(0) | Err(_) => return Err(RedisError::custom("ERR count should be greater than 0")),
+            Ok(n) => n,
+        },
+        _ => return Err(RedisError::Syntax),
+    };
+
+    Ok((keys, end, count))
+}
+
+fn mpop_reply(popped: Option<Popped<ListPop>>) -> Value {
+    match popped {
+        Some((key, items)) => Value::from_iter([
+            Value::from(key),
+            items.into_iter().map(Value::bulk_string).collect(),
+        ]),
+        None => Value::Null,
+    }
+}
+
+pub async fn lmpop(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let (keys, end, count) = parse_mpop_args(args)?;
+
+    let popped = blocking::pop_first(&state, keys, &ListPop { end, count })?;
+    Ok(mpop_reply(popped))
+}
+
+pub async fn blmpop(
+    state: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+    context: ExecContext,
+) -> Result<Value, RedisError> {
+    let [timeout, args @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let timeout = parse_timeout(timeout)?;
+    let (keys, end, count) = parse_mpop_args(args)?;
+
+    let popped = blocking::block(
+        &state,
+        conn_state,
+        keys,
+        ListPop { end, count },
+        timeout,
+        context,
+    )
+    .await?;
+    Ok(mpop_reply(popped))
 }
@@ -1,47 +1,155 @@
-use std::{
-    fmt::Display,
-    sync::Arc,
-    time::{Duration, SystemTime},
-};
+use std::{collections::HashMap, fmt::Display, future::Future, ops::BitOr, pin::Pin, sync::Arc};
 
-use anyhow::Context;
-use serde::Deserialize;
-use strum::{EnumString, IntoStaticStr};
+use strum::{EnumIter, EnumString, IntoEnumIterator, IntoStaticStr};
 
-use crate::{resp::Value, ConnectionMode, ConnectionState, MapValue, MapValueContent, State};
+use crate::{
+    bytes::Bytes, error::RedisError, resp::Value, ConnectionMode, ConnectionState, MapValueContent,
+    State,
+};
 
+pub mod acl;
+pub mod args;
+pub mod bitmap;
+pub mod blocking;
+pub mod cluster;
+pub mod connection;
+pub mod expire;
+pub mod generic;
+pub mod geo;
+pub mod hash;
+pub mod help;
+pub mod introspection;
 pub mod list;
 pub mod persistence;
 pub mod pubsub;
 pub mod replication;
+pub mod set;
+pub mod sort;
 pub mod sorted_set;
 pub mod stream;
+pub mod string;
 pub mod transaction;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, EnumString, IntoStaticStr)]
+/// The error for a command given the wrong number of arguments, where `name` is lowercase and
+/// includes the subcommand if there is one, e.g. `config|set`
+pub fn wrong_arity(name: &str) -> RedisError {
+    RedisError::custom(format!(
+        "ERR wrong number of arguments for '{name}' command"
+    ))
+}
+
+/// The text of a member of a list, hash, set or sorted set.  Unlike keys and string values,
+/// members aren't binary-safe, so ones which aren't valid UTF-8 are rejected rather than mangled.
+pub fn member(arg: &Bytes) -> Result<String, RedisError> {
+    arg.to_str().map(str::to_string).ok_or_else(|| {
+        RedisError::custom("ERR members of lists, hashes, sets and sorted sets must be valid UTF-8")
+    })
+}
+
+/// The error for a command we don't know, which quotes the start of what was sent like Redis does
+pub fn unknown_command(name: &Bytes, args: &[Bytes]) -> Value {
+    const MAX_LEN: usize = 128;
+    let truncate = |s: &Bytes, len: usize| {
+        let s = s.to_string_lossy();
+        s.chars().take(len).collect::<String>()
+    };
+
+    let mut quoted = String::new();
+    for arg in args {
+        if quoted.len() >= MAX_LEN {
+            break;
+        }
+        quoted += &format!("'{}' ", truncate(arg, MAX_LEN - quoted.len()));
+    }
+
+    // Errors are sent on a single line
+    let error = format!(
+        "ERR unknown command '{}', with args beginning with: {quoted}",
+        truncate(name, MAX_LEN)
+    );
+    Value::simple_error(error.replace(['\r', '\n'], " "))
+}
+
+/// Where a command is being run from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecContext {
+    Normal,
+    /// Queued by `MULTI` and run by `EXEC`, which must run every command straight through
+    Transaction,
+}
+
+impl ExecContext {
+    /// Whether a blocking command may wait for other clients, rather than acting like its
+    /// non-blocking variant
+    pub fn can_block(self) -> bool {
+        self == Self::Normal
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, EnumString, IntoStaticStr, EnumIter)]
 #[strum(serialize_all = "UPPERCASE")]
 pub enum Command {
     Ping,
     Echo,
+    Hello,
+    Auth,
+    Client,
+    Command,
+    Acl,
     Set,
+    SetNx,
+    SetEx,
+    PSetEx,
+    GetSet,
     Get,
+    GetEx,
+    GetDel,
+    Append,
+    StrLen,
+    Lcs,
+
+    SetBit,
+    GetBit,
+    BitCount,
+    BitPos,
+    BitOp,
 
     RPush,
     LPush,
     LRange,
     LLen,
     LPop,
+    RPop,
+    LInsert,
+    LSet,
+    LRem,
+    LIndex,
     BLPop,
+    LMPop,
+    BLMPop,
 
     Type,
     XAdd,
     XRange,
     XRead,
+    XTrim,
+    XSetId,
+    XGroup,
+    XReadGroup,
+    XAck,
+    XPending,
+    XClaim,
+    XAutoClaim,
 
     Incr,
+    IncrBy,
+    Decr,
+    DecrBy,
     Multi,
     Exec,
     Discard,
+    Watch,
+    Unwatch,
 
     Info,
     ReplConf,
@@ -49,17 +157,329 @@ pub enum Command {
 
     Config,
     Keys,
+    Del,
+    Unlink,
+    Rename,
+    RenameNx,
+    Copy,
+    DbSize,
+    FlushDb,
+    FlushAll,
+    Scan,
+    Object,
+    BgRewriteAof,
+    Save,
 
     Subscribe,
     Unsubscribe,
     Publish,
+    PSubscribe,
+    PUnsubscribe,
+    PubSub,
 
     ZAdd,
     ZRank,
     ZRange,
+    ZRevRange,
+    ZRangeByScore,
+    ZRevRangeByScore,
+    ZRangeStore,
+    ZUnion,
+    ZInter,
+    ZDiff,
+    ZUnionStore,
+    ZInterStore,
+    ZDiffStore,
     ZCard,
     ZScore,
+    ZMScore,
     ZRem,
+    ZRemRangeByRank,
+    ZRemRangeByScore,
+    ZRemRangeByLex,
+    ZPopMin,
+    ZPopMax,
+    BZPopMin,
+    BZPopMax,
+
+    GeoAdd,
+    GeoPos,
+    GeoDist,
+    GeoHash,
+
+    HSet,
+    HGet,
+    HMGet,
+    HGetAll,
+    HDel,
+    HLen,
+    HExists,
+    HKeys,
+    HVals,
+    HSetNx,
+    HStrLen,
+    HRandField,
+    HExpire,
+    HPExpire,
+    HExpireAt,
+    HPExpireAt,
+    HTtl,
+    HPTtl,
+    HPersist,
+
+    SAdd,
+    SRem,
+    SMembers,
+    SIsMember,
+    SCard,
+
+    Sort,
+    #[strum(serialize = "SORT_RO")]
+    SortRo,
+
+    Cluster,
+
+    Expire,
+    PExpire,
+    ExpireAt,
+    PExpireAt,
+    ExpireTime,
+    PExpireTime,
+    Ttl,
+    PTtl,
+    Persist,
+}
+
+/// A command's handler, wrapped by [`handler!`] so that they all have the same type
+pub type Handler =
+    for<'a> fn(
+        Arc<State>,
+        &'a mut ConnectionState,
+        &'a [Bytes],
+        ExecContext,
+    ) -> Pin<Box<dyn Future<Output = Result<Value, RedisError>> + Send + 'a>>;
+
+/// Wrap a handler as a [`Handler`], passing it the [`ExecContext`] if it's given `context`
+macro_rules! handler {
+    ($f:path) => {{
+        let handler: Handler = |state, conn_state, args, _| Box::pin($f(state, conn_state, args));
+        handler
+    }};
+    ($f:path, context) => {{
+        let handler: Handler =
+            |state, conn_state, args, context| Box::pin($f(state, conn_state, args, context));
+        handler
+    }};
+}
+
+/// Properties of a command, which combine with `|`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Flags(u8);
+
+impl Flags {
+    pub const NONE: Self = Self(0);
+    /// Changes keys, so it goes to replicas and the AOF, and can't be run on read-only replicas
+    pub const WRITE: Self = Self(1 << 0);
+    /// Reads keys without changing them
+    pub const READONLY: Self = Self(1 << 1);
+    /// May wait on other clients before completing
+    pub const BLOCKING: Self = Self(1 << 2);
+    /// May be run by a RESP2 client which is subscribed to something
+    pub const PUBSUB_ALLOWED: Self = Self(1 << 3);
+    /// Runs straight away inside `MULTI`, rather than being queued for `EXEC`
+    pub const TRANSACTION: Self = Self(1 << 4);
+    /// Replied to even when our master sends it, e.g. `REPLCONF GETACK`
+    pub const REPLY_TO_MASTER: Self = Self(1 << 5);
+    /// May be run before authenticating
+    pub const NO_AUTH: Self = Self(1 << 6);
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Flags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// The ACL categories that commands are grouped into, e.g. `@string` or `@dangerous`, which are
+/// the same as Redis' so that ACL rules written for Redis mean the same here
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, EnumString, IntoStaticStr, EnumIter)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+pub enum Category {
+    Keyspace,
+    Read,
+    Write,
+    Set,
+    SortedSet,
+    List,
+    Hash,
+    String,
+    Bitmap,
+    HyperLogLog,
+    Geo,
+    Stream,
+    PubSub,
+    Admin,
+    Fast,
+    Slow,
+    Blocking,
+    Dangerous,
+    Connection,
+    Transaction,
+    Scripting,
+}
+
+impl Category {
+    pub fn to_str(self) -> &'static str {
+        <&str>::from(self)
+    }
+}
+
+/// Which of a command's arguments are keys, counting the command name as argument 0
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Keys {
+    None,
+    /// Every `step`th argument from `first` to `last`, where a negative `last` counts back from
+    /// the end, like the key positions Redis gives in `COMMAND`
+    Range {
+        first: usize,
+        last: isize,
+        step: usize,
+    },
+    /// A count at argument `index` followed by that many keys, as well as argument 1 if it's a
+    /// `destination`, e.g. `ZUNIONSTORE destination numkeys key [key ...]`
+    NumKeys {
+        index: usize,
+        destination: bool,
+    },
+    /// Those after `STREAMS`, which are followed by as many IDs, for `XREAD` and `XREADGROUP`
+    Streams,
+    /// Argument 1 and the `STORE` destination, for `SORT`
+    Sort,
+}
+
+impl Keys {
+    const ONE: Self = Self::range(1, 1);
+    const ALL: Self = Self::range(1, -1);
+
+    const fn range(first: usize, last: isize) -> Self {
+        Self::Range {
+            first,
+            last,
+            step: 1,
+        }
+    }
+
+    /// Whether finding the keys means looking at the arguments, rather than just their positions
+    pub fn is_movable(self) -> bool {
+        matches!(self, Self::NumKeys { .. } | Self::Streams | Self::Sort)
+    }
+
+    /// The first key, last key and step as given by `COMMAND`, which are all 0 for commands with
+    /// no keys, and only cover the keys at fixed positions
+    pub fn spec(self) -> (i64, i64, i64) {
+        match self {
+            Self::Range { first, last, step } => (first as i64, last as i64, step as i64),
+            Self::NumKeys {
+                destination: true, ..
+            } => (1, 1, 1),
+            Self::Sort => (1, 1, 1),
+            Self::None | Self::NumKeys { .. } | Self::Streams => (0, 0, 0),
+        }
+    }
+
+    /// The positions of the keys in `args`, which start with the command name, or `None` if the
+    /// arguments don't say where the keys are
+    pub fn positions(self, args: &[Bytes]) -> Option<Vec<usize>> {
+        let positions = match self {
+            Self::None => Vec::new(),
+            Self::Range { first, last, step } => {
+                let last = if last < 0 {
+                    args.len().checked_add_signed(last)?
+                } else {
+                    last as usize
+                };
+                (first..=last.min(args.len().checked_sub(1)?))
+                    .step_by(step)
+                    .collect()
+            }
+            Self::NumKeys { index, destination } => {
+                let count: usize = args.get(index)?.parse().ok()?;
+                let first = index + 1;
+                if first + count > args.len() {
+                    return None;
+                }
+                destination
+                    .then_some(1)
+                    .into_iter()
+                    .chain(first..first + count)
+                    .collect()
+            }
+            Self::Streams => {
+                let streams = args
+                    .iter()
+                    .position(|arg| arg.eq_ignore_ascii_case(b"streams"))?;
+                let rest = args.len() - streams - 1;
+                if rest == 0 || !rest.is_multiple_of(2) {
+                    return None;
+                }
+                (streams + 1..streams + 1 + rest / 2).collect()
+            }
+            Self::Sort => {
+                if args.len() < 2 {
+                    return None;
+                }
+                // Options are skipped along with their arguments, which may look like options
+                let mut positions = vec![1];
+                let mut i = 2;
+                while i < args.len() {
+                    match &*args[i].to_uppercase() {
+                        "BY" | "GET" => i += 2,
+                        "LIMIT" => i += 3,
+                        "STORE" => {
+                            positions.push(i + 1);
+                            i += 2;
+                        }
+                        _ => i += 1,
+                    }
+                }
+                positions.retain(|&i| i < args.len());
+                positions
+            }
+        };
+        Some(positions)
+    }
+}
+
+/// What there is to know about a command: how to run it, and how it behaves, for everything that
+/// needs to treat commands differently
+#[derive(Clone, Copy)]
+pub struct CommandInfo {
+    pub handler: Handler,
+    /// How many arguments it takes, counting the command name: exactly this many if it's positive,
+    /// or at least `-arity` if it's negative, like Redis
+    pub arity: i32,
+    pub flags: Flags,
+    pub keys: Keys,
+    /// The usage of each subcommand, for commands which have them, which is given to `HELP`
+    pub help: Option<&'static [&'static str]>,
+}
+
+impl CommandInfo {
+    /// Whether the command can be given `argc` arguments, counting the command name
+    pub fn accepts(&self, argc: usize) -> bool {
+        let arity = self.arity.unsigned_abs() as usize;
+        if self.arity < 0 {
+            argc >= arity
+        } else {
+            argc == arity
+        }
+    }
 }
 
 impl Display for Command {
@@ -73,82 +493,523 @@ impl Command {
         <&str>::from(self)
     }
 
-    pub const fn is_write(self) -> bool {
-        match self {
-            Self::Ping
-            | Self::Echo
-            | Self::Get
-            | Self::LRange
-            | Self::LLen
-            | Self::Type
-            | Self::XRange
-            | Self::XRead
-            | Self::Multi
-            | Self::Exec
-            | Self::Discard
-            | Self::Info
-            | Self::ReplConf
-            | Self::PSync
-            | Self::Config
-            | Self::Keys
-            | Self::Subscribe
-            | Self::Unsubscribe
-            | Self::Publish
-            | Self::ZRank
-            | Self::ZRange
-            | Self::ZCard
-            | Self::ZScore => false,
+    /// The command table, which is what dispatch, replication and everything else go by
+    pub fn info(self) -> CommandInfo {
+        const NONE: Flags = Flags::NONE;
+        const WRITE: Flags = Flags::WRITE;
+        const READONLY: Flags = Flags::READONLY;
+        const BLOCKING: Flags = Flags::BLOCKING;
+        const PUBSUB_ALLOWED: Flags = Flags::PUBSUB_ALLOWED;
+        const TRANSACTION: Flags = Flags::TRANSACTION;
+        const NO_AUTH: Flags = Flags::NO_AUTH;
+        const NO_KEYS: Keys = Keys::None;
+        const ONE: Keys = Keys::ONE;
+        const ALL: Keys = Keys::ALL;
+        let num_keys = |index| Keys::NumKeys {
+            index,
+            destination: false,
+        };
+        let store_num_keys = |index| Keys::NumKeys {
+            index,
+            destination: true,
+        };
 
-            Self::Set
-            | Self::RPush
-            | Self::LPush
-            | Self::LPop
-            | Self::BLPop
-            | Self::XAdd
-            | Self::Incr
-            | Self::ZAdd
-            | Self::ZRem => true,
+        let (handler, arity, flags, keys) = match self {
+            // Connections
+            Self::Ping => (handler!(connection::ping), -1, PUBSUB_ALLOWED, NO_KEYS),
+            Self::Echo => (handler!(connection::echo), 2, NONE, NO_KEYS),
+            Self::Hello => (handler!(connection::hello), -1, NO_AUTH, NO_KEYS),
+            Self::Auth => (handler!(connection::auth), -2, NO_AUTH, NO_KEYS),
+            Self::Client => (handler!(connection::client), -2, NONE, NO_KEYS),
+            Self::Command => (handler!(introspection::command), -1, NONE, NO_KEYS),
+            Self::Acl => (handler!(acl::acl), -2, NONE, NO_KEYS),
+
+            // Strings
+            Self::Set => (handler!(string::set), -3, WRITE, ONE),
+            Self::SetNx => (handler!(string::setnx), 3, WRITE, ONE),
+            Self::SetEx => (handler!(string::setex), 4, WRITE, ONE),
+            Self::PSetEx => (handler!(string::psetex), 4, WRITE, ONE),
+            Self::GetSet => (handler!(string::getset), 3, WRITE, ONE),
+            Self::Get => (handler!(get), 2, READONLY, ONE),
+            Self::GetEx => (handler!(string::getex), -2, WRITE, ONE),
+            Self::GetDel => (handler!(string::getdel), 2, WRITE, ONE),
+            Self::Append => (handler!(string::append), 3, WRITE, ONE),
+            Self::StrLen => (handler!(string::strlen), 2, READONLY, ONE),
+            Self::Lcs => (handler!(string::lcs), -3, READONLY, Keys::range(1, 2)),
+            Self::Incr => (handler!(transaction::incr), 2, WRITE, ONE),
+            Self::IncrBy => (handler!(transaction::incrby), 3, WRITE, ONE),
+            Self::Decr => (handler!(transaction::decr), 2, WRITE, ONE),
+            Self::DecrBy => (handler!(transaction::decrby), 3, WRITE, ONE),
+
+            // Bitmaps
+            Self::SetBit => (handler!(bitmap::setbit), 4, WRITE, ONE),
+            Self::GetBit => (handler!(bitmap::getbit), 3, READONLY, ONE),
+            Self::BitCount => (handler!(bitmap::bitcount), -2, READONLY, ONE),
+            Self::BitPos => (handler!(bitmap::bitpos), -3, READONLY, ONE),
+            Self::BitOp => (handler!(bitmap::bitop), -4, WRITE, Keys::range(2, -1)),
+
+            // Lists
+            Self::RPush => (handler!(list::rpush), -3, WRITE, ONE),
+            Self::LPush => (handler!(list::lpush), -3, WRITE, ONE),
+            Self::LRange => (handler!(list::lrange), 4, READONLY, ONE),
+            Self::LLen => (handler!(list::llen), 2, READONLY, ONE),
+            Self::LPop => (handler!(list::lpop), -2, WRITE, ONE),
+            Self::RPop => (handler!(list::rpop), -2, WRITE, ONE),
+            Self::LInsert => (handler!(list::linsert), 5, WRITE, ONE),
+            Self::LSet => (handler!(list::lset), 4, WRITE, ONE),
+            Self::LRem => (handler!(list::lrem), 4, WRITE, ONE),
+            Self::LIndex => (handler!(list::lindex), 3, READONLY, ONE),
+            Self::BLPop => (
+                handler!(list::blpop, context),
+                -3,
+                WRITE | BLOCKING,
+                Keys::range(1, -2),
+            ),
+            Self::LMPop => (handler!(list::lmpop), -4, WRITE, num_keys(1)),
+            Self::BLMPop => (
+                handler!(list::blmpop, context),
+                -5,
+                WRITE | BLOCKING,
+                num_keys(2),
+            ),
+
+            // Streams
+            Self::Type => (handler!(stream::ty), 2, READONLY, ONE),
+            Self::XAdd => (handler!(stream::xadd), -5, WRITE, ONE),
+            Self::XRange => (handler!(stream::xrange), -4, READONLY, ONE),
+            Self::XRead => (
+                handler!(stream::xread, context),
+                -4,
+                READONLY | BLOCKING,
+                Keys::Streams,
+            ),
+            Self::XTrim => (handler!(stream::xtrim), -4, WRITE, ONE),
+            Self::XSetId => (handler!(stream::xsetid), 3, WRITE, ONE),
+            Self::XGroup => (handler!(stream::xgroup), -2, WRITE, Keys::range(2, 2)),
+            Self::XReadGroup => (
+                handler!(stream::xreadgroup, context),
+                -7,
+                WRITE | BLOCKING,
+                Keys::Streams,
+            ),
+            Self::XAck => (handler!(stream::xack), -4, WRITE, ONE),
+            Self::XPending => (handler!(stream::xpending), -3, READONLY, ONE),
+            Self::XClaim => (handler!(stream::xclaim), -6, WRITE, ONE),
+            Self::XAutoClaim => (handler!(stream::xautoclaim), -6, WRITE, ONE),
+
+            // Transactions
+            Self::Multi => (handler!(transaction::multi), 1, TRANSACTION, NO_KEYS),
+            Self::Exec => (handler!(transaction::exec), 1, TRANSACTION, NO_KEYS),
+            Self::Discard => (handler!(transaction::discard), 1, TRANSACTION, NO_KEYS),
+            Self::Watch => (handler!(transaction::watch), -2, TRANSACTION, ALL),
+            Self::Unwatch => (handler!(transaction::unwatch), 1, NONE, NO_KEYS),
+
+            // Replication
+            Self::Info => (handler!(replication::info), -1, NONE, NO_KEYS),
+            Self::ReplConf => (
+                handler!(replication::replconf),
+                -1,
+                Flags::REPLY_TO_MASTER,
+                NO_KEYS,
+            ),
+            Self::PSync => (handler!(replication::psync), 3, NONE, NO_KEYS),
+
+            // Persistence and the keyspace
+            Self::Config => (handler!(persistence::config), -2, NONE, NO_KEYS),
+            Self::Keys => (handler!(persistence::keys), 2, READONLY, NO_KEYS),
+            Self::BgRewriteAof => (handler!(persistence::bgrewriteaof), 1, NONE, NO_KEYS),
+            Self::Save => (handler!(persistence::save), 1, NONE, NO_KEYS),
+            Self::Del => (handler!(generic::del), -2, WRITE, ALL),
+            Self::Unlink => (handler!(generic::unlink), -2, WRITE, ALL),
+            Self::Rename => (handler!(generic::rename), 3, WRITE, Keys::range(1, 2)),
+            Self::RenameNx => (handler!(generic::renamenx), 3, WRITE, Keys::range(1, 2)),
+            Self::Copy => (handler!(generic::copy), -3, WRITE, Keys::range(1, 2)),
+            Self::DbSize => (handler!(generic::dbsize), 1, READONLY, NO_KEYS),
+            Self::FlushDb => (handler!(generic::flushdb), -1, WRITE, NO_KEYS),
+            Self::FlushAll => (handler!(generic::flushall), -1, WRITE, NO_KEYS),
+            Self::Scan => (handler!(generic::scan), -2, READONLY, NO_KEYS),
+            Self::Object => (handler!(generic::object), -2, READONLY, Keys::range(2, 2)),
+
+            // Pub/sub
+            Self::Subscribe => (handler!(pubsub::subscribe), -2, PUBSUB_ALLOWED, NO_KEYS),
+            Self::Unsubscribe => (handler!(pubsub::unsubscribe), -1, PUBSUB_ALLOWED, NO_KEYS),
+            Self::PSubscribe => (handler!(pubsub::psubscribe), -2, PUBSUB_ALLOWED, NO_KEYS),
+            Self::PUnsubscribe => (handler!(pubsub::punsubscribe), -1, PUBSUB_ALLOWED, NO_KEYS),
+            Self::Publish => (handler!(pubsub::publish), 3, PUBSUB_ALLOWED, NO_KEYS),
+            Self::PubSub => (handler!(pubsub::pubsub), -2, NONE, NO_KEYS),
+
+            // Sorted sets
+            Self::ZAdd => (handler!(sorted_set::zadd), -4, WRITE, ONE),
+            Self::ZRank => (handler!(sorted_set::zrank), 3, READONLY, ONE),
+            Self::ZRange => (handler!(sorted_set::zrange), -4, READONLY, ONE),
+            Self::ZRevRange => (handler!(sorted_set::zrevrange), -4, READONLY, ONE),
+            Self::ZRangeByScore => (handler!(sorted_set::zrangebyscore), -4, READONLY, ONE),
+            Self::ZRevRangeByScore => (handler!(sorted_set::zrevrangebyscore), -4, READONLY, ONE),
+            Self::ZRangeStore => (
+                handler!(sorted_set::zrangestore),
+                -5,
+                WRITE,
+                Keys::range(1, 2),
+            ),
+            Self::ZUnion => (handler!(sorted_set::zunion), -3, READONLY, num_keys(1)),
+            Self::ZInter => (handler!(sorted_set::zinter), -3, READONLY, num_keys(1)),
+            Self::ZDiff => (handler!(sorted_set::zdiff), -3, READONLY, num_keys(1)),
+            Self::ZUnionStore => (
+                handler!(sorted_set::zunionstore),
+                -4,
+                WRITE,
+                store_num_keys(2),
+            ),
+            Self::ZInterStore => (
+                handler!(sorted_set::zinterstore),
+                -4,
+                WRITE,
+                store_num_keys(2),
+            ),
+            Self::ZDiffStore => (
+                handler!(sorted_set::zdiffstore),
+                -4,
+                WRITE,
+                store_num_keys(2),
+            ),
+            Self::ZCard => (handler!(sorted_set::zcard), 2, READONLY, ONE),
+            Self::ZScore => (handler!(sorted_set::zscore), 3, READONLY, ONE),
+            Self::ZMScore => (handler!(sorted_set::zmscore), -3, READONLY, ONE),
+            Self::ZRem => (handler!(sorted_set::zrem), -3, WRITE, ONE),
+            Self::ZRemRangeByRank => (handler!(sorted_set::zremrangebyrank), 4, WRITE, ONE),
+            Self::ZRemRangeByScore => (handler!(sorted_set::zremrangebyscore), 4, WRITE, ONE),
+            Self::ZRemRangeByLex => (handler!(sorted_set::zremrangebylex), 4, WRITE, ONE),
+            Self::ZPopMin => (handler!(sorted_set::zpopmin), -2, WRITE, ONE),
+            Self::ZPopMax => (handler!(sorted_set::zpopmax), -2, WRITE, ONE),
+            Self::BZPopMin => (
+                handler!(sorted_set::bzpopmin, context),
+                -3,
+                WRITE | BLOCKING,
+                Keys::range(1, -2),
+            ),
+            Self::BZPopMax => (
+                handler!(sorted_set::bzpopmax, context),
+                -3,
+                WRITE | BLOCKING,
+                Keys::range(1, -2),
+            ),
+
+            // Geospatial indexes
+            Self::GeoAdd => (handler!(geo::geoadd), -5, WRITE, ONE),
+            Self::GeoPos => (handler!(geo::geopos), -2, READONLY, ONE),
+            Self::GeoDist => (handler!(geo::geodist), -4, READONLY, ONE),
+            Self::GeoHash => (handler!(geo::geohash), -2, READONLY, ONE),
+
+            // Hashes
+            Self::HSet => (handler!(hash::hset), -4, WRITE, ONE),
+            Self::HGet => (handler!(hash::hget), 3, READONLY, ONE),
+            Self::HMGet => (handler!(hash::hmget), -3, READONLY, ONE),
+            Self::HGetAll => (handler!(hash::hgetall), 2, READONLY, ONE),
+            Self::HDel => (handler!(hash::hdel), -3, WRITE, ONE),
+            Self::HLen => (handler!(hash::hlen), 2, READONLY, ONE),
+            Self::HExists => (handler!(hash::hexists), 3, READONLY, ONE),
+            Self::HKeys => (handler!(hash::hkeys), 2, READONLY, ONE),
+            Self::HVals => (handler!(hash::hvals), 2, READONLY, ONE),
+            Self::HSetNx => (handler!(hash::hsetnx), 4, WRITE, ONE),
+            Self::HStrLen => (handler!(hash::hstrlen), 3, READONLY, ONE),
+            Self::HRandField => (handler!(hash::hrandfield), -2, READONLY, ONE),
+            Self::HExpire => (handler!(hash::hexpire), -6, WRITE, ONE),
+            Self::HPExpire => (handler!(hash::hpexpire), -6, WRITE, ONE),
+            Self::HExpireAt => (handler!(hash::hexpireat), -6, WRITE, ONE),
+            Self::HPExpireAt => (handler!(hash::hpexpireat), -6, WRITE, ONE),
+            Self::HTtl => (handler!(hash::httl), -5, READONLY, ONE),
+            Self::HPTtl => (handler!(hash::hpttl), -5, READONLY, ONE),
+            Self::HPersist => (handler!(hash::hpersist), -5, WRITE, ONE),
+
+            // Sets
+            Self::SAdd => (handler!(set::sadd), -3, WRITE, ONE),
+            Self::SRem => (handler!(set::srem), -3, WRITE, ONE),
+            Self::SMembers => (handler!(set::smembers), 2, READONLY, ONE),
+            Self::SIsMember => (handler!(set::sismember), 3, READONLY, ONE),
+            Self::SCard => (handler!(set::scard), 2, READONLY, ONE),
+
+            Self::Sort => (handler!(sort::sort), -2, WRITE, Keys::Sort),
+            Self::SortRo => (handler!(sort::sort_ro), -2, READONLY, ONE),
+
+            Self::Cluster => (handler!(cluster::cluster), -2, NONE, NO_KEYS),
+
+            // Expiry
+            Self::Expire => (handler!(expire::expire), -3, WRITE, ONE),
+            Self::PExpire => (handler!(expire::pexpire), -3, WRITE, ONE),
+            Self::ExpireAt => (handler!(expire::expireat), -3, WRITE, ONE),
+            Self::PExpireAt => (handler!(expire::pexpireat), -3, WRITE, ONE),
+            Self::ExpireTime => (handler!(expire::expiretime), 2, READONLY, ONE),
+            Self::PExpireTime => (handler!(expire::pexpiretime), 2, READONLY, ONE),
+            Self::Ttl => (handler!(expire::ttl), 2, READONLY, ONE),
+            Self::PTtl => (handler!(expire::pttl), 2, READONLY, ONE),
+            Self::Persist => (handler!(expire::persist), 2, WRITE, ONE),
+        };
+
+        CommandInfo {
+            handler,
+            arity,
+            flags,
+            keys,
+            help: help::subcommands(self),
         }
     }
 
-    pub const fn send_response(self) -> bool {
-        match self {
-            Self::ReplConf => true,
-            Self::Ping
-            | Self::Echo
-            | Self::Set
+    /// The ACL categories the command is in, which follow from its flags as well as what it
+    /// works on
+    pub fn categories(self) -> Vec<Category> {
+        use Category as C;
+
+        let flags = self.info().flags;
+        let mut categories: Vec<Category> = [
+            (Flags::WRITE, C::Write),
+            (Flags::READONLY, C::Read),
+            (Flags::BLOCKING, C::Blocking),
+        ]
+        .into_iter()
+        .filter(|(flag, _)| flags.contains(*flag))
+        .map(|(_, category)| category)
+        .collect();
+
+        let group: &[Category] = match self {
+            Self::Ping | Self::Echo | Self::Hello | Self::Auth | Self::Client | Self::Command => {
+                &[C::Connection]
+            }
+            Self::Acl => &[C::Admin, C::Dangerous],
+
+            Self::Set
+            | Self::SetNx
+            | Self::SetEx
+            | Self::PSetEx
+            | Self::GetSet
             | Self::Get
-            | Self::RPush
+            | Self::GetEx
+            | Self::GetDel
+            | Self::Append
+            | Self::StrLen
+            | Self::Lcs
+            | Self::Incr
+            | Self::IncrBy
+            | Self::Decr
+            | Self::DecrBy => &[C::String],
+
+            Self::SetBit | Self::GetBit | Self::BitCount | Self::BitPos | Self::BitOp => {
+                &[C::Bitmap]
+            }
+
+            Self::RPush
             | Self::LPush
             | Self::LRange
             | Self::LLen
             | Self::LPop
+            | Self::RPop
+            | Self::LInsert
+            | Self::LSet
+            | Self::LRem
+            | Self::LIndex
             | Self::BLPop
-            | Self::Type
-            | Self::XAdd
+            | Self::LMPop
+            | Self::BLMPop => &[C::List],
+
+            Self::XAdd
             | Self::XRange
             | Self::XRead
-            | Self::Incr
-            | Self::Multi
-            | Self::Exec
-            | Self::Discard
-            | Self::Info
-            | Self::PSync
-            | Self::Config
-            | Self::Keys
-            | Self::Subscribe
+            | Self::XTrim
+            | Self::XSetId
+            | Self::XGroup
+            | Self::XReadGroup
+            | Self::XAck
+            | Self::XPending
+            | Self::XClaim
+            | Self::XAutoClaim => &[C::Stream],
+
+            Self::Multi | Self::Exec | Self::Discard | Self::Watch | Self::Unwatch => {
+                &[C::Transaction]
+            }
+
+            Self::Info => &[C::Dangerous],
+            Self::ReplConf | Self::PSync | Self::Config | Self::BgRewriteAof | Self::Save => {
+                &[C::Admin, C::Dangerous]
+            }
+            Self::Keys | Self::FlushDb | Self::FlushAll => &[C::Keyspace, C::Dangerous],
+            Self::Type
+            | Self::Del
+            | Self::Unlink
+            | Self::Rename
+            | Self::RenameNx
+            | Self::Copy
+            | Self::DbSize
+            | Self::Scan
+            | Self::Object
+            | Self::Expire
+            | Self::PExpire
+            | Self::ExpireAt
+            | Self::PExpireAt
+            | Self::ExpireTime
+            | Self::PExpireTime
+            | Self::Ttl
+            | Self::PTtl
+            | Self::Persist => &[C::Keyspace],
+
+            Self::Subscribe
             | Self::Unsubscribe
             | Self::Publish
-            | Self::ZAdd
+            | Self::PSubscribe
+            | Self::PUnsubscribe
+            | Self::PubSub => &[C::PubSub],
+
+            Self::ZAdd
             | Self::ZRank
             | Self::ZRange
+            | Self::ZRevRange
+            | Self::ZRangeByScore
+            | Self::ZRevRangeByScore
+            | Self::ZRangeStore
+            | Self::ZUnion
+            | Self::ZInter
+            | Self::ZDiff
+            | Self::ZUnionStore
+            | Self::ZInterStore
+            | Self::ZDiffStore
             | Self::ZCard
             | Self::ZScore
-            | Self::ZRem => false,
-        }
+            | Self::ZMScore
+            | Self::ZRem
+            | Self::ZRemRangeByRank
+            | Self::ZRemRangeByScore
+            | Self::ZRemRangeByLex
+            | Self::ZPopMin
+            | Self::ZPopMax
+            | Self::BZPopMin
+            | Self::BZPopMax => &[C::SortedSet],
+
+            Self::GeoAdd | Self::GeoPos | Self::GeoDist | Self::GeoHash => &[C::Geo],
+
+            Self::HSet
+            | Self::HGet
+            | Self::HMGet
+            | Self::HGetAll
+            | Self::HDel
+            | Self::HLen
+            | Self::HExists
+            | Self::HKeys
+            | Self::HVals
+            | Self::HSetNx
+            | Self::HStrLen
+            | Self::HRandField
+            | Self::HExpire
+            | Self::HPExpire
+            | Self::HExpireAt
+            | Self::HPExpireAt
+            | Self::HTtl
+            | Self::HPTtl
+            | Self::HPersist => &[C::Hash],
+
+            Self::SAdd | Self::SRem | Self::SMembers | Self::SIsMember | Self::SCard => &[C::Set],
+
+            Self::Sort => &[C::Set, C::SortedSet, C::List, C::Dangerous],
+            Self::SortRo => &[C::Set, C::SortedSet, C::List],
+
+            Self::Cluster => &[],
+        };
+        categories.extend(group);
+
+        // Those which take about constant time, as Redis counts them
+        let fast = matches!(
+            self,
+            Self::Ping
+                | Self::Echo
+                | Self::Hello
+                | Self::Auth
+                | Self::SetNx
+                | Self::GetSet
+                | Self::Get
+                | Self::GetEx
+                | Self::GetDel
+                | Self::Append
+                | Self::StrLen
+                | Self::Incr
+                | Self::IncrBy
+                | Self::Decr
+                | Self::DecrBy
+                | Self::GetBit
+                | Self::RPush
+                | Self::LPush
+                | Self::LLen
+                | Self::LPop
+                | Self::RPop
+                | Self::Type
+                | Self::XAdd
+                | Self::XSetId
+                | Self::XAck
+                | Self::Multi
+                | Self::Discard
+                | Self::Watch
+                | Self::Unwatch
+                | Self::Unlink
+                | Self::DbSize
+                | Self::Publish
+                | Self::ZAdd
+                | Self::ZRank
+                | Self::ZCard
+                | Self::ZScore
+                | Self::ZMScore
+                | Self::ZRem
+                | Self::ZPopMin
+                | Self::ZPopMax
+                | Self::BZPopMin
+                | Self::BZPopMax
+                | Self::HSet
+                | Self::HGet
+                | Self::HMGet
+                | Self::HDel
+                | Self::HLen
+                | Self::HExists
+                | Self::HSetNx
+                | Self::HStrLen
+                | Self::SAdd
+                | Self::SRem
+                | Self::SIsMember
+                | Self::SCard
+                | Self::Expire
+                | Self::PExpire
+                | Self::ExpireAt
+                | Self::PExpireAt
+                | Self::ExpireTime
+                | Self::PExpireTime
+                | Self::Ttl
+                | Self::PTtl
+                | Self::Persist
+        );
+        categories.push(if fast { C::Fast } else { C::Slow });
+
+        categories
+    }
+
+    pub fn is_write(self) -> bool {
+        self.info().flags.contains(Flags::WRITE)
     }
 
-    pub fn into_command_value(self, args: &[String]) -> Value {
+    /// Commands which are replied to even when our master sends them
+    pub fn send_response(self) -> bool {
+        self.info().flags.contains(Flags::REPLY_TO_MASTER)
+    }
+
+    /// Commands which may wait on other clients before completing
+    pub fn is_blocking(self) -> bool {
+        self.info().flags.contains(Flags::BLOCKING)
+    }
+
+    /// Commands which run straight away inside `MULTI`, rather than being queued for `EXEC`
+    pub fn runs_in_multi(self) -> bool {
+        self.info().flags.contains(Flags::TRANSACTION)
+    }
+
+    /// Commands which may be run before authenticating
+    pub fn needs_auth(self) -> bool {
+        !self.info().flags.contains(Flags::NO_AUTH)
+    }
+
+    pub fn into_command_value(self, args: &[Bytes]) -> Value {
         std::iter::once(Value::from(self))
             .chain(args.iter().map(Value::from))
             .collect()
@@ -157,117 +1018,30 @@ impl Command {
     pub async fn execute(
         self,
         conn_state: &mut ConnectionState,
-        args: &[String],
+        args: &[Bytes],
+        context: ExecContext,
     ) -> anyhow::Result<Value> {
-        eprintln!("Command::execute on {self:?}");
-        let state = Arc::clone(&conn_state.app_state);
-        let ret = match (self, conn_state.mode) {
-            // Intro
-            (Command::Ping, ConnectionMode::Normal) => {
-                Value::simple_string("PONG")
-            }
-            (Command::Echo, ConnectionMode::Normal) => Value::bulk_string(&args[0]),
-            (Command::Set, ConnectionMode::Normal) => set(state, conn_state, args).await?,
-            (Command::Get, ConnectionMode::Normal) => get(state, conn_state, args).await?,
-
-            // Lists
-            (Command::RPush, ConnectionMode::Normal) => {
-                list::rpush(state, conn_state, args).await?
-            }
-            (Command::LPush, ConnectionMode::Normal) => {
-                list::lpush(state, conn_state, args).await?
-            }
-            (Command::LRange, ConnectionMode::Normal) => {
-                list::lrange(state, conn_state, args).await?
-            }
-            (Command::LLen, ConnectionMode::Normal) => list::llen(state, conn_state, args).await?,
-            (Command::LPop, ConnectionMode::Normal) => list::lpop(state, conn_state, args).await?,
-            (Command::BLPop, ConnectionMode::Normal) => {
-                list::blpop(state, conn_state, args).await?
-            }
-
-            // Streams
-            (Command::Type, ConnectionMode::Normal) => stream::ty(state, conn_state, args).await?,
-            (Command::XAdd, ConnectionMode::Normal) => {
-                stream::xadd(state, conn_state, args).await?
-            }
-            (Command::XRange, ConnectionMode::Normal) => {
-                stream::xrange(state, conn_state, args).await?
-            }
-            (Command::XRead, ConnectionMode::Normal) => {
-                stream::xread(state, conn_state, args).await?
-            }
-
-            // Transactions
-            (Command::Incr, ConnectionMode::Normal) => {
-                transaction::incr(state, conn_state, args).await?
-            }
-            (Command::Multi, ConnectionMode::Normal) => {
-                conn_state.txn = Some(Vec::new());
-                Value::simple_string("OK")
-            }
-            (Command::Exec, ConnectionMode::Normal) => {
-                Value::simple_error("ERR EXEC without MULTI")
-            }
-            (Command::Discard, ConnectionMode::Normal) => {
-                Value::simple_error("ERR DISCARD without MULTI")
-            }
-
-            // Replication
-            (Command::Info, ConnectionMode::Normal) => {
-                replication::info(state, conn_state, args).await?
-            }
-            (Command::ReplConf, ConnectionMode::Normal) => {
-                replication::replconf(state, conn_state, args).await?
-            }
-            (Command::PSync, ConnectionMode::Normal) => {
-                replication::psync(state, conn_state, args).await?
-            }
-
-            // RDB Persistence
-            (Command::Config, ConnectionMode::Normal) => {
-                persistence::config(state, conn_state, args).await?
-            }
-            (Command::Keys, ConnectionMode::Normal) => {
-                persistence::keys(state, conn_state, args).await?
-            }
-
-            (Command::Subscribe, ConnectionMode::Normal | ConnectionMode::Subscribed) => {
-                pubsub::subscribe(state, conn_state, args).await?
-            }
-            (Command::Unsubscribe, ConnectionMode::Normal | ConnectionMode::Subscribed) => {
-                pubsub::unsubscribe(state, conn_state, args).await?
-            }
-            (Command::Ping, ConnectionMode::Subscribed) => {
-                Value::from_iter(["pong", ""])
-            }
-            (Command::Publish, ConnectionMode::Normal | ConnectionMode::Subscribed) => {
-                pubsub::publish(state, conn_state, args).await?
+        let info = self.info();
+        if let ConnectionMode::Subscribed = conn_state.command_mode() {
+            if !info.flags.contains(Flags::PUBSUB_ALLOWED) {
+                return Ok(Value::simple_error(format!("ERR Can't execute '{self}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context")));
             }
+        }
 
-            (Command::ZAdd, ConnectionMode::Normal) => {
-                sorted_set::zadd(state, conn_state, args).await?
-            }
-            (Command::ZRank, ConnectionMode::Normal) => {
-                sorted_set::zrank(state, conn_state, args).await?
-            }
-            (Command::ZRange, ConnectionMode::Normal) => {
-                sorted_set::zrange(state, conn_state, args).await?
-            }
-            (Command::ZCard, ConnectionMode::Normal) => {
-                sorted_set::zcard(state, conn_state, args).await?
+        if let (Some(lines), [subcommand]) = (info.help, args) {
+            if subcommand.eq_ignore_ascii_case(b"help") {
+                return Ok(help::reply(self, lines));
             }
-            (Command::ZScore, ConnectionMode::Normal) => {
-                sorted_set::zscore(state, conn_state, args).await?
-            }
-            (Command::ZRem, ConnectionMode::Normal) => {
-                sorted_set::zrem(state, conn_state, args).await?
-            }
-
-            (cmd, ConnectionMode::Subscribed) => Value::simple_error(format!("ERR Can't execute '{cmd}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context"))
-        };
+        }
 
-        Ok(ret)
+        let state = Arc::clone(&conn_state.app_state);
+        match (info.handler)(state, conn_state, args, context).await {
+            Ok(value) => Ok(value),
+            // Only errors which aren't the client's fault drop the connection
+            Err(RedisError::Internal(err)) => Err(err),
+            Err(RedisError::WrongArity) => Ok(wrong_arity(&self.to_str().to_lowercase()).into()),
+            Err(err) => Ok(err.into()),
+        }
     }
 }
 
@@ -277,58 +1051,68 @@ impl From<Command> for Value {
     }
 }
 
-pub async fn set(
-    state: Arc<State>,
-    _: &mut ConnectionState,
-    args: &[String],
-) -> anyhow::Result<Value> {
-    let [key, value, ..] = args else {
-        todo!("args.len() < 2");
-    };
+/// The names clients run commands by, which are the commands' own unless `rename-command` has
+/// renamed or disabled them.  Our master and the AOF always use the commands' own names, since
+/// they're what we propagate.
+#[derive(Debug)]
+pub struct CommandTable {
+    /// Every enabled command by its name in uppercase
+    by_name: HashMap<String, Command>,
+    /// The name of every enabled command, in lowercase
+    names: HashMap<Command, String>,
+}
 
-    let value = MapValue {
-        value: MapValueContent::from(&**value),
-        expires_at: if args.len() > 2 && args[2].eq_ignore_ascii_case("px") {
-            let ms: u64 = args[3].parse().context("parsing millis until expiration")?;
+impl CommandTable {
+    /// The table with each command in `renames` given its new name, or disabled if it's `None`
+    pub fn new(renames: &[(Command, Option<String>)]) -> Self {
+        let mut names: HashMap<Command, String> = Command::iter()
+            .map(|command| (command, command.to_str().to_lowercase()))
+            .collect();
+        for (command, name) in renames {
+            match name {
+                Some(name) => names.insert(*command, name.to_lowercase()),
+                None => names.remove(command),
+            };
+        }
+        let by_name = names
+            .iter()
+            .map(|(&command, name)| (name.to_uppercase(), command))
+            .collect();
+        Self { by_name, names }
+    }
 
-            Some(SystemTime::now() + Duration::from_millis(ms))
-        } else {
-            None
-        },
-    };
+    pub fn lookup(&self, name: &Bytes) -> Option<Command> {
+        self.by_name.get(&name.to_uppercase()).copied()
+    }
 
-    state.map.insert(key.clone(), value);
-    Ok(Value::bulk_string("OK"))
+    /// The name clients run `command` by, if it isn't disabled
+    pub fn name(&self, command: Command) -> Option<&str> {
+        self.names.get(&command).map(String::as_str)
+    }
+
+    /// Every enabled command, in the order of [`Command`]
+    pub fn iter(&self) -> impl Iterator<Item = Command> + '_ {
+        Command::iter().filter(|command| self.names.contains_key(command))
+    }
 }
 
 pub async fn get(
     state: Arc<State>,
     _: &mut ConnectionState,
-    args: &[String],
-) -> anyhow::Result<Value> {
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
     let key = &args[0];
     let value = if let Some(value) = state.map.get(key) {
-        if value.expires_at.is_none_or(|e| SystemTime::now() < e) {
-            eprintln!("get {key} from map -> {:?}", value.value);
-            if let Some(expires_at) = value.expires_at {
-                let expires_in = expires_at.duration_since(SystemTime::now()).unwrap();
-                eprintln!("expires in {}s", expires_in.as_secs_f64());
-            }
-            match &value.value {
-                MapValueContent::Integer(n) => Value::bulk_string(n.to_string()),
-                MapValueContent::String(string) => Value::bulk_string(string.clone()),
-                MapValueContent::List(_) => Value::Null,
-                MapValueContent::Stream(_) => Value::Null,
-                MapValueContent::SortedSet(_) => Value::Null,
-            }
-        } else {
-            drop(value);
-            state.map.remove(key);
-            eprintln!("remove {key} from map because expired");
-            Value::Null
+        match &value.value {
+            MapValueContent::Integer(n) => Value::bulk_string(n.to_string()),
+            MapValueContent::String(string) => Value::bulk_bytes(string),
+            MapValueContent::List(_)
+            | MapValueContent::Stream(_)
+            | MapValueContent::SortedSet(_)
+            | MapValueContent::Hash(_)
+            | MapValueContent::Set(_) => return Err(RedisError::WrongType),
         }
     } else {
-        eprintln!("get {key} from map -> (nil)");
         Value::Null
     };
 
@@ -1,41 +1,62 @@
 use std::sync::Arc;
 
-use anyhow::{bail, ensure};
+use tracing::warn;
 
-use crate::{resp::Value, ConnectionState, State};
+use crate::{
+    aof,
+    bytes::Bytes,
+    config::{self, Param},
+    error::RedisError,
+    glob,
+    resp::{DataKind, Value},
+    ConnectionState, State,
+};
 
 pub async fn config(
     state: Arc<State>,
     _: &mut ConnectionState,
-    args: &[String],
-) -> anyhow::Result<Value> {
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
     let [method, fields @ ..] = args else {
-        bail!("TODO: args.len() < 1");
+        return Err(RedisError::WrongArity);
     };
 
     let ret = match &*method.to_lowercase() {
-        "get" => fields
-            .iter()
-            .flat_map(|f| {
-                [
-                    Value::from(f),
-                    match &**f {
-                        "dir" => state
-                            .dir
-                            .as_ref()
-                            .map(|p| Value::from(&*p.to_string_lossy()))
-                            .unwrap_or_default(),
-                        "dbfilename" => state
-                            .db_filename
-                            .as_ref()
-                            .map(Value::from)
-                            .unwrap_or_default(),
-                        _ => panic!("Unknown field '{f}'"),
-                    },
-                ]
-            })
-            .collect(),
-        _ => bail!("Unknown config method '{method}'"),
+        "get" => {
+            if fields.is_empty() {
+                return Err(super::wrong_arity("config|get"));
+            }
+            // Each parameter is given once, even if it matches more than one pattern
+            let patterns: Vec<Vec<u8>> = fields.iter().map(|f| f.to_ascii_lowercase()).collect();
+            let config = state.config();
+            Value::Map(
+                config::PARAMS
+                    .iter()
+                    .filter(|param| {
+                        patterns
+                            .iter()
+                            .any(|pattern| glob::matches(pattern, param.name.as_bytes()))
+                    })
+                    .map(|param| (Value::from(param.name), Value::from((param.get)(&config))))
+                    .collect(),
+            )
+        }
+        "set" => {
+            if fields.is_empty() || fields.len() % 2 != 0 {
+                return Err(super::wrong_arity("config|set"));
+            }
+            set_config(&state, fields)?;
+            Value::simple_string("OK")
+        }
+        "resetstat" => {
+            state.stats.reset();
+            Value::simple_string("OK")
+        }
+        _ => {
+            return Err(RedisError::custom(format!(
+                "ERR unknown subcommand '{method}'. Try CONFIG HELP."
+            )))
+        }
     };
 
     Ok(ret)
@@ -44,12 +65,118 @@ pub async fn config(
 pub async fn keys(
     state: Arc<State>,
     _: &mut ConnectionState,
-    args: &[String],
-) -> anyhow::Result<Value> {
-    let [filter] = args else {
-        bail!("TODO: args.len() != 1");
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [pattern] = args else {
+        return Err(RedisError::WrongArity);
     };
-    ensure!(filter == "*");
 
-    Ok(state.map.iter().map(|e| Value::from(e.key())).collect())
+    // The keyspace could be large, so the keys are streamed to the client as they're found
+    // rather than being collected up front
+    let (tx, reply) = Value::streamed(DataKind::Array);
+    let pattern = pattern.clone();
+    tokio::task::spawn_blocking(move || {
+        for e in state.map.iter() {
+            if state.map.is_expired(&e) || !glob::matches(&pattern, e.key()) {
+                continue;
+            }
+            // The client has gone
+            if tx.send(Value::from(e.key())).is_err() {
+                break;
+            }
+        }
+    });
+
+    Ok(reply)
+}
+
+pub async fn bgrewriteaof(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    _: &[Bytes],
+) -> Result<Value, RedisError> {
+    if state.aof.is_none() {
+        return Err(RedisError::custom(
+            "ERR Append only file is not enabled, use '--appendonly yes'",
+        ));
+    }
+
+    if aof::start_rewrite(state).await? {
+        Ok(Value::simple_string(
+            "Background append only file rewriting started",
+        ))
+    } else {
+        Err(RedisError::custom(
+            "ERR Background append only file rewriting already in progress",
+        ))
+    }
+}
+
+pub async fn save(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    _: &[Bytes],
+) -> Result<Value, RedisError> {
+    match state.save().await {
+        Ok(()) => Ok(Value::simple_string("OK")),
+        Err(err) => {
+            warn!("error saving the rdb file: {err:?}");
+            Err(RedisError::custom(
+                "ERR Error saving the rdb file, see the server log for details",
+            ))
+        }
+    }
+}
+
+/// Set each parameter in `pairs` of names and values, or none of them if any can't be set
+fn set_config(state: &State, pairs: &[Bytes]) -> Result<(), RedisError> {
+    let failed = |name: &str, reason: &dyn std::fmt::Display| {
+        RedisError::custom(format!(
+            "ERR CONFIG SET failed (possibly related to argument '{name}') - {reason}"
+        ))
+    };
+
+    let mut changes: Vec<(&Param, String)> = Vec::new();
+    for pair in pairs.chunks_exact(2) {
+        let name = pair[0].to_string_lossy();
+        let Some(param) = config::param(&name) else {
+            return Err(RedisError::custom(format!(
+                "ERR Unknown option or number of arguments for CONFIG SET - '{name}'"
+            )));
+        };
+        if !param.mutable {
+            return Err(failed(&name, &"can't set immutable config"));
+        }
+        if changes.iter().any(|(p, _)| p.name == param.name) {
+            return Err(failed(&name, &"duplicate parameter"));
+        }
+        changes.push((param, pair[1].to_string()));
+    }
+
+    let mut config = state.config.write().unwrap_or_else(|err| err.into_inner());
+    let old = config.clone();
+    for (param, value) in &changes {
+        if let Err(reason) = (param.set)(&mut config, value) {
+            *config = old;
+            return Err(failed(param.name, &reason));
+        }
+    }
+
+    // If a change can't be put into effect, those which already were are put back
+    for (i, (param, _)) in changes.iter().enumerate() {
+        let Some(apply) = param.apply else {
+            continue;
+        };
+        if let Err(err) = apply(state, &config) {
+            *config = old;
+            for (param, _) in &changes[..i] {
+                if let Some(apply) = param.apply {
+                    let _ = apply(state, &config);
+                }
+            }
+            return Err(failed(param.name, &err));
+        }
+    }
+
+    Ok(())
 }
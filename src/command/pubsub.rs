@@ -1,70 +1,257 @@
 use std::sync::Arc;
 
-use anyhow::bail;
+use anyhow::Context;
 
-use crate::{resp::Value, ConnectionMode, ConnectionState, State};
+use crate::{
+    bytes::Bytes, error::RedisError, glob, resp::Value, ConnectionMode, ConnectionState, Listeners,
+    State,
+};
+
+/// Reply with each of `replies` in turn.  All but the last are sent straight away, and the last
+/// is returned to be sent as usual.
+fn reply_each(
+    conn_state: &ConnectionState,
+    mut replies: Vec<Value>,
+    command: &str,
+) -> Result<Value, RedisError> {
+    let last = replies.pop().expect("there is at least one reply");
+    for reply in replies {
+        conn_state
+            .tx()
+            .send(reply)
+            .with_context(|| format!("replying to {command}"))?;
+    }
+    Ok(last)
+}
 
 pub async fn subscribe(
     state: Arc<State>,
     conn_state: &mut ConnectionState,
-    args: &[String],
-) -> anyhow::Result<Value> {
-    let [channel] = args else {
-        bail!("TODO: args.len() != 1");
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    conn_state.mode = ConnectionMode::Subscribed;
+
+    let replies = args
+        .iter()
+        .map(|channel| {
+            if conn_state.channels.insert(channel.clone()) {
+                state
+                    .channel_listeners
+                    .entry(channel.clone())
+                    .or_default()
+                    .push(conn_state.tx().clone());
+            }
+
+            Value::Push(vec![
+                Value::from("subscribe"),
+                Value::from(channel),
+                Value::from(conn_state.subscription_count()),
+            ])
+        })
+        .collect();
+    reply_each(conn_state, replies, "subscribe")
+}
+
+/// Unsubscribe from each of the given channels, or from every channel if none are given
+pub async fn unsubscribe(
+    _: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let channels: Vec<Bytes> = if args.is_empty() {
+        conn_state.channels.iter().cloned().collect()
+    } else {
+        args.to_vec()
     };
 
+    // Unsubscribing from nothing still gets a reply
+    if channels.is_empty() {
+        return Ok(Value::Push(vec![
+            Value::from("unsubscribe"),
+            Value::Null,
+            Value::from(conn_state.subscription_count()),
+        ]));
+    }
+
+    let replies = channels
+        .iter()
+        .map(|channel| {
+            let len = conn_state.unsubscribe(channel);
+            Value::Push(vec![
+                Value::from("unsubscribe"),
+                Value::from(channel),
+                Value::from(len),
+            ])
+        })
+        .collect();
+    reply_each(conn_state, replies, "unsubscribe")
+}
+
+pub async fn psubscribe(
+    state: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
     conn_state.mode = ConnectionMode::Subscribed;
-    conn_state.channels.insert(channel.clone());
 
-    state
-        .channel_listeners
-        .entry(channel.clone())
-        .or_default()
-        .push(conn_state.tx().clone());
+    let replies = args
+        .iter()
+        .map(|pattern| {
+            if conn_state.patterns.insert(pattern.clone()) {
+                state
+                    .pattern_listeners
+                    .entry(pattern.clone())
+                    .or_default()
+                    .push(conn_state.tx().clone());
+            }
 
-    Ok(Value::from_iter([
-        Value::from("subscribe"),
-        Value::from(channel),
-        Value::from(conn_state.channels.len()),
-    ]))
+            Value::Push(vec![
+                Value::from("psubscribe"),
+                Value::from(pattern),
+                Value::from(conn_state.subscription_count()),
+            ])
+        })
+        .collect();
+    reply_each(conn_state, replies, "psubscribe")
 }
 
-pub async fn unsubscribe(
+/// Unsubscribe from each of the given patterns, or from every pattern if none are given
+pub async fn punsubscribe(
     _: Arc<State>,
     conn_state: &mut ConnectionState,
-    args: &[String],
-) -> anyhow::Result<Value> {
-    let [channel] = args else {
-        bail!("TODO: args.len() != 1");
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let patterns: Vec<Bytes> = if args.is_empty() {
+        conn_state.patterns.iter().cloned().collect()
+    } else {
+        args.to_vec()
     };
 
-    let len = conn_state.unsubscribe(channel);
+    if patterns.is_empty() {
+        return Ok(Value::Push(vec![
+            Value::from("punsubscribe"),
+            Value::Null,
+            Value::from(conn_state.subscription_count()),
+        ]));
+    }
 
-    Ok(Value::from_iter([
-        Value::from("unsubscribe"),
-        Value::from(channel),
-        Value::from(len),
-    ]))
+    let replies = patterns
+        .iter()
+        .map(|pattern| {
+            let len = conn_state.punsubscribe(pattern);
+            Value::Push(vec![
+                Value::from("punsubscribe"),
+                Value::from(pattern),
+                Value::from(len),
+            ])
+        })
+        .collect();
+    reply_each(conn_state, replies, "punsubscribe")
+}
+
+/// Send `message` to everyone listening on `name`, returning how many there are.  Only a read
+/// lock is held while sending, so publishes to the same channel don't wait on each other; clients
+/// which have hung up are forgotten afterwards.
+fn send_to_listeners(listeners: &Listeners, name: &[u8], message: &Value) -> usize {
+    let (len, closed) = match listeners.get(name) {
+        Some(senders) => {
+            let sent = senders
+                .iter()
+                .filter(|sender| sender.send(message.clone()).is_ok())
+                .count();
+            (sent, sent < senders.len())
+        }
+        None => return 0,
+    };
+
+    if closed {
+        if let Some(mut senders) = listeners.get_mut(name) {
+            senders.retain(|sender| !sender.is_closed());
+        }
+    }
+    len
 }
 
 pub async fn publish(
     state: Arc<State>,
     _: &mut ConnectionState,
-    args: &[String],
-) -> anyhow::Result<Value> {
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
     let [channel, value] = args else {
-        bail!("TODO: args.len() != 1");
+        return Err(RedisError::WrongArity);
     };
 
-    let len = if let Some(mut listeners) = state.channel_listeners.get_mut(channel) {
-        listeners.retain(|l| {
-            l.send(Value::from_iter(["message", channel, value]))
-                .is_ok()
-        });
-        listeners.len()
-    } else {
-        0
-    };
+    // Messages are encoded once and shared, rather than encoded for each subscriber
+    let message = Value::encoded_push(&[
+        Value::from("message"),
+        Value::from(channel),
+        Value::from(value),
+    ])
+    .await?;
+    let mut len = send_to_listeners(&state.channel_listeners, channel, &message);
+
+    // Clients subscribed to matching patterns get the pattern along with the message
+    let patterns: Vec<Bytes> = state
+        .pattern_listeners
+        .iter()
+        .filter(|listeners| glob::matches(listeners.key(), channel))
+        .map(|listeners| listeners.key().clone())
+        .collect();
+    for pattern in patterns {
+        let message = Value::encoded_push(&[
+            Value::from("pmessage"),
+            Value::from(&pattern),
+            Value::from(channel),
+            Value::from(value),
+        ])
+        .await?;
+        len += send_to_listeners(&state.pattern_listeners, &pattern, &message);
+    }
 
     Ok(Value::from(len))
 }
+
+pub async fn pubsub(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [subcommand, args @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    Ok(match (&*subcommand.to_uppercase(), args) {
+        ("CHANNELS", [] | [_]) => {
+            let pattern = args.first();
+            state
+                .channel_listeners
+                .iter()
+                .filter(|listeners| !listeners.is_empty())
+                .filter(|listeners| pattern.is_none_or(|p| glob::matches(p, listeners.key())))
+                .map(|listeners| Value::from(listeners.key()))
+                .collect()
+        }
+        ("NUMSUB", channels) => channels
+            .iter()
+            .flat_map(|channel| {
+                let count = state
+                    .channel_listeners
+                    .get(channel)
+                    .map_or(0, |listeners| listeners.len());
+                [Value::from(channel), Value::from(count)]
+            })
+            .collect(),
+        ("NUMPAT", []) => Value::from(
+            state
+                .pattern_listeners
+                .iter()
+                .filter(|listeners| !listeners.is_empty())
+                .count(),
+        ),
+        _ => {
+            return Err(RedisError::custom(format!(
+                "ERR unknown subcommand or wrong number of arguments for '{subcommand}'"
+            )))
+        }
+    })
+}
@@ -1,56 +1,121 @@
 use std::{
     fmt::Write,
     sync::{atomic::Ordering, Arc},
+    time::Instant,
 };
 
-use anyhow::{bail, ensure, Context};
+use anyhow::{anyhow, Context};
 
-use crate::{resp::Value, ConnectionState, State};
+use crate::{bytes::Bytes, error::RedisError, resp::Value, ConnectionState, Replica, State};
 
 pub async fn info(
     state: Arc<State>,
     _: &mut ConnectionState,
-    args: &[String],
-) -> anyhow::Result<Value> {
-    let [section, ..] = args else {
-        bail!("TODO: args.len() < 1");
-    };
-    ensure!(
-        section == "replication",
-        "Section '{section}' is not implemented."
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let section = args.first().map(|s| s.to_lowercase());
+    let all = matches!(
+        section.as_deref(),
+        None | Some("all" | "default" | "everything")
     );
+
     let mut s = String::new();
-    writeln!(s, "role:{}", state.role).expect("write to string does not fail");
-    writeln!(s, "master_replid:{}", state.replication_id).expect("write to string does not fail");
-    writeln!(
-        s,
-        "master_repl_offset:{}",
-        state.replication_offset.load(Ordering::SeqCst)
-    )
-    .expect("write to string does not fail");
+    if all || section.as_deref() == Some("replication") {
+        writeln!(s, "# Replication").expect("write to string does not fail");
+        writeln!(s, "role:{}", state.role).expect("write to string does not fail");
+        writeln!(s, "connected_slaves:{}", state.replicas.read().await.len())
+            .expect("write to string does not fail");
+        writeln!(s, "master_replid:{}", state.replication_id)
+            .expect("write to string does not fail");
+        writeln!(
+            s,
+            "master_repl_offset:{}",
+            state.replication_offset.load(Ordering::SeqCst)
+        )
+        .expect("write to string does not fail");
+    }
+
+    if all || section.as_deref() == Some("stats") {
+        if !s.is_empty() {
+            writeln!(s).expect("write to string does not fail");
+        }
+        writeln!(s, "# Stats").expect("write to string does not fail");
+        writeln!(
+            s,
+            "total_connections_received:{}",
+            state
+                .stats
+                .total_connections_received
+                .load(Ordering::Relaxed)
+        )
+        .expect("write to string does not fail");
+        writeln!(
+            s,
+            "total_commands_processed:{}",
+            state.stats.total_commands_processed.load(Ordering::Relaxed)
+        )
+        .expect("write to string does not fail");
+        for (name, counter) in [
+            ("expired_keys", &state.stats.expired_keys),
+            ("evicted_keys", &state.stats.evicted_keys),
+            ("keyspace_hits", &state.stats.keyspace_hits),
+            ("keyspace_misses", &state.stats.keyspace_misses),
+        ] {
+            writeln!(s, "{name}:{}", counter.load(Ordering::Relaxed))
+                .expect("write to string does not fail");
+        }
+    }
+
     Ok(Value::from(s))
 }
 
 pub async fn replconf(
     state: Arc<State>,
-    _: &mut ConnectionState,
-    args: &[String],
-) -> anyhow::Result<Value> {
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
     let [field, args @ ..] = args else {
-        bail!("TODO: args.len() < 1");
+        return Ok(Value::simple_string("OK"));
     };
 
     let ret = match &*field.to_lowercase() {
         "listening-port" | "capa" => Value::simple_string("OK"),
         "getack" => {
-            ensure!(args[0] == "*", "args[0] == '{}'", args[0]);
+            if args.first().is_none_or(|arg| arg != "*") {
+                return Err(RedisError::Syntax);
+            }
             Value::from_iter([
                 "REPLCONF",
                 "ACK",
                 &state.replication_offset.load(Ordering::SeqCst).to_string(),
             ])
         }
-        _ => bail!("Field '{field}' is not supported."),
+        "ack" => {
+            let [offset] = args else {
+                return Err(RedisError::WrongArity);
+            };
+            let offset = offset.parse().map_err(|_| RedisError::NotAnInteger)?;
+
+            let tx = conn_state.tx();
+            if let Some(replica) = state
+                .replicas
+                .write()
+                .await
+                .iter_mut()
+                .find(|r| r.tx.same_channel(tx))
+            {
+                replica.ack_offset = offset;
+                replica.last_ack = Instant::now();
+            }
+
+            conn_state.skip_reply = true;
+            Value::Null
+        }
+        _ => {
+            return Err(RedisError::custom(format!(
+                "ERR Unrecognized REPLCONF option: {field}"
+            )))
+        }
     };
 
     Ok(ret)
@@ -59,18 +124,25 @@ pub async fn replconf(
 pub async fn psync(
     state: Arc<State>,
     conn_state: &mut ConnectionState,
-    args: &[String],
-) -> anyhow::Result<Value> {
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
     let [replication_id, replication_offset] = args else {
-        bail!("TODO: args.len() != 2");
+        return Err(RedisError::WrongArity);
     };
 
-    ensure!(
-        replication_id == "?" && replication_offset == "-1",
-        "Replication id is not '?', got {replication_id} OR Replication offset is not '-1', got {replication_offset}"
-    );
+    if replication_id != "?" || replication_offset != "-1" {
+        return Err(anyhow!(
+            "Replication id is not '?', got {replication_id} OR Replication offset is not '-1', got {replication_offset}"
+        )
+        .into());
+    }
 
-    state.replicas.write().await.push(conn_state.tx().clone());
+    state
+        .replicas
+        .write()
+        .await
+        .push(Replica::new(conn_state.tx().clone()));
+    conn_state.replica = true;
 
     conn_state
         .tx()
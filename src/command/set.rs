@@ -0,0 +1,109 @@
+use std::{collections::HashSet, sync::Arc};
+
+use crate::{
+    bytes::Bytes, error::RedisError, resp::Value, ConnectionState, MapValue, MapValueContent, State,
+};
+
+/// Run `f` on the set at `key`, treating a missing key as an empty set
+fn with_set<T>(
+    state: &State,
+    key: &Bytes,
+    f: impl FnOnce(&HashSet<String>) -> T,
+) -> Result<T, RedisError> {
+    match state.map.get(key) {
+        Some(value) => value.value.as_set().map(f),
+        None => Ok(f(&HashSet::new())),
+    }
+}
+
+pub async fn sadd(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, members @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+    let members = members
+        .iter()
+        .map(super::member)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut value = state.map.get_or_insert_with(key.clone(), || MapValue {
+        value: MapValueContent::Set(HashSet::new()),
+        expires_at: None,
+    });
+    let set = value.value.as_set_mut()?;
+
+    let added = members
+        .into_iter()
+        .filter(|m| set.insert(m.clone()))
+        .count();
+    Ok(Value::from(added))
+}
+
+pub async fn srem(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, members @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let Some(mut value) = state.map.get_mut(key) else {
+        return Ok(Value::from(0));
+    };
+    let set = value.value.as_set_mut()?;
+
+    let removed = members
+        .iter()
+        .filter(|m| m.to_str().is_some_and(|m| set.remove(m)))
+        .count();
+
+    // Empty sets don't exist
+    if set.is_empty() {
+        drop(value);
+        state.map.remove(key);
+    }
+
+    Ok(Value::from(removed))
+}
+
+pub async fn smembers(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    with_set(&state, key, |set| set.iter().map(Value::from).collect())
+}
+
+pub async fn sismember(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, member] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    with_set(&state, key, |set| {
+        Value::from(member.to_str().is_some_and(|m| set.contains(m)) as i64)
+    })
+}
+
+pub async fn scard(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    with_set(&state, key, |set| Value::from(set.len()))
+}
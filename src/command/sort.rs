@@ -0,0 +1,226 @@
+use std::{cmp::Ordering, sync::Arc};
+
+use crate::{
+    bytes::Bytes, error::RedisError, listpack::List, resp::Value, ConnectionState, MapValue,
+    MapValueContent, State,
+};
+
+struct SortOptions<'a> {
+    by: Option<&'a Bytes>,
+    limit: Option<(isize, isize)>,
+    get: Vec<&'a Bytes>,
+    desc: bool,
+    alpha: bool,
+    store: Option<&'a Bytes>,
+}
+
+fn parse_options(args: &[Bytes], allow_store: bool) -> Result<SortOptions<'_>, RedisError> {
+    let mut opts = SortOptions {
+        by: None,
+        limit: None,
+        get: Vec::new(),
+        desc: false,
+        alpha: false,
+        store: None,
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match &*arg.to_uppercase() {
+            "ASC" => opts.desc = false,
+            "DESC" => opts.desc = true,
+            "ALPHA" => opts.alpha = true,
+            "BY" => {
+                let Some(pattern) = args.next() else {
+                    return Err(RedisError::Syntax);
+                };
+                opts.by = Some(pattern);
+            }
+            "LIMIT" => {
+                let (Some(offset), Some(count)) = (args.next(), args.next()) else {
+                    return Err(RedisError::Syntax);
+                };
+                let (Ok(offset), Ok(count)) = (offset.parse(), count.parse()) else {
+                    return Err(RedisError::NotAnInteger);
+                };
+                opts.limit = Some((offset, count));
+            }
+            "GET" => {
+                let Some(pattern) = args.next() else {
+                    return Err(RedisError::Syntax);
+                };
+                opts.get.push(pattern);
+            }
+            "STORE" if allow_store => {
+                let Some(dest) = args.next() else {
+                    return Err(RedisError::Syntax);
+                };
+                opts.store = Some(dest);
+            }
+            _ => return Err(RedisError::Syntax),
+        }
+    }
+
+    Ok(opts)
+}
+
+/// The first option of `SORT` or `SORT_RO` given as `args` (starting with the command name)
+/// which reads keys by pattern, i.e. `BY` with a `*` or `GET`, which users need access to every
+/// key to use
+pub fn pattern_option(args: &[Bytes]) -> Option<&'static str> {
+    let opts = parse_options(args.get(2..)?, true).ok()?;
+    if opts.by.is_some_and(|by| by.contains(&b'*')) {
+        Some("BY")
+    } else if !opts.get.is_empty() {
+        Some("GET")
+    } else {
+        None
+    }
+}
+
+/// Look up the string stored at the key built by replacing the first `*` in `pattern` with
+/// `element`.  The special pattern `#` refers to the element itself.
+fn lookup(state: &State, pattern: &[u8], element: &str) -> Option<String> {
+    if pattern == b"#" {
+        return Some(element.into());
+    }
+
+    let mut key = pattern.to_vec();
+    if let Some(star) = pattern.iter().position(|&c| c == b'*') {
+        key.splice(star..=star, element.bytes());
+    }
+    let value = state.map.get(&key)?;
+    match value.value {
+        MapValueContent::Integer(n) => Some(n.to_string()),
+        MapValueContent::String(ref s) => Some(String::from_utf8_lossy(s).into_owned()),
+        MapValueContent::List(_)
+        | MapValueContent::Stream(_)
+        | MapValueContent::SortedSet(_)
+        | MapValueContent::Hash(_)
+        | MapValueContent::Set(_) => None,
+    }
+}
+
+async fn sort_inner(
+    state: Arc<State>,
+    args: &[Bytes],
+    allow_store: bool,
+) -> Result<Value, RedisError> {
+    let [key, args @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let opts = parse_options(args, allow_store)?;
+
+    let mut elements: Vec<String> = if let Some(value) = state.map.get(key) {
+        match value.value {
+            MapValueContent::List(ref items) => items.iter().map(String::from).collect(),
+            MapValueContent::SortedSet(ref set) => set.iter().map(|e| e.value.clone()).collect(),
+            MapValueContent::Set(ref set) => set.iter().cloned().collect(),
+            MapValueContent::String(_)
+            | MapValueContent::Integer(_)
+            | MapValueContent::Stream(_)
+            | MapValueContent::Hash(_) => return Err(RedisError::WrongType),
+        }
+    } else {
+        Vec::new()
+    };
+
+    // `BY` with a pattern that has no `*` means "don't sort", which is useful with `GET`
+    let dont_sort = opts.by.is_some_and(|by| !by.contains(&b'*'));
+
+    if !dont_sort {
+        let weight = |element: &String| match opts.by {
+            Some(by) => lookup(&state, by, element),
+            None => Some(element.clone()),
+        };
+
+        if opts.alpha {
+            let mut keyed: Vec<_> = elements.into_iter().map(|e| (weight(&e), e)).collect();
+            keyed.sort_by(|(a, ea), (b, eb)| a.cmp(b).then_with(|| ea.cmp(eb)));
+            elements = keyed.into_iter().map(|(_, e)| e).collect();
+        } else {
+            let mut scored = Vec::with_capacity(elements.len());
+            for element in elements {
+                let score = match weight(&element).map(|w| w.parse::<f64>()) {
+                    Some(Ok(score)) => score,
+                    Some(Err(_)) => {
+                        return Err(RedisError::custom(
+                            "ERR One or more scores can't be converted into double",
+                        ))
+                    }
+                    None => 0.,
+                };
+                scored.push((score, element));
+            }
+            scored.sort_by(|(a, ea), (b, eb)| {
+                a.partial_cmp(b)
+                    .unwrap_or(Ordering::Equal)
+                    .then_with(|| ea.cmp(eb))
+            });
+            elements = scored.into_iter().map(|(_, e)| e).collect();
+        }
+
+        if opts.desc {
+            elements.reverse();
+        }
+    }
+
+    if let Some((offset, count)) = opts.limit {
+        let offset = offset.max(0) as usize;
+        let count = if count < 0 {
+            elements.len()
+        } else {
+            count as usize
+        };
+        elements = elements.into_iter().skip(offset).take(count).collect();
+    }
+
+    let result: Vec<Option<String>> = if opts.get.is_empty() {
+        elements.into_iter().map(Some).collect()
+    } else {
+        elements
+            .iter()
+            .flat_map(|e| opts.get.iter().map(|pattern| lookup(&state, pattern, e)))
+            .collect()
+    };
+
+    if let Some(dest) = opts.store {
+        let len = result.len();
+        if len == 0 {
+            state.map.remove(dest);
+        } else {
+            let mut list: List = result.into_iter().map(Option::unwrap_or_default).collect();
+            list.convert_if_needed(state.config().list_max_listpack_size);
+            state.map.insert(
+                dest.clone(),
+                MapValue {
+                    value: MapValueContent::List(list),
+                    expires_at: None,
+                },
+            );
+        }
+        return Ok(Value::from(len));
+    }
+
+    Ok(result
+        .into_iter()
+        .map(|v| v.map(Value::from).unwrap_or_default())
+        .collect())
+}
+
+pub async fn sort(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    sort_inner(state, args, true).await
+}
+
+pub async fn sort_ro(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    sort_inner(state, args, false).await
+}
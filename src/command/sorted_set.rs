@@ -1,205 +1,1130 @@
-use std::sync::Arc;
+use std::{collections::HashMap, ops::RangeInclusive, sync::Arc};
 
-use anyhow::Context;
+use tracing::warn;
 
-use crate::{resp::Value, ConnectionState, MapValueContent, SetEntry, State};
+use super::{
+    args::ArgParser,
+    blocking::{self, parse_timeout, BlockingPop},
+    ExecContext,
+};
+use crate::{
+    bytes::Bytes,
+    error::RedisError,
+    resp::{format_double, Value},
+    zset::SortedSet,
+    ConnectionState, MapValue, MapValueContent, SetEntry, State,
+};
 
-pub async fn zadd(
-    state: Arc<State>,
-    _: &mut ConnectionState,
-    args: &[String],
-) -> anyhow::Result<Value> {
-    let [key, score, value] = args else {
-        todo!("args.len() != 3");
-    };
+/// A blocking pop from the sorted sets in `BZPOPMIN`/`BZPOPMAX`
+#[derive(Debug)]
+pub struct ZSetPop {
+    max: bool,
+    count: usize,
+}
+
+impl BlockingPop for ZSetPop {
+    type Items = SortedSet;
+    type Popped = Vec<SetEntry>;
+
+    fn items(value: &mut MapValueContent) -> Result<&mut SortedSet, RedisError> {
+        value.as_zset_mut()
+    }
+
+    fn is_empty(items: &SortedSet) -> bool {
+        items.is_empty()
+    }
 
-    let MapValueContent::SortedSet(ref mut set) = state
-        .map
-        .entry(key.clone())
-        .or_insert(crate::MapValue {
+    fn pop(&self, items: &mut SortedSet) -> Option<Vec<SetEntry>> {
+        let popped: Vec<_> = (0..self.count).map_while(|_| items.pop(self.max)).collect();
+        (!popped.is_empty()).then_some(popped)
+    }
+
+    fn restore(&self, items: &mut SortedSet, popped: Vec<SetEntry>) {
+        items.extend(popped);
+    }
+
+    fn pop_command(&self, key: &Bytes, popped: &Vec<SetEntry>) -> Value {
+        let command = if self.max { "ZPOPMAX" } else { "ZPOPMIN" };
+        Value::from_iter([
+            Value::from(command),
+            Value::from(key),
+            Value::from(popped.len().to_string()),
+        ])
+    }
+
+    fn give_back(
+        &self,
+        state: &State,
+        conn_state: &mut ConnectionState,
+        key: Bytes,
+        popped: Vec<SetEntry>,
+    ) -> anyhow::Result<()> {
+        let mut value = state.map.get_or_insert_with(key.clone(), || MapValue {
             value: MapValueContent::SortedSet(Default::default()),
             expires_at: None,
-        })
-        .value
-    else {
-        todo!()
+        });
+        let Ok(set) = value.value.as_zset_mut() else {
+            warn!(
+                "dropping members popped for a disconnected client: {}",
+                RedisError::WrongType
+            );
+            conn_state.propagate_as = Some(Vec::new());
+            return Ok(());
+        };
+
+        let zadd = [Value::from("ZADD"), Value::from(&key)]
+            .into_iter()
+            .chain(
+                popped
+                    .iter()
+                    .flat_map(|e| [Value::from(format_double(e.score)), Value::from(&e.value)]),
+            )
+            .collect();
+        set.extend(popped);
+
+        let pops = state.blocked.signal(&key, &mut value.value);
+        conn_state.propagate_as = Some(std::iter::once(zadd).chain(pops).collect());
+
+        if value.value.as_zset().is_ok_and(SortedSet::is_empty) {
+            drop(value);
+            state.map.remove(&key);
+        }
+        Ok(())
+    }
+}
+
+/// Run `f` on the sorted set at `key`, treating a missing key as an empty set
+pub(crate) fn with_zset<T>(
+    state: &State,
+    key: &Bytes,
+    f: impl FnOnce(&SortedSet) -> T,
+) -> Result<T, RedisError> {
+    match state.map.get(key) {
+        Some(value) => value.value.as_zset().map(f),
+        None => Ok(f(&SortedSet::default())),
+    }
+}
+
+/// Run `f` on the sorted set at `key`, returning `None` if there isn't one.  The key is removed if
+/// `f` leaves the set empty.
+fn with_zset_mut<T>(
+    state: &State,
+    key: &Bytes,
+    f: impl FnOnce(&mut SortedSet) -> T,
+) -> Result<Option<T>, RedisError> {
+    let Some(mut value) = state.map.get_mut(key) else {
+        return Ok(None);
     };
+    let set = value.value.as_zset_mut()?;
+
+    let ret = f(set);
+    if set.is_empty() {
+        drop(value);
+        state.map.remove(key);
+    }
+    Ok(Some(ret))
+}
+
+/// Resolve a range of possibly-negative ranks against a set of length `len`, returning `None` if
+/// the range is empty
+fn rank_range(start: i64, stop: i64, len: usize) -> Option<RangeInclusive<usize>> {
+    let resolve = |rank: i64| if rank < 0 { len as i64 + rank } else { rank };
+    let start = resolve(start).max(0);
+    let stop = resolve(stop).min(len as i64 - 1);
+
+    (start <= stop).then_some(start as usize..=stop as usize)
+}
+
+/// One end of a range of scores, like `1.5`, `(1.5` or `-inf`
+#[derive(Debug, Clone, Copy)]
+struct ScoreBound {
+    score: f64,
+    exclusive: bool,
+}
+
+impl ScoreBound {
+    fn parse(s: &str) -> Option<Self> {
+        let (score, exclusive) = match s.strip_prefix('(') {
+            Some(score) => (score, true),
+            None => (s, false),
+        };
+        let score: f64 = score.parse().ok()?;
+        (!score.is_nan()).then_some(Self { score, exclusive })
+    }
+}
+
+/// A range of scores, as taken by `ZRANGE ... BYSCORE` and friends
+#[derive(Debug, Clone, Copy)]
+struct ScoreRange {
+    min: ScoreBound,
+    max: ScoreBound,
+}
+
+impl ScoreRange {
+    fn parse(min: &Bytes, max: &Bytes) -> Result<Self, RedisError> {
+        match (
+            ScoreBound::parse(&min.to_string_lossy()),
+            ScoreBound::parse(&max.to_string_lossy()),
+        ) {
+            (Some(min), Some(max)) => Ok(Self { min, max }),
+            _ => Err(RedisError::custom("ERR min or max is not a float")),
+        }
+    }
 
-    let mut removed = false;
-    set.retain(|e| {
-        if e.value == *value {
-            removed = true;
-            false
+    fn above_min(&self, score: f64) -> bool {
+        if self.min.exclusive {
+            score > self.min.score
         } else {
-            true
+            score >= self.min.score
         }
-    });
+    }
+
+    fn below_max(&self, score: f64) -> bool {
+        if self.max.exclusive {
+            score < self.max.score
+        } else {
+            score <= self.max.score
+        }
+    }
+
+    fn contains(&self, score: f64) -> bool {
+        self.above_min(score) && self.below_max(score)
+    }
+}
+
+/// One end of a range of members, like `[a`, `(a`, `-` or `+`
+#[derive(Debug, Clone)]
+enum LexBound {
+    NegInf,
+    PosInf,
+    Inclusive(String),
+    Exclusive(String),
+}
+
+impl LexBound {
+    fn parse(s: &str) -> Option<Self> {
+        match s.split_at_checked(1)? {
+            ("-", "") => Some(Self::NegInf),
+            ("+", "") => Some(Self::PosInf),
+            ("[", member) => Some(Self::Inclusive(member.into())),
+            ("(", member) => Some(Self::Exclusive(member.into())),
+            _ => None,
+        }
+    }
+}
+
+/// A range of members, as taken by `ZRANGE ... BYLEX` and friends.  These only make sense when
+/// every member has the same score.
+#[derive(Debug, Clone)]
+struct LexRange {
+    min: LexBound,
+    max: LexBound,
+}
+
+impl LexRange {
+    fn parse(min: &Bytes, max: &Bytes) -> Result<Self, RedisError> {
+        match (
+            LexBound::parse(&min.to_string_lossy()),
+            LexBound::parse(&max.to_string_lossy()),
+        ) {
+            (Some(min), Some(max)) => Ok(Self { min, max }),
+            _ => Err(RedisError::custom(
+                "ERR min or max not valid string range item",
+            )),
+        }
+    }
+
+    fn above_min(&self, member: &str) -> bool {
+        match self.min {
+            LexBound::NegInf => true,
+            LexBound::PosInf => false,
+            LexBound::Inclusive(ref min) => member >= min.as_str(),
+            LexBound::Exclusive(ref min) => member > min.as_str(),
+        }
+    }
+
+    fn below_max(&self, member: &str) -> bool {
+        match self.max {
+            LexBound::NegInf => false,
+            LexBound::PosInf => true,
+            LexBound::Inclusive(ref max) => member <= max.as_str(),
+            LexBound::Exclusive(ref max) => member < max.as_str(),
+        }
+    }
+
+    fn contains(&self, member: &str) -> bool {
+        self.above_min(member) && self.below_max(member)
+    }
+}
+
+/// The flags which can come before the score/member pairs in `ZADD`
+#[derive(Debug, Default)]
+struct ZAddOptions {
+    nx: bool,
+    xx: bool,
+    gt: bool,
+    lt: bool,
+    ch: bool,
+    incr: bool,
+}
+
+impl ZAddOptions {
+    /// Parse the leading flags in `args`, returning them along with the remaining arguments
+    fn parse(args: &[Bytes]) -> Result<(Self, &[Bytes]), RedisError> {
+        let mut opts = Self::default();
+        let mut args = ArgParser::new(args);
+        while let Some(flag) = args.peek_keyword() {
+            match &*flag {
+                "NX" => opts.nx = true,
+                "XX" => opts.xx = true,
+                "GT" => opts.gt = true,
+                "LT" => opts.lt = true,
+                "CH" => opts.ch = true,
+                "INCR" => opts.incr = true,
+                _ => break,
+            }
+            args.next_arg();
+        }
+
+        if opts.nx && opts.xx {
+            return Err(RedisError::custom(
+                "ERR XX and NX options at the same time are not compatible",
+            ));
+        }
+        if (opts.gt && opts.lt) || ((opts.gt || opts.lt) && opts.nx) {
+            return Err(RedisError::custom(
+                "ERR GT, LT, and/or NX options at the same time are not compatible",
+            ));
+        }
+
+        Ok((opts, args.rest()))
+    }
+}
 
-    set.insert(SetEntry {
-        score: score
-            .parse()
-            .with_context(|| format!("parsing score '{score}'"))?,
-        value: value.clone(),
+pub async fn zadd(
+    state: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, args @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let (opts, pairs) = ZAddOptions::parse(args)?;
+
+    if pairs.is_empty() || pairs.len() % 2 != 0 {
+        return Err(RedisError::Syntax);
+    }
+    if opts.incr && pairs.len() > 2 {
+        return Err(RedisError::custom(
+            "ERR INCR option supports a single increment-element pair",
+        ));
+    }
+
+    // Check every score before touching the set, so that a bad one doesn't leave it half-updated
+    let mut entries = Vec::with_capacity(pairs.len() / 2);
+    for pair in pairs.chunks_exact(2) {
+        let score = match pair[0].parse::<f64>() {
+            Ok(score) if !score.is_nan() => score,
+            _ => return Err(RedisError::NotAFloat),
+        };
+        entries.push((score, super::member(&pair[1])?));
+    }
+
+    let mut value = state.map.get_or_insert_with(key.clone(), || MapValue {
+        value: MapValueContent::SortedSet(Default::default()),
+        expires_at: None,
     });
+    let set = value.value.as_zset_mut()?;
+
+    let mut added = 0;
+    let mut changed = 0;
+    // The final score of the last member, which is only replied with for `INCR`
+    let mut last_score = None;
+    for (score, member) in entries {
+        let old = set.score(&member);
+        let new = match old {
+            Some(old) if opts.incr => old + score,
+            _ => score,
+        };
+        if new.is_nan() {
+            return Err(RedisError::custom(
+                "ERR resulting score is not a number (NaN)",
+            ));
+        }
+
+        match old {
+            None if opts.xx => last_score = None,
+            None => {
+                set.insert(member.clone(), new);
+                added += 1;
+                last_score = Some(new);
+            }
+            Some(_) if opts.nx => last_score = None,
+            Some(old) if (opts.gt && new <= old) || (opts.lt && new >= old) => last_score = None,
+            Some(old) => {
+                if new != old {
+                    set.insert(member.clone(), new);
+                    changed += 1;
+                }
+                last_score = Some(new);
+            }
+        }
+    }
+
+    // Replicas need to see the `ZADD`, followed by any pops for clients we just served
+    let pops = state.blocked.signal(key, &mut value.value);
+    if !pops.is_empty() {
+        let zadd = std::iter::once(Value::from("ZADD"))
+            .chain(std::iter::once(key).chain(args).map(Value::from))
+            .collect();
+        conn_state.propagate_as = Some(std::iter::once(zadd).chain(pops).collect());
+    }
 
-    Ok(Value::from(if removed { 0 } else { 1 }))
+    // `XX` may have stopped us from adding anything to a set that we just created, or we may
+    // have handed everything to blocked clients
+    if value.value.as_zset().is_ok_and(SortedSet::is_empty) {
+        drop(value);
+        state.map.remove(key);
+    }
+
+    Ok(if opts.incr {
+        last_score.map(Value::Double).unwrap_or_default()
+    } else if opts.ch {
+        Value::from(added + changed)
+    } else {
+        Value::from(added)
+    })
 }
 
 pub async fn zrank(
     state: Arc<State>,
     _: &mut ConnectionState,
-    args: &[String],
-) -> anyhow::Result<Value> {
-    let [key, value] = args else {
-        todo!("args.len() != 2");
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, member] = args else {
+        return Err(RedisError::WrongArity);
     };
 
-    let map_value = state.map.get(key);
-    let set = if let Some(ref value) = map_value {
-        if let MapValueContent::SortedSet(ref set) = value.value {
-            set
+    with_zset(&state, key, |set| {
+        member
+            .to_str()
+            .and_then(|member| set.rank(member))
+            .map(Value::from)
+            .unwrap_or_default()
+    })
+}
+
+/// What a range query is over
+#[derive(Debug, Clone)]
+enum RangeBy {
+    Rank(i64, i64),
+    Score(ScoreRange),
+    Lex(LexRange),
+}
+
+/// A query over a sorted set, as taken by `ZRANGE` and the commands built on it
+#[derive(Debug, Clone)]
+struct RangeQuery {
+    by: RangeBy,
+    rev: bool,
+    /// The offset and count from `LIMIT`, where a negative count means "all"
+    limit: Option<(i64, i64)>,
+}
+
+impl RangeQuery {
+    /// Parse the arguments of `ZRANGE` after the key, returning the query and whether `WITHSCORES`
+    /// was given
+    fn parse(args: &[Bytes]) -> Result<(Self, bool), RedisError> {
+        let [start, stop, opts @ ..] = args else {
+            return Err(RedisError::Syntax);
+        };
+
+        let mut by_score = false;
+        let mut by_lex = false;
+        let mut rev = false;
+        let mut limit = None;
+        let mut withscores = false;
+
+        let mut opts = opts.iter();
+        while let Some(opt) = opts.next() {
+            match &*opt.to_uppercase() {
+                "BYSCORE" => by_score = true,
+                "BYLEX" => by_lex = true,
+                "REV" => rev = true,
+                "WITHSCORES" => withscores = true,
+                "LIMIT" => {
+                    let (Some(offset), Some(count)) = (opts.next(), opts.next()) else {
+                        return Err(RedisError::Syntax);
+                    };
+                    let (Ok(offset), Ok(count)) = (offset.parse(), count.parse()) else {
+                        return Err(RedisError::NotAnInteger);
+                    };
+                    limit = Some((offset, count));
+                }
+                _ => return Err(RedisError::Syntax),
+            }
+        }
+
+        if by_score && by_lex {
+            return Err(RedisError::Syntax);
+        }
+        if limit.is_some() && !by_score && !by_lex {
+            return Err(RedisError::custom("ERR syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX"));
+        }
+        if withscores && by_lex {
+            return Err(RedisError::custom(
+                "ERR syntax error, WITHSCORES not supported in combination with BYLEX",
+            ));
+        }
+
+        // Reversed score and lex ranges are given from the top down
+        let (min, max) = if rev { (stop, start) } else { (start, stop) };
+        let by = if by_score {
+            RangeBy::Score(ScoreRange::parse(min, max)?)
+        } else if by_lex {
+            RangeBy::Lex(LexRange::parse(min, max)?)
         } else {
-            todo!()
+            let (Ok(start), Ok(stop)) = (start.parse(), stop.parse()) else {
+                return Err(RedisError::NotAnInteger);
+            };
+            RangeBy::Rank(start, stop)
+        };
+
+        Ok((Self { by, rev, limit }, withscores))
+    }
+
+    /// The entries of `set` which match this query, in the order that they should be returned
+    fn run<'a>(&self, set: &'a SortedSet) -> Vec<&'a SetEntry> {
+        let matching: Box<dyn Iterator<Item = &SetEntry>> = match (&self.by, self.rev) {
+            (&RangeBy::Rank(start, stop), rev) => {
+                let Some(ranks) = rank_range(start, stop, set.len()) else {
+                    return Vec::new();
+                };
+                Box::new(set.walk_from_rank(*ranks.start(), rev).take(ranks.count()))
+            }
+            (RangeBy::Score(range), false) => Box::new(
+                set.walk_from(|e| !range.above_min(e.score), false)
+                    .take_while(|e| range.below_max(e.score)),
+            ),
+            (RangeBy::Score(range), true) => Box::new(
+                set.walk_from(|e| range.below_max(e.score), true)
+                    .take_while(|e| range.above_min(e.score)),
+            ),
+            (RangeBy::Lex(range), false) => Box::new(
+                set.walk_from(|e| !range.above_min(&e.value), false)
+                    .take_while(|e| range.below_max(&e.value)),
+            ),
+            (RangeBy::Lex(range), true) => Box::new(
+                set.walk_from(|e| range.below_max(&e.value), true)
+                    .take_while(|e| range.above_min(&e.value)),
+            ),
+        };
+
+        match self.limit {
+            Some((offset, _)) if offset < 0 => Vec::new(),
+            Some((offset, count)) if count >= 0 => matching
+                .skip(offset as usize)
+                .take(count as usize)
+                .collect(),
+            Some((offset, _)) => matching.skip(offset as usize).collect(),
+            None => matching.collect(),
         }
-    } else {
-        return Ok(Value::Null);
-    };
+    }
+}
 
-    let ret = if let Some((i, _value)) = set.iter().enumerate().find(|(_, v)| v.value == *value) {
-        Value::from(i)
+/// The reply for a range of entries, with each member followed by its score if `withscores` is
+/// set
+fn range_reply(entries: &[&SetEntry], withscores: bool) -> Value {
+    if withscores {
+        entries
+            .iter()
+            .flat_map(|e| [Value::from(&e.value), Value::from(format_double(e.score))])
+            .collect()
     } else {
-        Value::Null
+        entries.iter().map(|e| Value::from(&e.value)).collect()
+    }
+}
+
+/// Run the `ZRANGE`-style query in `args` against the sorted set at `key`
+fn range(state: &State, key: &Bytes, args: &[Bytes]) -> Result<Value, RedisError> {
+    let (query, withscores) = RangeQuery::parse(args)?;
+
+    with_zset(state, key, |set| range_reply(&query.run(set), withscores))
+}
+
+/// Shared by the pre-6.2 range commands, which are `ZRANGE` with some of its options implied
+fn legacy_range(state: &State, args: &[Bytes], implied: &[&str]) -> Result<Value, RedisError> {
+    let [key, start, stop, rest @ ..] = args else {
+        return Err(RedisError::WrongArity);
     };
 
-    Ok(ret)
+    let args: Vec<Bytes> = [start.clone(), stop.clone()]
+        .into_iter()
+        .chain(implied.iter().map(|&opt| Bytes::from(opt)))
+        .chain(rest.iter().cloned())
+        .collect();
+    range(state, key, &args)
 }
 
 pub async fn zrange(
     state: Arc<State>,
     _: &mut ConnectionState,
-    args: &[String],
-) -> anyhow::Result<Value> {
-    let [key, min, max] = args else {
-        todo!("args.len() != 3");
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, args @ ..] = args else {
+        return Err(RedisError::WrongArity);
     };
 
-    let min: isize = min.parse().context("parsing min")?;
-    let max: isize = max.parse().context("parsing max")?;
+    range(&state, key, args)
+}
 
-    let map_value = state.map.get(key);
-    let set = if let Some(ref value) = map_value {
-        if let MapValueContent::SortedSet(ref set) = value.value {
-            set
-        } else {
-            todo!()
-        }
-    } else {
-        return Ok(Value::empty_array());
+pub async fn zrevrange(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    legacy_range(&state, args, &["REV"])
+}
+
+pub async fn zrangebyscore(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    legacy_range(&state, args, &["BYSCORE"])
+}
+
+pub async fn zrevrangebyscore(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    legacy_range(&state, args, &["BYSCORE", "REV"])
+}
+
+/// Replace whatever is at `dst` with `entries` (or remove it if there are none), handing them to
+/// any clients blocked on `dst`.  `command` and `args` are the command being run, which is what
+/// replicas see before the pops for those clients.
+fn store(
+    state: &State,
+    conn_state: &mut ConnectionState,
+    command: &str,
+    args: &[Bytes],
+    dst: &Bytes,
+    entries: SortedSet,
+) -> Value {
+    let len = entries.len();
+    if entries.is_empty() {
+        state.map.remove(dst);
+        return Value::from(len);
+    }
+
+    state.map.insert(
+        dst.clone(),
+        MapValue {
+            value: MapValueContent::SortedSet(entries),
+            expires_at: None,
+        },
+    );
+
+    let Some(mut value) = state.map.get_mut(dst) else {
+        return Value::from(len);
     };
+    let pops = state.blocked.signal(dst, &mut value.value);
+    if !pops.is_empty() {
+        let write = std::iter::once(Value::from(command))
+            .chain(args.iter().map(Value::from))
+            .collect();
+        conn_state.propagate_as = Some(std::iter::once(write).chain(pops).collect());
+    }
+    if value.value.as_zset().is_ok_and(SortedSet::is_empty) {
+        drop(value);
+        state.map.remove(dst);
+    }
 
-    let min = if min < 0 {
-        set.len().saturating_add_signed(min)
-    } else {
-        min as usize
+    Value::from(len)
+}
+
+pub async fn zrangestore(
+    state: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [dst, src, query_args @ ..] = args else {
+        return Err(RedisError::WrongArity);
     };
-    let max = if max < 0 {
-        set.len().saturating_add_signed(max)
-    } else {
-        max as usize
+
+    let query = match RangeQuery::parse(query_args)? {
+        (_, true) => return Err(RedisError::Syntax),
+        (query, false) => query,
     };
 
-    let ret: Value = set
-        .iter()
-        .skip(min)
-        .take(max - min + 1)
-        .map(|e| Value::from(&e.value))
-        .collect();
+    let entries = with_zset(&state, src, |set| {
+        query.run(set).into_iter().cloned().collect::<SortedSet>()
+    })?;
 
-    Ok(ret)
+    Ok(store(&state, conn_state, "ZRANGESTORE", args, dst, entries))
 }
 
 pub async fn zcard(
     state: Arc<State>,
     _: &mut ConnectionState,
-    args: &[String],
-) -> anyhow::Result<Value> {
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
     let [key] = args else {
-        todo!("args.len() != 1");
+        return Err(RedisError::WrongArity);
     };
 
-    let map_value = state.map.get(key);
-    let len = if let Some(ref value) = map_value {
-        if let MapValueContent::SortedSet(ref set) = value.value {
-            set.len()
-        } else {
-            todo!()
-        }
-    } else {
-        0
-    };
+    with_zset(&state, key, |set| Value::from(set.len()))
+}
 
-    Ok(Value::from(len))
+/// The score of `member` as a reply, which is a double for RESP3 clients
+fn score_of(set: &SortedSet, member: &Bytes) -> Value {
+    member
+        .to_str()
+        .and_then(|member| set.score(member))
+        .map(Value::Double)
+        .unwrap_or_default()
 }
 
 pub async fn zscore(
     state: Arc<State>,
     _: &mut ConnectionState,
-    args: &[String],
-) -> anyhow::Result<Value> {
-    let [key, value] = args else {
-        todo!("args.len() != 2");
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, member] = args else {
+        return Err(RedisError::WrongArity);
     };
 
-    let map_value = state.map.get(key);
-    let set = if let Some(ref value) = map_value {
-        if let MapValueContent::SortedSet(ref set) = value.value {
-            set
-        } else {
-            todo!()
+    with_zset(&state, key, |set| score_of(set, member))
+}
+
+pub async fn zmscore(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, members @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    with_zset(&state, key, |set| {
+        members.iter().map(|m| score_of(set, m)).collect()
+    })
+}
+
+pub async fn zrem(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, members @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let removed = with_zset_mut(&state, key, |set| {
+        members
+            .iter()
+            .filter(|m| m.to_str().is_some_and(|m| set.remove(m).is_some()))
+            .count()
+    })?;
+
+    Ok(Value::from(removed.unwrap_or(0)))
+}
+
+/// Shared by the `ZREMRANGEBY*` commands: remove every entry for which `in_range` returns `true`,
+/// given its rank and the entry itself
+fn remove_range(
+    state: &State,
+    key: &Bytes,
+    mut in_range: impl FnMut(usize, &SetEntry) -> bool,
+) -> Result<Value, RedisError> {
+    let removed = with_zset_mut(state, key, |set| {
+        let members: Vec<_> = set
+            .iter()
+            .enumerate()
+            .filter(|(rank, e)| in_range(*rank, e))
+            .map(|(_, e)| e.value.clone())
+            .collect();
+        for member in &members {
+            set.remove(member);
         }
-    } else {
-        return Ok(Value::Null);
+        members.len()
+    })?;
+
+    Ok(Value::from(removed.unwrap_or(0)))
+}
+
+pub async fn zremrangebyrank(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, start, stop] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let (Ok(start), Ok(stop)) = (start.parse(), stop.parse()) else {
+        return Err(RedisError::NotAnInteger);
     };
 
-    let ret = set
-        .iter()
-        .find(|e| e.value == *value)
-        .map(|v| Value::from(v.score.to_string()))
-        .unwrap_or_default();
+    let len = with_zset(&state, key, |set| set.len())?;
+    let Some(ranks) = rank_range(start, stop, len) else {
+        return Ok(Value::from(0));
+    };
 
-    Ok(ret)
+    remove_range(&state, key, |rank, _| ranks.contains(&rank))
 }
 
-pub async fn zrem(
+pub async fn zremrangebyscore(
     state: Arc<State>,
     _: &mut ConnectionState,
-    args: &[String],
-) -> anyhow::Result<Value> {
-    let [key, value] = args else {
-        todo!("args.len() != 2");
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, min, max] = args else {
+        return Err(RedisError::WrongArity);
     };
 
-    let MapValueContent::SortedSet(ref mut set) = state
-        .map
-        .entry(key.clone())
-        .or_insert(crate::MapValue {
-            value: MapValueContent::SortedSet(Default::default()),
-            expires_at: None,
-        })
-        .value
-    else {
-        todo!()
+    let range = ScoreRange::parse(min, max)?;
+
+    remove_range(&state, key, |_, e| range.contains(e.score))
+}
+
+pub async fn zremrangebylex(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, min, max] = args else {
+        return Err(RedisError::WrongArity);
     };
 
-    let mut removed = 0;
-    set.retain(|e| {
-        if e.value == *value {
-            removed += 1;
-            false
-        } else {
-            true
+    let range = LexRange::parse(min, max)?;
+
+    remove_range(&state, key, |_, e| range.contains(&e.value))
+}
+
+/// Shared by `ZPOPMIN` and `ZPOPMAX`
+fn zpop(state: &State, args: &[Bytes], max: bool) -> Result<Value, RedisError> {
+    let [key, count @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let count = match count {
+        [] => 1,
+        [count] => match count.parse::<usize>() {
+            Ok(count) => count,
+            Err(_) => {
+                return Err(RedisError::custom(
+                    "ERR value is out of range, must be positive",
+                ))
+            }
+        },
+        _ => return Err(RedisError::Syntax),
+    };
+
+    let popped = blocking::pop_first(state, std::slice::from_ref(key), &ZSetPop { max, count })?;
+    Ok(popped
+        .into_iter()
+        .flat_map(|(_, entries)| entries)
+        .flat_map(|e| [Value::from(e.value), Value::from(format_double(e.score))])
+        .collect())
+}
+
+pub async fn zpopmin(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    zpop(&state, args, false)
+}
+
+pub async fn zpopmax(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    zpop(&state, args, true)
+}
+
+/// Shared by `BZPOPMIN` and `BZPOPMAX`
+async fn bzpop(
+    state: &State,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+    max: bool,
+    context: ExecContext,
+) -> Result<Value, RedisError> {
+    let [keys @ .., timeout] = args else {
+        return Err(RedisError::WrongArity);
+    };
+    if keys.is_empty() {
+        return Err(RedisError::WrongArity);
+    }
+
+    let timeout = parse_timeout(timeout)?;
+
+    Ok(
+        match blocking::block(
+            state,
+            conn_state,
+            keys,
+            ZSetPop { max, count: 1 },
+            timeout,
+            context,
+        )
+        .await?
+        {
+            Some((key, mut popped)) => {
+                let entry = popped.pop().expect("pops are never empty");
+                Value::from_iter([
+                    Value::from(key),
+                    Value::from(entry.value),
+                    Value::from(format_double(entry.score)),
+                ])
+            }
+            None => Value::Null,
+        },
+    )
+}
+
+pub async fn bzpopmin(
+    state: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+    context: ExecContext,
+) -> Result<Value, RedisError> {
+    bzpop(&state, conn_state, args, false, context).await
+}
+
+pub async fn bzpopmax(
+    state: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+    context: ExecContext,
+) -> Result<Value, RedisError> {
+    bzpop(&state, conn_state, args, true, context).await
+}
+
+/// How `ZUNION` and `ZINTER` combine the scores of a member which is in several sets
+#[derive(Debug, Clone, Copy)]
+enum Aggregate {
+    Sum,
+    Min,
+    Max,
+}
+
+impl Aggregate {
+    fn apply(self, a: f64, b: f64) -> f64 {
+        match self {
+            // inf + -inf
+            Self::Sum => Some(a + b).filter(|s| !s.is_nan()).unwrap_or(0.),
+            Self::Min => a.min(b),
+            Self::Max => a.max(b),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SetOp {
+    Union,
+    Inter,
+    Diff,
+}
+
+/// The arguments of `ZUNION`, `ZINTER` and `ZDIFF` and their `STORE` forms
+struct SetOpArgs<'a> {
+    keys: &'a [Bytes],
+    weights: Vec<f64>,
+    aggregate: Aggregate,
+    withscores: bool,
+}
+
+impl<'a> SetOpArgs<'a> {
+    /// Parse `numkeys key... [WEIGHTS weight...] [AGGREGATE SUM|MIN|MAX] [WITHSCORES]`, where
+    /// only the options which make sense for `op` are allowed
+    fn parse(args: &'a [Bytes], op: SetOp, command: &str, store: bool) -> Result<Self, RedisError> {
+        let Some((numkeys, rest)) = args.split_first() else {
+            return Err(RedisError::Syntax);
+        };
+        let numkeys = match numkeys.parse::<i64>() {
+            Ok(n) if n < 1 => {
+                return Err(RedisError::custom(format!(
+                    "ERR at least 1 input key is needed for '{command}' command"
+                )))
+            }
+            Ok(n) => n as usize,
+            Err(_) => return Err(RedisError::NotAnInteger),
+        };
+        if numkeys > rest.len() {
+            return Err(RedisError::Syntax);
         }
-    });
 
-    Ok(Value::from(removed))
+        let (keys, opts) = rest.split_at(numkeys);
+        let mut parsed = Self {
+            keys,
+            weights: vec![1.; numkeys],
+            aggregate: Aggregate::Sum,
+            withscores: false,
+        };
+
+        let mut opts = opts.iter();
+        while let Some(opt) = opts.next() {
+            match &*opt.to_uppercase() {
+                "WEIGHTS" if op != SetOp::Diff => {
+                    for weight in parsed.weights.iter_mut() {
+                        let Some(Ok(w)) = opts.next().map(|w| w.parse::<f64>()) else {
+                            return Err(RedisError::custom("ERR weight value is not a float"));
+                        };
+                        *weight = w;
+                    }
+                }
+                "AGGREGATE" if op != SetOp::Diff => {
+                    parsed.aggregate = match opts.next().map(|a| a.to_uppercase()).as_deref() {
+                        Some("SUM") => Aggregate::Sum,
+                        Some("MIN") => Aggregate::Min,
+                        Some("MAX") => Aggregate::Max,
+                        _ => return Err(RedisError::Syntax),
+                    };
+                }
+                "WITHSCORES" if !store => parsed.withscores = true,
+                _ => return Err(RedisError::Syntax),
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    /// Combine the sets at our keys.  Plain sets can be used too, with every member scoring 1.
+    fn run(&self, state: &State, op: SetOp) -> Result<SortedSet, RedisError> {
+        let mut combined: Option<HashMap<String, f64>> = None;
+        for (key, &weight) in self.keys.iter().zip(&self.weights) {
+            let members: HashMap<String, f64> = match state.map.get(key) {
+                Some(value) => match value.value {
+                    MapValueContent::SortedSet(ref set) => {
+                        set.iter().map(|e| (e.value.clone(), e.score)).collect()
+                    }
+                    MapValueContent::Set(ref set) => set.iter().map(|m| (m.clone(), 1.)).collect(),
+                    _ => return Err(RedisError::WrongType),
+                },
+                None => HashMap::new(),
+            };
+
+            // 0 * inf
+            let weighted = |score: f64| Some(score * weight).filter(|s| !s.is_nan()).unwrap_or(0.);
+            let Some(ref mut combined) = combined else {
+                combined = Some(
+                    members
+                        .into_iter()
+                        .map(|(m, score)| (m, weighted(score)))
+                        .collect(),
+                );
+                continue;
+            };
+
+            match op {
+                SetOp::Union => {
+                    for (member, score) in members {
+                        let score = weighted(score);
+                        combined
+                            .entry(member)
+                            .and_modify(|s| *s = self.aggregate.apply(*s, score))
+                            .or_insert(score);
+                    }
+                }
+                SetOp::Inter => combined.retain(|member, s| match members.get(member) {
+                    Some(&score) => {
+                        *s = self.aggregate.apply(*s, weighted(score));
+                        true
+                    }
+                    None => false,
+                }),
+                SetOp::Diff => combined.retain(|member, _| !members.contains_key(member)),
+            }
+        }
+
+        Ok(combined
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(value, score)| SetEntry { score, value })
+            .collect())
+    }
+}
+
+/// Shared by `ZUNION`, `ZINTER` and `ZDIFF`
+fn set_op(state: &State, args: &[Bytes], op: SetOp, command: &str) -> Result<Value, RedisError> {
+    let parsed = SetOpArgs::parse(args, op, command, false)?;
+
+    let entries = parsed.run(state, op)?;
+    Ok(range_reply(
+        &entries.iter().collect::<Vec<_>>(),
+        parsed.withscores,
+    ))
+}
+
+/// Shared by `ZUNIONSTORE`, `ZINTERSTORE` and `ZDIFFSTORE`
+fn set_op_store(
+    state: &State,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+    op: SetOp,
+    command: &str,
+) -> Result<Value, RedisError> {
+    let [dst, op_args @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let parsed = SetOpArgs::parse(op_args, op, &command.to_lowercase(), true)?;
+
+    let entries = parsed.run(state, op)?;
+    Ok(store(state, conn_state, command, args, dst, entries))
+}
+
+pub async fn zunion(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    set_op(&state, args, SetOp::Union, "zunion")
+}
+
+pub async fn zinter(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    set_op(&state, args, SetOp::Inter, "zinter")
+}
+
+pub async fn zdiff(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    set_op(&state, args, SetOp::Diff, "zdiff")
+}
+
+pub async fn zunionstore(
+    state: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    set_op_store(&state, conn_state, args, SetOp::Union, "ZUNIONSTORE")
+}
+
+pub async fn zinterstore(
+    state: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    set_op_store(&state, conn_state, args, SetOp::Inter, "ZINTERSTORE")
+}
+
+pub async fn zdiffstore(
+    state: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    set_op_store(&state, conn_state, args, SetOp::Diff, "ZDIFFSTORE")
 }
@@ -1,329 +1,1381 @@
 use std::{
-    collections::BTreeMap,
+    borrow::Cow,
+    collections::HashMap,
     ops::Bound,
     sync::Arc,
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    time::{Duration, SystemTime},
 };
 
-use anyhow::{bail, Context};
-use tokio::{
-    sync::{mpsc, Mutex},
-    task::JoinSet,
+use super::{args::ArgParser, blocking, expire::unix_millis, ExecContext};
+use crate::{
+    bytes::Bytes,
+    error::RedisError,
+    resp::Value,
+    stream::{ConsumerGroup, PendingEntry, Stream, StreamId, NODE_MAX_ENTRIES},
+    ConnectionState, MapValue, MapValueContent, State,
 };
 
-use crate::{resp::Value, ConnectionState, MapValue, MapValueContent, State, StreamEvent};
-
 pub async fn ty(
     state: Arc<State>,
     _: &mut ConnectionState,
-    args: &[String],
-) -> anyhow::Result<Value> {
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
     let [key, ..] = args else {
-        todo!("args.len() < 1");
+        return Err(RedisError::WrongArity);
     };
 
-    let kind = if let Some(val) = state.map.get(key) {
-        match val.value {
-            MapValueContent::String(_) | MapValueContent::Integer(_) => "string",
-            MapValueContent::List(_) => "list",
-            MapValueContent::Stream(_) => "stream",
-            MapValueContent::SortedSet(_) => "sorted_set",
-        }
-    } else {
-        "none"
-    };
+    let kind = state
+        .map
+        .get(key)
+        .map_or("none", |val| val.value.type_name());
 
     Ok(Value::simple_string(kind))
 }
 
-pub async fn xadd(
-    state: Arc<State>,
-    _: &mut ConnectionState,
-    args: &[String],
-) -> anyhow::Result<Value> {
-    let [key, id_string, kv_pairs @ ..] = args else {
-        todo!("args.len() < 2");
-    };
+/// How to trim a stream, from the `MAXLEN`/`MINID` options of `XADD` and `XTRIM`
+#[derive(Debug, Clone, Copy)]
+struct Trim {
+    strategy: TrimStrategy,
+    /// Whether `~` was given, allowing more entries than asked for to be kept
+    approx: bool,
+    /// The most entries to remove, or `None` for no limit
+    limit: Option<usize>,
+}
 
-    assert!(kv_pairs.len() % 2 == 0);
+#[derive(Debug, Clone, Copy)]
+enum TrimStrategy {
+    MaxLen(usize),
+    MinId(StreamId),
+}
 
-    let millis: u64 = if id_string == "*" {
-        assert_eq!(id_string, "*");
-        let millis: u64 = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .context("It's not < 1970")?
-            .as_millis()
-            .try_into()
-            .context("we're 584.9 million years in the future")?;
+impl Trim {
+    /// Parse `MAXLEN|MINID [=|~] threshold [LIMIT count]` from the start of `args`
+    fn parse(args: &mut ArgParser) -> Result<Self, RedisError> {
+        let by_len = match args.keyword().as_deref() {
+            Some("MAXLEN") => true,
+            Some("MINID") => false,
+            _ => return Err(RedisError::Syntax),
+        };
 
-        millis
-    } else {
-        let (millis, _) = id_string.split_once('-').context("invalid id")?;
-        millis.parse().context("millis provided invalid format")?
-    };
-
-    let seq = if let Some(seq) = id_string
-        .split_once('-')
-        .map(|(_, s)| s)
-        .and_then(|s| (s != "*").then_some(s))
-    {
-        seq.parse().context("seq provided invalid format")?
-    } else if let Some(x) = state.map.get(key) {
-        match x.value {
-            MapValueContent::String(_) | MapValueContent::Integer(_) => todo!(),
-            MapValueContent::List(_) => todo!(),
-            MapValueContent::Stream(ref map) => {
-                if let Some(last) = map.range(..(millis + 1, 0)).map(|(k, _)| *k).next_back() {
-                    if last.0 == millis {
-                        last.1 + 1
-                    } else {
-                        0
-                    }
-                } else if millis == 0 {
-                    1
-                } else {
-                    0
+        let approx = if args.flag("~") {
+            true
+        } else {
+            args.flag("=");
+            false
+        };
+        let strategy = if by_len {
+            match args.int::<i64>()? {
+                maxlen if maxlen < 0 => {
+                    return Err(RedisError::custom("ERR The MAXLEN argument must be >= 0."));
                 }
+                maxlen => TrimStrategy::MaxLen(maxlen as usize),
             }
-            MapValueContent::SortedSet(_) => todo!(),
+        } else {
+            TrimStrategy::MinId(parse_stream_id(&args.value()?.to_string_lossy())?)
+        };
+
+        // Approximate trimming does a bounded amount of work by default
+        let mut limit = approx.then_some(100 * NODE_MAX_ENTRIES);
+        if args.flag("LIMIT") {
+            let count = match args.int::<i64>()? {
+                count if count < 0 => {
+                    return Err(RedisError::custom("ERR The LIMIT argument must be >= 0."));
+                }
+                count => count as usize,
+            };
+            if !approx {
+                return Err(RedisError::custom(
+                    "ERR syntax error, LIMIT cannot be used without the special ~ option",
+                ));
+            }
+            limit = (count != 0).then_some(count);
         }
-    } else if millis == 0 {
-        1
-    } else {
-        0
-    };
 
-    let id = (millis, seq);
+        Ok(Self {
+            strategy,
+            approx,
+            limit,
+        })
+    }
 
-    if id == (0, 0) {
-        return Ok(Value::simple_error(
-            "ERR The ID specified in XADD must be greater than 0-0",
-        ));
+    /// Trim `stream`, returning the number of entries removed
+    fn apply(&self, stream: &mut Stream) -> usize {
+        match self.strategy {
+            TrimStrategy::MaxLen(maxlen) => stream.trim_to_len(maxlen, self.approx, self.limit),
+            TrimStrategy::MinId(min_id) => stream.trim_before(min_id, self.approx, self.limit),
+        }
     }
+}
 
-    if let Some(mut x) = state.map.get_mut(key) {
-        match x.value {
-            MapValueContent::String(_) | MapValueContent::Integer(_) => todo!(),
-            MapValueContent::List(_) => todo!(),
-            MapValueContent::Stream(ref mut s) => {
-                if let Some(last_id) = s.last_key_value().map(|(k, _)| *k) {
-                    if id <= last_id {
-                        return Ok(Value::simple_error("ERR The ID specified in XADD is equal or smaller than the target stream top item"));
-                    }
+/// The command which replicas can use to repeat a trim exactly, whatever options it was given
+fn trim_command(key: &Bytes, stream: &Stream) -> Value {
+    Value::from_iter([
+        Value::from("XTRIM"),
+        Value::from(key),
+        Value::from("MAXLEN"),
+        Value::from("="),
+        Value::from(stream.len().to_string()),
+    ])
+}
+
+/// The ID given to `XADD`, which may leave some or all of it to be generated
+#[derive(Debug, Clone, Copy)]
+enum IdSpec {
+    /// `*`
+    Auto,
+    /// `<ms>-*`
+    AutoSeq(u64),
+    Explicit(StreamId),
+}
+
+impl IdSpec {
+    fn parse(id: &str) -> Result<Self, RedisError> {
+        if id == "*" {
+            return Ok(Self::Auto);
+        }
+        if let Some(ms) = id.strip_suffix("-*") {
+            return ms
+                .parse()
+                .map(Self::AutoSeq)
+                .map_err(|_| invalid_stream_id());
+        }
+        parse_stream_id(id).map(Self::Explicit)
+    }
+
+    /// The ID to add to a stream whose last ID is `last`
+    fn resolve(self, last: StreamId) -> Result<StreamId, RedisError> {
+        let exhausted = || {
+            RedisError::custom(
+                "ERR The stream has exhausted the last possible ID, unable to add more items",
+            )
+        };
+
+        let id = match self {
+            Self::Auto => {
+                let now = unix_millis(SystemTime::now()).max(0) as u64;
+                if now > last.0 {
+                    (now, 0)
+                } else {
+                    (last.0, last.1.checked_add(1).ok_or_else(exhausted)?)
                 }
-                s.insert(id, kv_pairs.into());
             }
-            MapValueContent::SortedSet(_) => todo!(),
+            Self::AutoSeq(ms) if ms == last.0 => (ms, last.1.checked_add(1).ok_or_else(exhausted)?),
+            Self::AutoSeq(ms) => (ms, 0),
+            Self::Explicit(id) => id,
+        };
+
+        if id <= last {
+            return Err(RedisError::custom(
+                "ERR The ID specified in XADD is equal or smaller than the target stream top item",
+            ));
         }
-    } else {
-        state.map.insert(
-            key.clone(),
-            MapValue {
-                value: MapValueContent::Stream(BTreeMap::from_iter([(id, kv_pairs.into())])),
-                expires_at: None,
-            },
-        );
+        Ok(id)
     }
+}
 
-    if let Some(mut txs) = state.waiting_on_stream.get_mut(key) {
-        txs.retain(|tx| {
-            tx.send(StreamEvent {
-                id,
-                kv_pairs: kv_pairs.into(),
-            })
-            .is_ok()
-        });
+fn invalid_stream_id() -> RedisError {
+    RedisError::custom("ERR Invalid stream ID specified as stream command argument")
+}
+
+/// Parse a full stream ID (`<ms>-<seq>`), or just the milliseconds with the sequence number as 0
+fn parse_stream_id(id: &str) -> Result<StreamId, RedisError> {
+    let parsed = match id.split_once('-') {
+        Some((ms, seq)) => ms.parse().and_then(|ms| Ok((ms, seq.parse()?))),
+        None => id.parse().map(|ms| (ms, 0)),
+    };
+    parsed.map_err(|_| invalid_stream_id())
+}
+
+pub async fn xadd(
+    state: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, args @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let mut args = ArgParser::new(args);
+    let mut nomkstream = false;
+    let mut trim = None;
+    while let Some(opt) = args.peek_keyword() {
+        match &*opt {
+            "NOMKSTREAM" => {
+                nomkstream = true;
+                args.next_arg();
+            }
+            "MAXLEN" | "MINID" => trim = Some(Trim::parse(&mut args)?),
+            _ => break,
+        }
     }
 
+    let [id, fields @ ..] = args.rest() else {
+        return Err(RedisError::Syntax);
+    };
+    if fields.is_empty() || fields.len() % 2 != 0 {
+        return Err(super::wrong_arity("xadd"));
+    }
+
+    let spec = match IdSpec::parse(&id.to_string_lossy())? {
+        IdSpec::Explicit((0, 0)) => {
+            return Err(RedisError::custom(
+                "ERR The ID specified in XADD must be greater than 0-0",
+            ));
+        }
+        spec => spec,
+    };
+
+    let value = if nomkstream {
+        state.map.get_mut(key)
+    } else {
+        Some(state.map.get_or_insert_with(key.clone(), || MapValue {
+            value: MapValueContent::Stream(Stream::default()),
+            expires_at: None,
+        }))
+    };
+    let Some(mut value) = value else {
+        conn_state.propagate_as = Some(Vec::new());
+        return Ok(Value::Null);
+    };
+    let stream = value.value.as_stream_mut()?;
+
+    let id = spec.resolve(stream.last_id())?;
+    stream.insert(id, fields);
+
+    // Replicas get the ID that we generated, and an exact trim in place of whatever we were asked
+    // to do, so that they end up with the same entries
+    let mut propagate = vec![std::iter::once(Value::from("XADD"))
+        .chain([Value::from(key), id_to_value(id)])
+        .chain(fields.iter().map(Value::from))
+        .collect()];
+    if trim.is_some_and(|trim| trim.apply(stream) > 0) {
+        propagate.push(trim_command(key, stream));
+    }
+    conn_state.propagate_as = Some(propagate);
+
+    // Readers only need waking, so there's nothing more for replicas to apply
+    state.blocked.signal(key, &mut value.value);
+
     Ok(id_to_value(id))
 }
 
-fn id_to_value(id: (u64, u64)) -> Value {
-    Value::bulk_string(format!("{}-{}", id.0, id.1))
+pub async fn xtrim(
+    state: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, args @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let mut args = ArgParser::new(args);
+    let trim = Trim::parse(&mut args)?;
+    args.finish()?;
+
+    conn_state.propagate_as = Some(Vec::new());
+
+    let Some(mut value) = state.map.get_mut(key) else {
+        return Ok(Value::from(0));
+    };
+    let stream = value.value.as_stream_mut()?;
+
+    let removed = trim.apply(stream);
+    if removed > 0 {
+        conn_state.propagate_as = Some(vec![trim_command(key, stream)]);
+    }
+
+    Ok(Value::from(removed))
+}
+
+pub async fn xsetid(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, id] = args else {
+        return Err(RedisError::WrongArity);
+    };
+    let id = parse_stream_id(&id.to_string_lossy())?;
+
+    let Some(mut value) = state.map.get_mut(key) else {
+        return Err(RedisError::NoSuchKey);
+    };
+    let stream = value.value.as_stream_mut()?;
+
+    if stream.iter().next_back().is_some_and(|(top, _)| id < top) {
+        return Err(RedisError::custom(
+            "ERR The ID specified in XSETID is smaller than the target stream top item",
+        ));
+    }
+    stream.set_last_id(id);
+
+    Ok(Value::simple_string("OK"))
 }
 
-fn parse_id((millis, seq): (&str, &str)) -> anyhow::Result<(u64, u64)> {
-    let millis = millis.parse().context("parsing start millis")?;
-    let seq = seq.parse().context("parsing start seq")?;
-    Ok((millis, seq))
+fn id_to_value(id: (u64, u64)) -> Value {
+    Value::bulk_string(format!("{}-{}", id.0, id.1))
 }
 
+/// Parse one end of an `XRANGE` interval: an ID (with the sequence number defaulting to
+/// `default`), the same prefixed with `(` to exclude it, or `unbounded_symbol`
 fn parse_bound(
     bound: &str,
     unbounded_symbol: &str,
     default: u64,
-) -> anyhow::Result<Bound<(u64, u64)>> {
-    Ok(if bound == unbounded_symbol {
-        Bound::Unbounded
-    } else if let Some(x) = bound.split_once('-') {
-        Bound::Included(parse_id(x)?)
+) -> Result<Bound<StreamId>, RedisError> {
+    if bound == unbounded_symbol {
+        return Ok(Bound::Unbounded);
+    }
+
+    let (id, exclusive) = match bound.strip_prefix('(') {
+        Some(id) => (id, true),
+        None => (bound, false),
+    };
+    let id = match id.split_once('-') {
+        Some(_) => parse_stream_id(id)?,
+        None => (id.parse().map_err(|_| invalid_stream_id())?, default),
+    };
+
+    Ok(if exclusive {
+        Bound::Excluded(id)
     } else {
-        Bound::Included((bound.parse().context("parsing bound")?, default))
+        Bound::Included(id)
     })
 }
 
+/// Whether there can't be any IDs between `start` and `end`
+fn is_empty_interval(start: Bound<StreamId>, end: Bound<StreamId>) -> bool {
+    match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start) | Bound::Excluded(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end)) => start >= end,
+        _ => false,
+    }
+}
+
 pub async fn xrange(
     state: Arc<State>,
     _: &mut ConnectionState,
-    args: &[String],
-) -> anyhow::Result<Value> {
-    let [key, start, end, ..] = args else {
-        todo!("args.len() < 3");
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, start, end, args @ ..] = args else {
+        return Err(RedisError::WrongArity);
     };
 
-    let start = parse_bound(start, "-", 0)?;
-    let end = parse_bound(end, "+", u64::MAX)?;
-
-    let ret = if let Some(x) = state.map.get(key) {
-        match x.value {
-            MapValueContent::String(_) | MapValueContent::Integer(_) => todo!(),
-            MapValueContent::List(_) => todo!(),
-            MapValueContent::Stream(ref map) => map
-                .range((start, end))
-                .map(|(k, v)| Value::from_iter([id_to_value(*k), v.iter().collect()]))
-                .collect(),
-            MapValueContent::SortedSet(_) => todo!(),
-        }
-    } else {
-        Value::Null
+    let count = match args {
+        [] => None,
+        [opt, count] if opt.eq_ignore_ascii_case(b"count") => match count.parse::<i64>() {
+            Ok(count) => Some(count.max(0) as usize),
+            Err(_) => return Err(RedisError::NotAnInteger),
+        },
+        _ => return Err(RedisError::Syntax),
     };
 
-    Ok(ret)
-}
+    let (start, end) = match (
+        parse_bound(&start.to_string_lossy(), "-", 0),
+        parse_bound(&end.to_string_lossy(), "+", u64::MAX),
+    ) {
+        (Ok(start), Ok(end)) => (start, end),
+        (Err(e), _) | (_, Err(e)) => return Err(e),
+    };
 
-async fn xread_streams(state: Arc<State>, streams: &[String]) -> anyhow::Result<Value> {
-    assert_eq!(streams.len() % 2, 0);
+    let Some(value) = state.map.get(key) else {
+        return Ok(Value::empty_array());
+    };
+    let stream = value.value.as_stream()?;
 
-    let (keys, starts) = streams.split_at(streams.len() / 2);
+    if is_empty_interval(start, end) {
+        return Ok(Value::empty_array());
+    }
 
-    assert_eq!(keys.len(), starts.len());
+    Ok(stream
+        .range((start, end))
+        .take(count.unwrap_or(usize::MAX))
+        .map(|(k, v)| Value::from_iter([id_to_value(k), v.iter().collect()]))
+        .collect())
+}
 
+/// The entries after `start` in each stream, up to `count` from each, or nil if there are none
+fn xread_streams(
+    state: &State,
+    keys: &[Bytes],
+    starts: &[Bytes],
+    count: Option<usize>,
+) -> Result<Value, RedisError> {
     let mut ret = Vec::with_capacity(keys.len());
 
     for (key, start) in keys.iter().zip(starts) {
-        if let Some(x) = state.map.get(key) {
-            match x.value {
-                MapValueContent::String(_) | MapValueContent::Integer(_) => todo!(),
-                MapValueContent::List(_) => todo!(),
-                MapValueContent::Stream(ref map) => {
-                    let start = parse_id(
-                        start
-                            .split_once('-')
-                            .expect("start should always be a valid id 🤞"),
-                    )?;
-                    ret.push(Value::from_iter([
-                        Value::bulk_string(key),
-                        map.range((Bound::Excluded(start), Bound::Unbounded))
-                            .map(|(k, v)| Value::from_iter([id_to_value(*k), v.iter().collect()]))
-                            .collect(),
-                    ]));
-                }
-                MapValueContent::SortedSet(_) => todo!(),
+        let Some(value) = state.map.get(key) else {
+            continue;
+        };
+        let stream = value.value.as_stream()?;
+
+        let entries: Vec<_> = match start.as_bytes() {
+            // Only entries added from now on
+            b"$" => continue,
+            // Only the last entry
+            b"+" => stream.iter().next_back().into_iter().collect(),
+            _ => {
+                let start = parse_stream_id(&start.to_string_lossy())?;
+                stream
+                    .range((Bound::Excluded(start), Bound::Unbounded))
+                    .take(count.unwrap_or(usize::MAX))
+                    .collect()
             }
+        };
+        if entries.is_empty() {
+            continue;
         }
+
+        ret.push(Value::from_iter([
+            Value::bulk_string(key),
+            entries
+                .into_iter()
+                .map(|(k, v)| Value::from_iter([id_to_value(k), v.iter().collect()]))
+                .collect(),
+        ]));
     }
 
-    Ok(Value::from(ret))
+    Ok(if ret.is_empty() {
+        Value::Null
+    } else {
+        Value::from(ret)
+    })
 }
 
-async fn xread_block(state: Arc<State>, args: &[String]) -> anyhow::Result<Value> {
-    let [timeout, streams_str, streams @ ..] = args else {
-        todo!("args.len() < 3");
+pub async fn xread(
+    state: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+    context: ExecContext,
+) -> Result<Value, RedisError> {
+    let mut args = args;
+    let mut count = None;
+    let mut block = None;
+    let streams = loop {
+        let Some((opt, rest)) = args.split_first() else {
+            return Err(RedisError::Syntax);
+        };
+        match (&*opt.to_uppercase(), rest) {
+            ("COUNT", [n, rest @ ..]) => {
+                let Ok(n) = n.parse::<i64>() else {
+                    return Err(RedisError::NotAnInteger);
+                };
+                count = (n > 0).then_some(n as usize);
+                args = rest;
+            }
+            ("BLOCK", [ms, rest @ ..]) => {
+                let Ok(ms) = ms.parse::<u64>() else {
+                    return Err(RedisError::custom(
+                        "ERR timeout is not an integer or out of range",
+                    ));
+                };
+                block = Some(Duration::from_millis(ms));
+                args = rest;
+            }
+            ("STREAMS", rest) => break rest,
+            _ => return Err(RedisError::Syntax),
+        }
     };
-    assert_eq!(streams_str, "streams");
-
-    let timeout = Duration::from_millis(timeout.parse().context("invalid timeout provided")?);
 
+    if streams.is_empty() || streams.len() % 2 != 0 {
+        return Err(RedisError::custom("ERR Unbalanced 'xread' list of streams: for each stream key an ID or '$' must be specified."));
+    }
     let (keys, starts) = streams.split_at(streams.len() / 2);
 
-    assert_eq!(keys.len(), starts.len());
-
-    let ret = Arc::new(Mutex::new(Vec::<(String, Vec<Value>)>::with_capacity(
-        if timeout.is_zero() { 1 } else { keys.len() },
-    )));
+    let read = xread_streams(&state, keys, starts, count)?;
+    let Some(block) = block.filter(|_| matches!(read, Value::Null) && context.can_block()) else {
+        return Ok(read);
+    };
 
-    let mut jset = JoinSet::new();
+    // Only entries added from now on count, so `$` and `+` (which has already been read if there
+    // was a last entry) stand for each stream's last ID as it is now
+    let mut after = HashMap::with_capacity(keys.len());
     for (key, start) in keys.iter().zip(starts) {
-        let (tx, rx) = mpsc::unbounded_channel();
-        state
-            .waiting_on_stream
-            .entry(key.clone())
-            .or_default()
-            .push(tx);
-
-        let ret = Arc::clone(&ret);
-
-        let key = key.clone();
-        let start = if start == "$" {
-            None
-        } else {
-            Some(parse_id(
-                start.split_once('-').context("id should be correct")?,
-            )?)
+        let id = match start.as_bytes() {
+            b"$" | b"+" => match state.map.get(key) {
+                Some(value) => value.value.as_stream()?.last_id(),
+                None => (0, 0),
+            },
+            _ => parse_stream_id(&start.to_string_lossy())?,
         };
+        after.insert(key.clone(), id);
+    }
+    let starts: Vec<Bytes> = keys
+        .iter()
+        .map(|key| {
+            let (ms, seq) = after[key];
+            Bytes::from(format!("{ms}-{seq}"))
+        })
+        .collect();
+    let after = Arc::new(after);
 
-        let fut = async move {
-            let mut rx = rx;
-            while let Some(StreamEvent { id, kv_pairs }) = rx.recv().await {
-                let mut ret = ret.lock().await;
-                let idx = ret
-                    .iter()
-                    .enumerate()
-                    .find_map(|(i, v)| (v.0 == *key).then_some(i));
+    let deadline = blocking::deadline((!block.is_zero()).then_some(block));
+    loop {
+        let after = Arc::clone(&after);
+        let ready = move |key: &Bytes, value: &mut MapValueContent| {
+            value
+                .as_stream()
+                .is_ok_and(|stream| after.get(key).is_some_and(|&id| stream.last_id() > id))
+        };
+        if !blocking::wait_until(&state, conn_state, keys, deadline, ready).await {
+            return Ok(Value::Null);
+        }
 
-                if start.is_some_and(|start| id <= start) {
-                    continue;
-                }
+        let read = xread_streams(&state, keys, &starts, count)?;
+        if !matches!(read, Value::Null) {
+            return Ok(read);
+        }
+    }
+}
 
-                let new = Value::from_iter([id_to_value(id), Value::from_iter(kv_pairs)]);
+/// Call `f` with the stream at `key`, if it has a consumer group called `group`.  Otherwise
+/// return the error that `no_group` gives.
+fn with_group<T>(
+    state: &State,
+    key: &Bytes,
+    group: &str,
+    no_group: impl FnOnce() -> RedisError,
+    f: impl FnOnce(&mut Stream) -> T,
+) -> Result<T, RedisError> {
+    let Some(mut value) = state.map.get_mut(key) else {
+        return Err(no_group());
+    };
+    let stream = value.value.as_stream_mut()?;
+    if !stream.groups.contains_key(group) {
+        return Err(no_group());
+    }
+    Ok(f(stream))
+}
 
-                if let Some(idx) = idx {
-                    ret[idx].1.push(new);
-                } else {
-                    ret.push((key.clone(), vec![new]));
-                    if timeout.is_zero() {
-                        return;
+/// The error for a group which doesn't exist, as worded by most of the group commands
+fn no_such_group(key: &Bytes, group: &str) -> RedisError {
+    RedisError::custom(format!(
+        "NOGROUP No such key '{key}' or consumer group '{group}'"
+    ))
+}
+
+/// The error for a group which doesn't exist, as worded by `XGROUP`
+fn no_such_group_for_key(key: &Bytes, group: &str) -> RedisError {
+    RedisError::custom(format!(
+        "NOGROUP No such consumer group '{group}' for key name '{key}'"
+    ))
+}
+
+/// An entry as replied to clients, which is nil if it's been deleted since it was delivered
+fn entry_value(id: StreamId, fields: Option<&[Bytes]>) -> Value {
+    Value::from_iter([
+        id_to_value(id),
+        fields.map_or(Value::Null, |fields| fields.iter().collect()),
+    ])
+}
+
+/// The command which replicas can use to repeat delivering (or claiming) the entry `id` to a
+/// consumer, with the same delivery time and count as we gave it
+fn claim_command(
+    key: &Bytes,
+    group: &str,
+    id: StreamId,
+    pending: &PendingEntry,
+    last_delivered: StreamId,
+) -> Value {
+    Value::from_iter([
+        Value::from("XCLAIM"),
+        Value::from(key),
+        Value::from(group),
+        Value::from(&pending.consumer),
+        Value::from("0"),
+        id_to_value(id),
+        Value::from("TIME"),
+        Value::from(pending.delivered_at.to_string()),
+        Value::from("RETRYCOUNT"),
+        Value::from(pending.delivery_count.to_string()),
+        Value::from("FORCE"),
+        Value::from("JUSTID"),
+        Value::from("LASTID"),
+        id_to_value(last_delivered),
+    ])
+}
+
+pub async fn xgroup(
+    state: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [subcommand, args @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let no_key = || {
+        RedisError::custom(
+            "ERR The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically.",
+        )
+    };
+    let now = unix_millis(SystemTime::now());
+    let subcommand = subcommand.to_uppercase();
+
+    // Only `CREATE` can make the stream, so the rest need it to exist already
+    if let (false, Some(key)) = (subcommand == "CREATE", args.first()) {
+        if state.map.get(key).is_none() {
+            return Err(no_key());
+        }
+    }
+
+    match (&*subcommand, args) {
+        ("CREATE", [key, group, id, opts @ ..]) => {
+            let mkstream = match opts {
+                [] => false,
+                [opt] if opt.eq_ignore_ascii_case(b"mkstream") => true,
+                _ => return Err(RedisError::Syntax),
+            };
+            let group = group.to_string_lossy();
+            let id = match id.as_bytes() {
+                b"$" => None,
+                id => Some(parse_stream_id(&String::from_utf8_lossy(id))?),
+            };
+
+            let value = if mkstream {
+                Some(state.map.get_or_insert_with(key.clone(), || MapValue {
+                    value: MapValueContent::Stream(Stream::default()),
+                    expires_at: None,
+                }))
+            } else {
+                state.map.get_mut(key)
+            };
+            let Some(mut value) = value else {
+                return Err(no_key());
+            };
+            let stream = value.value.as_stream_mut()?;
+            if stream.groups.contains_key(&*group) {
+                return Err(RedisError::custom(
+                    "BUSYGROUP Consumer Group name already exists",
+                ));
+            }
+
+            // Replicas need the ID that `$` stood for
+            let id = id.unwrap_or(stream.last_id());
+            stream
+                .groups
+                .insert(group.to_string(), ConsumerGroup::new(id));
+            conn_state.propagate_as = Some(vec![Value::from_iter([
+                Value::from("XGROUP"),
+                Value::from("CREATE"),
+                Value::from(key),
+                Value::from(&*group),
+                id_to_value(id),
+                Value::from("MKSTREAM"),
+            ])]);
+            Ok(Value::simple_string("OK"))
+        }
+        ("SETID", [key, group, id]) => {
+            let group = &*group.to_string_lossy();
+            let id = match id.as_bytes() {
+                b"$" => None,
+                id => Some(parse_stream_id(&String::from_utf8_lossy(id))?),
+            };
+            let id = with_group(
+                &state,
+                key,
+                group,
+                || no_such_group_for_key(key, group),
+                |stream| {
+                    let id = id.unwrap_or(stream.last_id());
+                    stream.groups.get_mut(group).expect("group exists").last_delivered = id;
+                    id
+                },
+            )?;
+            conn_state.propagate_as = Some(vec![Value::from_iter([
+                Value::from("XGROUP"),
+                Value::from("SETID"),
+                Value::from(key),
+                Value::from(group),
+                id_to_value(id),
+            ])]);
+            Ok(Value::simple_string("OK"))
+        }
+        ("DESTROY", [key, group]) => {
+            let Some(mut value) = state.map.get_mut(key) else {
+                return Ok(Value::from(0));
+            };
+            let stream = value.value.as_stream_mut()?;
+            Ok(Value::from(
+                stream.groups.remove(&*group.to_string_lossy()).is_some() as i64,
+            ))
+        }
+        ("CREATECONSUMER", [key, group, consumer]) => {
+            let (group, consumer) = (&*group.to_string_lossy(), &*consumer.to_string_lossy());
+            let created = with_group(
+                &state,
+                key,
+                group,
+                || no_such_group_for_key(key, group),
+                |stream| {
+                    let group = stream.groups.get_mut(group).expect("group exists");
+                    let created = !group.consumers.contains_key(consumer);
+                    group.consumer(consumer, now);
+                    created
+                },
+            )?;
+            Ok(Value::from(created as i64))
+        }
+        ("DELCONSUMER", [key, group, consumer]) => {
+            let (group, consumer) = (&*group.to_string_lossy(), &*consumer.to_string_lossy());
+            let deleted = with_group(
+                &state,
+                key,
+                group,
+                || no_such_group_for_key(key, group),
+                |stream| {
+                    let group = stream.groups.get_mut(group).expect("group exists");
+                    group.consumers.remove(consumer);
+                    let before = group.pending.len();
+                    group.pending.retain(|_, entry| entry.consumer != *consumer);
+                    before - group.pending.len()
+                },
+            )?;
+            Ok(Value::from(deleted))
+        }
+        _ => Err(RedisError::custom(format!(
+            "ERR unknown subcommand or wrong number of arguments for '{subcommand}'. Try XGROUP HELP."
+        ))),
+    }
+}
+
+/// The options for `XREADGROUP`
+#[derive(Debug)]
+struct ReadGroupOptions<'a> {
+    group: Cow<'a, str>,
+    consumer: Cow<'a, str>,
+    count: Option<usize>,
+    block: Option<Duration>,
+    noack: bool,
+    keys: &'a [Bytes],
+    ids: &'a [Bytes],
+}
+
+impl<'a> ReadGroupOptions<'a> {
+    fn parse(args: &'a [Bytes]) -> Result<Self, RedisError> {
+        let syntax_error = || RedisError::Syntax;
+        let not_an_integer = || RedisError::NotAnInteger;
+
+        let [opt, group, consumer, args @ ..] = args else {
+            return Err(syntax_error());
+        };
+        let mut args = args;
+        if !opt.eq_ignore_ascii_case(b"group") {
+            return Err(syntax_error());
+        }
+
+        let mut count = None;
+        let mut block = None;
+        let mut noack = false;
+        let streams = loop {
+            let Some((opt, rest)) = args.split_first() else {
+                return Err(syntax_error());
+            };
+            match (&*opt.to_uppercase(), rest) {
+                ("COUNT", [n, rest @ ..]) => {
+                    let n = n.parse::<i64>().map_err(|_| not_an_integer())?;
+                    count = (n > 0).then_some(n as usize);
+                    args = rest;
+                }
+                ("BLOCK", [ms, rest @ ..]) => {
+                    let ms = ms.parse::<i64>().map_err(|_| {
+                        RedisError::custom("ERR timeout is not an integer or out of range")
+                    })?;
+                    if ms < 0 {
+                        return Err(RedisError::custom("ERR timeout is negative"));
                     }
+                    block = Some(Duration::from_millis(ms as u64));
+                    args = rest;
                 }
+                ("NOACK", rest) => {
+                    noack = true;
+                    args = rest;
+                }
+                ("STREAMS", rest) => break rest,
+                _ => return Err(syntax_error()),
             }
         };
-        if timeout.is_zero() {
-            jset.spawn(fut);
-        } else {
-            jset.spawn(async move {
-                let _ = tokio::time::timeout(timeout, fut).await;
-            });
+
+        if streams.is_empty() || streams.len() % 2 != 0 {
+            return Err(RedisError::custom("ERR Unbalanced 'xreadgroup' list of streams: for each stream key an ID or '>' must be specified."));
         }
+        let (keys, ids) = streams.split_at(streams.len() / 2);
+
+        Ok(Self {
+            group: group.to_string_lossy(),
+            consumer: consumer.to_string_lossy(),
+            count,
+            block,
+            noack,
+            keys,
+            ids,
+        })
     }
+}
+
+/// Read from a single stream for `XREADGROUP`, returning the entries for the reply (or `None` if
+/// there are no new entries) and adding the commands that replicas need to `propagate`
+fn read_group(
+    state: &State,
+    opts: &ReadGroupOptions,
+    key: &Bytes,
+    id: &Bytes,
+    now: i64,
+    propagate: &mut Vec<Value>,
+) -> Result<Option<Vec<Value>>, RedisError> {
+    let start = match id.as_bytes() {
+        b">" => None,
+        _ => Some(parse_stream_id(&id.to_string_lossy())?),
+    };
+    let no_group = || {
+        RedisError::custom(format!(
+            "NOGROUP No such key '{key}' or consumer group '{}' in XREADGROUP with GROUP option",
+            &*opts.group
+        ))
+    };
 
-    while let Some(x) = jset.join_next().await {
-        x?;
-        if timeout.is_zero() {
-            break;
+    with_group(state, key, &opts.group, no_group, |stream| {
+        let count = opts.count.unwrap_or(usize::MAX);
+        let last_delivered = stream.groups[&*opts.group].last_delivered;
+
+        let Some(start) = start else {
+            // New entries, which are delivered to this consumer
+            let entries: Vec<_> = stream
+                .range((Bound::Excluded(last_delivered), Bound::Unbounded))
+                .take(count)
+                .collect();
+
+            let group = stream.groups.get_mut(&*opts.group).expect("group exists");
+            let created = !group.consumers.contains_key(&*opts.consumer);
+            group.consumer(&opts.consumer, now).seen_at = now;
+            if entries.is_empty() {
+                if created {
+                    propagate.push(Value::from_iter([
+                        Value::from("XGROUP"),
+                        Value::from("CREATECONSUMER"),
+                        Value::from(key),
+                        Value::from(&*opts.group),
+                        Value::from(&*opts.consumer),
+                    ]));
+                }
+                return None;
+            }
+
+            let (last, _) = *entries.last().expect("there are entries");
+            group.last_delivered = last;
+            if opts.noack {
+                propagate.push(Value::from_iter([
+                    Value::from("XGROUP"),
+                    Value::from("SETID"),
+                    Value::from(key),
+                    Value::from(&*opts.group),
+                    id_to_value(last),
+                ]));
+            } else {
+                for &(id, _) in &entries {
+                    let pending = PendingEntry {
+                        consumer: opts.consumer.to_string(),
+                        delivered_at: now,
+                        delivery_count: 1,
+                    };
+                    propagate.push(claim_command(key, &opts.group, id, &pending, last));
+                    group.pending.insert(id, pending);
+                }
+            }
+
+            return Some(
+                entries
+                    .iter()
+                    .map(|(id, fields)| entry_value(*id, Some(fields)))
+                    .collect(),
+            );
+        };
+
+        // The history of entries delivered to this consumer and not yet acknowledged
+        let group = &stream.groups[&*opts.group];
+        let history = group
+            .pending
+            .range((Bound::Excluded(start), Bound::Unbounded))
+            .filter(|(_, entry)| entry.consumer == *opts.consumer)
+            .take(count)
+            .map(|(id, _)| entry_value(*id, stream.get(*id).as_deref()))
+            .collect();
+        stream
+            .groups
+            .get_mut(&*opts.group)
+            .expect("group exists")
+            .consumer(&opts.consumer, now)
+            .seen_at = now;
+        Some(history)
+    })
+}
+
+pub async fn xreadgroup(
+    state: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+    context: ExecContext,
+) -> Result<Value, RedisError> {
+    let opts = ReadGroupOptions::parse(args)?;
+
+    // Only reads of new entries can block
+    let can_block =
+        context.can_block() && opts.block.is_some() && opts.ids.iter().all(|id| id == ">");
+
+    let deadline = blocking::deadline(opts.block.filter(|block| !block.is_zero()));
+    loop {
+        let now = unix_millis(SystemTime::now());
+        let mut propagate = Vec::new();
+        let mut ret = Vec::new();
+        for (key, id) in opts.keys.iter().zip(opts.ids) {
+            if let Some(entries) = read_group(&state, &opts, key, id, now, &mut propagate)? {
+                ret.push(Value::from_iter([Value::from(key), Value::from(entries)]))
+            }
+        }
+        conn_state.propagate_as = Some(propagate);
+
+        if !ret.is_empty() {
+            return Ok(Value::from(ret));
+        }
+        if !can_block {
+            return Ok(Value::Null);
+        }
+
+        // Wait for an entry which hasn't been delivered to the group, then try again.  The group
+        // going away wakes us too, so that we can reply with the error.
+        let group = opts.group.to_string();
+        let ready = move |_: &Bytes, value: &mut MapValueContent| {
+            value.as_stream().is_ok_and(|stream| {
+                stream
+                    .groups
+                    .get(&group)
+                    .is_none_or(|group| group.last_delivered < stream.last_id())
+            })
+        };
+        if !blocking::wait_until(&state, conn_state, opts.keys, deadline, ready).await {
+            return Ok(Value::Null);
         }
     }
+}
 
-    let ret = Arc::into_inner(ret)
-        .expect("Everything dropped since the futures are done")
-        .into_inner();
+pub async fn xack(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, group, ids @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+    let group = &*group.to_string_lossy();
 
-    Ok(if ret.is_empty() {
-        Value::Null
-    } else {
-        ret.into_iter()
-            .map(|(k, v)| Value::from_iter([Value::from(k), Value::from(v)]))
-            .collect()
-    })
+    let ids = ids
+        .iter()
+        .map(|id| parse_stream_id(&id.to_string_lossy()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // A missing stream or group acknowledges nothing, rather than being an error
+    let Some(mut value) = state.map.get_mut(key) else {
+        return Ok(Value::from(0));
+    };
+    let Some(group) = value.value.as_stream_mut()?.groups.get_mut(group) else {
+        return Ok(Value::from(0));
+    };
+
+    let acked = ids
+        .iter()
+        .filter(|id| group.pending.remove(id).is_some())
+        .count();
+    Ok(Value::from(acked))
 }
 
-pub async fn xread(
+pub async fn xpending(
     state: Arc<State>,
     _: &mut ConnectionState,
-    args: &[String],
-) -> anyhow::Result<Value> {
-    match &*args[0] {
-        "streams" => xread_streams(state, &args[1..]).await,
-        "block" => xread_block(state, &args[1..]).await,
-        subcmd => bail!("Unknown subcommand '{subcmd}'"),
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, group, args @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+    let group = &*group.to_string_lossy();
+
+    let not_an_integer = || RedisError::NotAnInteger;
+
+    // The extended form: [IDLE min-idle-time] start end count [consumer]
+    let (min_idle, args) = match args {
+        [opt, idle, rest @ ..] if opt.eq_ignore_ascii_case(b"idle") => match idle.parse::<i64>() {
+            Ok(idle) => (Some(idle), rest),
+            Err(_) => return Err(not_an_integer()),
+        },
+        args => (None, args),
+    };
+    let extended = match args {
+        [] if min_idle.is_none() => None,
+        [start, end, count] => Some((start, end, count, None)),
+        [start, end, count, consumer] => Some((start, end, count, Some(consumer))),
+        _ => return Err(RedisError::Syntax),
+    };
+
+    let Some((start, end, count, consumer)) = extended else {
+        return with_group(
+            &state,
+            key,
+            group,
+            || no_such_group(key, group),
+            |stream| {
+                let group = &stream.groups[group];
+                let (Some((first, _)), Some((last, _))) = (
+                    group.pending.first_key_value(),
+                    group.pending.last_key_value(),
+                ) else {
+                    return Value::from_iter([
+                        Value::from(0),
+                        Value::Null,
+                        Value::Null,
+                        Value::Null,
+                    ]);
+                };
+
+                Value::from_iter([
+                    Value::from(group.pending.len()),
+                    id_to_value(*first),
+                    id_to_value(*last),
+                    group
+                        .pending_counts()
+                        .into_iter()
+                        .map(|(consumer, count)| {
+                            Value::from_iter([
+                                Value::from(consumer),
+                                Value::from(count.to_string()),
+                            ])
+                        })
+                        .collect(),
+                ])
+            },
+        );
+    };
+
+    let start = parse_bound(&start.to_string_lossy(), "-", 0)?;
+    let end = parse_bound(&end.to_string_lossy(), "+", u64::MAX)?;
+    let count = count.parse::<i64>().map_err(|_| not_an_integer())?.max(0) as usize;
+
+    let now = unix_millis(SystemTime::now());
+    with_group(
+        &state,
+        key,
+        group,
+        || no_such_group(key, group),
+        |stream| {
+            if is_empty_interval(start, end) {
+                return Value::empty_array();
+            }
+
+            stream.groups[group]
+                .pending
+                .range((start, end))
+                .filter(|(_, entry)| consumer.is_none_or(|consumer| *consumer == *entry.consumer))
+                .filter(|(_, entry)| {
+                    min_idle.is_none_or(|min_idle| now - entry.delivered_at >= min_idle)
+                })
+                .take(count)
+                .map(|(id, entry)| {
+                    Value::from_iter([
+                        id_to_value(*id),
+                        Value::from(&entry.consumer),
+                        Value::from(now - entry.delivered_at),
+                        Value::from(entry.delivery_count as i64),
+                    ])
+                })
+                .collect()
+        },
+    )
+}
+
+/// How claimed entries should be updated, from the options of `XCLAIM`
+#[derive(Debug, Clone, Copy)]
+struct ClaimOptions {
+    /// The new delivery time, in unix millis
+    delivered_at: i64,
+    retry_count: Option<u64>,
+    force: bool,
+    justid: bool,
+    last_id: Option<StreamId>,
+}
+
+/// Give the pending entry `id` to `consumer`, returning whether it was claimed.  `JUSTID` claims
+/// don't count as a delivery.
+fn claim(
+    group: &mut ConsumerGroup,
+    consumer: &str,
+    id: StreamId,
+    opts: &ClaimOptions,
+) -> Option<PendingEntry> {
+    let pending = group.pending.get_mut(&id)?;
+    pending.consumer = consumer.to_string();
+    pending.delivered_at = opts.delivered_at;
+    match opts.retry_count {
+        Some(count) => pending.delivery_count = count,
+        None if !opts.justid => pending.delivery_count += 1,
+        None => {}
     }
+    Some(pending.clone())
+}
+
+pub async fn xclaim(
+    state: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, group, consumer, min_idle, args @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+    let (group, consumer) = (&*group.to_string_lossy(), &*consumer.to_string_lossy());
+
+    let not_an_integer = || RedisError::NotAnInteger;
+    let Ok(min_idle) = min_idle.parse::<i64>() else {
+        return Err(RedisError::custom(
+            "ERR Invalid min-idle-time argument for XCLAIM",
+        ));
+    };
+
+    let now = unix_millis(SystemTime::now());
+    let ids_len = args
+        .iter()
+        .take_while(|arg| parse_stream_id(&arg.to_string_lossy()).is_ok())
+        .count();
+    let (ids, opts) = args.split_at(ids_len);
+    if ids.is_empty() {
+        return Err(RedisError::Syntax);
+    }
+    let ids: Vec<_> = ids
+        .iter()
+        .map(|id| parse_stream_id(&id.to_string_lossy()).expect("checked above"))
+        .collect();
+
+    let mut claim_opts = ClaimOptions {
+        delivered_at: now,
+        retry_count: None,
+        force: false,
+        justid: false,
+        last_id: None,
+    };
+    let mut opts = opts.iter();
+    while let Some(opt) = opts.next() {
+        match &*opt.to_uppercase() {
+            "FORCE" => claim_opts.force = true,
+            "JUSTID" => claim_opts.justid = true,
+            "IDLE" | "TIME" | "RETRYCOUNT" | "LASTID" => {
+                let Some(arg) = opts.next() else {
+                    return Err(RedisError::Syntax);
+                };
+                match &*opt.to_uppercase() {
+                    "IDLE" => match arg.parse::<i64>() {
+                        Ok(idle) => claim_opts.delivered_at = now - idle,
+                        Err(_) => return Err(not_an_integer()),
+                    },
+                    "TIME" => match arg.parse::<i64>() {
+                        Ok(time) => claim_opts.delivered_at = time,
+                        Err(_) => return Err(not_an_integer()),
+                    },
+                    "RETRYCOUNT" => match arg.parse::<u64>() {
+                        Ok(count) => claim_opts.retry_count = Some(count),
+                        Err(_) => return Err(not_an_integer()),
+                    },
+                    _ => claim_opts.last_id = Some(parse_stream_id(&arg.to_string_lossy())?),
+                }
+            }
+            _ => {
+                return Err(RedisError::custom(format!(
+                    "ERR Unrecognized XCLAIM option '{opt}'"
+                )))
+            }
+        }
+    }
+    // A delivery time in the future would make the entry look like it has negative idle time
+    claim_opts.delivered_at = claim_opts.delivered_at.min(now);
+
+    let mut propagate = Vec::new();
+    let claimed = with_group(
+        &state,
+        key,
+        group,
+        || no_such_group(key, group),
+        |stream| {
+            let entries: Vec<_> = ids.iter().map(|&id| (id, stream.get(id))).collect();
+
+            let group_name = group;
+            let group = stream.groups.get_mut(group).expect("group exists");
+            if let Some(last_id) = claim_opts.last_id {
+                group.last_delivered = group.last_delivered.max(last_id);
+            }
+            group.consumer(consumer, now).seen_at = now;
+
+            let mut claimed = Vec::new();
+            for (id, fields) in entries {
+                let Some(fields) = fields else {
+                    // Entries which have been deleted can't be claimed, and are no longer pending
+                    if group.pending.remove(&id).is_some() {
+                        propagate.push(Value::from_iter([
+                            Value::from("XACK"),
+                            Value::from(key),
+                            Value::from(group_name),
+                            id_to_value(id),
+                        ]));
+                    }
+                    continue;
+                };
+
+                match group.pending.get(&id) {
+                    Some(pending) if now - pending.delivered_at < min_idle => continue,
+                    Some(_) => {}
+                    None if claim_opts.force => {
+                        group.pending.insert(
+                            id,
+                            PendingEntry {
+                                consumer: consumer.to_string(),
+                                delivered_at: now,
+                                delivery_count: 0,
+                            },
+                        );
+                    }
+                    None => continue,
+                }
+
+                let pending = claim(group, consumer, id, &claim_opts).expect("entry is pending");
+                propagate.push(claim_command(
+                    key,
+                    group_name,
+                    id,
+                    &pending,
+                    group.last_delivered,
+                ));
+                claimed.push(if claim_opts.justid {
+                    id_to_value(id)
+                } else {
+                    entry_value(id, Some(&fields))
+                });
+            }
+            claimed
+        },
+    );
+
+    conn_state.propagate_as = Some(propagate);
+    Ok(Value::from(claimed?))
+}
+
+pub async fn xautoclaim(
+    state: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, group, consumer, min_idle, start, opts @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+    let (group, consumer) = (&*group.to_string_lossy(), &*consumer.to_string_lossy());
+
+    let Ok(min_idle) = min_idle.parse::<i64>() else {
+        return Err(RedisError::custom(
+            "ERR Invalid min-idle-time argument for XAUTOCLAIM",
+        ));
+    };
+    let start = parse_bound(&start.to_string_lossy(), "-", 0)?;
+
+    let mut count = 100;
+    let mut justid = false;
+    let mut opts = opts.iter();
+    while let Some(opt) = opts.next() {
+        match &*opt.to_uppercase() {
+            "COUNT" => match opts.next().map(|n| n.parse::<usize>()) {
+                Some(Ok(n)) if n > 0 => count = n,
+                _ => return Err(RedisError::custom("ERR COUNT must be > 0")),
+            },
+            "JUSTID" => justid = true,
+            _ => return Err(RedisError::Syntax),
+        }
+    }
+
+    let now = unix_millis(SystemTime::now());
+    let claim_opts = ClaimOptions {
+        delivered_at: now,
+        retry_count: None,
+        force: false,
+        justid,
+        last_id: None,
+    };
+
+    let mut propagate = Vec::new();
+    let claimed = with_group(
+        &state,
+        key,
+        group,
+        || no_such_group(key, group),
+        |stream| {
+            let pending = &stream.groups[group].pending;
+            // Like Redis, look at a bounded number of pending entries so that a long scan of busy
+            // entries doesn't stall everything else
+            let mut scanned = pending.range((start, Bound::Unbounded)).map(|(id, entry)| {
+                let idle = now - entry.delivered_at >= min_idle;
+                (*id, idle, stream.get(*id))
+            });
+            let mut candidates = Vec::new();
+            let mut to_claim = count;
+            for (id, idle, fields) in scanned.by_ref().take(count.saturating_mul(10)) {
+                // Deleted entries are dropped whatever their idle time
+                if fields.is_none() || idle {
+                    candidates.push((id, fields));
+                    if idle {
+                        to_claim -= 1;
+                    }
+                }
+                if to_claim == 0 {
+                    break;
+                }
+            }
+            let next = scanned.next().map_or((0, 0), |(id, _, _)| id);
+
+            let group_name = group;
+            let group = stream.groups.get_mut(group).expect("group exists");
+            group.consumer(consumer, now).seen_at = now;
+
+            let mut claimed = Vec::new();
+            let mut deleted = Vec::new();
+            for (id, fields) in candidates {
+                let Some(fields) = fields else {
+                    group.pending.remove(&id);
+                    propagate.push(Value::from_iter([
+                        Value::from("XACK"),
+                        Value::from(key),
+                        Value::from(group_name),
+                        id_to_value(id),
+                    ]));
+                    deleted.push(id_to_value(id));
+                    continue;
+                };
+
+                let pending = claim(group, consumer, id, &claim_opts).expect("entry is pending");
+                propagate.push(claim_command(
+                    key,
+                    group_name,
+                    id,
+                    &pending,
+                    group.last_delivered,
+                ));
+                claimed.push(if justid {
+                    id_to_value(id)
+                } else {
+                    entry_value(id, Some(&fields))
+                });
+            }
+
+            Value::from_iter([
+                id_to_value(next),
+                Value::from(claimed),
+                Value::from(deleted),
+            ])
+        },
+    );
+
+    conn_state.propagate_as = Some(propagate);
+    claimed
 }
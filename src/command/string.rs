@@ -0,0 +1,508 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use super::{
+    args::ArgParser,
+    expire::{absolute_expire, relative_expire, unix_millis},
+};
+use crate::{
+    bytes::Bytes, error::RedisError, resp::Value, ConnectionState, MapValue, MapValueContent, State,
+};
+
+/// The bytes of a string value, or a WRONGTYPE error if it isn't a string
+pub(crate) fn string_value(content: &MapValueContent) -> Result<Vec<u8>, RedisError> {
+    match content {
+        MapValueContent::Integer(n) => Ok(n.to_string().into_bytes()),
+        MapValueContent::String(s) => Ok(s.clone()),
+        MapValueContent::List(_)
+        | MapValueContent::Stream(_)
+        | MapValueContent::SortedSet(_)
+        | MapValueContent::Hash(_)
+        | MapValueContent::Set(_) => Err(RedisError::WrongType),
+    }
+}
+
+/// How `GETEX` should change the expiry of a key
+enum ExpiryChange {
+    Keep,
+    Persist,
+    /// Unix millis
+    At(i64),
+}
+
+impl ExpiryChange {
+    fn parse(args: &[Bytes]) -> Result<Self, RedisError> {
+        let mut args = ArgParser::new(args);
+        let parsed = match args.keyword().as_deref() {
+            None => Self::Keep,
+            Some("PERSIST") => Self::Persist,
+            Some("EX") => Self::At(relative_expire(args.value()?, 1000, "getex")?),
+            Some("PX") => Self::At(relative_expire(args.value()?, 1, "getex")?),
+            Some("EXAT") => Self::At(absolute_expire(args.value()?, 1000, "getex")?),
+            Some("PXAT") => Self::At(absolute_expire(args.value()?, 1, "getex")?),
+            Some(_) => return Err(RedisError::Syntax),
+        };
+        args.finish()?;
+
+        Ok(parsed)
+    }
+}
+
+pub async fn getex(
+    state: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, args @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let change = ExpiryChange::parse(args)?;
+
+    // Nothing changes unless the key exists and holds a string
+    conn_state.propagate_as = Some(Vec::new());
+
+    let Some(mut value) = state.map.get_mut(key) else {
+        return Ok(Value::Null);
+    };
+
+    let ret = Value::bulk_bytes(&string_value(&value.value)?);
+
+    match change {
+        ExpiryChange::Keep => {}
+        ExpiryChange::Persist => {
+            value.expires_at = None;
+            conn_state.propagate_as = Some(vec![Value::from_iter([
+                Value::from("PERSIST"),
+                Value::from(key),
+            ])]);
+        }
+        ExpiryChange::At(at) => {
+            if at <= unix_millis(SystemTime::now()) {
+                drop(value);
+                state.map.remove(key);
+            } else {
+                value.expires_at = Some(UNIX_EPOCH + Duration::from_millis(at as u64));
+            }
+            // `PEXPIREAT` with a time in the past deletes the key on the other side too
+            conn_state.propagate_as = Some(vec![Value::from_iter([
+                Value::from("PEXPIREAT"),
+                Value::from(key),
+                Value::from(at.to_string()),
+            ])]);
+        }
+    }
+
+    Ok(ret)
+}
+
+pub async fn getdel(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let Some(value) = state.map.get(key) else {
+        return Ok(Value::Null);
+    };
+
+    let ret = Value::bulk_bytes(&string_value(&value.value)?);
+    drop(value);
+
+    state.map.remove(key);
+    Ok(ret)
+}
+
+pub async fn append(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, suffix] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let mut value = state.map.get_or_insert_with(key.clone(), || MapValue {
+        value: MapValueContent::String(Vec::new()),
+        expires_at: None,
+    });
+
+    let mut s = string_value(&value.value)?;
+    s.extend_from_slice(suffix);
+
+    let len = s.len();
+    value.value = MapValueContent::String(s);
+    Ok(Value::from(len))
+}
+
+pub async fn strlen(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let Some(value) = state.map.get(key) else {
+        return Ok(Value::from(0));
+    };
+
+    let s = string_value(&value.value)?;
+    Ok(Value::from(s.len()))
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+enum SetCondition {
+    #[default]
+    Always,
+    /// Only set the key if it doesn't exist
+    Nx,
+    /// Only set the key if it already exists
+    Xx,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+enum SetExpiry {
+    /// Clear any existing expiry
+    #[default]
+    None,
+    /// Keep the existing expiry
+    Keep,
+    /// Unix millis
+    At(i64),
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct SetOptions {
+    condition: SetCondition,
+    expiry: SetExpiry,
+    /// Reply with the old value rather than `OK`
+    get: bool,
+}
+
+impl SetOptions {
+    fn parse(args: &[Bytes]) -> Result<Self, RedisError> {
+        let mut opts = Self::default();
+
+        let mut args = ArgParser::new(args);
+        while let Some(arg) = args.keyword() {
+            match (&*arg, opts.condition, opts.expiry) {
+                ("NX", SetCondition::Always | SetCondition::Nx, _) => {
+                    opts.condition = SetCondition::Nx
+                }
+                ("XX", SetCondition::Always | SetCondition::Xx, _) => {
+                    opts.condition = SetCondition::Xx
+                }
+                ("GET", _, _) => opts.get = true,
+                ("KEEPTTL", _, SetExpiry::None | SetExpiry::Keep) => opts.expiry = SetExpiry::Keep,
+                ("EX" | "PX" | "EXAT" | "PXAT", _, SetExpiry::None) => {
+                    opts.expiry = SetExpiry::At(Self::parse_expiry(&arg, args.value()?, "set")?);
+                }
+                _ => return Err(RedisError::Syntax),
+            }
+        }
+
+        Ok(opts)
+    }
+
+    fn parse_expiry(unit: &str, time: &Bytes, command: &str) -> Result<i64, RedisError> {
+        if time.parse::<i64>().is_ok_and(|t| t <= 0) {
+            return Err(RedisError::custom(format!(
+                "ERR invalid expire time in '{command}' command"
+            )));
+        }
+
+        match unit {
+            "EX" => relative_expire(time, 1000, command),
+            "PX" => relative_expire(time, 1, command),
+            "EXAT" => absolute_expire(time, 1000, command),
+            "PXAT" => absolute_expire(time, 1, command),
+            _ => unreachable!("only called with expiry options"),
+        }
+    }
+}
+
+/// Set `key` to `value` following `opts`, returning the reply for `SET`.  The write is
+/// propagated as a `SET` with an absolute expiry, so replicas and the AOF agree on when the key
+/// expires.
+fn set_with_options(
+    state: &State,
+    conn_state: &mut ConnectionState,
+    key: &Bytes,
+    value: &Bytes,
+    opts: SetOptions,
+) -> Result<Value, RedisError> {
+    conn_state.propagate_as = Some(Vec::new());
+
+    let mut entry = state.map.get_mut(key);
+
+    let old = match entry.as_ref().map(|v| string_value(&v.value)) {
+        Some(Ok(s)) => Some(s),
+        Some(Err(e)) if opts.get => return Err(e),
+        Some(Err(_)) => None,
+        None => None,
+    };
+
+    let exists = entry.is_some();
+    let should_set = match opts.condition {
+        SetCondition::Always => true,
+        SetCondition::Nx => !exists,
+        SetCondition::Xx => exists,
+    };
+
+    let reply = if opts.get {
+        old.map(|old| Value::bulk_bytes(&old)).unwrap_or_default()
+    } else if should_set {
+        Value::bulk_string("OK")
+    } else {
+        Value::Null
+    };
+
+    if !should_set {
+        return Ok(reply);
+    }
+
+    let expires_at = match opts.expiry {
+        SetExpiry::None => None,
+        SetExpiry::Keep => entry.as_ref().and_then(|v| v.expires_at),
+        SetExpiry::At(at) => Some(UNIX_EPOCH + Duration::from_millis(at.max(0) as u64)),
+    };
+
+    let new = MapValue {
+        value: MapValueContent::from(value.as_bytes()),
+        expires_at,
+    };
+    match entry {
+        Some(ref mut entry) => **entry = new,
+        None => {
+            drop(entry);
+            state.map.insert(key.clone(), new);
+        }
+    }
+
+    let mut propagated = vec![Value::from("SET"), Value::from(key), Value::from(value)];
+    match opts.expiry {
+        SetExpiry::None => {}
+        SetExpiry::Keep => propagated.push(Value::from("KEEPTTL")),
+        SetExpiry::At(at) => {
+            propagated.push(Value::from("PXAT"));
+            propagated.push(Value::from(at.to_string()));
+        }
+    }
+    conn_state.propagate_as = Some(vec![Value::from(propagated)]);
+
+    Ok(reply)
+}
+
+pub async fn set(
+    state: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, value, args @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let opts = SetOptions::parse(args)?;
+    set_with_options(&state, conn_state, key, value, opts)
+}
+
+pub async fn setnx(
+    state: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, value] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let opts = SetOptions {
+        condition: SetCondition::Nx,
+        ..Default::default()
+    };
+    let was_set = !matches!(
+        set_with_options(&state, conn_state, key, value, opts)?,
+        Value::Null
+    );
+    Ok(Value::from(was_set as i64))
+}
+
+/// `SETEX`/`PSETEX`, with the expiry given in `unit` (`EX` or `PX`)
+fn set_expiring(
+    state: &State,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+    unit: &str,
+    command: &str,
+) -> Result<Value, RedisError> {
+    let [key, time, value] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let opts = SetOptions {
+        expiry: SetExpiry::At(SetOptions::parse_expiry(unit, time, command)?),
+        ..Default::default()
+    };
+    set_with_options(state, conn_state, key, value, opts)
+}
+
+pub async fn setex(
+    state: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    set_expiring(&state, conn_state, args, "EX", "setex")
+}
+
+pub async fn psetex(
+    state: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    set_expiring(&state, conn_state, args, "PX", "psetex")
+}
+
+pub async fn getset(
+    state: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, value] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let opts = SetOptions {
+        get: true,
+        ..Default::default()
+    };
+    set_with_options(&state, conn_state, key, value, opts)
+}
+
+pub async fn lcs(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key_a, key_b, args @ ..] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let mut get_len = false;
+    let mut get_idx = false;
+    let mut with_match_len = false;
+    let mut min_match_len = 0;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match &*arg.to_uppercase() {
+            "LEN" => get_len = true,
+            "IDX" => get_idx = true,
+            "WITHMATCHLEN" => with_match_len = true,
+            "MINMATCHLEN" => {
+                let Some(len) = args.next() else {
+                    return Err(RedisError::Syntax);
+                };
+                let Ok(len) = len.parse::<i64>() else {
+                    return Err(RedisError::NotAnInteger);
+                };
+                min_match_len = len.max(0) as usize;
+            }
+            _ => return Err(RedisError::Syntax),
+        }
+    }
+
+    if get_len && get_idx {
+        return Err(RedisError::custom(
+            "ERR If you want both the length and indexes, please just use IDX.",
+        ));
+    }
+
+    let lookup = |key: &Bytes| match state.map.get(key) {
+        Some(value) => string_value(&value.value)
+            .map_err(|_| RedisError::custom("ERR The specified keys must contain string values")),
+        None => Ok(Vec::new()),
+    };
+    let (a, b) = match (lookup(key_a), lookup(key_b)) {
+        (Ok(a), Ok(b)) => (a, b),
+        (Err(e), _) | (_, Err(e)) => return Err(e),
+    };
+
+    // table[i][j] is the length of the LCS of a[..i] and b[..j]
+    let width = b.len() + 1;
+    let mut table = vec![0u32; (a.len() + 1) * width];
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            table[i * width + j] = if a[i - 1] == b[j - 1] {
+                table[(i - 1) * width + j - 1] + 1
+            } else {
+                table[(i - 1) * width + j].max(table[i * width + j - 1])
+            };
+        }
+    }
+    let len = table[a.len() * width + b.len()] as usize;
+
+    if get_len {
+        return Ok(Value::from(len));
+    }
+
+    // Walk back through the table to recover the subsequence, collecting the ranges of
+    // contiguous matches (from the end of the strings to the start) as we go
+    let mut result = vec![0u8; len];
+    let mut matches = Vec::new();
+    let mut range: Option<((usize, usize), (usize, usize))> = None;
+    let (mut i, mut j, mut idx) = (a.len(), b.len(), len);
+    while i > 0 && j > 0 {
+        let emit = if a[i - 1] == b[j - 1] {
+            result[idx - 1] = a[i - 1];
+            range = match range {
+                Some(((_, a_end), (_, b_end))) => Some(((i - 1, a_end), (j - 1, b_end))),
+                None => Some(((i - 1, i - 1), (j - 1, j - 1))),
+            };
+            idx -= 1;
+            i -= 1;
+            j -= 1;
+            // We've reached the start of one of the strings, so this range can't grow
+            i == 0 || j == 0
+        } else {
+            if table[(i - 1) * width + j] > table[i * width + j - 1] {
+                i -= 1;
+            } else {
+                j -= 1;
+            }
+            range.is_some()
+        };
+
+        if emit {
+            if let Some(((a_start, a_end), (b_start, b_end))) = range.take() {
+                let match_len = a_end - a_start + 1;
+                if match_len >= min_match_len {
+                    let mut m = vec![
+                        Value::from_iter([Value::from(a_start), Value::from(a_end)]),
+                        Value::from_iter([Value::from(b_start), Value::from(b_end)]),
+                    ];
+                    if with_match_len {
+                        m.push(Value::from(match_len));
+                    }
+                    matches.push(Value::from(m));
+                }
+            }
+        }
+    }
+
+    if get_idx {
+        return Ok(Value::from_iter([
+            Value::from("matches"),
+            Value::from(matches),
+            Value::from("len"),
+            Value::from(len),
+        ]));
+    }
+
+    Ok(Value::from(result))
+}
@@ -1,41 +1,206 @@
 use std::sync::Arc;
 
-use anyhow::bail;
+use crate::{
+    bytes::Bytes, error::RedisError, resp::Value, ConnectionState, MapValue, MapValueContent, State,
+};
 
-use crate::{resp::Value, ConnectionState, MapValue, MapValueContent, State};
+use super::{Command, ExecContext};
+
+/// Add `delta` to the integer stored at `key`, creating it as `0` if it doesn't exist
+fn incr_by(state: &State, key: &Bytes, delta: i64) -> Result<Value, RedisError> {
+    let mut value = state.map.get_or_insert_with(key.clone(), || MapValue {
+        value: MapValueContent::Integer(0),
+        expires_at: None,
+    });
+
+    let current = match value.value {
+        MapValueContent::Integer(n) => n,
+        MapValueContent::String(ref s) => match std::str::from_utf8(s).map(str::parse) {
+            Ok(Ok(n)) => n,
+            _ => return Err(RedisError::NotAnInteger),
+        },
+        MapValueContent::List(_)
+        | MapValueContent::Stream(_)
+        | MapValueContent::SortedSet(_)
+        | MapValueContent::Hash(_)
+        | MapValueContent::Set(_) => return Err(RedisError::WrongType),
+    };
+
+    let Some(new) = current.checked_add(delta) else {
+        return Err(RedisError::custom(
+            "ERR increment or decrement would overflow",
+        ));
+    };
+
+    value.value = MapValueContent::Integer(new);
+    Ok(Value::from(new))
+}
+
+fn parse_delta(delta: &Bytes) -> Result<i64, RedisError> {
+    delta.parse().map_err(|_| RedisError::NotAnInteger)
+}
 
 pub async fn incr(
     state: Arc<State>,
     _: &mut ConnectionState,
-    args: &[String],
-) -> anyhow::Result<Value> {
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
     let [key, ..] = args else {
-        bail!("TODO: args.len() < 1");
-    };
-
-    let value = if let Some(mut x) = state.map.get_mut(key) {
-        match x.value {
-            MapValueContent::Integer(ref mut val) => {
-                *val += 1;
-                Value::from(*val)
-            }
-            MapValueContent::String(_)
-            | MapValueContent::List(_)
-            | MapValueContent::Stream(_)
-            | MapValueContent::SortedSet(_) => {
-                Value::simple_error("ERR value is not an integer or out of range")
-            }
+        return Err(RedisError::WrongArity);
+    };
+
+    incr_by(&state, key, 1)
+}
+
+pub async fn incrby(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, delta] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    let delta = parse_delta(delta)?;
+    incr_by(&state, key, delta)
+}
+
+pub async fn decr(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    incr_by(&state, key, -1)
+}
+
+pub async fn decrby(
+    state: Arc<State>,
+    _: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    let [key, delta] = args else {
+        return Err(RedisError::WrongArity);
+    };
+
+    match parse_delta(delta)?.checked_neg() {
+        Some(delta) => incr_by(&state, key, delta),
+        None => Err(RedisError::custom("ERR decrement would overflow")),
+    }
+}
+
+pub async fn multi(
+    _: Arc<State>,
+    conn_state: &mut ConnectionState,
+    _: &[Bytes],
+) -> Result<Value, RedisError> {
+    if conn_state.txn.is_some() {
+        return Err(RedisError::custom("ERR MULTI calls can not be nested"));
+    }
+
+    conn_state.txn = Some(Vec::new());
+    conn_state.txn_failed = false;
+    Ok(Value::simple_string("OK"))
+}
+
+pub async fn exec(
+    state: Arc<State>,
+    conn_state: &mut ConnectionState,
+    _: &[Bytes],
+) -> Result<Value, RedisError> {
+    let Some(queued) = conn_state.txn.take() else {
+        return Err(RedisError::custom("ERR EXEC without MULTI"));
+    };
+    if std::mem::take(&mut conn_state.txn_failed) {
+        conn_state.unwatch_all();
+        return Err(RedisError::custom(
+            "EXECABORT Transaction discarded because of previous errors.",
+        ));
+    }
+
+    // If anything being watched has changed, the transaction is aborted
+    let changed = conn_state
+        .watching
+        .iter()
+        .any(|(key, &version)| state.map.watched_changed(key, version));
+    conn_state.unwatch_all();
+    if changed {
+        return Ok(Value::Null);
+    }
+
+    // The AOF lock is held throughout, so that a rewrite can't see only part of the transaction
+    let aof = match state.aof {
+        Some(ref aof) => Some(aof.lock().await),
+        None => None,
+    };
+
+    let mut ret = Vec::with_capacity(queued.len());
+    for cmd in queued {
+        // A command failing doesn't stop the rest of the transaction, it just gets an error in
+        // its place in the reply
+        let reply = match conn_state.run_command(&cmd, ExecContext::Transaction).await {
+            Ok(reply) => reply.unwrap_or_default(),
+            Err(err) => RedisError::from(err).into(),
+        };
+        ret.push(reply);
+    }
+
+    // Wrapped in `MULTI` and `EXEC`, so that replicas apply the whole transaction at once
+    let writes = std::mem::take(&mut conn_state.txn_writes);
+    if !writes.is_empty() {
+        let writes = std::iter::once(Command::Multi.into_command_value(&[]))
+            .chain(writes)
+            .chain(std::iter::once(Command::Exec.into_command_value(&[])))
+            .collect();
+        state.propagate_all(aof, writes).await?;
+    }
+
+    Ok(Value::from(ret))
+}
+
+pub async fn discard(
+    _: Arc<State>,
+    conn_state: &mut ConnectionState,
+    _: &[Bytes],
+) -> Result<Value, RedisError> {
+    if conn_state.txn.take().is_none() {
+        return Err(RedisError::custom("ERR DISCARD without MULTI"));
+    }
+
+    conn_state.unwatch_all();
+    Ok(Value::simple_string("OK"))
+}
+
+pub async fn watch(
+    state: Arc<State>,
+    conn_state: &mut ConnectionState,
+    args: &[Bytes],
+) -> Result<Value, RedisError> {
+    if args.is_empty() {
+        return Err(RedisError::WrongArity);
+    }
+    if conn_state.txn.is_some() {
+        return Err(RedisError::custom("ERR WATCH inside MULTI is not allowed"));
+    }
+
+    for key in args {
+        if !conn_state.watching.contains_key(key) {
+            let version = state.map.watch(key);
+            conn_state.watching.insert(key.clone(), version);
         }
-    } else {
-        state.map.insert(
-            key.clone(),
-            MapValue {
-                value: MapValueContent::Integer(1),
-                expires_at: None,
-            },
-        );
-        Value::from(1)
-    };
-
-    Ok(value)
+    }
+
+    Ok(Value::simple_string("OK"))
+}
+
+pub async fn unwatch(
+    _: Arc<State>,
+    conn_state: &mut ConnectionState,
+    _: &[Bytes],
+) -> Result<Value, RedisError> {
+    conn_state.unwatch_all();
+    Ok(Value::simple_string("OK"))
 }
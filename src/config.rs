@@ -0,0 +1,768 @@
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{bail, Context};
+
+use crate::{aof::FsyncPolicy, command::Command, log::LogLevel, systemd::Supervised, tls, State};
+
+/// Save the RDB file once there have been at least `changes` writes in `secs` seconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SavePoint {
+    pub secs: u64,
+    pub changes: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub port: u16,
+    pub replicaof: Option<String>,
+    pub dir: Option<PathBuf>,
+    pub db_filename: Option<String>,
+    /// When the RDB file is saved automatically, which is never unless set
+    pub save: Vec<SavePoint>,
+
+    pub min_replicas_to_write: usize,
+    pub min_replicas_max_lag: u64,
+    pub replica_read_only: bool,
+
+    pub appendonly: bool,
+    pub appenddirname: String,
+    pub appendfilename: String,
+    pub appendfsync: FsyncPolicy,
+
+    pub cluster_enabled: bool,
+
+    pub list_max_listpack_size: i64,
+
+    /// The longest bulk string a client may send
+    pub proto_max_bulk_len: usize,
+    /// The most elements an aggregate sent by a client may have
+    pub proto_max_multibulk_len: usize,
+
+    /// Accepted for compatibility, but nothing is evicted to stay under it
+    pub maxmemory: u64,
+    /// One of [`MAXMEMORY_POLICIES`], which isn't acted on either
+    pub maxmemory_policy: String,
+
+    /// Whether `DEL` frees large values in the background, like `UNLINK`
+    pub lazyfree_lazy_user_del: bool,
+    /// Whether expired keys' values are freed in the background
+    pub lazyfree_lazy_expire: bool,
+
+    pub daemonize: bool,
+    pub pidfile: Option<PathBuf>,
+    /// Whether to let systemd know when we're ready and stopping
+    pub supervised: Supervised,
+
+    /// Close clients which have been idle for this many seconds, unless 0
+    pub timeout: u64,
+    /// Send TCP keepalives after a connection has been quiet for this many seconds, unless 0
+    pub tcp_keepalive: u64,
+    /// Whether to send small writes straight away, rather than waiting to batch them up
+    pub tcp_nodelay: bool,
+
+    /// The password clients must `AUTH` with as the default user before running commands
+    pub requirepass: Option<String>,
+    /// Where users are loaded from at startup, and by `ACL LOAD` and `ACL SAVE`
+    pub aclfile: Option<PathBuf>,
+    /// Commands given another name by `rename-command`, or disabled if it's `None`
+    pub rename_commands: Vec<(Command, Option<String>)>,
+
+    /// Whether the raw RESP sent and received on every connection is logged, which can be
+    /// changed with `CONFIG SET`
+    pub protocol_trace: bool,
+
+    pub loglevel: LogLevel,
+    /// Where logs are written instead of stderr, which can be changed with `CONFIG SET`
+    pub logfile: Option<PathBuf>,
+    /// Rotate the log file once it's this many bytes, unless 0
+    pub logfile_max_size: u64,
+    /// Rotate the log file once it's been written to for this many seconds, unless 0
+    pub logfile_rotate_interval: u64,
+
+    /// The port to accept TLS connections on, unless 0
+    pub tls_port: u16,
+    pub tls_cert_file: Option<PathBuf>,
+    pub tls_key_file: Option<PathBuf>,
+    /// The CA that clients' and our master's certificates are checked against
+    pub tls_ca_cert_file: Option<PathBuf>,
+    pub tls_auth_clients: tls::AuthClients,
+    /// Whether we connect to our master over TLS
+    pub tls_replication: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            port: 6379,
+            replicaof: None,
+            dir: None,
+            db_filename: None,
+            save: Vec::new(),
+            min_replicas_to_write: 0,
+            min_replicas_max_lag: 10,
+            replica_read_only: true,
+            appendonly: false,
+            appenddirname: "appendonlydir".into(),
+            appendfilename: "appendonly.aof".into(),
+            appendfsync: FsyncPolicy::EverySec,
+            cluster_enabled: false,
+            list_max_listpack_size: -2,
+            proto_max_bulk_len: 512 * 1024 * 1024,
+            proto_max_multibulk_len: i32::MAX as usize,
+            maxmemory: 0,
+            maxmemory_policy: "noeviction".into(),
+            lazyfree_lazy_user_del: false,
+            lazyfree_lazy_expire: false,
+            daemonize: false,
+            pidfile: None,
+            supervised: Supervised::No,
+            timeout: 0,
+            tcp_keepalive: 300,
+            tcp_nodelay: true,
+            requirepass: None,
+            aclfile: None,
+            rename_commands: Vec::new(),
+            protocol_trace: false,
+            loglevel: LogLevel::Notice,
+            logfile: None,
+            logfile_max_size: 0,
+            logfile_rotate_interval: 0,
+            tls_port: 0,
+            tls_cert_file: None,
+            tls_key_file: None,
+            tls_ca_cert_file: None,
+            tls_auth_clients: tls::AuthClients::Yes,
+            tls_replication: false,
+        }
+    }
+}
+
+impl Config {
+    /// Where the RDB file lives, which is `dump.rdb` in the working directory unless configured
+    pub fn rdb_path(&self) -> PathBuf {
+        self.dir
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("."))
+            .join(self.db_filename.as_deref().unwrap_or("dump.rdb"))
+    }
+}
+
+/// A parameter which `CONFIG GET` and `CONFIG SET` know by name
+pub struct Param {
+    pub name: &'static str,
+    /// The value as `CONFIG GET` shows it
+    pub get: fn(&Config) -> String,
+    /// Parse a value into the config, or explain why it's no good
+    pub set: fn(&mut Config, &str) -> Result<(), String>,
+    /// Whether `CONFIG SET` can change it, rather than it only being set on startup
+    pub mutable: bool,
+    /// Puts a change into effect outside of the config itself, once it's been set
+    pub apply: Option<fn(&State, &Config) -> anyhow::Result<()>>,
+}
+
+impl Param {
+    const fn new(
+        name: &'static str,
+        get: fn(&Config) -> String,
+        set: fn(&mut Config, &str) -> Result<(), String>,
+    ) -> Self {
+        Self {
+            name,
+            get,
+            set,
+            mutable: true,
+            apply: None,
+        }
+    }
+
+    const fn immutable(self) -> Self {
+        Self {
+            mutable: false,
+            ..self
+        }
+    }
+
+    const fn on_change(self, apply: fn(&State, &Config) -> anyhow::Result<()>) -> Self {
+        Self {
+            apply: Some(apply),
+            ..self
+        }
+    }
+}
+
+impl std::fmt::Debug for Param {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Param")
+            .field("name", &self.name)
+            .field("mutable", &self.mutable)
+            .finish_non_exhaustive()
+    }
+}
+
+fn yes_no(value: &str) -> Result<bool, String> {
+    match &*value.to_lowercase() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err("argument must be 'yes' or 'no'".into()),
+    }
+}
+
+fn show_yes_no(value: bool) -> String {
+    if value { "yes" } else { "no" }.into()
+}
+
+fn int<T: FromStr>(value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| "argument couldn't be parsed into an integer".into())
+}
+
+/// An empty value, which unsets optional parameters like `requirepass`
+fn non_empty(value: &str) -> Option<String> {
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+fn show_path(path: &Option<PathBuf>) -> String {
+    path.as_deref()
+        .map(|p| p.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// The values `maxmemory-policy` can be set to
+const MAXMEMORY_POLICIES: &[&str] = &[
+    "volatile-lru",
+    "volatile-lfu",
+    "volatile-random",
+    "volatile-ttl",
+    "allkeys-lru",
+    "allkeys-lfu",
+    "allkeys-random",
+    "noeviction",
+];
+
+/// Every parameter, in the order `CONFIG GET *` lists them
+pub static PARAMS: &[Param] = &[
+    Param::new(
+        "port",
+        |c| c.port.to_string(),
+        |c, v| {
+            c.port = int(v)?;
+            Ok(())
+        },
+    )
+    .immutable(),
+    // Kept as `host:port`, but shown like it's given, as `host port`
+    Param::new(
+        "replicaof",
+        |c| {
+            c.replicaof
+                .as_deref()
+                .unwrap_or_default()
+                .replacen(':', " ", 1)
+        },
+        |c, v| {
+            let (host, port) = v
+                .split_once(' ')
+                .ok_or("argument must be '<host> <port>'")?;
+            c.replicaof = Some(format!("{host}:{port}"));
+            Ok(())
+        },
+    )
+    .immutable(),
+    Param::new(
+        "dir",
+        |c| {
+            c.dir
+                .as_deref()
+                .unwrap_or(Path::new("."))
+                .to_string_lossy()
+                .into_owned()
+        },
+        |c, v| {
+            let dir = PathBuf::from(v);
+            if !dir.is_dir() {
+                return Err("No such file or directory".into());
+            }
+            c.dir = Some(dir);
+            Ok(())
+        },
+    ),
+    Param::new(
+        "dbfilename",
+        |c| c.db_filename.as_deref().unwrap_or("dump.rdb").into(),
+        |c, v| {
+            if v.contains('/') {
+                return Err("dbfilename can't be a path, just a filename".into());
+            }
+            c.db_filename = Some(v.into());
+            Ok(())
+        },
+    ),
+    // Given as pairs of seconds and changes, e.g. `3600 1 300 100`
+    Param::new(
+        "save",
+        |c| {
+            c.save
+                .iter()
+                .map(|point| format!("{} {}", point.secs, point.changes))
+                .collect::<Vec<_>>()
+                .join(" ")
+        },
+        |c, v| {
+            let numbers = v
+                .split_whitespace()
+                .map(int)
+                .collect::<Result<Vec<u64>, _>>()?;
+            if numbers.len() % 2 != 0 {
+                return Err("Invalid save parameters".into());
+            }
+            c.save = numbers
+                .chunks_exact(2)
+                .map(|pair| SavePoint {
+                    secs: pair[0],
+                    changes: pair[1],
+                })
+                .collect();
+            Ok(())
+        },
+    ),
+    Param::new(
+        "min-replicas-to-write",
+        |c| c.min_replicas_to_write.to_string(),
+        |c, v| {
+            c.min_replicas_to_write = int(v)?;
+            Ok(())
+        },
+    ),
+    Param::new(
+        "min-replicas-max-lag",
+        |c| c.min_replicas_max_lag.to_string(),
+        |c, v| {
+            c.min_replicas_max_lag = int(v)?;
+            Ok(())
+        },
+    ),
+    Param::new(
+        "replica-read-only",
+        |c| show_yes_no(c.replica_read_only),
+        |c, v| {
+            c.replica_read_only = yes_no(v)?;
+            Ok(())
+        },
+    ),
+    // The AOF is only opened on startup, so it can't be turned on or off while running
+    Param::new(
+        "appendonly",
+        |c| show_yes_no(c.appendonly),
+        |c, v| {
+            c.appendonly = yes_no(v)?;
+            Ok(())
+        },
+    )
+    .immutable(),
+    Param::new(
+        "appenddirname",
+        |c| c.appenddirname.clone(),
+        |c, v| {
+            c.appenddirname = v.into();
+            Ok(())
+        },
+    )
+    .immutable(),
+    Param::new(
+        "appendfilename",
+        |c| c.appendfilename.clone(),
+        |c, v| {
+            c.appendfilename = v.into();
+            Ok(())
+        },
+    )
+    .immutable(),
+    Param::new(
+        "appendfsync",
+        |c| c.appendfsync.to_string(),
+        |c, v| {
+            c.appendfsync = v.parse().map_err(|e: anyhow::Error| e.to_string())?;
+            Ok(())
+        },
+    )
+    .immutable(),
+    Param::new(
+        "cluster-enabled",
+        |c| show_yes_no(c.cluster_enabled),
+        |c, v| {
+            c.cluster_enabled = yes_no(v)?;
+            Ok(())
+        },
+    )
+    .immutable(),
+    Param::new(
+        "list-max-listpack-size",
+        |c| c.list_max_listpack_size.to_string(),
+        |c, v| {
+            c.list_max_listpack_size = int(v)?;
+            Ok(())
+        },
+    ),
+    Param::new(
+        "proto-max-bulk-len",
+        |c| c.proto_max_bulk_len.to_string(),
+        |c, v| match int(v)? {
+            0 => Err("argument must be greater than 0".into()),
+            len => {
+                c.proto_max_bulk_len = len;
+                Ok(())
+            }
+        },
+    ),
+    Param::new(
+        "proto-max-multibulk-len",
+        |c| c.proto_max_multibulk_len.to_string(),
+        |c, v| match int(v)? {
+            0 => Err("argument must be greater than 0".into()),
+            len => {
+                c.proto_max_multibulk_len = len;
+                Ok(())
+            }
+        },
+    ),
+    Param::new(
+        "maxmemory",
+        |c| c.maxmemory.to_string(),
+        |c, v| {
+            c.maxmemory = int(v)?;
+            Ok(())
+        },
+    ),
+    Param::new(
+        "maxmemory-policy",
+        |c| c.maxmemory_policy.clone(),
+        |c, v| {
+            let v = v.to_lowercase();
+            if !MAXMEMORY_POLICIES.contains(&&*v) {
+                return Err("argument(s) must be one of the following: ".to_string()
+                    + &MAXMEMORY_POLICIES.join(", "));
+            }
+            c.maxmemory_policy = v;
+            Ok(())
+        },
+    ),
+    Param::new(
+        "lazyfree-lazy-user-del",
+        |c| show_yes_no(c.lazyfree_lazy_user_del),
+        |c, v| {
+            c.lazyfree_lazy_user_del = yes_no(v)?;
+            Ok(())
+        },
+    ),
+    Param::new(
+        "lazyfree-lazy-expire",
+        |c| show_yes_no(c.lazyfree_lazy_expire),
+        |c, v| {
+            c.lazyfree_lazy_expire = yes_no(v)?;
+            Ok(())
+        },
+    )
+    .on_change(|state, c| {
+        state.map.set_lazy_expire(c.lazyfree_lazy_expire);
+        Ok(())
+    }),
+    Param::new(
+        "daemonize",
+        |c| show_yes_no(c.daemonize),
+        |c, v| {
+            c.daemonize = yes_no(v)?;
+            Ok(())
+        },
+    )
+    .immutable(),
+    Param::new(
+        "pidfile",
+        |c| show_path(&c.pidfile),
+        |c, v| {
+            c.pidfile = non_empty(v).map(PathBuf::from);
+            Ok(())
+        },
+    )
+    .immutable(),
+    Param::new(
+        "supervised",
+        |c| c.supervised.to_string(),
+        |c, v| {
+            c.supervised = v.parse().map_err(|e: anyhow::Error| e.to_string())?;
+            Ok(())
+        },
+    )
+    .immutable(),
+    Param::new(
+        "timeout",
+        |c| c.timeout.to_string(),
+        |c, v| {
+            c.timeout = int(v)?;
+            Ok(())
+        },
+    ),
+    // These two only apply to new connections
+    Param::new(
+        "tcp-keepalive",
+        |c| c.tcp_keepalive.to_string(),
+        |c, v| {
+            c.tcp_keepalive = int(v)?;
+            Ok(())
+        },
+    ),
+    Param::new(
+        "tcp-nodelay",
+        |c| show_yes_no(c.tcp_nodelay),
+        |c, v| {
+            c.tcp_nodelay = yes_no(v)?;
+            Ok(())
+        },
+    ),
+    Param::new(
+        "requirepass",
+        |c| c.requirepass.clone().unwrap_or_default(),
+        |c, v| {
+            c.requirepass = non_empty(v);
+            Ok(())
+        },
+    )
+    .on_change(|state, c| {
+        state.acl.set_requirepass(c.requirepass.as_deref());
+        Ok(())
+    }),
+    Param::new(
+        "aclfile",
+        |c| show_path(&c.aclfile),
+        |c, v| {
+            c.aclfile = non_empty(v).map(PathBuf::from);
+            Ok(())
+        },
+    )
+    .immutable(),
+    Param::new(
+        "protocol-trace",
+        |c| show_yes_no(c.protocol_trace),
+        |c, v| {
+            c.protocol_trace = yes_no(v)?;
+            Ok(())
+        },
+    ),
+    Param::new(
+        "loglevel",
+        |c| c.loglevel.to_string(),
+        |c, v| {
+            c.loglevel = v.parse().map_err(|e: anyhow::Error| e.to_string())?;
+            Ok(())
+        },
+    )
+    .on_change(|state, c| state.log.set_level(c.loglevel)),
+    // Reopens the file even if it's unchanged, for after it has been rotated
+    Param::new(
+        "logfile",
+        |c| {
+            c.logfile
+                .as_deref()
+                .map(|p| p.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        },
+        |c, v| {
+            c.logfile = non_empty(v).map(PathBuf::from);
+            Ok(())
+        },
+    )
+    .on_change(|state, c| Ok(state.log.reopen(c.logfile.as_deref())?)),
+    Param::new(
+        "logfile-max-size",
+        |c| c.logfile_max_size.to_string(),
+        |c, v| {
+            c.logfile_max_size = int(v)?;
+            Ok(())
+        },
+    )
+    .on_change(|state, c| {
+        state.log.set_rotation(c);
+        Ok(())
+    }),
+    Param::new(
+        "logfile-rotate-interval",
+        |c| c.logfile_rotate_interval.to_string(),
+        |c, v| {
+            c.logfile_rotate_interval = int(v)?;
+            Ok(())
+        },
+    )
+    .on_change(|state, c| {
+        state.log.set_rotation(c);
+        Ok(())
+    }),
+    Param::new(
+        "tls-port",
+        |c| c.tls_port.to_string(),
+        |c, v| {
+            c.tls_port = int(v)?;
+            Ok(())
+        },
+    )
+    .immutable(),
+    Param::new(
+        "tls-cert-file",
+        |c| show_path(&c.tls_cert_file),
+        |c, v| {
+            c.tls_cert_file = non_empty(v).map(PathBuf::from);
+            Ok(())
+        },
+    )
+    .immutable(),
+    Param::new(
+        "tls-key-file",
+        |c| show_path(&c.tls_key_file),
+        |c, v| {
+            c.tls_key_file = non_empty(v).map(PathBuf::from);
+            Ok(())
+        },
+    )
+    .immutable(),
+    Param::new(
+        "tls-ca-cert-file",
+        |c| show_path(&c.tls_ca_cert_file),
+        |c, v| {
+            c.tls_ca_cert_file = non_empty(v).map(PathBuf::from);
+            Ok(())
+        },
+    )
+    .immutable(),
+    Param::new(
+        "tls-auth-clients",
+        |c| c.tls_auth_clients.to_string(),
+        |c, v| {
+            c.tls_auth_clients = v.parse().map_err(|e: anyhow::Error| e.to_string())?;
+            Ok(())
+        },
+    )
+    .immutable(),
+    Param::new(
+        "tls-replication",
+        |c| show_yes_no(c.tls_replication),
+        |c, v| {
+            c.tls_replication = yes_no(v)?;
+            Ok(())
+        },
+    )
+    .immutable(),
+];
+
+/// The parameter called `name`, ignoring case
+pub fn param(name: &str) -> Option<&'static Param> {
+    PARAMS
+        .iter()
+        .find(|param| param.name.eq_ignore_ascii_case(name))
+}
+
+impl Config {
+    /// The config given on the command line, like `redis-server`'s: an optional config file,
+    /// followed by `--<parameter> <value>` options which override it.  Like Redis, a value can
+    /// be split over several arguments, e.g. `--replicaof 127.0.0.1 6379` or `--save 60 1`.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> anyhow::Result<Self> {
+        let mut args = args.into_iter().peekable();
+        let mut config = Self::default();
+
+        if let Some(path) = args.next_if(|arg| !arg.starts_with('-')) {
+            config
+                .load_file(Path::new(&path))
+                .with_context(|| format!("loading config file {path}"))?;
+        }
+
+        while let Some(arg) = args.next() {
+            let name = match &*arg {
+                // Not a `redis-server` option, but we've always taken it
+                "-p" => "port",
+                _ => match arg.strip_prefix("--") {
+                    Some(name) => name,
+                    None => bail!("unexpected argument '{arg}'"),
+                },
+            };
+            let mut value = Vec::new();
+            while let Some(part) = args.next_if(|arg| !arg.starts_with("--") || arg == "--") {
+                value.push(part);
+            }
+            if value.is_empty() {
+                bail!("missing value for '{arg}'");
+            }
+            config.set(name, &value.join(" "))?;
+        }
+
+        Ok(config)
+    }
+
+    /// Apply a config file, where each line is a parameter followed by its value
+    fn load_file(&mut self, path: &Path) -> anyhow::Result<()> {
+        let contents = std::fs::read_to_string(path)?;
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let value = value.trim();
+            // Values may be quoted, e.g. `save ""`
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(value);
+            self.set(name, value)
+                .with_context(|| format!("on line {}", i + 1))?;
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, name: &str, value: &str) -> anyhow::Result<()> {
+        // Not a parameter, since it can only be given on startup, and `CONFIG GET` would give
+        // away the names of renamed commands
+        if name.eq_ignore_ascii_case("rename-command") {
+            return self.rename_command(value);
+        }
+        let Some(param) = param(name) else {
+            bail!("unknown parameter '{name}'");
+        };
+        (param.set)(self, value).map_err(|reason| anyhow::anyhow!("invalid {name}: {reason}"))
+    }
+
+    /// Apply `rename-command <command> <name>`, where an empty name (e.g. `""`) disables the
+    /// command.  Like Redis, a command's old name is gone once it's renamed, so it can't be
+    /// renamed again, and it can't be renamed to a name which is still in use.
+    fn rename_command(&mut self, value: &str) -> anyhow::Result<()> {
+        let words: Vec<&str> = value
+            .split_whitespace()
+            .map(|word| word.trim_matches('"'))
+            .collect();
+        let (old, new) = match *words.as_slice() {
+            [old] => (old, ""),
+            [old, new] => (old, new),
+            _ => bail!("rename-command needs a command and its new name"),
+        };
+
+        let renamed = |command: Command| self.rename_commands.iter().any(|(c, _)| *c == command);
+        let command = match old.to_uppercase().parse::<Command>() {
+            Ok(command) if !renamed(command) => command,
+            _ => bail!("no such command '{old}' to rename"),
+        };
+        let in_use = self.rename_commands.iter().any(|(_, name)| {
+            name.as_deref()
+                .is_some_and(|name| name.eq_ignore_ascii_case(new))
+        }) || new
+            .to_uppercase()
+            .parse::<Command>()
+            .is_ok_and(|other| other != command && !renamed(other));
+        if in_use {
+            bail!("can't rename '{old}' to '{new}', which is already a command");
+        }
+
+        self.rename_commands
+            .push((command, (!new.is_empty()).then(|| new.to_string())));
+        Ok(())
+    }
+}
@@ -0,0 +1,62 @@
+//! The errors which commands reply with, kept apart from those which drop the connection.
+
+use std::fmt::Display;
+
+use crate::resp::Value;
+
+#[derive(Debug)]
+pub enum RedisError {
+    WrongType,
+    NotAnInteger,
+    NotAFloat,
+    Syntax,
+    NoSuchKey,
+    OutOfRange,
+    /// The command was given the wrong number of arguments, which is named in the reply by
+    /// [`Command::execute`](crate::command::Command::execute)
+    WrongArity,
+    /// Any other error, as the whole line the client gets, e.g. `ERR ...` or `NOPROTO ...`
+    Custom(String),
+    /// Something which isn't the client's fault, which closes the connection rather than being
+    /// replied with
+    Internal(anyhow::Error),
+}
+
+impl RedisError {
+    pub fn custom(message: impl Into<String>) -> Self {
+        Self::Custom(message.into())
+    }
+}
+
+impl Display for RedisError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::WrongType => {
+                write!(
+                    f,
+                    "WRONGTYPE Operation against a key holding the wrong kind of value"
+                )
+            }
+            Self::NotAnInteger => write!(f, "ERR value is not an integer or out of range"),
+            Self::NotAFloat => write!(f, "ERR value is not a valid float"),
+            Self::Syntax => write!(f, "ERR syntax error"),
+            Self::NoSuchKey => write!(f, "ERR no such key"),
+            Self::OutOfRange => write!(f, "ERR value is out of range"),
+            Self::WrongArity => write!(f, "ERR wrong number of arguments"),
+            Self::Custom(message) => write!(f, "{message}"),
+            Self::Internal(err) => write!(f, "ERR {err:#}"),
+        }
+    }
+}
+
+impl From<anyhow::Error> for RedisError {
+    fn from(err: anyhow::Error) -> Self {
+        Self::Internal(err)
+    }
+}
+
+impl From<RedisError> for Value {
+    fn from(err: RedisError) -> Self {
+        Value::simple_error(err.to_string())
+    }
+}
@@ -0,0 +1,78 @@
+//! Redis-style glob matching, as used by `KEYS`, `SCAN ... MATCH` and pattern subscriptions.
+//!
+//! Supported syntax:
+//! - `*` matches any sequence of bytes (including none)
+//! - `?` matches exactly one byte
+//! - `[abc]`, `[a-z]` and `[^abc]` match one byte from (or not from) a set
+//! - `\x` matches `x` literally
+
+/// Whether `string` matches the glob `pattern`
+pub fn matches(mut pattern: &[u8], mut string: &[u8]) -> bool {
+    while let Some(&p) = pattern.first() {
+        let Some(&c) = string.first() else {
+            // Only trailing stars can match the empty remainder
+            return pattern.iter().all(|&p| p == b'*');
+        };
+
+        match p {
+            b'*' => {
+                while pattern.get(1) == Some(&b'*') {
+                    pattern = &pattern[1..];
+                }
+                if pattern.len() == 1 {
+                    return true;
+                }
+                return (0..string.len()).any(|i| matches(&pattern[1..], &string[i..]));
+            }
+            b'?' => {}
+            b'[' => {
+                pattern = &pattern[1..];
+                let negate = pattern.first() == Some(&b'^');
+                if negate {
+                    pattern = &pattern[1..];
+                }
+
+                let mut matched = false;
+                loop {
+                    match *pattern {
+                        [b'\\', escaped, ..] => {
+                            matched |= escaped == c;
+                            pattern = &pattern[2..];
+                        }
+                        // An unterminated set is treated as if it were closed
+                        [b']', ..] | [] => break,
+                        [start, b'-', end, ..] => {
+                            let (start, end) = (start.min(end), start.max(end));
+                            matched |= (start..=end).contains(&c);
+                            pattern = &pattern[3..];
+                        }
+                        [other, ..] => {
+                            matched |= other == c;
+                            pattern = &pattern[1..];
+                        }
+                    }
+                }
+
+                if matched == negate {
+                    return false;
+                }
+            }
+            b'\\' if pattern.len() >= 2 => {
+                pattern = &pattern[1..];
+                if pattern[0] != c {
+                    return false;
+                }
+            }
+            _ => {
+                if p != c {
+                    return false;
+                }
+            }
+        }
+
+        pattern = pattern.get(1..).unwrap_or_default();
+        string = &string[1..];
+    }
+
+    string.is_empty()
+}
@@ -0,0 +1,564 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    hash::{DefaultHasher, Hash, Hasher},
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::SystemTime,
+};
+
+use dashmap::{
+    iter::Iter,
+    mapref::{
+        entry::Entry,
+        one::{Ref, RefMut},
+    },
+    DashMap,
+};
+
+use crate::{bytes::Bytes, config::Config, stats::Stats, MapValue, MapValueContent};
+
+pub const CLUSTER_SLOTS: u16 = 16384;
+
+/// Values with more elements than this are dropped on a background thread when freed lazily,
+/// since it's only worth the overhead of handing them over for large ones
+const LAZYFREE_THRESHOLD: usize = 64;
+
+/// Roughly how much work it is to drop `value`, in elements
+fn free_effort(value: &MapValueContent) -> usize {
+    match value {
+        MapValueContent::Integer(_) | MapValueContent::String(_) => 1,
+        MapValueContent::List(list) => list.len(),
+        MapValueContent::Stream(stream) => stream.len(),
+        MapValueContent::SortedSet(set) => set.len(),
+        MapValueContent::Hash(hash) => hash.len(),
+        MapValueContent::Set(set) => set.len(),
+    }
+}
+
+/// Drop `value`, on a background thread if it's large enough that doing so could hold up the
+/// caller
+pub(crate) fn free_lazily(value: MapValue) {
+    if free_effort(&value.value) > LAZYFREE_THRESHOLD {
+        tokio::task::spawn_blocking(move || drop(value));
+    }
+}
+
+/// CRC16 (XMODEM), as used by Redis Cluster for key hashing
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &b in bytes {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// The cluster slot that `key` belongs to.  If the key contains a non-empty hash tag (`{...}`),
+/// only the tag is hashed, so related keys can be forced into the same slot.
+pub fn key_slot(key: &[u8]) -> u16 {
+    let hashed = key
+        .iter()
+        .position(|&b| b == b'{')
+        .and_then(|start| {
+            let rest = &key[start + 1..];
+            let len = rest.iter().position(|&b| b == b'}')?;
+            (len > 0).then(|| &rest[..len])
+        })
+        .unwrap_or(key);
+
+    crc16(hashed) % CLUSTER_SLOTS
+}
+
+/// The position of `key` in `SCAN` order.  Positions are never 0, so that a cursor of 0 can mean
+/// both "start" and "done".
+fn scan_position(key: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    (hasher.finish() >> 1) + 1
+}
+
+/// How many parts the `SCAN` index is split into by position, so that writers to different keys
+/// rarely wait on each other
+const SCAN_BUCKETS: usize = 256;
+
+/// The most keys `SCAN` allocates room for before it knows how many it will find
+const SCAN_PREALLOC: usize = 1024;
+
+/// Keys by their [`scan_position`], for part of the range of positions
+type ScanBucket = BTreeSet<(u64, Bytes)>;
+
+/// The part of the `SCAN` index holding keys at `pos`
+fn scan_bucket(pos: u64) -> usize {
+    ((pos - 1) >> (63 - SCAN_BUCKETS.trailing_zeros())) as usize
+}
+
+/// A key which at least one client is `WATCH`ing
+#[derive(Debug, Default)]
+struct Watched {
+    watchers: usize,
+    /// Bumped whenever the key is modified
+    version: u64,
+}
+
+/// A value borrowed mutably from the keyspace.  If its expiry is changed, the expiry index is
+/// updated when it's dropped, and anyone watching the key is told that it may have changed.
+pub(crate) struct ValueMut<'a> {
+    keyspace: &'a Keyspace,
+    value: RefMut<'a, Bytes, MapValue>,
+    /// The expiry the value had when it was borrowed
+    expires_at: Option<SystemTime>,
+}
+
+impl<'a> ValueMut<'a> {
+    fn new(keyspace: &'a Keyspace, value: RefMut<'a, Bytes, MapValue>) -> Self {
+        Self {
+            keyspace,
+            expires_at: value.expires_at,
+            value,
+        }
+    }
+}
+
+impl Deref for ValueMut<'_> {
+    type Target = MapValue;
+
+    fn deref(&self) -> &Self::Target {
+        &self.value
+    }
+}
+
+impl DerefMut for ValueMut<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.value
+    }
+}
+
+impl Drop for ValueMut<'_> {
+    fn drop(&mut self) {
+        // This runs before the map's lock is released, so nothing can see the key with its new
+        // expiry before it's indexed
+        if self.value.expires_at != self.expires_at {
+            self.keyspace
+                .index_expiry(self.value.key(), self.expires_at, self.value.expires_at);
+        }
+        self.keyspace.touch(self.value.key());
+    }
+}
+
+/// All of the keys in the database.
+///
+/// This wraps the underlying map so that secondary indexes (like the per-slot key index used in
+/// cluster mode) stay in sync with every key that is created or deleted.
+#[derive(Debug)]
+pub struct Keyspace {
+    map: DashMap<Bytes, MapValue>,
+    slots: Option<Box<[Mutex<HashSet<Bytes>>]>>,
+    /// Every key by its [`scan_position`], so that `SCAN` can start from its cursor rather than
+    /// looking through the whole map
+    scan_index: Box<[Mutex<ScanBucket>]>,
+    /// The times at which something in a key expires (the key itself, or one of its hash fields),
+    /// in order, so that the active expiry cycle only has to look at keys which are due.  Each is
+    /// counted, since a key and its fields could expire at the same time.
+    ///
+    /// Entries are removed when a key's own expiry changes, but may otherwise outlive what they
+    /// refer to, so a key being due doesn't mean it has anything to expire.
+    expiries: Mutex<BTreeMap<(SystemTime, Bytes), usize>>,
+    /// Replicas never expire keys themselves, and keep serving them until their master says to
+    /// delete them
+    replica: bool,
+    /// Keys which have expired but whose deletion hasn't been propagated yet
+    expired: Mutex<Vec<Bytes>>,
+    /// Whether expired values are freed with [`free_lazily`], which can be changed while running
+    lazy_expire: AtomicBool,
+    /// The keys which clients are watching for changes, for `WATCH`
+    watched: DashMap<Bytes, Watched>,
+    /// Shared with the server, to count hits, misses and expired keys
+    stats: Arc<Stats>,
+}
+
+impl Keyspace {
+    pub fn new(config: &Config, stats: Arc<Stats>) -> Self {
+        Self {
+            map: Default::default(),
+            slots: config
+                .cluster_enabled
+                .then(|| (0..CLUSTER_SLOTS).map(|_| Default::default()).collect()),
+            scan_index: (0..SCAN_BUCKETS).map(|_| Default::default()).collect(),
+            expiries: Default::default(),
+            replica: config.replicaof.is_some(),
+            expired: Default::default(),
+            lazy_expire: AtomicBool::new(config.lazyfree_lazy_expire),
+            watched: Default::default(),
+            stats,
+        }
+    }
+
+    pub fn set_lazy_expire(&self, lazy_expire: bool) {
+        self.lazy_expire.store(lazy_expire, Ordering::Relaxed);
+    }
+
+    /// Start watching `key` for changes, returning its current version
+    pub fn watch(&self, key: &[u8]) -> u64 {
+        // A key which has already expired shouldn't count as changing when it's removed
+        self.expire_if_needed(key);
+
+        let mut watched = self.watched.entry(Bytes::from(key)).or_default();
+        watched.watchers += 1;
+        watched.version
+    }
+
+    /// Stop watching `key`, which must have been watched with [`Self::watch`]
+    pub fn unwatch(&self, key: &[u8]) {
+        if let Entry::Occupied(mut e) = self.watched.entry(Bytes::from(key)) {
+            e.get_mut().watchers -= 1;
+            if e.get().watchers == 0 {
+                e.remove();
+            }
+        }
+    }
+
+    /// Whether `key` has changed since [`Self::watch`] returned `version`, including by expiring
+    pub fn watched_changed(&self, key: &[u8], version: u64) -> bool {
+        let changed = self
+            .watched
+            .get(key)
+            .is_none_or(|watched| watched.version != version);
+        changed
+            || self
+                .map
+                .get(key)
+                .is_some_and(|value| self.is_expired(&value))
+    }
+
+    /// Note that `key` has (or may have) been modified
+    fn touch(&self, key: &[u8]) {
+        if let Some(mut watched) = self.watched.get_mut(key) {
+            watched.version += 1;
+        }
+    }
+
+    /// Whether `value` should be treated as though it doesn't exist
+    pub(crate) fn is_expired(&self, value: &MapValue) -> bool {
+        !self.replica && value.is_expired()
+    }
+
+    /// Whether any keys have expired since [`Self::take_expired`] was last called
+    pub fn has_expired(&self) -> bool {
+        !self
+            .expired
+            .lock()
+            .expect("expired keys lock poisoned")
+            .is_empty()
+    }
+
+    /// The keys which have expired since this was last called, which need deleting on replicas
+    /// and in the AOF
+    pub fn take_expired(&self) -> Vec<Bytes> {
+        std::mem::take(&mut self.expired.lock().expect("expired keys lock poisoned"))
+    }
+
+    /// Record that something in `key` (its own expiry, or one of its hash fields') now expires at
+    /// `new` instead of `old`
+    pub(crate) fn index_expiry(
+        &self,
+        key: &[u8],
+        old: Option<SystemTime>,
+        new: Option<SystemTime>,
+    ) {
+        if old == new {
+            return;
+        }
+
+        let mut expiries = self.expiries.lock().expect("expiry index lock poisoned");
+        if let Some(old) = old {
+            let entry = (old, Bytes::from(key));
+            if let Some(count) = expiries.get_mut(&entry) {
+                *count -= 1;
+                if *count == 0 {
+                    expiries.remove(&entry);
+                }
+            }
+        }
+        if let Some(new) = new {
+            *expiries.entry((new, Bytes::from(key))).or_default() += 1;
+        }
+    }
+
+    /// Remove `key` if `pred` returns true for its value, keeping the indexes in sync
+    fn remove_if(&self, key: &[u8], pred: impl FnOnce(&MapValue) -> bool) -> Option<MapValue> {
+        let Entry::Occupied(e) = self.map.entry(Bytes::from(key)) else {
+            return None;
+        };
+        if !pred(e.get()) {
+            return None;
+        }
+
+        self.index_expiry(key, e.get().expires_at, None);
+        self.index_remove(key);
+        self.touch(key);
+        Some(e.remove())
+    }
+
+    fn index_insert(&self, key: &[u8]) {
+        let pos = scan_position(key);
+        self.scan_index[scan_bucket(pos)]
+            .lock()
+            .expect("scan index lock poisoned")
+            .insert((pos, Bytes::from(key)));
+        if let Some(ref slots) = self.slots {
+            slots[key_slot(key) as usize]
+                .lock()
+                .expect("slot index lock poisoned")
+                .insert(Bytes::from(key));
+        }
+    }
+
+    fn index_remove(&self, key: &[u8]) {
+        let pos = scan_position(key);
+        self.scan_index[scan_bucket(pos)]
+            .lock()
+            .expect("scan index lock poisoned")
+            .remove(&(pos, Bytes::from(key)));
+        if let Some(ref slots) = self.slots {
+            slots[key_slot(key) as usize]
+                .lock()
+                .expect("slot index lock poisoned")
+                .remove(key);
+        }
+    }
+
+    /// Remove `key` if it has expired, returning whether it was removed
+    fn expire_if_needed(&self, key: &[u8]) -> bool {
+        match self.remove_if(key, |v| self.is_expired(v)) {
+            Some(value) => {
+                self.push_expired(Bytes::from(key), value);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Free the value of a key which has expired, and queue its deletion to be propagated
+    fn push_expired(&self, key: Bytes, value: MapValue) {
+        Stats::incr(&self.stats.expired_keys);
+        if self.lazy_expire.load(Ordering::Relaxed) {
+            free_lazily(value);
+        }
+        self.expired
+            .lock()
+            .expect("expired keys lock poisoned")
+            .push(key);
+    }
+
+    /// Get the value at `key` to read it, lazily removing it if it has expired (unless this is a
+    /// replica).  Counts towards `keyspace_hits` or `keyspace_misses`.
+    pub(crate) fn get(&self, key: &[u8]) -> Option<Ref<'_, Bytes, MapValue>> {
+        let value = self.lookup(key);
+        Stats::incr(match value {
+            Some(_) => &self.stats.keyspace_hits,
+            None => &self.stats.keyspace_misses,
+        });
+        value
+    }
+
+    /// [`Self::get`], without counting the lookup
+    fn lookup(&self, key: &[u8]) -> Option<Ref<'_, Bytes, MapValue>> {
+        let value = self.map.get(key)?;
+        if self.is_expired(&value) {
+            drop(value);
+            self.expire_if_needed(key);
+            return None;
+        }
+        Some(value)
+    }
+
+    /// Get the value at `key` mutably, lazily removing it if it has expired (unless this is a replica)
+    pub(crate) fn get_mut(&self, key: &[u8]) -> Option<ValueMut<'_>> {
+        let value = self.map.get_mut(key)?;
+        if self.is_expired(&value) {
+            drop(value);
+            self.expire_if_needed(key);
+            return None;
+        }
+        Some(ValueMut::new(self, value))
+    }
+
+    pub(crate) fn insert(&self, key: Bytes, value: MapValue) -> Option<MapValue> {
+        // Keys usually come straight from a command, so they're sliced out of its read buffer
+        match self.map.entry(key.compact()) {
+            Entry::Occupied(mut e) => {
+                self.index_expiry(e.key(), e.get().expires_at, value.expires_at);
+                self.touch(e.key());
+                Some(e.insert(value))
+            }
+            Entry::Vacant(e) => {
+                self.index_insert(e.key());
+                self.index_expiry(e.key(), None, value.expires_at);
+                self.touch(e.key());
+                e.insert(value);
+                None
+            }
+        }
+    }
+
+    pub(crate) fn remove(&self, key: &[u8]) -> Option<(Bytes, MapValue)> {
+        self.remove_if(key, |_| true)
+            .map(|value| (Bytes::from(key), value))
+    }
+
+    /// Get the value at `key`, inserting `default` first if there is none
+    pub(crate) fn get_or_insert_with(
+        &self,
+        key: Bytes,
+        default: impl FnOnce() -> MapValue,
+    ) -> ValueMut<'_> {
+        let value = match self.map.entry(key.compact()) {
+            Entry::Occupied(mut e) => {
+                if self.is_expired(e.get()) {
+                    let value = default();
+                    self.index_expiry(e.key(), e.get().expires_at, value.expires_at);
+                    let old = e.insert(value);
+                    self.push_expired(e.key().clone(), old);
+                }
+                e.into_ref()
+            }
+            Entry::Vacant(e) => {
+                let value = default();
+                self.index_insert(e.key());
+                self.index_expiry(e.key(), None, value.expires_at);
+                e.insert(value)
+            }
+        };
+        ValueMut::new(self, value)
+    }
+
+    /// Remove every key which has expired, returning how many were removed.  Expired hash fields
+    /// are removed too, along with any hash which they leave empty.
+    pub fn remove_expired(&self) -> usize {
+        if self.replica {
+            return 0;
+        }
+        let now = SystemTime::now();
+
+        // Take the keys which are due first, so the index isn't locked while we touch the map
+        let mut due = Vec::new();
+        {
+            let mut expiries = self.expiries.lock().expect("expiry index lock poisoned");
+            while let Some(entry) = expiries.first_entry() {
+                if entry.key().0 > now {
+                    break;
+                }
+                let ((_, key), _) = entry.remove_entry();
+                due.push(key);
+            }
+        }
+
+        let mut removed = 0;
+        for key in due {
+            if let Some(mut value) = self.map.get_mut(&key) {
+                if let MapValueContent::Hash(ref mut hash) = value.value {
+                    hash.retain(|_, field| !field.is_expired());
+                }
+            }
+
+            let expired = self.remove_if(&key, |v| {
+                v.is_expired() || matches!(v.value, MapValueContent::Hash(ref h) if h.is_empty())
+            });
+            if let Some(value) = expired {
+                self.push_expired(key, value);
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    /// Remove every key, returning the removed values so that the caller can choose where to
+    /// drop them.
+    ///
+    /// Each key is removed along with its index entries, rather than the indexes being cleared
+    /// afterwards, so that keys written meanwhile keep theirs.
+    pub(crate) fn take_all(&self) -> Vec<MapValue> {
+        let keys: Vec<Bytes> = self.map.iter().map(|e| e.key().clone()).collect();
+        keys.iter()
+            .filter_map(|key| self.remove_if(key, |_| true))
+            .collect()
+    }
+
+    /// Roughly `count` keys starting at `cursor`, along with the cursor to continue from (0 once
+    /// every key has been visited).
+    ///
+    /// Keys are visited in order of a hash of their name, so the cursor stays valid while keys
+    /// are added and removed: every key which exists for the whole scan is returned at least once.
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<Bytes>) {
+        // `COUNT` comes from the client, so it's only a hint for how much to allocate up front
+        let mut keys = Vec::with_capacity(count.min(SCAN_PREALLOC));
+        let mut next = 0;
+        let buckets = self.scan_index.get(scan_bucket(cursor.max(1))..);
+        for bucket in buckets.unwrap_or_default() {
+            let bucket = bucket.lock().expect("scan index lock poisoned");
+
+            // Keys which share a position aren't split between two calls
+            let mut last = None;
+            for (pos, key) in bucket.range((cursor, Bytes::default())..) {
+                if keys.len() >= count && last != Some(pos) {
+                    next = *pos;
+                    break;
+                }
+                keys.push(key.clone());
+                last = Some(pos);
+            }
+            if next != 0 {
+                break;
+            }
+        }
+
+        // Keys which expired but haven't been removed yet are skipped.  The map isn't touched
+        // while the index is locked, since writers lock them the other way around.
+        keys.retain(|key| self.map.get(key).is_some_and(|v| !self.is_expired(&v)));
+        (next, keys)
+    }
+
+    /// Number of keys which haven't expired
+    pub fn key_count(&self) -> usize {
+        self.map.iter().filter(|e| !self.is_expired(e)).count()
+    }
+
+    /// Iterate over every key, including ones which have expired but not yet been removed
+    pub(crate) fn iter(&self) -> Iter<'_, Bytes, MapValue> {
+        self.map.iter()
+    }
+
+    /// Number of keys in `slot`, or `None` if cluster mode is disabled
+    pub fn count_keys_in_slot(&self, slot: u16) -> Option<usize> {
+        let slots = self.slots.as_ref()?;
+        Some(
+            slots[slot as usize]
+                .lock()
+                .expect("slot index lock poisoned")
+                .len(),
+        )
+    }
+
+    /// Up to `count` keys in `slot`, or `None` if cluster mode is disabled
+    pub fn keys_in_slot(&self, slot: u16, count: usize) -> Option<Vec<Bytes>> {
+        let slots = self.slots.as_ref()?;
+        Some(
+            slots[slot as usize]
+                .lock()
+                .expect("slot index lock poisoned")
+                .iter()
+                .take(count)
+                .cloned()
+                .collect(),
+        )
+    }
+}
@@ -0,0 +1,295 @@
+//! The representations used for lists.
+//!
+//! Small lists are packed into a single buffer (like Redis' listpack), which saves the allocation
+//! and bookkeeping of a `String` per item.  Once a list grows past `list-max-listpack-size` it's
+//! converted into a regular [`VecDeque`], which is faster to modify in the middle.
+
+use std::collections::VecDeque;
+
+/// Write `n` as a LEB128 varint
+pub(crate) fn write_varint(buf: &mut Vec<u8>, mut n: usize) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            buf.push(byte);
+            return;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+/// Read a LEB128 varint from the start of `buf`, returning it and the number of bytes it took
+pub(crate) fn read_varint(buf: &[u8]) -> (usize, usize) {
+    let mut n = 0;
+    for (i, &byte) in buf.iter().enumerate() {
+        n |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return (n, i + 1);
+        }
+    }
+    unreachable!("listpack entry length is truncated")
+}
+
+/// The encoded form of a single entry: its length, followed by its bytes
+fn encode(item: &str) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(item.len() + 2);
+    write_varint(&mut buf, item.len());
+    buf.extend_from_slice(item.as_bytes());
+    buf
+}
+
+/// A list of strings packed one after the other into a single buffer
+#[derive(Debug, Clone, Default)]
+pub struct Listpack {
+    buf: Vec<u8>,
+    len: usize,
+}
+
+impl Listpack {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The size of the packed entries, in bytes
+    pub fn bytes(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// The range of `buf` taken up by the entry starting at `offset`, and the range of its data
+    fn entry_at(&self, offset: usize) -> (std::ops::Range<usize>, std::ops::Range<usize>) {
+        let (len, header) = read_varint(&self.buf[offset..]);
+        let data = offset + header..offset + header + len;
+        (offset..data.end, data)
+    }
+
+    /// The offset of the entry at `index`, or the end of the buffer if `index == len`
+    fn offset_of(&self, index: usize) -> usize {
+        let mut offset = 0;
+        for _ in 0..index {
+            offset = self.entry_at(offset).0.end;
+        }
+        offset
+    }
+
+    pub fn iter(&self) -> ListpackIter<'_> {
+        ListpackIter {
+            listpack: self,
+            offset: 0,
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Option<&str> {
+        self.iter().nth(index)
+    }
+
+    pub fn insert(&mut self, index: usize, item: &str) {
+        let offset = self.offset_of(index);
+        self.buf.splice(offset..offset, encode(item));
+        self.len += 1;
+    }
+
+    pub fn set(&mut self, index: usize, item: &str) {
+        let (entry, _) = self.entry_at(self.offset_of(index));
+        self.buf.splice(entry, encode(item));
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<String> {
+        if index >= self.len {
+            return None;
+        }
+
+        let (entry, data) = self.entry_at(self.offset_of(index));
+        let item = String::from_utf8(self.buf[data].to_vec()).expect("listpack entry is UTF-8");
+        self.buf.drain(entry);
+        self.len -= 1;
+        Some(item)
+    }
+
+    pub fn push_back(&mut self, item: &str) {
+        self.buf.extend(encode(item));
+        self.len += 1;
+    }
+
+    /// Keep only the items for which `keep` returns `true`
+    pub fn retain(&mut self, mut keep: impl FnMut(&str) -> bool) {
+        let mut kept = Listpack::default();
+        for item in self.iter().filter(|item| keep(item)) {
+            kept.push_back(item);
+        }
+        *self = kept;
+    }
+}
+
+pub struct ListpackIter<'a> {
+    listpack: &'a Listpack,
+    offset: usize,
+}
+
+impl<'a> Iterator for ListpackIter<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.listpack.buf.len() {
+            return None;
+        }
+
+        let (entry, data) = self.listpack.entry_at(self.offset);
+        self.offset = entry.end;
+        Some(std::str::from_utf8(&self.listpack.buf[data]).expect("listpack entry is UTF-8"))
+    }
+}
+
+/// Whether a listpack with `len` entries taking up `bytes` bytes fits within `max_size`, which is
+/// in the format of `list-max-listpack-size`: positive values limit the number of entries, and -1
+/// to -5 limit the size to 4, 8, 16, 32 or 64 KiB.
+fn fits(len: usize, bytes: usize, max_size: i64) -> bool {
+    if max_size >= 0 {
+        len as u64 <= max_size as u64
+    } else {
+        let shift = (max_size.unsigned_abs() - 1).min(4);
+        bytes <= 4096 << shift
+    }
+}
+
+/// The items of a list, in whichever representation suits its size
+#[derive(Debug, Clone)]
+pub enum List {
+    Packed(Listpack),
+    Linked(VecDeque<String>),
+}
+
+impl Default for List {
+    fn default() -> Self {
+        Self::Packed(Listpack::default())
+    }
+}
+
+impl FromIterator<String> for List {
+    fn from_iter<T: IntoIterator<Item = String>>(iter: T) -> Self {
+        let mut list = List::default();
+        for item in iter {
+            list.push_back(item);
+        }
+        list
+    }
+}
+
+impl List {
+    /// The name of this representation, as reported by `OBJECT ENCODING`
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Self::Packed(_) => "listpack",
+            Self::Linked(_) => "quicklist",
+        }
+    }
+
+    /// Switch to the linked representation if the packed one has grown past `max_size` (see
+    /// [`fits`]).  Lists never switch back, even if they shrink again.
+    pub fn convert_if_needed(&mut self, max_size: i64) {
+        let Self::Packed(ref packed) = self else {
+            return;
+        };
+
+        if !fits(packed.len(), packed.bytes(), max_size) {
+            *self = Self::Linked(packed.iter().map(String::from).collect());
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Packed(packed) => packed.len(),
+            Self::Linked(items) => items.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        match self {
+            Self::Packed(packed) => Iter::Packed(packed.iter()),
+            Self::Linked(items) => Iter::Linked(items.iter()),
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Option<&str> {
+        match self {
+            Self::Packed(packed) => packed.get(index),
+            Self::Linked(items) => items.get(index).map(String::as_str),
+        }
+    }
+
+    pub fn push_front(&mut self, item: String) {
+        match self {
+            Self::Packed(packed) => packed.insert(0, &item),
+            Self::Linked(items) => items.push_front(item),
+        }
+    }
+
+    pub fn push_back(&mut self, item: String) {
+        match self {
+            Self::Packed(packed) => packed.push_back(&item),
+            Self::Linked(items) => items.push_back(item),
+        }
+    }
+
+    pub fn pop_front(&mut self) -> Option<String> {
+        match self {
+            Self::Packed(packed) => packed.remove(0),
+            Self::Linked(items) => items.pop_front(),
+        }
+    }
+
+    pub fn pop_back(&mut self) -> Option<String> {
+        match self {
+            Self::Packed(packed) => packed.remove(packed.len().checked_sub(1)?),
+            Self::Linked(items) => items.pop_back(),
+        }
+    }
+
+    /// Insert `item` at `index`, which must be no greater than the length of the list
+    pub fn insert(&mut self, index: usize, item: String) {
+        match self {
+            Self::Packed(packed) => packed.insert(index, &item),
+            Self::Linked(items) => items.insert(index, item),
+        }
+    }
+
+    /// Replace the item at `index`, which must be in range
+    pub fn set(&mut self, index: usize, item: String) {
+        match self {
+            Self::Packed(packed) => packed.set(index, &item),
+            Self::Linked(items) => items[index] = item,
+        }
+    }
+
+    /// Keep only the items for which `keep` returns `true`, visiting them in order
+    pub fn retain(&mut self, mut keep: impl FnMut(&str) -> bool) {
+        match self {
+            Self::Packed(packed) => packed.retain(keep),
+            Self::Linked(items) => items.retain(|item| keep(item)),
+        }
+    }
+}
+
+pub enum Iter<'a> {
+    Packed(ListpackIter<'a>),
+    Linked(std::collections::vec_deque::Iter<'a, String>),
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Self::Packed(iter) => iter.next(),
+            Self::Linked(iter) => iter.next().map(String::as_str),
+        }
+    }
+}
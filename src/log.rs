@@ -0,0 +1,348 @@
+//! Logging, with levels named like Redis' `loglevel` and lines in the same format as Redis':
+//!
+//! ```text
+//! 12345:M 16 Oct 2026 10:00:00.123 * client{id=3 addr=127.0.0.1:50000}: accepted connection
+//! ```
+//!
+//! That is, the process ID, whether we're a master (`M`) or replica (`S`), the time (in UTC), and
+//! a mark for the level, followed by any spans that the event happened in.
+//!
+//! Logs go to stderr, or to `logfile` if it's set.  The log file can be rotated once it gets too
+//! big or old, and is reopened by `CONFIG SET logfile`, so that tools like `logrotate` can move it
+//! out of the way first.  `loglevel` can be changed with `CONFIG SET` too.
+
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+use tracing::{level_filters::LevelFilter, Event, Level, Subscriber};
+use tracing_subscriber::{
+    fmt::{format::Writer, FmtContext, FormatEvent, FormatFields, FormattedFields, MakeWriter},
+    layer::SubscriberExt,
+    registry::LookupSpan,
+    reload,
+    util::SubscriberInitExt,
+    Registry,
+};
+
+use crate::config::Config;
+
+/// How much is logged, from most to least
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogLevel {
+    /// Everything, including every command received and reply sent
+    Debug,
+    /// Connections coming and going, and other comings and goings like the replication handshake
+    Verbose,
+    /// What's worth seeing in production, like the server starting
+    Notice,
+    /// Only things going wrong
+    Warning,
+    Nothing,
+}
+
+impl std::str::FromStr for LogLevel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "debug" => Ok(Self::Debug),
+            "verbose" => Ok(Self::Verbose),
+            "notice" => Ok(Self::Notice),
+            "warning" => Ok(Self::Warning),
+            "nothing" => Ok(Self::Nothing),
+            _ => bail!(
+                "loglevel must be one of 'debug', 'verbose', 'notice', 'warning' or 'nothing'"
+            ),
+        }
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Debug => "debug",
+            Self::Verbose => "verbose",
+            Self::Notice => "notice",
+            Self::Warning => "warning",
+            Self::Nothing => "nothing",
+        })
+    }
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Debug => LevelFilter::TRACE,
+            LogLevel::Verbose => LevelFilter::DEBUG,
+            LogLevel::Notice => LevelFilter::INFO,
+            LogLevel::Warning => LevelFilter::WARN,
+            LogLevel::Nothing => LevelFilter::OFF,
+        }
+    }
+}
+
+/// Start logging as configured, returning the [`Logger`] which changes to the config are applied
+/// through
+pub fn init(config: &Config) -> anyhow::Result<Logger> {
+    let writer = LogWriter::new(config)?;
+    let (level, level_handle) = reload::Layer::new(LevelFilter::from(config.loglevel));
+    tracing_subscriber::registry()
+        .with(level)
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_writer(writer.clone())
+                .with_ansi(false)
+                .event_format(RedisFormat {
+                    role: if config.replicaof.is_some() { 'S' } else { 'M' },
+                }),
+        )
+        .init();
+    Ok(Logger {
+        writer,
+        level: level_handle,
+    })
+}
+
+/// The running logger, whose level and output can be changed while running
+#[derive(Debug)]
+pub struct Logger {
+    writer: LogWriter,
+    level: reload::Handle<LevelFilter, Registry>,
+}
+
+impl Logger {
+    pub fn set_level(&self, level: LogLevel) -> anyhow::Result<()> {
+        self.level
+            .reload(LevelFilter::from(level))
+            .context("changing log level")
+    }
+
+    /// Log to the file at `path` from now on (or stderr for `None`), reopening it even if it's the
+    /// one already being logged to, in case it has been moved
+    pub fn reopen(&self, path: Option<&Path>) -> io::Result<()> {
+        let file = path.map(LogFile::open).transpose()?;
+        self.writer.output().file = file;
+        Ok(())
+    }
+
+    /// Apply `logfile-max-size` and `logfile-rotate-interval` from `config`
+    pub fn set_rotation(&self, config: &Config) {
+        let mut output = self.writer.output();
+        (output.max_size, output.max_age) = rotation(config);
+    }
+}
+
+/// Where logs are written, which is shared between the subscriber and [`Logger`]
+#[derive(Debug, Clone)]
+struct LogWriter(Arc<Mutex<Output>>);
+
+#[derive(Debug)]
+struct Output {
+    /// `None` while logging to stderr
+    file: Option<LogFile>,
+    /// Rotate the file once it's this many bytes, if set
+    max_size: Option<u64>,
+    /// Rotate the file once it's been open this long, if set
+    max_age: Option<Duration>,
+}
+
+#[derive(Debug)]
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    opened: Instant,
+}
+
+impl LogFile {
+    fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            size: file.metadata()?.len(),
+            file,
+            opened: Instant::now(),
+        })
+    }
+}
+
+impl LogWriter {
+    fn new(config: &Config) -> anyhow::Result<Self> {
+        let file = match config.logfile {
+            Some(ref path) => Some(
+                LogFile::open(path)
+                    .with_context(|| format!("opening log file {}", path.display()))?,
+            ),
+            None => None,
+        };
+        let (max_size, max_age) = rotation(config);
+        Ok(Self(Arc::new(Mutex::new(Output {
+            file,
+            max_size,
+            max_age,
+        }))))
+    }
+
+    fn output(&self) -> std::sync::MutexGuard<'_, Output> {
+        self.0.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// The size and age at which the log file is rotated, where 0 in `config` means never
+fn rotation(config: &Config) -> (Option<u64>, Option<Duration>) {
+    (
+        (config.logfile_max_size > 0).then_some(config.logfile_max_size),
+        (config.logfile_rotate_interval > 0)
+            .then(|| Duration::from_secs(config.logfile_rotate_interval)),
+    )
+}
+
+impl Output {
+    /// Move the log file aside to `<path>.<unix time>` and start a new one, if it's due
+    fn rotate_if_needed(&mut self) -> io::Result<()> {
+        let Some(ref current) = self.file else {
+            return Ok(());
+        };
+        let too_big = self.max_size.is_some_and(|max| current.size >= max);
+        let too_old = self
+            .max_age
+            .is_some_and(|max| current.opened.elapsed() >= max);
+        if !too_big && !too_old {
+            return Ok(());
+        }
+
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut rotated = current.path.clone().into_os_string();
+        rotated.push(format!(".{secs}"));
+        std::fs::rename(&current.path, rotated)?;
+        self.file = Some(LogFile::open(&current.path)?);
+        Ok(())
+    }
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut output = self.output();
+        // Losing a rotation isn't worth losing the line over
+        let _ = output.rotate_if_needed();
+        match output.file {
+            Some(ref mut file) => {
+                let written = file.file.write(buf)?;
+                file.size += written as u64;
+                Ok(written)
+            }
+            None => io::stderr().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.output().file {
+            Some(ref mut file) => file.file.flush(),
+            None => io::stderr().flush(),
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for LogWriter {
+    type Writer = Self;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+/// Formats events like Redis' log lines
+struct RedisFormat {
+    role: char,
+}
+
+impl<S, N> FormatEvent<S, N> for RedisFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mark = match *event.metadata().level() {
+            Level::TRACE | Level::DEBUG => '.',
+            Level::INFO => '*',
+            Level::WARN | Level::ERROR => '#',
+        };
+        write!(
+            writer,
+            "{}:{} {} {mark} ",
+            std::process::id(),
+            self.role,
+            Timestamp(SystemTime::now())
+        )?;
+
+        if let Some(scope) = ctx.event_scope() {
+            for span in scope.from_root() {
+                write!(writer, "{}", span.name())?;
+                if let Some(fields) = span.extensions().get::<FormattedFields<N>>() {
+                    if !fields.is_empty() {
+                        write!(writer, "{{{fields}}}")?;
+                    }
+                }
+                write!(writer, ": ")?;
+            }
+        }
+
+        ctx.field_format().format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
+    }
+}
+
+/// A time shown like `16 Oct 2026 10:00:00.123`
+struct Timestamp(SystemTime);
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const MONTHS: [&str; 12] = [
+            "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+        ];
+
+        let since_epoch = self.0.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let secs = since_epoch.as_secs();
+        let (year, month, day) = civil_from_days((secs / 86400) as i64);
+        let secs_of_day = secs % 86400;
+        write!(
+            f,
+            "{day:02} {} {year} {:02}:{:02}:{:02}.{:03}",
+            MONTHS[month as usize - 1],
+            secs_of_day / 3600,
+            secs_of_day / 60 % 60,
+            secs_of_day % 60,
+            since_epoch.subsec_millis()
+        )
+    }
+}
+
+/// The `(year, month, day)` of a number of days since 1970-01-01, using Howard Hinnant's
+/// `civil_from_days`
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
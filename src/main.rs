@@ -1,30 +1,60 @@
 use std::{
-    collections::{BTreeMap, BTreeSet, HashSet, VecDeque},
+    collections::{HashMap, HashSet},
     fmt::Display,
+    future::Future,
     net::SocketAddr,
+    os::unix::process::CommandExt,
     path::PathBuf,
+    pin::Pin,
+    process::Stdio,
     sync::{
-        atomic::{AtomicUsize, Ordering},
+        atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering},
         Arc,
     },
-    time::SystemTime,
+    time::{Duration, Instant, SystemTime},
 };
 
-use anyhow::{bail, ensure, Context};
-use command::Command;
+use ::bytes::BytesMut;
+use anyhow::{ensure, Context};
+use bytes::Bytes;
+use command::{Command, ExecContext};
+use config::Config;
 use dashmap::DashMap;
+use error::RedisError;
+use keyspace::Keyspace;
 use rand::{distr::Alphanumeric, Rng};
-use resp::Value;
+use resp::{ProtocolError, Value};
+use stats::Stats;
 use tokio::{
     fs::File,
-    io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader},
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
     net::{TcpListener, TcpStream},
-    sync::{mpsc, oneshot, RwLock},
+    signal::unix::{signal, SignalKind},
+    sync::{mpsc, watch, Mutex, MutexGuard, RwLock},
 };
-
+use tokio_rustls::rustls::pki_types::ServerName;
+use trace::Tracer;
+use tracing::{debug, info, info_span, trace, warn, Instrument};
+
+pub mod acl;
+pub mod aof;
+pub mod bytes;
+pub mod client;
 pub mod command;
+pub mod config;
+pub mod error;
+pub mod glob;
+pub mod keyspace;
+pub mod listpack;
+pub mod log;
 pub mod rdb;
 pub mod resp;
+pub mod stats;
+pub mod stream;
+pub mod systemd;
+pub mod tls;
+pub mod trace;
+pub mod zset;
 
 #[derive(Debug, Clone)]
 struct SetEntry {
@@ -32,52 +62,102 @@ struct SetEntry {
     value: String,
 }
 
-impl PartialEq for SetEntry {
-    fn eq(&self, other: &Self) -> bool {
-        self.value.eq(&other.value)
-    }
+#[derive(Debug, Clone)]
+enum MapValueContent {
+    Integer(i64),
+    String(Vec<u8>),
+    List(listpack::List),
+    Stream(stream::Stream),
+    SortedSet(zset::SortedSet),
+    Hash(HashMap<String, HashField>),
+    Set(HashSet<String>),
 }
 
-impl Eq for SetEntry {}
+impl MapValueContent {
+    /// The name of this value's type, as reported by `TYPE`
+    fn type_name(&self) -> &'static str {
+        match self {
+            Self::String(_) | Self::Integer(_) => "string",
+            Self::List(_) => "list",
+            Self::Stream(_) => "stream",
+            Self::SortedSet(_) => "zset",
+            Self::Hash(_) => "hash",
+            Self::Set(_) => "set",
+        }
+    }
 
-impl PartialOrd for SetEntry {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.cmp(other))
+    /// The name of this value's internal representation, as reported by `OBJECT ENCODING`
+    fn encoding(&self) -> &'static str {
+        match self {
+            Self::Integer(_) => "int",
+            Self::String(s) if s.len() <= 44 => "embstr",
+            Self::String(_) => "raw",
+            Self::List(list) => list.encoding(),
+            Self::Stream(_) => "stream",
+            Self::SortedSet(_) => "skiplist",
+            Self::Hash(_) | Self::Set(_) => "hashtable",
+        }
     }
 }
 
-impl Ord for SetEntry {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        use std::cmp::Ordering;
-        if self.value == other.value {
-            return Ordering::Equal;
+/// Accessors for each type of value, which give the `WRONGTYPE` error for any other type
+macro_rules! typed_accessors {
+    ($($variant:ident($ty:ty) => $as_ref:ident, $as_mut:ident;)*) => {
+        impl MapValueContent {
+            $(
+                fn $as_ref(&self) -> Result<&$ty, RedisError> {
+                    match self {
+                        Self::$variant(value) => Ok(value),
+                        _ => Err(RedisError::WrongType),
+                    }
+                }
+
+                fn $as_mut(&mut self) -> Result<&mut $ty, RedisError> {
+                    match self {
+                        Self::$variant(value) => Ok(value),
+                        _ => Err(RedisError::WrongType),
+                    }
+                }
+            )*
         }
-        match self.score.partial_cmp(&other.score) {
-            Some(Ordering::Equal) => self.value.cmp(&other.value),
-            ord => ord
-                .with_context(|| format!("can't compare floats {} and {}", self.score, other.score))
-                .unwrap(),
+    };
+}
+
+typed_accessors! {
+    List(listpack::List) => as_list, as_list_mut;
+    Stream(stream::Stream) => as_stream, as_stream_mut;
+    SortedSet(zset::SortedSet) => as_zset, as_zset_mut;
+    Hash(HashMap<String, HashField>) => as_hash, as_hash_mut;
+    Set(HashSet<String>) => as_set, as_set_mut;
+}
+
+impl From<&[u8]> for MapValueContent {
+    fn from(value: &[u8]) -> Self {
+        match std::str::from_utf8(value).map(str::parse) {
+            Ok(Ok(num)) => Self::Integer(num),
+            _ => Self::String(value.into()),
         }
     }
 }
 
+/// A field of a hash, which may expire independently of the hash itself
 #[derive(Debug, Clone)]
-enum MapValueContent {
-    Integer(i64),
-    String(String),
-    List(VecDeque<String>),
-    Stream(BTreeMap<(u64, u64), Vec<String>>),
-    SortedSet(BTreeSet<SetEntry>),
+struct HashField {
+    value: String,
+    expires_at: Option<SystemTime>,
 }
 
-impl From<&str> for MapValueContent {
-    fn from(value: &str) -> Self {
-        if let Ok(num) = value.parse() {
-            Self::Integer(num)
-        } else {
-            Self::String(value.into())
+impl HashField {
+    fn new(value: String) -> Self {
+        Self {
+            value,
+            expires_at: None,
         }
     }
+
+    fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|e| e <= SystemTime::now())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -86,9 +166,10 @@ struct MapValue {
     expires_at: Option<SystemTime>,
 }
 
-struct StreamEvent {
-    id: (u64, u64),
-    kv_pairs: Vec<String>,
+impl MapValue {
+    fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|e| e <= SystemTime::now())
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -106,37 +187,69 @@ impl Display for Role {
     }
 }
 
+#[derive(Debug)]
+struct Replica {
+    tx: mpsc::UnboundedSender<Value>,
+    ack_offset: usize,
+    last_ack: Instant,
+}
+
+impl Replica {
+    fn new(tx: mpsc::UnboundedSender<Value>) -> Self {
+        Self {
+            tx,
+            ack_offset: 0,
+            last_ack: Instant::now(),
+        }
+    }
+}
+
+/// The clients listening on each channel or pattern
+pub type Listeners = DashMap<Bytes, Vec<mpsc::UnboundedSender<Value>>>;
+
 #[derive(Debug)]
 pub struct State {
-    map: DashMap<String, MapValue>,
-    waiting_on_list: DashMap<String, VecDeque<oneshot::Sender<String>>>,
-    waiting_on_stream: DashMap<String, Vec<mpsc::UnboundedSender<StreamEvent>>>,
+    map: Keyspace,
+    blocked: command::blocking::BlockedClients,
+    clients: client::Clients,
     role: Role,
 
     master_tx: RwLock<Option<mpsc::UnboundedSender<Value>>>,
     replication_id: String,
     replication_offset: AtomicUsize,
-    listening_port: u16,
-    replicas: RwLock<Vec<mpsc::UnboundedSender<Value>>>,
-
-    channel_listeners: DashMap<String, Vec<mpsc::UnboundedSender<Value>>>,
-
-    dir: Option<PathBuf>,
-    db_filename: Option<String>,
+    replicas: RwLock<Vec<Replica>>,
+
+    channel_listeners: Listeners,
+    /// Clients subscribed to channels matching glob patterns, by pattern
+    pattern_listeners: Listeners,
+
+    /// Behind a lock so that `CONFIG SET` can change it, which is never held across an `.await`
+    config: std::sync::RwLock<Config>,
+    /// The names clients run commands by, which `rename-command` can change
+    commands: command::CommandTable,
+    acl: acl::Acl,
+    aof: Option<Mutex<aof::Aof>>,
+    stats: Arc<Stats>,
+    log: log::Logger,
+    next_client_id: AtomicU64,
+
+    /// The number of writes since the RDB file was last saved, which `save` points count
+    dirty: AtomicU64,
+    last_save: std::sync::Mutex<Instant>,
 }
 
 impl State {
-    fn new(
-        role: Role,
-        listening_port: u16,
-        dir: Option<PathBuf>,
-        db_filename: Option<String>,
-    ) -> Self {
+    fn new(config: Config, aof: Option<aof::Aof>, log: log::Logger) -> Self {
+        let stats = Arc::new(Stats::default());
         Self {
-            map: Default::default(),
-            waiting_on_list: Default::default(),
-            waiting_on_stream: Default::default(),
-            role,
+            map: Keyspace::new(&config, Arc::clone(&stats)),
+            blocked: Default::default(),
+            clients: Default::default(),
+            role: config
+                .replicaof
+                .clone()
+                .map(Role::Replica)
+                .unwrap_or(Role::Master),
             master_tx: Default::default(),
             replication_id: rand::rng()
                 .sample_iter(Alphanumeric)
@@ -144,93 +257,275 @@ impl State {
                 .map(char::from)
                 .collect(),
             replication_offset: Default::default(),
-            listening_port,
             replicas: Default::default(),
             channel_listeners: Default::default(),
-            dir,
-            db_filename,
+            pattern_listeners: Default::default(),
+            log,
+            next_client_id: AtomicU64::new(1),
+            dirty: AtomicU64::new(0),
+            last_save: std::sync::Mutex::new(Instant::now()),
+            commands: command::CommandTable::new(&config.rename_commands),
+            acl: acl::Acl::new(&config),
+            config: std::sync::RwLock::new(config),
+            aof: aof.map(Mutex::new),
+            stats,
         }
     }
 
+    pub fn config(&self) -> std::sync::RwLockReadGuard<'_, Config> {
+        self.config.read().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Save the keyspace to the RDB file, as `SAVE` does
+    async fn save(&self) -> anyhow::Result<()> {
+        let path = self.config().rdb_path();
+        // Writes which land while saving may not make it into the file, so they still count
+        let dirty = self.dirty.load(Ordering::Relaxed);
+        rdb::save(self, &path).await?;
+        self.dirty.fetch_sub(dirty, Ordering::Relaxed);
+        *self.last_save.lock().unwrap_or_else(|err| err.into_inner()) = Instant::now();
+        Ok(())
+    }
+
+    /// Whether any of the `save` points has been reached since the last save
+    fn should_save(&self) -> bool {
+        let dirty = self.dirty.load(Ordering::Relaxed);
+        let since_save = self
+            .last_save
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .elapsed();
+        self.config()
+            .save
+            .iter()
+            .any(|point| dirty >= point.changes && since_save >= Duration::from_secs(point.secs))
+    }
+
     pub fn is_replica(&self) -> bool {
         matches!(self.role, Role::Replica(_))
     }
 
+    /// Whether enough replicas have acknowledged recently for this master to accept writes, as
+    /// configured by `min-replicas-to-write` and `min-replicas-max-lag`.
+    async fn has_good_replicas(&self) -> bool {
+        if self.config().min_replicas_to_write == 0 || self.config().min_replicas_max_lag == 0 {
+            return true;
+        }
+
+        let max_lag = Duration::from_secs(self.config().min_replicas_max_lag);
+        let good = self
+            .replicas
+            .read()
+            .await
+            .iter()
+            .filter(|r| r.last_ack.elapsed() <= max_lag)
+            .count();
+
+        good >= self.config().min_replicas_to_write
+    }
+
+    /// Send a command down the replication stream to every connected replica.
+    ///
+    /// On a master this also advances the replication offset by the encoded size of the command,
+    /// since that is what replicas will count when they process it.  A replica's offset instead
+    /// tracks the bytes it has processed from its own master.
+    async fn propagate(&self, value: Value) -> anyhow::Result<()> {
+        if !self.is_replica() {
+            let mut buf = BytesMut::new();
+            value
+                .encode_into(&mut buf, 2)
+                .context("encoding propagated command")?;
+            self.replication_offset
+                .fetch_add(buf.len(), Ordering::SeqCst);
+        }
+
+        self.replicas
+            .write()
+            .await
+            .retain(|replica| replica.tx.send(value.clone()).is_ok());
+
+        Ok(())
+    }
+
+    /// Append `values` to the AOF through `aof`, the held AOF lock if it's enabled, and then send
+    /// them to replicas
+    async fn propagate_all(
+        &self,
+        aof: Option<MutexGuard<'_, aof::Aof>>,
+        values: Vec<Value>,
+    ) -> anyhow::Result<()> {
+        if let Some(mut aof) = aof {
+            for value in &values {
+                aof.append(value).await?;
+            }
+        }
+
+        for value in values {
+            self.propagate(value).await?;
+        }
+        Ok(())
+    }
+
+    /// The `DEL`s which replicas and the AOF need for the keys which have expired since this was
+    /// last called
+    fn expired_dels(&self) -> Vec<Value> {
+        self.map
+            .take_expired()
+            .into_iter()
+            .map(|key| Command::Del.into_command_value(&[key]))
+            .collect()
+    }
+
     async fn do_handshake(self: Arc<Self>) -> anyhow::Result<()> {
         let Role::Replica(ref master) = self.role else {
             panic!("this redis server is not a replica!");
         };
 
+        let (connector, port) = {
+            let config = self.config();
+            if config.tls_replication {
+                (Some(tls::connector(&config)?), config.tls_port)
+            } else {
+                (None, config.port)
+            }
+        };
+        let port = port.to_string();
+
         let stream = TcpStream::connect(master).await?;
-        let (read, mut write) = stream.into_split();
-        let mut read = BufReader::new(read);
+        tune_socket(&stream, &self.config()).context("setting up connection to master")?;
+        let (read, mut write): (MasterRead, MasterWrite) = match connector {
+            Some(connector) => {
+                let host = master.rsplit_once(':').map_or(&**master, |(host, _)| host);
+                let name = ServerName::try_from(host.to_string())
+                    .with_context(|| format!("invalid master hostname '{host}'"))?;
+                let stream = connector
+                    .connect(name, stream)
+                    .await
+                    .context("connecting to master over TLS")?;
+                let (read, write) = tokio::io::split(stream);
+                (Box::new(read), Box::new(write))
+            }
+            None => {
+                let (read, write) = stream.into_split();
+                (Box::new(read), Box::new(write))
+            }
+        };
+        let mut read = resp::Reader::new(read);
+        // The connection is set up before the handshake so that it can be traced under its ID
+        let conn = ConnectionState::new(None, Arc::clone(&self));
+        let tracer = conn.tracer();
 
         // PING command
-        Value::from_iter(["PING"])
-            .write_to(&mut write)
+        send_to_master(&mut write, &tracer, Value::from_iter(["PING"]))
             .await
             .context("sending PING in handshake")?;
 
-        let (pong, _) = resp::parse(&mut read)
+        let (pong, _) = read
+            .parse()
             .await
             .context("reading response to PING command")?;
+        tracer.received(read.last_frame());
 
-        ensure!(pong == serde_json::json!("PONG"));
-        eprintln!("received pong response from ping command");
+        ensure!(pong.as_str() == Some("PONG"));
+        debug!("received pong response from ping command");
 
-        Value::from_iter([
-            "REPLCONF",
-            "listening-port",
-            &self.listening_port.to_string(),
-        ])
-        .write_to(&mut write)
+        send_to_master(
+            &mut write,
+            &tracer,
+            Value::from_iter(["REPLCONF", "listening-port", &port]),
+        )
         .await
         .context("sending first REPLCONF in handshake")?;
 
-        let (ok, _) = resp::parse(&mut read)
+        let (ok, _) = read
+            .parse()
             .await
             .context("reading response from first REPLCONF command")?;
+        tracer.received(read.last_frame());
 
-        ensure!(ok == serde_json::json!("OK"));
-        eprintln!("received OK response from first REPLCONF command");
+        ensure!(ok.as_str() == Some("OK"));
+        debug!("received OK response from first REPLCONF command");
 
-        Value::from_iter(["REPLCONF", "capa", "psync2"])
-            .write_to(&mut write)
-            .await
-            .context("sending second REPLCONF in handshake")?;
+        send_to_master(
+            &mut write,
+            &tracer,
+            Value::from_iter(["REPLCONF", "capa", "psync2"]),
+        )
+        .await
+        .context("sending second REPLCONF in handshake")?;
 
-        let (ok, _) = resp::parse(&mut read)
+        let (ok, _) = read
+            .parse()
             .await
             .context("reading response from second REPLCONF command")?;
+        tracer.received(read.last_frame());
 
-        ensure!(ok == serde_json::json!("OK"));
-        eprintln!("received OK response from second REPLCONF command");
+        ensure!(ok.as_str() == Some("OK"));
+        debug!("received OK response from second REPLCONF command");
 
-        Value::from_iter(["PSYNC", "?", "-1"])
-            .write_to(&mut write)
+        send_to_master(&mut write, &tracer, Value::from_iter(["PSYNC", "?", "-1"]))
             .await
             .context("sending PSYNC in handshake")?;
 
-        let (ok, _) = resp::parse(&mut read)
+        let (ok, _) = read
+            .parse()
             .await
             .context("reading response from PSYNC command")?;
+        tracer.received(read.last_frame());
 
-        dbg!(&ok);
-        ensure!(ok.as_str().unwrap().starts_with("FULLRESYNC"));
-        eprintln!("received FULLRESYNC response from PSYNC command");
+        let fullresync = ok.as_str().context("PSYNC response should be a string")?;
+        ensure!(fullresync.starts_with("FULLRESYNC"));
+        debug!(
+            response = fullresync,
+            "received FULLRESYNC response from PSYNC command"
+        );
 
-        let _rdb = resp::get_rdb(&mut read)
+        // Our offset continues from wherever the master's replication stream is right now
+        let offset = fullresync
+            .rsplit_once(' ')
+            .context("FULLRESYNC response should contain an offset")?
+            .1
+            .parse()
+            .context("parsing FULLRESYNC offset")?;
+        self.replication_offset.store(offset, Ordering::SeqCst);
+
+        let rdb = read
+            .get_rdb()
             .await
             .context("reading rdb response from PSYNC command")?;
+        tracer.received(&rdb);
 
-        let state = Arc::clone(&self);
-        let conn = ConnectionState::new(None, state);
-        tokio::spawn(async move { conn.handle_connection(read, write).await.unwrap() });
+        tokio::spawn(
+            async move { conn.handle_connection(read, write).await.unwrap() }
+                .instrument(info_span!("master")),
+        );
+
+        // Let the master know we're still alive, so it can track our lag
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(1));
+            loop {
+                interval.tick().await;
+                let Some(ref tx) = *self.master_tx.read().await else {
+                    continue;
+                };
+                let offset = self.replication_offset.load(Ordering::SeqCst).to_string();
+                if tx
+                    .send(Value::from_iter(["REPLCONF", "ACK", &offset]))
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
 
         Ok(())
     }
 }
 
+/// Our connection to our master, which may or may not be over TLS
+type MasterRead = Box<dyn AsyncRead + Unpin + Send>;
+type MasterWrite = Box<dyn AsyncWrite + Unpin + Send>;
+
 #[derive(Debug, Clone, Copy, Default)]
 pub enum ConnectionMode {
     #[default]
@@ -240,23 +535,147 @@ pub enum ConnectionMode {
 
 #[derive(Debug)]
 pub struct ConnectionState {
+    /// Unique among every client since the server started, like Redis' client IDs
+    id: u64,
     addr: Option<SocketAddr>,
-    txn: Option<Vec<Vec<String>>>,
-    channels: HashSet<String>,
+    txn: Option<Vec<Vec<Bytes>>>,
+    /// Set when a command is rejected while queueing a transaction, so that `EXEC` discards it
+    txn_failed: bool,
+    /// The writes made by the commands that `EXEC` is running, which are propagated together once
+    /// it's done
+    txn_writes: Vec<Value>,
+    /// The keys this client is `WATCH`ing, with the version each had when it was watched
+    watching: HashMap<Bytes, u64>,
+    channels: HashSet<Bytes>,
+    patterns: HashSet<Bytes>,
     app_state: Arc<State>,
     mode: ConnectionMode,
+    /// The RESP version agreed with `HELLO`, shared with the task which writes replies so that it
+    /// knows how to encode them
+    protocol: Arc<AtomicU8>,
+    /// The user the client is authenticated as, which is `None` until it runs `AUTH` if the
+    /// `default` user needs a password
+    user: Option<String>,
+    /// The name given with `CLIENT SETNAME` or `HELLO SETNAME`
+    name: Option<String>,
+    connected: Instant,
+    /// When the last command was received
+    last_interaction: Instant,
+    /// The command being run, or which was run last, as `CLIENT LIST` names it
+    last_command: Option<String>,
+    /// Set once this connection is a replica which has synced with us
+    replica: bool,
+    /// Set while running a blocking command
+    blocked: bool,
     tx: Option<mpsc::UnboundedSender<Value>>,
+    /// Set by commands which must not be replied to, e.g. `REPLCONF ACK`
+    skip_reply: bool,
+    /// Set by commands which should reach replicas and the AOF as different commands than the
+    /// one which was run, e.g. so that relative expiries are sent as absolute ones
+    propagate_as: Option<Vec<Value>>,
+    /// Becomes `true` once the client hangs up, so that blocked commands can stop waiting
+    closed: Option<watch::Receiver<bool>>,
+}
+
+/// However a connection ends, including with an error or by its task being aborted, it stops being
+/// listed and leaves no subscriptions or watched keys behind
+impl Drop for ConnectionState {
+    fn drop(&mut self) {
+        self.unsubscribe_all();
+        self.unwatch_all();
+        self.app_state.clients.remove(self.id);
+    }
 }
 
 impl ConnectionState {
     pub fn new(addr: Option<SocketAddr>, app_state: Arc<State>) -> Self {
+        // Our master and the AOF don't need to authenticate
+        let user = (addr.is_none() || app_state.acl.is_open("default")).then(|| "default".into());
         Self {
+            id: app_state.next_client_id.fetch_add(1, Ordering::Relaxed),
             addr,
             txn: None,
+            txn_failed: false,
+            txn_writes: Vec::new(),
+            watching: Default::default(),
             channels: Default::default(),
+            patterns: Default::default(),
             app_state,
             mode: Default::default(),
+            protocol: Arc::new(AtomicU8::new(2)),
+            user,
+            name: None,
+            connected: Instant::now(),
+            last_interaction: Instant::now(),
+            last_command: None,
+            replica: false,
+            blocked: false,
             tx: None,
+            skip_reply: false,
+            propagate_as: None,
+            closed: None,
+        }
+    }
+
+    pub fn protocol(&self) -> u8 {
+        self.protocol.load(Ordering::SeqCst)
+    }
+
+    /// This connection's details, as `CLIENT LIST` shows them
+    pub fn client_info(&self) -> client::ClientInfo {
+        client::ClientInfo {
+            id: self.id,
+            addr: self.addr,
+            name: self.name.clone(),
+            connected: self.connected,
+            last_interaction: self.last_interaction,
+            command: self.last_command.clone(),
+            channels: self.channels.len(),
+            patterns: self.patterns.len(),
+            queued: self.txn.as_ref().map(Vec::len),
+            replica: self.replica,
+            blocked: self.blocked,
+            user: self.user.clone(),
+            protocol: self.protocol(),
+        }
+    }
+
+    /// Update this connection's entry in the registry of clients
+    fn publish_client_info(&self) {
+        self.app_state.clients.update(self.client_info());
+    }
+
+    fn tracer(&self) -> Tracer {
+        Tracer::new(Arc::clone(&self.app_state), self.id)
+    }
+
+    pub fn set_protocol(&self, protocol: u8) {
+        self.protocol.store(protocol, Ordering::SeqCst);
+    }
+
+    /// The mode which decides what commands may be run.  RESP3 clients can run anything while
+    /// subscribed, since replies can be told apart from the messages which are pushed to them.
+    pub fn command_mode(&self) -> ConnectionMode {
+        match self.mode {
+            ConnectionMode::Subscribed if self.protocol() >= 3 => ConnectionMode::Normal,
+            mode => mode,
+        }
+    }
+
+    /// What this client may send, which is very little until it authenticates, like Redis
+    fn limits(&self) -> resp::Limits {
+        if self.is_master() {
+            resp::Limits::NONE
+        } else if self.user.is_none() {
+            resp::Limits {
+                max_bulk_len: 16 * 1024,
+                max_multibulk_len: 10,
+            }
+        } else {
+            resp::Limits {
+                max_bulk_len: self.app_state.config().proto_max_bulk_len,
+                max_multibulk_len: self.app_state.config().proto_max_multibulk_len,
+            }
         }
     }
 
@@ -264,132 +683,347 @@ impl ConnectionState {
         self.addr.is_none()
     }
 
+    /// The command called `name`, which is by its own name for our master and the AOF, since
+    /// that's what we propagate, but as renamed by `rename-command` for everyone else
+    fn lookup_command(&self, name: &Bytes) -> Option<Command> {
+        if self.is_master() {
+            name.to_uppercase().parse().ok()
+        } else {
+            self.app_state.commands.lookup(name)
+        }
+    }
+
     pub fn tx(&self) -> &mpsc::UnboundedSender<Value> {
         // TODO: this unwrap hurts me
         self.tx.as_ref().unwrap()
     }
 
-    pub fn unsubscribe(&mut self, channel: &str) -> usize {
-        self.channels.remove(channel);
+    /// The number of channels and patterns this client is subscribed to
+    pub fn subscription_count(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+
+    /// Remove this client from the listeners on `name`
+    fn remove_listener(&self, listeners: &Listeners, name: &[u8]) {
         let tx = self.tx();
-        if let Some(mut channels) = self.app_state.channel_listeners.get_mut(channel) {
-            if let Some(idx) = channels
+        if let Some(mut listeners) = listeners.get_mut(name) {
+            if let Some(idx) = listeners
                 .iter()
                 .enumerate()
                 .find_map(|(i, c)| (c.same_channel(tx)).then_some(i))
             {
-                channels.swap_remove(idx);
+                listeners.swap_remove(idx);
             }
         }
+    }
 
-        let len = self.channels.len();
+    /// Leave subscribed mode if there's nothing left to listen to, returning the number of
+    /// subscriptions left
+    fn subscriptions_changed(&mut self) -> usize {
+        let len = self.subscription_count();
         if len == 0 {
             self.mode = ConnectionMode::Normal;
         }
         len
     }
 
+    pub fn unsubscribe(&mut self, channel: &[u8]) -> usize {
+        self.channels.remove(channel);
+        self.remove_listener(&self.app_state.channel_listeners, channel);
+        self.subscriptions_changed()
+    }
+
+    pub fn punsubscribe(&mut self, pattern: &[u8]) -> usize {
+        self.patterns.remove(pattern);
+        self.remove_listener(&self.app_state.pattern_listeners, pattern);
+        self.subscriptions_changed()
+    }
+
     pub fn unsubscribe_all(&mut self) {
-        let tx = self.tx();
-        for channel in &self.channels {
-            if let Some(mut channels) = self.app_state.channel_listeners.get_mut(channel) {
-                if let Some(idx) = channels
-                    .iter()
-                    .enumerate()
-                    .find_map(|(i, c)| (!c.same_channel(tx)).then_some(i))
-                {
-                    channels.swap_remove(idx);
-                }
-            }
+        for channel in std::mem::take(&mut self.channels) {
+            self.unsubscribe(&channel);
+        }
+        for pattern in std::mem::take(&mut self.patterns) {
+            self.punsubscribe(&pattern);
+        }
+    }
+
+    /// Stop watching every key, as after `EXEC`, `DISCARD` or `UNWATCH`
+    pub fn unwatch_all(&mut self) {
+        for key in self.watching.keys() {
+            self.app_state.map.unwatch(key);
         }
+        self.watching.clear();
     }
 
-    async fn run_command(&mut self, command: &[String]) -> anyhow::Result<Option<Value>> {
-        let (command, args) = command.split_first().expect("command length >= 1");
+    /// The reply to a command which was refused before it ran, which also fails the transaction
+    /// being queued, if there is one.  Our master doesn't want replies.
+    fn reject(&mut self, error: Value, context: ExecContext) -> Option<Value> {
+        if self.txn.is_some() && context == ExecContext::Normal {
+            self.txn_failed = true;
+        }
+        (!self.is_master()).then_some(error)
+    }
+
+    /// Check that the client may run `command`, given as `full_command` starting with its name:
+    /// that it has authenticated if it needs to, and that its user is allowed to
+    fn check_permissions(&self, command: Command, full_command: &[Bytes]) -> Result<(), Value> {
+        if self.is_master() || !command.needs_auth() {
+            return Ok(());
+        }
+        let Some(ref user) = self.user else {
+            return Err(Value::simple_error("NOAUTH Authentication required."));
+        };
+        self.app_state
+            .acl
+            .check(user, command, full_command)
+            .map_err(|denied| {
+                let name = client::command_name(command, full_command);
+                Value::simple_error(denied.error(user, &name))
+            })
+    }
+
+    /// Run a command.  This is boxed so that `EXEC` can run the commands it queued through here.
+    fn run_command<'a>(
+        &'a mut self,
+        command: &'a [Bytes],
+        context: ExecContext,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<Option<Value>>> + Send + 'a>> {
+        Box::pin(self.run_command_inner(command, context))
+    }
 
-        let command: Command = command.to_uppercase().parse().context("parsing command")?;
+    async fn run_command_inner(
+        &mut self,
+        command: &[Bytes],
+        context: ExecContext,
+    ) -> anyhow::Result<Option<Value>> {
+        let full_command = command;
+        let (name, args) = command.split_first().expect("command length >= 1");
+
+        let Some(command) = self.lookup_command(name) else {
+            return Ok(self.reject(command::unknown_command(name, args), context));
+        };
+        if !command.info().accepts(args.len() + 1) {
+            let error = command::wrong_arity(&command.to_str().to_lowercase()).into();
+            return Ok(self.reject(error, context));
+        }
+
+        Stats::incr(&self.app_state.stats.total_commands_processed);
+
+        if let Err(error) = self.check_permissions(command, full_command) {
+            return Ok(self.reject(error, context));
+        }
 
         if command.is_write() {
-            self.app_state
-                .replicas
-                .write()
-                .await
-                .retain(|replica| replica.send(command.into_command_value(args)).is_ok());
+            if !self.is_master()
+                && self.app_state.is_replica()
+                && self.app_state.config().replica_read_only
+            {
+                return Ok(Some(Value::simple_error(
+                    "READONLY You can't write against a read only replica.",
+                )));
+            }
+
+            if !self.is_master()
+                && !self.app_state.is_replica()
+                && !self.app_state.has_good_replicas().await
+            {
+                return Ok(Some(Value::simple_error(
+                    "NOREPLICAS Not enough good replicas to write.",
+                )));
+            }
         }
 
-        let ret = command.execute(self, args).await?;
+        // Writes hold the AOF lock while executing, so that a rewrite sees each write either in
+        // its snapshot or in the new incremental file, but never both.  Blocking commands can't
+        // hold it since they wait on other writes.
+        // Inside a transaction, `EXEC` holds it for the whole transaction instead.
+        let app_state = Arc::clone(&self.app_state);
+        let mut aof = match app_state.aof {
+            Some(ref aof)
+                if command.is_write()
+                    && !command.is_blocking()
+                    && context == ExecContext::Normal =>
+            {
+                Some(aof.lock().await)
+            }
+            _ => None,
+        };
+
+        let ret = command.execute(self, args, context).await?;
+        let propagate_as = self.propagate_as.take();
+
+        // Only writes which actually happened are fed to replicas and the AOF
+        let failed = matches!(ret, Value::SimpleError(_))
+            || (command.is_blocking() && matches!(ret, Value::Null) && propagate_as.is_none());
+        let propagate_write = command.is_write() && !failed;
+        if propagate_write {
+            app_state.dirty.fetch_add(1, Ordering::Relaxed);
+        }
+        if context == ExecContext::Transaction {
+            // `EXEC` propagates everything the transaction wrote together once it's done
+            self.txn_writes.extend(app_state.expired_dels());
+            if propagate_write {
+                self.txn_writes
+                    .extend(propagate_as.unwrap_or_else(|| vec![command.into_command_value(args)]));
+            }
+        } else if propagate_write || app_state.map.has_expired() {
+            if let (Some(ref aof_lock), None) = (&app_state.aof, &aof) {
+                aof = Some(aof_lock.lock().await);
+            }
+
+            // Replicas don't expire keys themselves, so any which expired while running the
+            // command (even if it was a read) are deleted there before the command runs.  They're
+            // taken with the AOF lock held so that they're ordered before any later writes.
+            let mut values = app_state.expired_dels();
+            if propagate_write {
+                values
+                    .extend(propagate_as.unwrap_or_else(|| vec![command.into_command_value(args)]));
+            }
+
+            app_state.propagate_all(aof, values).await?;
+        }
+
+        if std::mem::take(&mut self.skip_reply) {
+            return Ok(None);
+        }
 
         if command.send_response() {
-            eprintln!("send_response is true");
             return Ok(Some(ret));
         }
 
         if self.app_state.is_replica() && self.is_master() {
-            eprintln!("skipping response on master");
+            trace!("skipping response to master");
             return Ok(None);
         }
 
         Ok(Some(ret))
     }
 
-    async fn read_commands<R>(&mut self, mut r: R) -> anyhow::Result<()>
+    async fn read_commands<R>(&mut self, mut r: resp::Reader<R>) -> anyhow::Result<()>
     where
-        R: AsyncRead + AsyncBufRead + Unpin,
+        R: AsyncRead + Unpin,
     {
+        let (closed_tx, closed_rx) = watch::channel(false);
+        self.closed = Some(closed_rx);
+        let tracer = self.tracer();
+        let close = self
+            .app_state
+            .clients
+            .closer(self.id)
+            .context("connection should be registered")?;
+
         loop {
-            let filled = r.fill_buf().await.context("filling buf").unwrap();
+            let limits = self.limits();
+            let parsed = tokio::select! {
+                parsed = r.parse_limited(&limits) => parsed,
+                _ = close.notified() => {
+                    // Clients are closed once their user is deleted
+                    if self.user.as_ref().is_some_and(|user| !self.app_state.acl.exists(user)) {
+                        debug!("closing client of deleted user");
+                        return Ok(());
+                    }
+                    // We may have been asked just before the client sent something
+                    let timeout = Duration::from_secs(self.app_state.config().timeout);
+                    if timeout.is_zero() || !self.client_info().is_idle(timeout) {
+                        continue;
+                    }
+                    debug!("closing idle client");
+                    return Ok(());
+                }
+            };
+            let parsed = parsed.and_then(|parsed| {
+                parsed
+                    .map(|(value, bytes)| Ok((value.into_args()?, bytes)))
+                    .transpose()
+            });
+            let (full_command, bytes) = match parsed {
+                Ok(Some(parsed)) => {
+                    tracer.received(r.last_frame());
+                    parsed
+                }
+                Ok(None) => return Ok(()),
+                // Our master ought to know what it's doing, so there's no one to tell
+                Err(e) if self.is_master() => return Err(e).context("parsing command from master"),
+                Err(e) => match e.downcast::<ProtocolError>() {
+                    Ok(e) => {
+                        debug!("protocol error: {e}");
+                        self.tx().send(Value::simple_error(format!("ERR {e}")))?;
+                        if !e.recoverable {
+                            return Ok(());
+                        }
+                        continue;
+                    }
+                    Err(e) => return Err(e).context("parsing command"),
+                },
+            };
 
-            if filled.is_empty() {
-                return Ok(());
+            trace!(command = ?full_command, "received command");
+
+            // Empty commands are ignored, as Redis does
+            if full_command.is_empty() {
+                continue;
             }
 
-            let (value, bytes) = resp::parse(&mut r)
-                .await
-                .context("parsing command")
-                .unwrap();
-
-            let full_command: Vec<String> =
-                serde_json::from_value(value).context("parsing command")?;
-
-            eprintln!(
-                "[{}:{}:{}] received command = {:?}",
-                file!(),
-                line!(),
-                column!(),
-                &full_command
-            );
-
-            // TODO: handle error
-            assert!(!full_command.is_empty());
-
-            let ret = if let Some(ref mut txn_inner) = self.txn {
-                let command = full_command.first().expect("command length >= 1");
-                if command.eq_ignore_ascii_case("exec") {
-                    let mut ret = Vec::with_capacity(txn_inner.len());
-                    let txn_inner = self.txn.take().unwrap();
-                    for cmd in txn_inner {
-                        // TODO: don't unwrap
-                        ret.push(self.run_command(&cmd).await?.unwrap());
+            self.last_interaction = Instant::now();
+            let command = self.lookup_command(&full_command[0]);
+            self.last_command = command.map(|command| client::command_name(command, &full_command));
+            self.blocked = command.is_some_and(Command::is_blocking);
+            self.publish_client_info();
+
+            // While a command runs, watch for the client hanging up, so that blocked commands
+            // don't wait for (and take items meant for) a client which is gone
+            let ret: anyhow::Result<Option<Value>> = {
+                let run = async {
+                    // Inside `MULTI`, most commands are queued to be run by `EXEC`, but those we
+                    // don't know (including those disabled by `rename-command`), which have the
+                    // wrong number of arguments, or which the client may not run are rejected
+                    // straight away
+                    let queue = self.txn.is_some()
+                        && command.is_some_and(|command| {
+                            !command.runs_in_multi()
+                                && command.info().accepts(full_command.len())
+                                && self.check_permissions(command, &full_command).is_ok()
+                        });
+                    match self.txn {
+                        Some(ref mut queued) if queue => {
+                            queued.push(full_command.clone());
+                            // Our master doesn't want replies
+                            Ok((!self.is_master()).then(|| Value::simple_string("QUEUED")))
+                        }
+                        _ => self.run_command(&full_command, ExecContext::Normal).await,
+                    }
+                };
+                tokio::pin!(run);
+
+                let mut watching = true;
+                loop {
+                    tokio::select! {
+                        biased;
+                        ret = &mut run => break ret,
+                        more = r.fill(), if watching => {
+                            watching = false;
+                            if !more.unwrap_or(false) {
+                                let _ = closed_tx.send(true);
+                            }
+                        }
                     }
-                    self.txn = None;
-                    Some(Value::from(ret))
-                } else if command.eq_ignore_ascii_case("discard") {
-                    self.txn = None;
-                    Some(Value::simple_string("OK"))
-                } else {
-                    txn_inner.push(full_command.clone());
-                    Some(Value::simple_string("QUEUED"))
                 }
-            } else {
-                self.run_command(&full_command).await?
             };
 
+            // Every byte received from our master counts towards the replication offset, even if
+            // the command itself failed, so that our ACKs line up with the master's offset.
             if self.is_master() {
                 self.app_state
                     .replication_offset
                     .fetch_add(bytes, Ordering::SeqCst);
             }
 
+            let ret = ret?;
+            // The command may have changed the client's name, subscriptions and so on
+            self.blocked = false;
+            self.publish_client_info();
+
             if let Some(ret) = ret {
                 self.tx()
                     .send(ret)
@@ -398,56 +1032,208 @@ impl ConnectionState {
         }
     }
 
-    async fn handle_connection<R, W>(mut self, read: R, mut write: W) -> anyhow::Result<()>
+    async fn handle_connection<R, W>(
+        mut self,
+        read: resp::Reader<R>,
+        mut write: W,
+    ) -> anyhow::Result<()>
     where
-        R: AsyncBufRead + Unpin + Send + 'static,
+        R: AsyncRead + Unpin + Send + 'static,
         W: AsyncWrite + Unpin,
     {
-        if let Some(addr) = &self.addr {
-            eprintln!("accepted new connection: {addr}");
+        if self.is_master() {
+            debug!("accepted connection with master");
         } else {
-            eprintln!("accepted new connection with master");
+            debug!("accepted connection");
         }
 
         let (tx, mut rx) = mpsc::unbounded_channel::<Value>();
         self.tx = Some(tx);
+        self.publish_client_info();
 
-        if self.app_state.is_replica() {
+        if self.app_state.is_replica() && self.is_master() {
             *self.app_state.master_tx.write().await = Some(self.tx().clone());
         }
 
         let addr = self.addr;
-        let read_cmd_handle =
-            tokio::spawn(async move { self.read_commands(read).await.map(|_| self) });
-
-        while let Some(value) = rx.recv().await {
-            eprintln!(
-                "[{}:{}:{}] sending value    = {:?}",
-                file!(),
-                line!(),
-                column!(),
-                &value
-            );
-            value
-                .write_to(&mut write)
-                .await
-                .with_context(|| format!("sending value: {value:?}"))?;
+        let protocol = Arc::clone(&self.protocol);
+        let tracer = self.tracer();
+        let mut read_cmd_handle = tokio::spawn(
+            async move { self.read_commands(read).await.map(|_| self) }.in_current_span(),
+        );
+
+        // The reader hands back the connection state, which still holds the sender, so it
+        // finishing is what ends the connection rather than the channel closing
+        let this = loop {
+            tokio::select! {
+                biased;
+                Some(value) = rx.recv() => {
+                    if let Err(err) = send_replies(&mut write, value, &mut rx, &protocol, &tracer).await {
+                        // Dropping the connection state along with the reader cleans up after it
+                        read_cmd_handle.abort();
+                        return Err(err);
+                    }
+                }
+                this = &mut read_cmd_handle => break this??,
+            }
+        };
+        // Nothing else will be read, but there may be replies left to send, e.g. for a protocol
+        // error
+        if let Ok(value) = rx.try_recv() {
+            send_replies(&mut write, value, &mut rx, &protocol, &tracer).await?;
         }
+        drop(this);
 
-        if !read_cmd_handle.is_finished() {
-            eprintln!("Waiting for 'read_cmd_handle' to be finished");
+        if addr.is_some() {
+            debug!("connection closed");
+        } else {
+            debug!("connection with master closed");
         }
-        let mut this = read_cmd_handle.await??;
 
-        this.unsubscribe_all();
+        Ok(())
+    }
+}
 
-        if let Some(addr) = addr {
-            eprintln!("Connection terminated: {addr}");
+/// The most encoded replies to hold on to before writing them
+const MAX_REPLY_BATCH: usize = 64 * 1024;
+
+/// Write `value` and any other replies already waiting in `rx`, for a client which speaks the RESP
+/// version in `protocol`.  Pipelined commands produce many replies at once, so they're encoded
+/// together and sent with as few writes as possible.
+async fn send_replies<W>(
+    write: &mut W,
+    value: Value,
+    rx: &mut mpsc::UnboundedReceiver<Value>,
+    protocol: &AtomicU8,
+    tracer: &Tracer,
+) -> anyhow::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = BytesMut::new();
+    let mut next = Some(value);
+    while let Some(value) = next.take().or_else(|| rx.try_recv().ok()) {
+        trace!(?value, "sending value");
+        // `HELLO` may have switched protocol since the last reply
+        let protocol = protocol.load(Ordering::SeqCst);
+        if value.is_streamed() {
+            // Whatever came before goes first, then this is sent as its parts arrive
+            write.write_all(&buf).await.context("sending replies")?;
+            buf.clear();
+            tracer.sent_streamed();
+            value.write_as(write, protocol).await
         } else {
-            eprintln!("Connection with master terminated");
+            let start = buf.len();
+            value
+                .encode_into(&mut buf, protocol)
+                .inspect(|()| tracer.sent(&buf[start..]))
         }
+        .with_context(|| format!("sending value: {value:?}"))?;
 
-        Ok(())
+        if buf.len() >= MAX_REPLY_BATCH {
+            write.write_all(&buf).await.context("sending replies")?;
+            buf.clear();
+        }
+    }
+
+    write.write_all(&buf).await.context("sending replies")?;
+    Ok(())
+}
+
+/// Send a command to our master during the handshake
+async fn send_to_master<W>(write: &mut W, tracer: &Tracer, command: Value) -> anyhow::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = BytesMut::new();
+    command.encode_into(&mut buf, 2)?;
+    tracer.sent(&buf);
+    write.write_all(&buf).await?;
+    Ok(())
+}
+
+/// Re-run this program detached from the terminal, then exit.  The child gets the same arguments
+/// minus `--daemonize`, and a pidfile is always written when daemonized.
+fn daemonize(config: &Config) -> anyhow::Result<()> {
+    // Later options win, so this overrides `daemonize` wherever it was set
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    args.extend(["--daemonize".into(), "no".into()]);
+    if config.pidfile.is_none() {
+        args.extend(["--pidfile".into(), "/var/run/redis.pid".into()]);
+    }
+
+    let exe = std::env::current_exe().context("finding current executable")?;
+    std::process::Command::new(exe)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .process_group(0)
+        .spawn()
+        .context("spawning daemon process")?;
+
+    std::process::exit(0);
+}
+
+async fn shutdown(state: &State) -> anyhow::Result<()> {
+    info!("received shutdown signal, shutting down");
+    if let Err(err) = systemd::notify(&state.config(), "STOPPING=1") {
+        warn!("error notifying systemd that we're stopping: {err}");
+    }
+
+    if let Some(ref aof) = state.aof {
+        aof.lock().await.sync().await?;
+    }
+
+    // Like Redis, only save on the way out if saving is configured at all
+    if !state.config().save.is_empty() {
+        info!("saving the final rdb snapshot before exiting");
+        state.save().await?;
+    }
+
+    let pidfile = state.config().pidfile.clone();
+    if let Some(ref pidfile) = pidfile {
+        tokio::fs::remove_file(pidfile)
+            .await
+            .with_context(|| format!("removing pidfile {}", pidfile.display()))?;
+    }
+
+    Ok(())
+}
+
+/// Listen on `port`, unless it's 0
+async fn bind(port: u16) -> anyhow::Result<Option<TcpListener>> {
+    if port == 0 {
+        return Ok(None);
+    }
+    let addr = format!("127.0.0.1:{port}");
+    let listener = TcpListener::bind(&addr)
+        .await
+        .with_context(|| format!("listening on {addr}"))?;
+    info!("ready to accept connections at {addr}");
+    Ok(Some(listener))
+}
+
+/// Apply `tcp-keepalive` and `tcp-nodelay` to a new connection
+fn tune_socket(stream: &TcpStream, config: &Config) -> std::io::Result<()> {
+    stream.set_nodelay(config.tcp_nodelay)?;
+    if config.tcp_keepalive > 0 {
+        // Like Redis, give up after three probes, a third of the keepalive time apart
+        let time = Duration::from_secs(config.tcp_keepalive);
+        let keepalive = socket2::TcpKeepalive::new()
+            .with_time(time)
+            .with_interval((time / 3).max(Duration::from_secs(1)))
+            .with_retries(3);
+        socket2::SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+    }
+    Ok(())
+}
+
+/// Accept a connection from `listener`, or never if there isn't one
+async fn accept(listener: &Option<TcpListener>) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
     }
 }
 
@@ -455,62 +1241,76 @@ impl ConnectionState {
 async fn main() -> anyhow::Result<()> {
     let mut args = std::env::args();
     let program = args.next().expect("program is required");
-
-    let print_usage = || -> ! {
-        eprintln!("Usage: {program} [--port|-p <port>] [--replicaof <hostname port>]");
-        std::process::exit(1);
-    };
-
-    let mut port = 6379;
-    let mut master: Option<String> = None;
-    let mut dir: Option<PathBuf> = None;
-    let mut db_filename: Option<String> = None;
-    while let Some(arg) = args.next() {
-        match &*arg {
-            "--port" | "-p" => {
-                let Some(port_str) = args.next() else {
-                    print_usage();
-                };
-                port = port_str.parse().context("malformed port")?;
-            }
-            "--replicaof" if master.is_none() => {
-                let Some(master_str) = args.next() else {
-                    print_usage();
-                };
-                let (host, port) = master_str
-                    .split_once(' ')
-                    .context("malformed master server string")?;
-                master = Some(format!("{host}:{port}"));
+    let args: Vec<String> = args.collect();
+
+    match args.first().map(|arg| &**arg) {
+        Some("-h" | "--help") => {
+            eprintln!("Usage: {program} [/path/to/redis.conf] [--<parameter> <value>...]");
+            eprintln!("       {program} -v | --version");
+            eprintln!("       {program} -h | --help");
+            eprintln!();
+            eprintln!("Examples:");
+            eprintln!("       {program} --port 7777");
+            eprintln!("       {program} --replicaof 127.0.0.1 8888");
+            eprintln!("       {program} /etc/redis/6379.conf --appendonly yes --save \"\"");
+            eprintln!();
+            eprintln!("Parameters:");
+            for param in config::PARAMS {
+                eprintln!("       {}", param.name);
             }
-            "--dir" if dir.is_none() => {
-                let Some(dir_str) = args.next() else {
-                    print_usage();
-                };
-                dir = Some(PathBuf::from(dir_str));
-            }
-            "--dbfilename" if db_filename.is_none() => {
-                let Some(name) = args.next() else {
-                    print_usage();
-                };
-                db_filename = Some(name);
-            }
-            _ => bail!("Unexpected argument: {arg}"),
+            eprintln!("       rename-command");
+            std::process::exit(1);
+        }
+        Some("-v" | "--version") => {
+            println!("Redis server v={}", env!("CARGO_PKG_VERSION"));
+            return Ok(());
         }
+        _ => {}
     }
 
-    let mut state = State::new(
-        master.map(Role::Replica).unwrap_or(Role::Master),
-        port,
-        dir.clone(),
-        db_filename.clone(),
-    );
+    let config = Config::from_args(args).context("invalid arguments, see --help")?;
 
-    if let Some(ref dir) = dir {
-        let path = dir.join(
-            db_filename
-                .as_ref()
-                .expect("dir and dbfilename should both be specified"),
-        );
+    let log = log::init(&config)?;
+
+    if config.daemonize {
+        daemonize(&config)?;
+    }
+
+    if let Some(ref pidfile) = config.pidfile {
+        tokio::fs::write(pidfile, format!("{}\n", std::process::id()))
+            .await
+            .with_context(|| format!("writing pidfile {}", pidfile.display()))?;
+    }
+
+    let aof = if config.appendonly {
+        let aof_dir = config
+            .dir
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("."))
+            .join(&config.appenddirname);
+        Some(aof::Aof::open(aof_dir, config.appendfilename.clone(), config.appendfsync).await?)
+    } else {
+        None
+    };
+
+    let port = config.port;
+    let tls_port = config.tls_port;
+    let tls_acceptor = tls::acceptor(&config).context("setting up TLS")?;
+    let aclfile = config.aclfile.clone();
+    let mut state = State::new(config, aof, log);
+    if let Some(path) = aclfile {
+        let contents = tokio::fs::read_to_string(&path)
+            .await
+            .with_context(|| format!("reading ACL file {}", path.display()))?;
+        state
+            .acl
+            .load(&contents)
+            .with_context(|| format!("loading users from {}", path.display()))?;
+    }
+
+    // With AOF enabled, the AOF is the source of truth and the RDB file is ignored
+    if !state.config().appendonly {
+        let path = state.config().rdb_path();
         if tokio::fs::try_exists(&path)
             .await
             .with_context(|| format!("checking where {} exists", path.display()))?
@@ -525,27 +1325,167 @@ async fn main() -> anyhow::Result<()> {
 
     let state = Arc::new(state);
 
+    aof::load(&state)
+        .await
+        .context("loading append only file")?;
+
+    if let Some(ref aof) = state.aof {
+        if aof.lock().await.fsync_policy() == aof::FsyncPolicy::EverySec {
+            let state = Arc::clone(&state);
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(Duration::from_secs(1));
+                loop {
+                    interval.tick().await;
+                    let Some(ref aof) = state.aof else {
+                        unreachable!();
+                    };
+                    if let Err(err) = aof.lock().await.sync().await {
+                        warn!("error syncing append only file: {err:?}");
+                    }
+                }
+            });
+        }
+    }
+
+    // Save the RDB file whenever one of the `save` points is reached
+    {
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(1));
+            loop {
+                interval.tick().await;
+                if !state.should_save() {
+                    continue;
+                }
+                let dirty = state.dirty.load(Ordering::Relaxed);
+                info!("{dirty} changes since the last save, saving");
+                match state.save().await {
+                    Ok(()) => info!("db saved on disk"),
+                    Err(err) => warn!("error saving the rdb file: {err:?}"),
+                }
+            }
+        });
+    }
+
+    // Close clients which have been idle for longer than `timeout`
+    {
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(1));
+            loop {
+                interval.tick().await;
+                let timeout = Duration::from_secs(state.config().timeout);
+                if timeout.is_zero() {
+                    continue;
+                }
+                for client in state.clients.list() {
+                    if client.is_idle(timeout) {
+                        state.clients.close(client.id);
+                    }
+                }
+            }
+        });
+    }
+
+    // Actively expire keys, so ones which are never accessed again don't stick around forever
+    {
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_millis(100));
+            loop {
+                interval.tick().await;
+                let aof = match state.aof {
+                    Some(ref aof) => Some(aof.lock().await),
+                    None => None,
+                };
+                let expired = state.map.remove_expired();
+                if expired == 0 {
+                    continue;
+                }
+                debug!("actively expired {expired} keys");
+
+                if let Err(err) = state.propagate_all(aof, state.expired_dels()).await {
+                    warn!("error propagating expired keys: {err:?}");
+                }
+            }
+        });
+    }
+
     if state.is_replica() {
         let state = Arc::clone(&state);
         state.do_handshake().await?;
     }
 
-    let addr = format!("127.0.0.1:{port}");
-    let listener = TcpListener::bind(&addr).await?;
+    // Like Redis, port 0 means only accepting connections over TLS
+    let listener = bind(port).await?;
+    let tls_listener = match tls_acceptor {
+        Some(_) => bind(tls_port).await?,
+        None => None,
+    };
+    ensure!(
+        listener.is_some() || tls_listener.is_some(),
+        "nothing to listen on, since port is 0 and tls-port isn't set"
+    );
 
-    eprintln!("Listening for connections at {addr}.");
+    // Everything has been loaded by now, so we're ready as soon as we're listening
+    if let Err(err) = systemd::notify(
+        &state.config(),
+        "READY=1\nSTATUS=Ready to accept connections",
+    ) {
+        warn!("error notifying systemd that we're ready: {err}");
+    }
+
+    let mut sigterm = signal(SignalKind::terminate()).context("listening for SIGTERM")?;
+    let mut sigint = signal(SignalKind::interrupt()).context("listening for SIGINT")?;
 
     loop {
-        let (stream, addr) = listener.accept().await?;
+        let (stream, addr, tls) = tokio::select! {
+            accepted = accept(&listener) => {
+                let (stream, addr) = accepted?;
+                (stream, addr, None)
+            }
+            accepted = accept(&tls_listener) => {
+                let (stream, addr) = accepted?;
+                (stream, addr, tls_acceptor.clone())
+            }
+            _ = sigterm.recv() => break,
+            _ = sigint.recv() => break,
+        };
+        Stats::incr(&state.stats.total_connections_received);
+        if let Err(err) = tune_socket(&stream, &state.config()) {
+            warn!("error setting up connection from {addr}: {err}");
+        }
+
         let state = Arc::clone(&state);
-        tokio::spawn(async move {
-            let (read, write) = stream.into_split();
-            let read = BufReader::new(read);
-            let connection = ConnectionState::new(Some(addr), state);
-            match connection.handle_connection(read, write).await {
-                Ok(()) => {}
-                Err(err) => eprintln!("Error handling connection: {err:?}"),
+        let connection = ConnectionState::new(Some(addr), state);
+        let span = info_span!("client", id = connection.id, %addr);
+        tokio::spawn(
+            async move {
+                let result = match tls {
+                    Some(acceptor) => match acceptor.accept(stream).await {
+                        Ok(stream) => {
+                            let (read, write) = tokio::io::split(stream);
+                            let read = resp::Reader::new(read);
+                            connection.handle_connection(read, write).await
+                        }
+                        Err(err) => {
+                            debug!("error accepting TLS connection: {err}");
+                            return;
+                        }
+                    },
+                    None => {
+                        let (read, write) = stream.into_split();
+                        let read = resp::Reader::new(read);
+                        connection.handle_connection(read, write).await
+                    }
+                };
+                if let Err(err) = result {
+                    warn!("error handling connection: {err:?}");
+                }
             }
-        });
+            .instrument(span),
+        );
     }
+
+    shutdown(&state).await
 }
@@ -1,9 +1,36 @@
-use std::time::{Duration, SystemTime};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::{bail, ensure, Context};
 use tokio::io::{AsyncBufRead, AsyncReadExt};
+use tracing::debug;
 
-use crate::{MapValueContent, State};
+use crate::{
+    bytes::Bytes,
+    command::expire::unix_millis,
+    listpack,
+    stream::{Consumer, ConsumerGroup, PendingEntry, Stream, StreamId, NODE_MAX_ENTRIES},
+    zset::SortedSet,
+    HashField, MapValue, MapValueContent, SetEntry, State,
+};
+
+// The types of values, as numbered by Redis
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_STREAM_LISTPACKS: u8 = 15;
+/// A hash with expiry times on its fields, relative to the earliest of them, from RDB version 12
+const TYPE_HASH_METADATA: u8 = 24;
+
+// Flags on the entries of a stream node.  `SAMEFIELDS` entries have the same fields as the node's
+// master entry, so only their values are stored.
+const STREAM_ITEM_FLAG_DELETED: i64 = 1;
+const STREAM_ITEM_FLAG_SAMEFIELDS: i64 = 2;
 
 #[derive(Debug, Clone, Copy)]
 pub enum DecodedValue<'a> {
@@ -14,15 +41,41 @@ pub enum DecodedValue<'a> {
     I32(i32),
 }
 
-impl DecodedValue<'_> {
-    fn unwrap_string(&self) -> anyhow::Result<&str> {
-        match self {
-            DecodedValue::Bytes(_) => bail!("Got bytes, expected string"),
-            DecodedValue::String(s) => Ok(s),
-            DecodedValue::I8(_) => bail!("Got i8, expected string"),
-            DecodedValue::I16(_) => bail!("Got i16, expected string"),
-            DecodedValue::I32(_) => bail!("Got i32, expected string"),
+/// A length, or the special encoding of a string that follows in its place
+enum Length {
+    Len(usize),
+    Special(u8),
+}
+
+async fn read_length_encoded<R>(mut r: R) -> anyhow::Result<Length>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let first = r.read_u8().await.context("reading length")?;
+    let bottom_bits = first & 0b0011_1111;
+    Ok(Length::Len(match first >> 6 {
+        0b00 => bottom_bits as usize,
+        0b01 => {
+            let second = r.read_u8().await.context("reading second byte of length")?;
+            (bottom_bits as usize) << 8 | second as usize
         }
+        0b10 => match first {
+            0x80 => r.read_u32().await.context("reading 32-bit length")? as usize,
+            0x81 => r.read_u64().await.context("reading 64-bit length")? as usize,
+            _ => bail!("Unknown length encoding: 0x{first:02x}"),
+        },
+        0b11 => return Ok(Length::Special(bottom_bits)),
+        _ => unreachable!("first >> 6 is only two bits"),
+    }))
+}
+
+async fn read_length<R>(r: R) -> anyhow::Result<usize>
+where
+    R: AsyncBufRead + Unpin,
+{
+    match read_length_encoded(r).await? {
+        Length::Len(len) => Ok(len),
+        Length::Special(_) => bail!("expected a length, got a specially encoded string"),
     }
 }
 
@@ -30,35 +83,27 @@ async fn read_string_encoded<R>(mut r: R, buf: &mut Vec<u8>) -> anyhow::Result<D
 where
     R: AsyncBufRead + Unpin,
 {
-    let len = r.read_u8().await.context("reading length of string")?;
-    let kind = len >> 6;
-    let bottom_bits = len & 0b0011_1111;
-    let len = match kind {
-        0b00 => bottom_bits as usize,
-        0b01 => u16::from_le_bytes([
-            bottom_bits,
-            r.read_u8().await.context("reading second byte of length")?,
-        ]) as usize,
-        0b10 => r.read_u32_le().await.context("reading length")? as usize,
-        0b11 => match bottom_bits {
-            0 => {
-                return Ok(DecodedValue::I8(
-                    r.read_i8().await.context("reading 8-bit number")?,
-                ));
-            }
-            1 => {
-                return Ok(DecodedValue::I16(
-                    r.read_i16_le().await.context("reading 16-bit number")?,
-                ));
-            }
-            2 => {
-                return Ok(DecodedValue::I32(
-                    r.read_i32_le().await.context("reading 32-bit number")?,
-                ));
-            }
-            _ => bail!("Unknown special encoding of string: {}", bottom_bits),
-        },
-        _ => unreachable!("kind = len >> 6, so len is only two bits"),
+    let len = match read_length_encoded(&mut r)
+        .await
+        .context("reading length of string")?
+    {
+        Length::Len(len) => len,
+        Length::Special(0) => {
+            return Ok(DecodedValue::I8(
+                r.read_i8().await.context("reading 8-bit number")?,
+            ));
+        }
+        Length::Special(1) => {
+            return Ok(DecodedValue::I16(
+                r.read_i16_le().await.context("reading 16-bit number")?,
+            ));
+        }
+        Length::Special(2) => {
+            return Ok(DecodedValue::I32(
+                r.read_i32_le().await.context("reading 32-bit number")?,
+            ));
+        }
+        Length::Special(encoding) => bail!("Unknown special encoding of string: {encoding}"),
     };
 
     buf.resize(len, 0);
@@ -74,6 +119,37 @@ where
     }
 }
 
+/// Read a string, with integer encodings turned back into their digits
+async fn read_bytes<R>(r: R) -> anyhow::Result<Vec<u8>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut buf = Vec::new();
+    Ok(match read_string_encoded(r, &mut buf).await? {
+        DecodedValue::Bytes(_) | DecodedValue::String(_) => buf,
+        DecodedValue::I8(n) => n.to_string().into_bytes(),
+        DecodedValue::I16(n) => n.to_string().into_bytes(),
+        DecodedValue::I32(n) => n.to_string().into_bytes(),
+    })
+}
+
+async fn read_string<R>(r: R) -> anyhow::Result<String>
+where
+    R: AsyncBufRead + Unpin,
+{
+    String::from_utf8(read_bytes(r).await?).context("expected a UTF-8 string")
+}
+
+/// Read a stream ID, which is stored as two big-endian integers
+async fn read_stream_id<R>(mut r: R) -> anyhow::Result<StreamId>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let ms = r.read_u64().await.context("reading stream ID millis")?;
+    let seq = r.read_u64().await.context("reading stream ID sequence")?;
+    Ok((ms, seq))
+}
+
 async fn read_kv_pair<R>(mut r: R, buf: &mut Vec<u8>) -> anyhow::Result<(String, DecodedValue<'_>)>
 where
     R: AsyncBufRead + Unpin,
@@ -94,6 +170,282 @@ where
     Ok((key, value))
 }
 
+/// An element of a listpack, the format Redis uses for the nodes of a stream
+#[derive(Debug, Clone, Copy)]
+enum ListpackItem<'a> {
+    Int(i64),
+    Str(&'a [u8]),
+}
+
+impl ListpackItem<'_> {
+    fn int(&self) -> anyhow::Result<i64> {
+        match self {
+            Self::Int(n) => Ok(*n),
+            Self::Str(s) => str::from_utf8(s)
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .context("expected an integer in listpack"),
+        }
+    }
+
+    fn bytes(&self) -> Bytes {
+        match self {
+            Self::Int(n) => n.to_string().into(),
+            Self::Str(s) => Bytes::from(*s),
+        }
+    }
+}
+
+/// The number of bytes taken by the back-length of an element which is `len` bytes long
+fn listpack_backlen_size(len: usize) -> usize {
+    match len {
+        0..128 => 1,
+        128..16383 => 2,
+        16383..2097151 => 3,
+        2097151..268435455 => 4,
+        _ => 5,
+    }
+}
+
+fn read_listpack(lp: &[u8]) -> anyhow::Result<Vec<ListpackItem<'_>>> {
+    ensure!(lp.len() >= 7, "listpack is too short for its header");
+    let mut at = 6;
+    let mut items = Vec::new();
+    loop {
+        let rest = lp.get(at..).context("listpack is missing its end")?;
+        let (&first, _) = rest.split_first().context("listpack is missing its end")?;
+        if first == 0xff {
+            break;
+        }
+
+        let int = |len: usize| -> anyhow::Result<i64> {
+            let bytes = rest
+                .get(1..1 + len)
+                .context("listpack integer is truncated")?;
+            let mut buf = [0; 8];
+            buf[..len].copy_from_slice(bytes);
+            // Sign extend from `len` bytes
+            let shift = 64 - 8 * len as u32;
+            Ok(i64::from_le_bytes(buf) << shift >> shift)
+        };
+        let byte = |i: usize| rest.get(i).copied().context("truncated listpack entry");
+        let str = |start: usize, len: usize| -> anyhow::Result<ListpackItem<'_>> {
+            let bytes = rest
+                .get(start..start + len)
+                .context("truncated listpack entry")?;
+            Ok(ListpackItem::Str(bytes))
+        };
+        let (item, len) = match first {
+            0x00..=0x7f => (ListpackItem::Int(first as i64), 1),
+            0x80..=0xbf => {
+                let len = (first & 0x3f) as usize;
+                (str(1, len)?, 1 + len)
+            }
+            0xc0..=0xdf => {
+                let n = ((first & 0x1f) as i64) << 8 | byte(1)? as i64;
+                // Sign extend from 13 bits
+                (
+                    ListpackItem::Int(if n >= 1 << 12 { n - (1 << 13) } else { n }),
+                    2,
+                )
+            }
+            0xe0..=0xef => {
+                let len = ((first & 0x0f) as usize) << 8 | byte(1)? as usize;
+                (str(2, len)?, 2 + len)
+            }
+            0xf0 => {
+                let len = rest.get(1..5).context("truncated listpack entry")?;
+                let len = u32::from_le_bytes(len.try_into()?) as usize;
+                (str(5, len)?, 5 + len)
+            }
+            0xf1 => (ListpackItem::Int(int(2)?), 3),
+            0xf2 => (ListpackItem::Int(int(3)?), 4),
+            0xf3 => (ListpackItem::Int(int(4)?), 5),
+            0xf4 => (ListpackItem::Int(int(8)?), 9),
+            _ => bail!("Unknown listpack encoding: 0x{first:02x}"),
+        };
+        items.push(item);
+        at += len + listpack_backlen_size(len);
+    }
+    Ok(items)
+}
+
+/// Read the entries of a stream node, adding them to `stream`
+fn read_stream_node(stream: &mut Stream, master_id: StreamId, lp: &[u8]) -> anyhow::Result<()> {
+    let items = read_listpack(lp)?;
+    let mut items = items.iter();
+    let mut next = || items.next().context("stream node is truncated");
+
+    // The master entry: the counts of live and deleted entries, then the master fields
+    let count = next()?.int()? + next()?.int()?;
+    let master_fields = (0..next()?.int()?)
+        .map(|_| Ok(next()?.bytes()))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    ensure!(next()?.int()? == 0, "stream master entry isn't terminated");
+
+    for _ in 0..count {
+        let flags = next()?.int()?;
+        let ms = master_id.0.wrapping_add(next()?.int()? as u64);
+        let seq = master_id.1.wrapping_add(next()?.int()? as u64);
+        let mut fields = Vec::new();
+        if flags & STREAM_ITEM_FLAG_SAMEFIELDS != 0 {
+            for field in &master_fields {
+                fields.push(field.clone());
+                fields.push(next()?.bytes());
+            }
+        } else {
+            for _ in 0..next()?.int()? * 2 {
+                fields.push(next()?.bytes());
+            }
+        }
+        // The number of elements in the entry, which is only for walking backwards
+        next()?;
+
+        if flags & STREAM_ITEM_FLAG_DELETED == 0 {
+            stream.insert((ms, seq), &fields);
+        }
+    }
+    Ok(())
+}
+
+async fn read_stream<R>(mut r: R) -> anyhow::Result<Stream>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut stream = Stream::default();
+    for _ in 0..read_length(&mut r).await? {
+        let master_id = read_bytes(&mut r)
+            .await
+            .context("reading stream node key")?;
+        let master_id = read_stream_id(&master_id[..]).await?;
+        let lp = read_bytes(&mut r).await.context("reading stream node")?;
+        read_stream_node(&mut stream, master_id, &lp).context("reading stream node")?;
+    }
+
+    let len = read_length(&mut r).await?;
+    ensure!(
+        len == stream.len(),
+        "stream length doesn't match its entries"
+    );
+    let last_id = (
+        read_length(&mut r).await? as u64,
+        read_length(&mut r).await? as u64,
+    );
+    stream.set_last_id(last_id);
+
+    for _ in 0..read_length(&mut r).await? {
+        let name = read_string(&mut r).await.context("reading group name")?;
+        let last_delivered = (
+            read_length(&mut r).await? as u64,
+            read_length(&mut r).await? as u64,
+        );
+        let mut group = ConsumerGroup::new(last_delivered);
+
+        // The group's pending entries don't say which consumer they belong to, the consumers do
+        let mut deliveries = HashMap::new();
+        for _ in 0..read_length(&mut r).await? {
+            let id = read_stream_id(&mut r).await?;
+            let delivered_at = r.read_u64_le().await.context("reading delivery time")?;
+            let delivery_count = read_length(&mut r).await? as u64;
+            deliveries.insert(id, (delivered_at as i64, delivery_count));
+        }
+
+        for _ in 0..read_length(&mut r).await? {
+            let consumer = read_string(&mut r).await.context("reading consumer name")?;
+            let seen_at = r
+                .read_u64_le()
+                .await
+                .context("reading consumer seen time")?;
+            for _ in 0..read_length(&mut r).await? {
+                let id = read_stream_id(&mut r).await?;
+                let (delivered_at, delivery_count) = deliveries
+                    .remove(&id)
+                    .context("consumer's pending entry isn't pending in its group")?;
+                group.pending.insert(
+                    id,
+                    PendingEntry {
+                        consumer: consumer.clone(),
+                        delivered_at,
+                        delivery_count,
+                    },
+                );
+            }
+            group.consumers.insert(
+                consumer,
+                Consumer {
+                    seen_at: seen_at as i64,
+                },
+            );
+        }
+
+        stream.groups.insert(name, group);
+    }
+
+    Ok(stream)
+}
+
+async fn read_value<R>(mut r: R, kind: u8, state: &State) -> anyhow::Result<MapValueContent>
+where
+    R: AsyncBufRead + Unpin,
+{
+    Ok(match kind {
+        TYPE_STRING => MapValueContent::String(read_bytes(&mut r).await?),
+        TYPE_LIST => {
+            let mut list = listpack::List::default();
+            for _ in 0..read_length(&mut r).await? {
+                list.push_back(read_string(&mut r).await?);
+                list.convert_if_needed(state.config().list_max_listpack_size);
+            }
+            MapValueContent::List(list)
+        }
+        TYPE_SET => {
+            let mut set = std::collections::HashSet::new();
+            for _ in 0..read_length(&mut r).await? {
+                set.insert(read_string(&mut r).await?);
+            }
+            MapValueContent::Set(set)
+        }
+        TYPE_HASH => {
+            let mut hash = HashMap::new();
+            for _ in 0..read_length(&mut r).await? {
+                let field = read_string(&mut r).await?;
+                let value = read_string(&mut r).await?;
+                hash.insert(field, HashField::new(value));
+            }
+            MapValueContent::Hash(hash)
+        }
+        // Fields which have expired since are kept, to be expired like any others
+        TYPE_HASH_METADATA => {
+            let min_expire = r
+                .read_u64_le()
+                .await
+                .context("reading minimum field expiry")?;
+            let mut hash = HashMap::new();
+            for _ in 0..read_length(&mut r).await? {
+                // 0 for no expiry, or the expiry relative to the earliest one, plus 1
+                let ttl = read_length(&mut r).await? as u64;
+                let field = read_string(&mut r).await?;
+                let value = read_string(&mut r).await?;
+                let expires_at =
+                    (ttl != 0).then(|| UNIX_EPOCH + Duration::from_millis(min_expire + ttl - 1));
+                hash.insert(field, HashField { value, expires_at });
+            }
+            MapValueContent::Hash(hash)
+        }
+        TYPE_ZSET_2 => {
+            let mut set = SortedSet::default();
+            for _ in 0..read_length(&mut r).await? {
+                let member = read_string(&mut r).await?;
+                let score = r.read_f64_le().await.context("reading score")?;
+                set.insert(member, score);
+            }
+            MapValueContent::SortedSet(set)
+        }
+        TYPE_STREAM_LISTPACKS => MapValueContent::Stream(read_stream(&mut r).await?),
+        _ => bail!("Unsupported value type: {kind}"),
+    })
+}
+
 pub async fn read<R>(mut r: R, state: &mut State) -> anyhow::Result<()>
 where
     R: AsyncBufRead + Unpin,
@@ -104,126 +456,438 @@ where
         .context("reading magic string and version number")?;
 
     ensure!(
-        buf == *b"REDIS0011",
-        "Magic string/version number did not match expected value of 'REDIS0011': {buf:02x?}"
+        buf == *b"REDIS0011" || buf == *b"REDIS0012",
+        "Magic string/version number did not match expected value of 'REDIS0011' or 'REDIS0012': {buf:02x?}"
     );
 
     let mut buf = Vec::new();
+    // The expiry of the key that comes next, if it has one
+    let mut expire = None;
     loop {
         let section = r.read_u8().await.context("reading subsection tag")?;
         match section {
             0xfa => {
-                eprintln!("---------- metadata subsection ----------");
                 // metadata subsection
                 let (key, value) = read_kv_pair(&mut r, &mut buf)
                     .await
                     .context("reading metadata key-value pair")?;
 
-                std::eprintln!(
-                    "[{}:{}:{}] (key, value) = {:?}",
-                    std::file!(),
-                    std::line!(),
-                    std::column!(),
-                    (key, value)
-                );
+                debug!(?key, ?value, "read rdb metadata");
             }
             0xfe => {
-                eprintln!("---------- database subsection ----------");
                 // database subsection
-                let index = r.read_u8().await.context("reading database index")?;
-                dbg!(index);
-
-                let fb = r
-                    .read_u8()
+                let index = read_length(&mut r)
                     .await
-                    .context("reading hash table information")?;
-                ensure!(
-                    fb == 0xfb,
-                    "expected 0xfb for start of hash table information, got 0x{fb:02x}"
-                );
-
-                let hash_table_size = r.read_u8().await.context("reading hash table size")?;
-                let hash_table_expiry_size =
-                    r.read_u8().await.context("reading hash table size")?;
-
-                dbg!(hash_table_size);
-                dbg!(hash_table_expiry_size);
-
-                for _ in 0..hash_table_size {
-                    let flag = r.read_u8().await.context("reading kv-pair flag")?;
-
-                    let (key, value, expire) = match flag {
-                        0x00 => {
-                            let (key, value) = read_kv_pair(&mut r, &mut buf)
-                                .await
-                                .context("reading key-value pair")?;
-
-                            let value = value.unwrap_string()?.to_string();
-                            (key, value, None)
-                        }
-                        0xfd => {
-                            // expiry in secs
-                            let timestamp =
-                                r.read_u32_le().await.context("reading timeout timestamp")?;
-                            let expire =
-                                SystemTime::UNIX_EPOCH + Duration::from_secs(timestamp.into());
-
-                            let zero = r.read_u8().await.context("reading kv-pair flag")?;
-                            ensure!(zero == 0, "kv-pair flag was not zero: 0x{zero:02x}");
-
-                            let (key, value) = read_kv_pair(&mut r, &mut buf)
-                                .await
-                                .context("reading key-value pair")?;
-
-                            let value = value.unwrap_string()?.to_string();
-                            (key, value, Some(expire))
-                        }
-                        0xfc => {
-                            // expiry in millis
-                            let timestamp =
-                                r.read_u64_le().await.context("reading timeout timestamp")?;
-                            let expire = SystemTime::UNIX_EPOCH + Duration::from_millis(timestamp);
-
-                            let zero = r.read_u8().await.context("reading kv-pair flag")?;
-                            ensure!(zero == 0, "kv-pair flag was not zero: 0x{zero:02x}");
-
-                            let (key, value) = read_kv_pair(&mut r, &mut buf)
-                                .await
-                                .context("reading key-value pair")?;
-
-                            let value = value.unwrap_string()?.to_string();
-                            (key, value, Some(expire))
-                        }
-                        _ => bail!("Uknown kv-pair flag: {flag:02x}"),
-                    };
-
-                    std::eprintln!(
-                        "[{}:{}:{}] db entry (key, value, expire) = {:?}",
-                        std::file!(),
-                        std::line!(),
-                        std::column!(),
-                        (&key, &value, &expire)
-                    );
-
-                    state.map.insert(
-                        key,
-                        crate::MapValue {
-                            value: MapValueContent::String(value),
-                            expires_at: expire,
-                        },
-                    );
-                }
+                    .context("reading database index")?;
+                ensure!(index == 0, "only database 0 is supported, got {index}");
+            }
+            0xfb => {
+                // hash table sizes, which are only hints
+                read_length(&mut r)
+                    .await
+                    .context("reading hash table size")?;
+                read_length(&mut r)
+                    .await
+                    .context("reading expiry hash table size")?;
+            }
+            0xfd => {
+                // expiry in secs
+                let timestamp = r.read_u32_le().await.context("reading timeout timestamp")?;
+                expire = Some(SystemTime::UNIX_EPOCH + Duration::from_secs(timestamp.into()));
+            }
+            0xfc => {
+                // expiry in millis
+                let timestamp = r.read_u64_le().await.context("reading timeout timestamp")?;
+                expire = Some(SystemTime::UNIX_EPOCH + Duration::from_millis(timestamp));
             }
             0xff => {
-                eprintln!("---------- eof subsection ----------");
-                // EOF subsection
+                // EOF subsection, which is followed by a checksum that we don't check
                 break;
             }
-            tag => {
-                bail!("unexpected subsection tag: {tag:02x}");
+            kind => {
+                let key = Bytes::from(read_bytes(&mut r).await.context("reading key")?);
+                let value = read_value(&mut r, kind, state)
+                    .await
+                    .with_context(|| format!("reading value of '{key}'"))?;
+
+                state.map.insert(
+                    key,
+                    MapValue {
+                        value,
+                        expires_at: expire.take(),
+                    },
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Append a length, using the smallest encoding that fits it
+fn write_length(buf: &mut Vec<u8>, len: usize) {
+    match len {
+        0..64 => buf.push(len as u8),
+        64..16384 => buf.extend_from_slice(&[0x40 | (len >> 8) as u8, len as u8]),
+        _ => match u32::try_from(len) {
+            Ok(len) => {
+                buf.push(0x80);
+                buf.extend_from_slice(&len.to_be_bytes());
+            }
+            Err(_) => {
+                buf.push(0x81);
+                buf.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        },
+    }
+}
+
+fn write_string(buf: &mut Vec<u8>, s: &[u8]) {
+    write_length(buf, s.len());
+    buf.extend_from_slice(s);
+}
+
+fn write_stream_id(buf: &mut Vec<u8>, (ms, seq): StreamId) {
+    buf.extend_from_slice(&ms.to_be_bytes());
+    buf.extend_from_slice(&seq.to_be_bytes());
+}
+
+/// A listpack being built up, element by element
+#[derive(Debug, Default)]
+struct ListpackWriter {
+    buf: Vec<u8>,
+    len: usize,
+}
+
+impl ListpackWriter {
+    /// Add an element, given its encoding and data, followed by its back-length
+    fn push(&mut self, element: &[u8]) {
+        self.buf.extend_from_slice(element);
+
+        let len = element.len();
+        let backlen = listpack_backlen_size(len);
+        for i in (0..backlen).rev() {
+            let byte = (len >> (7 * i)) as u8 & 0x7f;
+            // Every byte but the first has its top bit set, so the length can be read backwards
+            self.buf
+                .push(if i == backlen - 1 { byte } else { byte | 0x80 });
+        }
+        self.len += 1;
+    }
+
+    fn push_int(&mut self, n: i64) {
+        match n {
+            0..=127 => self.push(&[n as u8]),
+            -4096..=4095 => self.push(&[0xc0 | (n >> 8) as u8 & 0x1f, n as u8]),
+            _ => {
+                let (tag, len) = match n {
+                    -0x8000..=0x7fff => (0xf1, 2),
+                    -0x800000..=0x7fffff => (0xf2, 3),
+                    -0x80000000..=0x7fffffff => (0xf3, 4),
+                    _ => (0xf4, 8),
+                };
+                let mut element = vec![tag];
+                element.extend_from_slice(&n.to_le_bytes()[..len]);
+                self.push(&element);
+            }
+        }
+    }
+
+    fn push_str(&mut self, s: &[u8]) {
+        let len = s.len();
+        let mut element = match len {
+            0..64 => vec![0x80 | len as u8],
+            64..4096 => vec![0xe0 | (len >> 8) as u8, len as u8],
+            _ => {
+                let mut element = vec![0xf0];
+                element.extend_from_slice(&(len as u32).to_le_bytes());
+                element
+            }
+        };
+        element.extend_from_slice(s);
+        self.push(&element);
+    }
+
+    fn finish(self) -> Vec<u8> {
+        let total = 6 + self.buf.len() + 1;
+        let mut lp = Vec::with_capacity(total);
+        lp.extend_from_slice(&(total as u32).to_le_bytes());
+        // Counts too big for the header are found by walking the listpack instead
+        lp.extend_from_slice(&(self.len.min(u16::MAX as usize) as u16).to_le_bytes());
+        lp.extend_from_slice(&self.buf);
+        lp.push(0xff);
+        lp
+    }
+}
+
+/// Encode a node of stream entries as a listpack, the same way Redis does
+fn write_stream_node(entries: &[(StreamId, Vec<Bytes>)]) -> Vec<u8> {
+    let mut lp = ListpackWriter::default();
+
+    // The master entry, whose fields later entries can share
+    let (master_id, master_fields) = &entries[0];
+    let master_fields: Vec<_> = master_fields.iter().step_by(2).collect();
+    lp.push_int(entries.len() as i64);
+    lp.push_int(0);
+    lp.push_int(master_fields.len() as i64);
+    for field in &master_fields {
+        lp.push_str(field);
+    }
+    lp.push_int(0);
+
+    for (id, fields) in entries {
+        let same_fields = fields.iter().step_by(2).eq(master_fields.iter().copied());
+        lp.push_int(if same_fields {
+            STREAM_ITEM_FLAG_SAMEFIELDS
+        } else {
+            0
+        });
+        lp.push_int(id.0.wrapping_sub(master_id.0) as i64);
+        lp.push_int(id.1.wrapping_sub(master_id.1) as i64);
+
+        let pairs = fields.len() / 2;
+        if same_fields {
+            for value in fields.iter().skip(1).step_by(2) {
+                lp.push_str(value);
+            }
+            lp.push_int(pairs as i64 + 3);
+        } else {
+            lp.push_int(pairs as i64);
+            for field in fields {
+                lp.push_str(field);
             }
+            lp.push_int(pairs as i64 * 2 + 4);
         }
     }
 
+    lp.finish()
+}
+
+fn write_stream(buf: &mut Vec<u8>, stream: &Stream) {
+    let entries: Vec<_> = stream.iter().collect();
+    let nodes: Vec<_> = entries.chunks(NODE_MAX_ENTRIES).collect();
+    write_length(buf, nodes.len());
+    for node in nodes {
+        let mut master_id = Vec::new();
+        write_stream_id(&mut master_id, node[0].0);
+        write_string(buf, &master_id);
+        write_string(buf, &write_stream_node(node));
+    }
+
+    write_length(buf, stream.len());
+    let (ms, seq) = stream.last_id();
+    write_length(buf, ms as usize);
+    write_length(buf, seq as usize);
+
+    write_length(buf, stream.groups.len());
+    for (name, group) in &stream.groups {
+        write_string(buf, name.as_bytes());
+        write_length(buf, group.last_delivered.0 as usize);
+        write_length(buf, group.last_delivered.1 as usize);
+
+        write_length(buf, group.pending.len());
+        for (id, pending) in &group.pending {
+            write_stream_id(buf, *id);
+            buf.extend_from_slice(&(pending.delivered_at as u64).to_le_bytes());
+            write_length(buf, pending.delivery_count as usize);
+        }
+
+        let mut pending_by_consumer: BTreeMap<&str, Vec<StreamId>> = BTreeMap::new();
+        for (id, pending) in &group.pending {
+            pending_by_consumer
+                .entry(&pending.consumer)
+                .or_default()
+                .push(*id);
+        }
+        write_length(buf, group.consumers.len());
+        for (name, consumer) in &group.consumers {
+            write_string(buf, name.as_bytes());
+            buf.extend_from_slice(&(consumer.seen_at as u64).to_le_bytes());
+            let ids = pending_by_consumer
+                .remove(name.as_str())
+                .unwrap_or_default();
+            write_length(buf, ids.len());
+            for id in ids {
+                write_stream_id(buf, id);
+            }
+        }
+    }
+}
+
+/// The live fields of `hash`, and the earliest expiry of any of them in unix millis, if any of
+/// them expire
+fn live_fields(hash: &HashMap<String, HashField>) -> (Vec<(&String, &HashField)>, Option<u64>) {
+    let fields: Vec<_> = hash.iter().filter(|(_, f)| !f.is_expired()).collect();
+    let min_expire = fields
+        .iter()
+        .filter_map(|(_, field)| field.expires_at)
+        .min()
+        .map(|at| unix_millis(at) as u64);
+    (fields, min_expire)
+}
+
+/// Append a value, preceded by its type and `key`
+fn write_value(buf: &mut Vec<u8>, key: &[u8], value: &MapValueContent) {
+    let kind = match value {
+        MapValueContent::Integer(_) | MapValueContent::String(_) => TYPE_STRING,
+        MapValueContent::List(_) => TYPE_LIST,
+        MapValueContent::Set(_) => TYPE_SET,
+        MapValueContent::Hash(hash) if live_fields(hash).1.is_some() => TYPE_HASH_METADATA,
+        MapValueContent::Hash(_) => TYPE_HASH,
+        MapValueContent::SortedSet(_) => TYPE_ZSET_2,
+        MapValueContent::Stream(_) => TYPE_STREAM_LISTPACKS,
+    };
+    buf.push(kind);
+    write_string(buf, key);
+
+    match value {
+        MapValueContent::Integer(n) => write_string(buf, n.to_string().as_bytes()),
+        MapValueContent::String(bytes) => write_string(buf, bytes),
+        MapValueContent::List(list) => {
+            write_length(buf, list.len());
+            for item in list.iter() {
+                write_string(buf, item.as_bytes());
+            }
+        }
+        MapValueContent::Set(set) => {
+            write_length(buf, set.len());
+            for member in set {
+                write_string(buf, member.as_bytes());
+            }
+        }
+        // Fields which have already expired are left out
+        MapValueContent::Hash(hash) => {
+            let (fields, min_expire) = live_fields(hash);
+            if let Some(min_expire) = min_expire {
+                buf.extend_from_slice(&min_expire.to_le_bytes());
+            }
+            write_length(buf, fields.len());
+            for (field, value) in fields {
+                if let Some(min_expire) = min_expire {
+                    let ttl = value
+                        .expires_at
+                        .map_or(0, |at| unix_millis(at) as u64 - min_expire + 1);
+                    write_length(buf, ttl as usize);
+                }
+                write_string(buf, field.as_bytes());
+                write_string(buf, value.value.as_bytes());
+            }
+        }
+        MapValueContent::SortedSet(set) => {
+            write_length(buf, set.len());
+            for SetEntry { score, value } in set {
+                write_string(buf, value.as_bytes());
+                buf.extend_from_slice(&score.to_le_bytes());
+            }
+        }
+        MapValueContent::Stream(stream) => write_stream(buf, stream),
+    }
+}
+
+/// Encode a snapshot of the keyspace as an RDB file
+fn dump(snapshot: &[(Bytes, MapValue)]) -> Vec<u8> {
+    let now = SystemTime::now();
+    // Hashes whose fields have all expired are gone too
+    let live: Vec<_> = snapshot
+        .iter()
+        .filter(|(_, value)| value.expires_at.is_none_or(|e| e > now))
+        .filter(|(_, value)| match value.value {
+            MapValueContent::Hash(ref hash) => !live_fields(hash).0.is_empty(),
+            _ => true,
+        })
+        .collect();
+
+    // Field expiry times need version 12, which older versions of Redis can't read, so it's only
+    // used when there are any
+    let field_expiry = live.iter().any(|(_, value)| match value.value {
+        MapValueContent::Hash(ref hash) => live_fields(hash).1.is_some(),
+        _ => false,
+    });
+    let mut buf = if field_expiry {
+        b"REDIS0012".to_vec()
+    } else {
+        b"REDIS0011".to_vec()
+    };
+    buf.push(0xfe);
+    write_length(&mut buf, 0);
+    buf.push(0xfb);
+    write_length(&mut buf, live.len());
+    write_length(
+        &mut buf,
+        live.iter().filter(|(_, v)| v.expires_at.is_some()).count(),
+    );
+
+    for (key, value) in live {
+        if let Some(expires_at) = value.expires_at {
+            let ms = expires_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64;
+            buf.push(0xfc);
+            buf.extend_from_slice(&ms.to_le_bytes());
+        }
+        write_value(&mut buf, key, &value.value);
+    }
+
+    buf.push(0xff);
+    // A zero checksum tells readers that it wasn't calculated
+    buf.extend_from_slice(&[0; 8]);
+    buf
+}
+
+/// Write the keyspace to `path`, replacing it all at once so that a crash part way through
+/// doesn't lose the previous save
+pub async fn save(state: &State, path: &Path) -> anyhow::Result<()> {
+    let snapshot: Vec<(Bytes, MapValue)> = state
+        .map
+        .iter()
+        .map(|e| (e.key().clone(), e.value().clone()))
+        .collect();
+    let buf = dump(&snapshot);
+
+    let tmp_path = path.with_file_name(format!("temp-{}.rdb", std::process::id()));
+    tokio::fs::write(&tmp_path, buf)
+        .await
+        .with_context(|| format!("writing {}", tmp_path.display()))?;
+    tokio::fs::rename(&tmp_path, path)
+        .await
+        .with_context(|| format!("moving rdb file into place at {}", path.display()))?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A listpack with a 6 byte header (which isn't checked) followed by `entries`
+    fn listpack(entries: &[u8]) -> Vec<u8> {
+        [&[0; 6][..], entries].concat()
+    }
+
+    #[test]
+    fn reads_listpack() {
+        let lp = listpack(&[0x05, 0x01, 0x82, b'h', b'i', 0x03, 0xff]);
+        let items = read_listpack(&lp).unwrap();
+        assert!(matches!(
+            items[..],
+            [ListpackItem::Int(5), ListpackItem::Str(b"hi")]
+        ));
+    }
+
+    #[test]
+    fn truncated_listpack_is_an_error() {
+        for entries in [
+            &[][..],
+            &[0x05, 0x01],
+            &[0x85, b'a', b'b'],
+            &[0xc1],
+            &[0xe0],
+            &[0xe0, 0x05, b'a'],
+            &[0xf0, 0x01, 0x00],
+            &[0xf0, 0x05, 0x00, 0x00, 0x00, b'a'],
+            &[0xf1, 0x01],
+        ] {
+            assert!(read_listpack(&listpack(entries)).is_err(), "{entries:?}");
+        }
+    }
+}
@@ -1,8 +1,15 @@
-use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Write};
 use std::hash::Hash;
+use std::sync::Arc;
 
-use anyhow::{bail, ensure, Context};
-use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use anyhow::{bail, Context};
+use bytes::{Buf, BufMut, BytesMut};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::{mpsc, Mutex},
+};
+
+use crate::bytes::Bytes;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 #[repr(u8)]
@@ -21,6 +28,7 @@ pub enum DataKind {
     Attribute = b'|',
     Set = b'~',
     Push = b'>',
+    Null = b'_',
 }
 
 impl From<DataKind> for u8 {
@@ -48,139 +56,600 @@ impl TryFrom<u8> for DataKind {
             b'|' => Ok(Self::Attribute),
             b'~' => Ok(Self::Set),
             b'>' => Ok(Self::Push),
+            b'_' => Ok(Self::Null),
             _ => bail!("Unknown datakind symbol: '{}'", value as char),
         }
     }
 }
 
-/// Take all the bytes, up to the \r\n and append them to `buf`.  Reads out the \r\n from the
-/// reader
-async fn take_until_delim<R>(r: &mut R, buf: &mut Vec<u8>) -> anyhow::Result<usize>
-where
-    R: AsyncBufRead + Unpin,
-{
-    let mut bytes = 0;
-    bytes += r.read_until(b'\r', buf).await?;
-    if r.read_u8().await? != b'\n' {
-        bail!("Expected '\\r\\n'");
+/// Something a client sent which isn't valid RESP
+#[derive(Debug)]
+pub struct ProtocolError {
+    message: String,
+    /// Whether the reader was left at the start of the next value, so that the client can be told
+    /// about the error and carry on.  Otherwise there's no telling where the next value starts.
+    pub recoverable: bool,
+}
+
+impl ProtocolError {
+    fn new(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            recoverable: true,
+        }
+    }
+
+    /// An error after which there's no telling where the next value starts
+    fn fatal(message: impl Into<String>) -> Self {
+        Self {
+            recoverable: false,
+            ..Self::new(message)
+        }
+    }
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Protocol error: {}", self.message)
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// A protocol error found while scanning a value, along with where the next value starts if the
+/// error is recoverable
+#[derive(Debug)]
+struct ScanError {
+    error: ProtocolError,
+    resume_at: usize,
+}
+
+/// How much is read from a connection at once
+const READ_SIZE: usize = 16 * 1024;
+
+/// The longest line that is buffered while waiting for its \r\n.  Bulk data doesn't count, since
+/// its length is known up front.
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+/// The line starting at `start` without its \r\n, along with where the line after it starts, or
+/// `None` if it hasn't all arrived yet
+fn line_at(buf: &[u8], start: usize) -> Result<Option<(&[u8], usize)>, ScanError> {
+    let Some(len) = buf[start..].iter().position(|&b| b == b'\n') else {
+        if buf.len() - start > MAX_LINE_LEN {
+            return Err(ScanError {
+                error: ProtocolError::fatal("line too long"),
+                resume_at: buf.len(),
+            });
+        }
+        return Ok(None);
     };
-    bytes += 1;
 
-    buf.pop().unwrap();
-    Ok(bytes)
+    let next = start + len + 1;
+    match &buf[start..next - 1] {
+        [line @ .., b'\r'] if !line.contains(&b'\r') => Ok(Some((line, next))),
+        _ => Err(ScanError {
+            error: ProtocolError::new("expected '\\r\\n' at the end of a line"),
+            resume_at: next,
+        }),
+    }
 }
 
-/// Take all the bytes, up to the \r\n and append them to `buf`.  Reads out the \r\n from the
-/// reader
-async fn take_delim<R>(r: &mut R) -> anyhow::Result<usize>
-where
-    R: AsyncBufRead + Unpin,
-{
-    let mut buf = [0u8; 2];
-    r.read_exact(&mut buf).await.context("reading delim")?;
+/// Where `len` bytes of bulk data starting at `start` end, including the \r\n after them, or
+/// `None` if they haven't all arrived yet
+fn bulk_end(buf: &[u8], start: usize, len: usize) -> Result<Option<usize>, ScanError> {
+    let end = start
+        .checked_add(len)
+        .and_then(|end| end.checked_add(2))
+        .ok_or_else(|| ScanError {
+            error: ProtocolError::fatal("invalid bulk length"),
+            resume_at: start,
+        })?;
+    if buf.len() < end {
+        return Ok(None);
+    }
 
-    ensure!(buf == *b"\r\n", "Expected buf to be '\\r\\n', got {buf:?}");
-    Ok(buf.len())
+    if buf[end - 2..end] != *b"\r\n" {
+        // The length was probably wrong, so whatever is left of the line goes too
+        let resume_at = buf[end - 2..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(buf.len(), |i| end - 2 + i + 1);
+        return Err(ScanError {
+            error: ProtocolError::new("expected '\\r\\n' after bulk data"),
+            resume_at,
+        });
+    }
+    Ok(Some(end))
 }
 
-pub async fn get_rdb<R>(r: &mut R) -> anyhow::Result<Vec<u8>>
-where
-    R: AsyncBufRead + Unpin,
-{
-    let kind = r.read_u8().await?;
-    let kind = DataKind::try_from(kind)?;
-    ensure!(
-        kind == DataKind::BulkString,
-        "Expected rdb to start with '$'"
-    );
+fn parse_len(line: &[u8], what: &str) -> Result<usize, ProtocolError> {
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|line| line.parse().ok())
+        .ok_or_else(|| ProtocolError::new(format!("invalid {what}: \"{}\"", line.escape_ascii())))
+}
 
-    let mut buf = Vec::new();
-    take_until_delim(r, &mut buf)
-        .await
-        .context("reading \\r\\n")?;
+/// Put a line of some kind, e.g. `+OK\r\n`
+fn put_line(buf: &mut BytesMut, kind: DataKind, line: impl Display) {
+    buf.put_u8(kind.into());
+    write!(buf, "{line}\r\n").expect("writing to a BytesMut can't fail");
+}
 
-    let len: usize = String::from_utf8(std::mem::take(&mut buf))
-        .context("invalid utf-8 string")?
-        .parse()
-        .context("invalid length string")?;
+/// Put the kind and length of an aggregate, which is always an array for RESP2.  For maps `len` is
+/// the number of pairs, which RESP2 gets as an array of the keys and values one after the other.
+fn put_header(buf: &mut BytesMut, kind: DataKind, len: usize, protocol: u8) {
+    match kind {
+        DataKind::Map | DataKind::Attribute if protocol < 3 => {
+            put_line(buf, DataKind::Array, len * 2)
+        }
+        _ if protocol < 3 => put_line(buf, DataKind::Array, len),
+        kind => put_line(buf, kind, len),
+    }
+}
+
+/// Put a bulk string-like value: its length followed by its data
+fn put_bulk(buf: &mut BytesMut, kind: DataKind, data: &[u8]) {
+    put_line(buf, kind, data.len());
+    buf.put_slice(data);
+    buf.put_slice(b"\r\n");
+}
 
-    buf.resize(len, 0);
+/// Group the keys and values of a map into pairs
+fn into_pairs(items: Vec<Value>) -> Vec<(Value, Value)> {
+    let mut items = items.into_iter();
+    std::iter::from_fn(|| Some((items.next()?, items.next()?))).collect()
+}
 
-    r.read_exact(&mut buf).await?;
+/// How deeply aggregates may be nested, which is far more than any command needs
+const MAX_NESTING: usize = 128;
 
-    Ok(buf)
+/// Caps on what the parser will read, so that a client can't make it allocate unbounded memory
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// The longest bulk string, like `proto-max-bulk-len`
+    pub max_bulk_len: usize,
+    /// The most elements in an aggregate
+    pub max_multibulk_len: usize,
 }
 
-pub async fn parse<R>(r: &mut R) -> anyhow::Result<(serde_json::Value, usize)>
-where
-    R: AsyncBufRead + Unpin,
-{
-    let mut bytes = 0;
-    let kind = r.read_u8().await?;
-    bytes += 1;
-    let kind = DataKind::try_from(kind)?;
+impl Limits {
+    /// For peers which are trusted, like our master or the AOF
+    pub const NONE: Self = Self {
+        max_bulk_len: usize::MAX,
+        max_multibulk_len: usize::MAX,
+    };
+}
 
-    let mut buf = Vec::new();
-    let value = match kind {
-        DataKind::SimpleString => {
-            bytes += take_until_delim(r, &mut buf).await?;
-            serde_json::Value::from(
-                String::from_utf8(buf).context("invalid utf-8 in simple string")?,
-            )
+/// Something which the value being scanned is still inside of
+#[derive(Debug)]
+enum Open {
+    /// An aggregate with this many values left to come
+    Sized(usize),
+    /// A streamed aggregate, which ends with a `.`, along with how many values it's had so far
+    /// and how many values make up each of its entries
+    Streamed { values: usize, per_entry: usize },
+    /// A streamed string, which ends with an empty chunk, along with its length so far
+    Chunks(usize),
+}
+
+/// How far a [`Reader`] has got through checking that the value at the front of its buffer has
+/// all arrived, so that it can carry on from there once more has rather than starting again
+#[derive(Debug, Default)]
+struct Scan {
+    /// Where the next line to look at starts
+    pos: usize,
+    /// The aggregates and streamed strings that `pos` is inside of, innermost last
+    open: Vec<Open>,
+}
+
+impl Scan {
+    /// Check whether the value at the front of `buf` has all arrived, returning its length if it
+    /// has.  Nothing is parsed beyond what it takes to find the end of the value.
+    fn scan(&mut self, buf: &[u8], limits: &Limits) -> Result<Option<usize>, ScanError> {
+        let mut scanned = self.scan_values(buf, limits);
+        match scanned {
+            Ok(None) => {}
+            Ok(Some(_)) => *self = Self::default(),
+            Err(ref mut e) => {
+                // Whatever is left of the value would be read as the values after it
+                if !self.open.is_empty() {
+                    e.error.recoverable = false;
+                }
+                *self = Self::default();
+            }
         }
-        DataKind::SimpleError => todo!(),
-        DataKind::Integer => todo!(),
-        DataKind::BulkString => {
-            bytes += take_until_delim(r, &mut buf).await?;
+        scanned
+    }
 
-            let len: usize = String::from_utf8(std::mem::take(&mut buf))
-                .context("invalid utf-8 string")?
-                .parse()
-                .context("invalid length string")?;
+    fn scan_values(&mut self, buf: &[u8], limits: &Limits) -> Result<Option<usize>, ScanError> {
+        loop {
+            let Some((line, next)) = line_at(buf, self.pos)? else {
+                return Ok(None);
+            };
+            let error = |error| ScanError {
+                error,
+                resume_at: next,
+            };
+            let too_long = || error(ProtocolError::fatal("invalid multibulk length"));
 
-            buf.resize(len, 0);
+            match self.open.last_mut() {
+                Some(Open::Chunks(so_far)) => {
+                    let [b';', len @ ..] = line else {
+                        return Err(error(ProtocolError::new("expected ';' before chunk")));
+                    };
+                    let len = parse_len(len, "chunk length").map_err(error)?;
+                    if len == 0 {
+                        self.open.pop();
+                        self.pos = next;
+                    } else {
+                        if *so_far + len > limits.max_bulk_len {
+                            return Err(error(ProtocolError::new("streamed string too long")));
+                        }
+                        let Some(end) = bulk_end(buf, next, len)? else {
+                            return Ok(None);
+                        };
+                        *so_far += len;
+                        self.pos = end;
+                        continue;
+                    }
+                }
+                Some(&mut Open::Streamed { values, per_entry }) if line == b"." => {
+                    if values % per_entry != 0 {
+                        return Err(error(ProtocolError::new(
+                            "invalid end of streamed aggregate",
+                        )));
+                    }
+                    self.open.pop();
+                    self.pos = next;
+                }
+                open => {
+                    if let Some(&mut Open::Streamed { values, per_entry }) = open {
+                        if values / per_entry >= limits.max_multibulk_len {
+                            return Err(too_long());
+                        }
+                    }
 
-            bytes += r.read_exact(&mut buf).await?;
+                    let Some(kind) = line.first().and_then(|&b| DataKind::try_from(b).ok()) else {
+                        let kind = buf[self.pos];
+                        return Err(error(ProtocolError::new(format!(
+                            "unknown type '{}'",
+                            kind.escape_ascii()
+                        ))));
+                    };
+                    let line = &line[1..];
 
-            bytes += take_delim(r).await?;
+                    match kind {
+                        // RESP2 has its own nulls, as bulk strings or arrays of length -1
+                        DataKind::BulkString | DataKind::Array if line == b"-1" => self.pos = next,
+                        // A streamed string is sent in chunks, each with its own length, until an
+                        // empty one
+                        DataKind::BulkString if line == b"?" => {
+                            self.pos = next;
+                            self.open.push(Open::Chunks(0));
+                            continue;
+                        }
+                        DataKind::BulkString | DataKind::BulkError | DataKind::VerbatimString => {
+                            let len = parse_len(line, "bulk length").map_err(error)?;
+                            // The data follows, so there's no carrying on from here
+                            if len > limits.max_bulk_len {
+                                return Err(error(ProtocolError::fatal(format!(
+                                    "invalid bulk length: \"{}\"",
+                                    line.escape_ascii()
+                                ))));
+                            }
+                            // The header is looked at again once the data has arrived
+                            let Some(end) = bulk_end(buf, next, len)? else {
+                                return Ok(None);
+                            };
+                            self.pos = end;
+                        }
+                        DataKind::Array
+                        | DataKind::Set
+                        | DataKind::Push
+                        | DataKind::Map
+                        | DataKind::Attribute => {
+                            if self.open.len() >= MAX_NESTING {
+                                return Err(error(ProtocolError::fatal(
+                                    "too many nested aggregates",
+                                )));
+                            }
+                            self.pos = next;
+                            // Maps are made of pairs, each of which is two values
+                            let per_entry = match kind {
+                                DataKind::Map | DataKind::Attribute => 2,
+                                _ => 1,
+                            };
+                            // Attributes come just before the value they're about, so that counts
+                            // as a second value along with the attribute itself
+                            if kind == DataKind::Attribute {
+                                self.open.push(Open::Sized(2));
+                            }
 
-            // TODO: Confirm that this is a valid assumtion
-            let data = String::from_utf8(buf).context("invalid utf-8 string")?;
+                            // Streamed aggregates have a `.` after their last element rather than
+                            // their length
+                            if line == b"?" {
+                                self.open.push(Open::Streamed {
+                                    values: 0,
+                                    per_entry,
+                                });
+                                continue;
+                            }
+                            let len = parse_len(line, "multibulk length").map_err(error)?;
+                            if len > limits.max_multibulk_len {
+                                return Err(too_long());
+                            }
+                            if len > 0 {
+                                self.open.push(Open::Sized(len * per_entry));
+                                continue;
+                            }
+                        }
+                        _ => self.pos = next,
+                    }
+                }
+            }
 
-            serde_json::Value::String(data)
+            // A whole value has been scanned, which may be the last in the aggregates it's in
+            loop {
+                match self.open.last_mut() {
+                    None => return Ok(Some(self.pos)),
+                    Some(Open::Sized(left)) => {
+                        *left -= 1;
+                        if *left > 0 {
+                            break;
+                        }
+                        self.open.pop();
+                    }
+                    Some(Open::Streamed { values, .. }) => {
+                        *values += 1;
+                        break;
+                    }
+                    Some(Open::Chunks(_)) => unreachable!("streamed strings only contain chunks"),
+                }
+            }
         }
-        DataKind::Array => {
-            bytes += take_until_delim(r, &mut buf).await?;
+    }
+}
+
+/// Take the line at `pos` out of a value which has already been scanned
+fn take_line<'a>(frame: &'a [u8], pos: &mut usize) -> Result<&'a [u8], ProtocolError> {
+    let (line, next) = line_at(frame, *pos)
+        .map_err(|e| e.error)?
+        .ok_or_else(|| ProtocolError::new("incomplete value"))?;
+    *pos = next;
+    Ok(line)
+}
 
-            let len: usize = String::from_utf8(buf)
-                .context("invalid utf-8 string")?
-                .parse()
-                .context("invalid length string")?;
+/// Take `len` bytes of bulk data (and the \r\n after them) at `pos` out of a value which has
+/// already been scanned, without copying them
+fn take_bulk(frame: &bytes::Bytes, pos: &mut usize, len: usize) -> Bytes {
+    let data = frame.slice(*pos..*pos + len);
+    *pos += len + 2;
+    data.into()
+}
 
-            let mut array = Vec::with_capacity(len);
+/// Parse the value at `pos` in `frame`, which has been scanned so it's known to be all there
+fn parse_value(frame: &bytes::Bytes, pos: &mut usize) -> Result<Value, ProtocolError> {
+    let line = take_line(frame, pos)?;
+    let (&kind, line) = line
+        .split_first()
+        .ok_or_else(|| ProtocolError::new("missing type"))?;
+    let kind = DataKind::try_from(kind)
+        .map_err(|_| ProtocolError::new(format!("unknown type '{}'", kind.escape_ascii())))?;
 
-            for i in 0..len {
-                let (value, num_bytes) = Box::pin(parse(r))
-                    .await
-                    .with_context(|| format!("parsing value at index {i} in array"))?;
-                bytes += num_bytes;
-                array.push(value);
+    let invalid =
+        |what: &str| ProtocolError::new(format!("invalid {what}: \"{}\"", line.escape_ascii()));
+    let text =
+        || std::str::from_utf8(line).map_err(|_| ProtocolError::new("invalid utf-8 in line"));
+    let value = match kind {
+        DataKind::SimpleString => Value::SimpleString(text()?.to_string()),
+        DataKind::SimpleError => Value::SimpleError(text()?.to_string()),
+        DataKind::Integer => Value::Integer(text()?.parse().map_err(|_| invalid("integer"))?),
+        DataKind::Null if line.is_empty() => Value::Null,
+        DataKind::Null => return Err(invalid("null")),
+        DataKind::Boolean => match line {
+            b"t" => Value::Boolean(true),
+            b"f" => Value::Boolean(false),
+            _ => return Err(invalid("boolean")),
+        },
+        DataKind::Double => Value::Double(text()?.parse().map_err(|_| invalid("double"))?),
+        DataKind::BigNumber => {
+            let digits = line
+                .strip_prefix(b"-")
+                .or_else(|| line.strip_prefix(b"+"))
+                .unwrap_or(line);
+            if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+                return Err(invalid("big number"));
+            }
+            Value::BigNumber(text()?.to_string())
+        }
+        DataKind::BulkString | DataKind::Array if line == b"-1" => Value::Null,
+        // The chunks of a streamed string are joined back together, which is the one time that
+        // bulk data gets copied
+        DataKind::BulkString if line == b"?" => {
+            let mut buf = Vec::new();
+            loop {
+                let chunk = take_line(frame, pos)?;
+                let len = parse_len(&chunk[1..], "chunk length")?;
+                if len == 0 {
+                    break Value::BulkString(buf.into());
+                }
+                buf.extend_from_slice(&take_bulk(frame, pos, len));
+            }
+        }
+        DataKind::BulkString | DataKind::BulkError | DataKind::VerbatimString => {
+            let data = take_bulk(frame, pos, parse_len(line, "bulk length")?);
+            match kind {
+                DataKind::BulkString => Value::BulkString(data),
+                DataKind::BulkError => Value::BulkError(
+                    data.to_str()
+                        .ok_or_else(|| ProtocolError::new("invalid utf-8 in bulk error"))?
+                        .to_string(),
+                ),
+                _ => {
+                    if data.get(3) != Some(&b':') {
+                        return Err(ProtocolError::new(
+                            "expected verbatim string to start with its encoding",
+                        ));
+                    }
+                    Value::VerbatimString {
+                        encoding: data[..3].try_into().expect("checked the length"),
+                        data: data[4..].to_vec(),
+                    }
+                }
             }
+        }
+        DataKind::Array | DataKind::Set | DataKind::Push | DataKind::Map | DataKind::Attribute => {
+            let per_entry = match kind {
+                DataKind::Map | DataKind::Attribute => 2,
+                _ => 1,
+            };
+
+            // Scanning has already made sure that everything here is actually in the frame
+            let items = if line == b"?" {
+                let mut items = Vec::new();
+                while frame.get(*pos) != Some(&b'.') {
+                    items.push(parse_value(frame, pos)?);
+                }
+                take_line(frame, pos)?;
+                items
+            } else {
+                let len = parse_len(line, "multibulk length")? * per_entry;
+                (0..len)
+                    .map(|_| parse_value(frame, pos))
+                    .collect::<Result<_, _>>()?
+            };
 
-            serde_json::Value::Array(array)
-        }
-        DataKind::Boolean => todo!(),
-        DataKind::Double => todo!(),
-        DataKind::BigNumber => todo!(),
-        DataKind::BulkError => todo!(),
-        DataKind::VerbatimString => todo!(),
-        DataKind::Map => todo!(),
-        DataKind::Attribute => todo!(),
-        DataKind::Set => todo!(),
-        DataKind::Push => todo!(),
+            match kind {
+                DataKind::Array => Value::Array(items),
+                DataKind::Set => Value::Set(items),
+                DataKind::Push => Value::Push(items),
+                DataKind::Map => Value::Map(into_pairs(items)),
+                // Attributes come just before the value they're about
+                _ => parse_value(frame, pos)?.with_attributes(into_pairs(items)),
+            }
+        }
     };
 
-    Ok((value, bytes))
+    Ok(value)
+}
+
+/// Reads values into a buffer which they're parsed out of, so that bulk strings are slices of it
+/// rather than copies
+pub struct Reader<R> {
+    inner: R,
+    buf: BytesMut,
+    scan: Scan,
+    /// The raw bytes of the last value parsed
+    frame: bytes::Bytes,
+}
+
+impl<R> Reader<R>
+where
+    R: AsyncRead + Unpin,
+{
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            buf: BytesMut::with_capacity(READ_SIZE),
+            scan: Scan::default(),
+            frame: bytes::Bytes::new(),
+        }
+    }
+
+    /// The raw bytes of the last value parsed, e.g. for `protocol-trace`
+    pub fn last_frame(&self) -> &[u8] {
+        &self.frame
+    }
+
+    /// Read whatever has arrived into the buffer, returning `false` if nothing more will
+    pub async fn fill(&mut self) -> anyhow::Result<bool> {
+        self.buf.reserve(READ_SIZE);
+        match self.inner.read_buf(&mut self.buf).await {
+            Ok(read) => Ok(read > 0),
+            // TLS peers hanging up without a `close_notify` have still hung up
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Fill the buffer, failing if nothing more will arrive
+    async fn fill_more(&mut self) -> anyhow::Result<()> {
+        if !self.fill().await? {
+            bail!(std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
+        }
+        Ok(())
+    }
+
+    /// Parse a single value of any kind from a trusted peer, returning it along with the number
+    /// of bytes read
+    pub async fn parse(&mut self) -> anyhow::Result<(Value, usize)> {
+        match self.parse_limited(&Limits::NONE).await? {
+            Some(parsed) => Ok(parsed),
+            None => bail!(std::io::Error::from(std::io::ErrorKind::UnexpectedEof)),
+        }
+    }
+
+    /// Parse a single value of any kind, returning it along with the number of bytes read, or
+    /// `None` if the other end hung up rather than sending another.
+    ///
+    /// Anything that isn't valid RESP, or which goes over `limits`, is a [`ProtocolError`], which
+    /// can be found by downcasting the error.  Any other error is from reading.
+    pub async fn parse_limited(
+        &mut self,
+        limits: &Limits,
+    ) -> anyhow::Result<Option<(Value, usize)>> {
+        loop {
+            if let Some(parsed) = self.try_parse(limits)? {
+                return Ok(Some(parsed));
+            }
+            if !self.fill().await? {
+                // Hanging up between values is fine, but not part way through one
+                if self.buf.is_empty() {
+                    return Ok(None);
+                }
+                bail!(std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
+            }
+        }
+    }
+
+    /// Parse the value at the front of the buffer if it has all arrived
+    fn try_parse(&mut self, limits: &Limits) -> Result<Option<(Value, usize)>, ProtocolError> {
+        match self.scan.scan(&self.buf, limits) {
+            Ok(Some(len)) => {
+                self.frame = self.buf.split_to(len).freeze();
+                Ok(Some((parse_value(&self.frame, &mut 0)?, len)))
+            }
+            Ok(None) => Ok(None),
+            Err(e) => {
+                if e.error.recoverable {
+                    self.buf.advance(e.resume_at);
+                }
+                Err(e.error)
+            }
+        }
+    }
+
+    /// Read the RDB file which a master sends after `FULLRESYNC`, which is like a bulk string
+    /// without the \r\n at the end
+    pub async fn get_rdb(&mut self) -> anyhow::Result<Bytes> {
+        let (len, start) = loop {
+            if let Some((line, next)) = line_at(&self.buf, 0).map_err(|e| e.error)? {
+                let len = line
+                    .strip_prefix(b"$")
+                    .context("Expected rdb to start with '$'")?;
+                break (parse_len(len, "rdb length")?, next);
+            }
+            self.fill_more().await?;
+        };
+
+        self.buf
+            .reserve((start + len).saturating_sub(self.buf.len()));
+        while self.buf.len() < start + len {
+            self.fill_more().await?;
+        }
+        self.buf.advance(start);
+        Ok(self.buf.split_to(len).freeze().into())
+    }
 }
 
 #[derive(Clone, Debug, Default)]
@@ -189,30 +658,54 @@ pub enum Value {
     SimpleString(String),
     SimpleError(String),
     Integer(i64),
-    BulkString(String),
+    BulkString(Bytes),
     Rdb(Vec<u8>),
     #[default]
     Null,
     Array(Vec<Value>),
     Boolean(bool),
     Double(f64),
-    BigNumber(i128),
+    /// The digits of an integer of any size, with an optional sign
+    BigNumber(String),
     BulkError(String),
     VerbatimString {
         encoding: [u8; 3],
         data: Vec<u8>,
     },
-    Map(HashMap<Value, Value>),
-    Attribute(HashMap<Value, Value>),
-    Set(HashSet<Value>),
+    /// Kept as a list of pairs, in the order they should be sent
+    Map(Vec<(Value, Value)>),
+    /// A value along with some out-of-band information about it, which RESP2 clients don't get
+    Attribute {
+        attributes: Vec<(Value, Value)>,
+        value: Box<Value>,
+    },
+    Set(Vec<Value>),
     Push(Vec<Value>),
+    /// A string or aggregate whose parts are sent to it as they're produced, for replies whose
+    /// length isn't known up front.  RESP3 clients get it streamed as the parts arrive, but RESP2
+    /// can't do that so they're collected first.  Strings are made of bulk string chunks.
+    Streamed {
+        kind: DataKind,
+        parts: Arc<Mutex<mpsc::UnboundedReceiver<Value>>>,
+    },
+    /// An aggregate whose elements have already been encoded, so that the same message can be
+    /// sent to many clients without being cloned or encoded again for each of them
+    Encoded {
+        kind: DataKind,
+        len: usize,
+        elements: Arc<[u8]>,
+    },
 }
 
 impl Value {
-    pub fn bulk_string(arg: impl Into<String>) -> Value {
+    pub fn bulk_string(arg: impl Into<Bytes>) -> Value {
         Self::BulkString(arg.into())
     }
 
+    pub fn bulk_bytes(bytes: &[u8]) -> Value {
+        Self::BulkString(bytes.into())
+    }
+
     pub fn simple_string(arg: impl Into<String>) -> Value {
         Self::SimpleString(arg.into())
     }
@@ -225,61 +718,322 @@ impl Value {
         Self::SimpleError(arg.into())
     }
 
+    /// This value with `attributes` attached, which RESP3 clients get before it
+    pub fn with_attributes(self, attributes: Vec<(Value, Value)>) -> Value {
+        match self {
+            Value::Attribute {
+                attributes: mut existing,
+                value,
+            } => {
+                existing.extend(attributes);
+                Value::Attribute {
+                    attributes: existing,
+                    value,
+                }
+            }
+            value => Value::Attribute {
+                attributes,
+                value: Box::new(value),
+            },
+        }
+    }
+
+    /// A streamed string or aggregate of `kind`, along with the sender for its parts.  It ends
+    /// once the sender is dropped.
+    pub fn streamed(kind: DataKind) -> (mpsc::UnboundedSender<Value>, Value) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let value = Value::Streamed {
+            kind,
+            parts: Arc::new(Mutex::new(rx)),
+        };
+        (tx, value)
+    }
+
+    /// A push of `values`, encoded up front to be shared between clients.  The elements are
+    /// encoded as RESP2, so they must look the same in RESP3 too.
+    pub async fn encoded_push(values: &[Value]) -> anyhow::Result<Value> {
+        let mut elements = BytesMut::new();
+        for value in values {
+            value.encode_into(&mut elements, 2)?;
+        }
+        Ok(Value::Encoded {
+            kind: DataKind::Push,
+            len: values.len(),
+            elements: Arc::from(&elements[..]),
+        })
+    }
+
+    /// Write this value as RESP2, which is what replicas and the AOF speak
     pub async fn write_to<W>(&self, w: &mut W) -> anyhow::Result<()>
     where
         W: AsyncWrite + Unpin,
     {
+        self.write_as(w, 2).await
+    }
+
+    /// Encode this value for a client which speaks RESP version `protocol`, so that commands
+    /// don't need to know which one that is.  Clients which haven't switched to RESP3 with
+    /// `HELLO` get the closest RESP2 equivalent of the types it doesn't have: booleans become
+    /// integers, doubles, big numbers and verbatim strings become bulk strings, bulk errors become
+    /// simple errors, and the aggregates all become arrays.
+    ///
+    /// Streamed values can't be encoded without waiting for their parts, so they must be written
+    /// with [`Self::write_as`].
+    pub fn encode_into(&self, buf: &mut BytesMut, protocol: u8) -> anyhow::Result<()> {
+        let resp2 = protocol < 3;
         match self {
-            Value::SimpleString(s) => {
-                w.write_u8(DataKind::SimpleString.into()).await?;
-                w.write_all(format!("{s}\r\n").as_bytes()).await?;
+            Value::SimpleString(s) => put_line(buf, DataKind::SimpleString, s),
+            Value::SimpleError(e) => put_line(buf, DataKind::SimpleError, e),
+            Value::Integer(n) => put_line(buf, DataKind::Integer, n),
+            Value::BulkString(s) => put_bulk(buf, DataKind::BulkString, s.as_bytes()),
+            // Sent during replication without the trailing `\r\n`
+            Value::Rdb(s) => {
+                put_line(buf, DataKind::BulkString, s.len());
+                buf.put_slice(s);
             }
-            Value::SimpleError(e) => {
-                w.write_u8(DataKind::SimpleError.into()).await?;
-                w.write_all(format!("{e}\r\n").as_bytes()).await?;
+            Value::Null if resp2 => buf.put_slice(b"$-1\r\n"),
+            Value::Null => buf.put_slice(b"_\r\n"),
+            Value::Boolean(b) if resp2 => put_line(buf, DataKind::Integer, *b as i64),
+            Value::Boolean(b) => put_line(buf, DataKind::Boolean, if *b { 't' } else { 'f' }),
+            Value::Double(d) if resp2 => {
+                put_bulk(buf, DataKind::BulkString, format_double(*d).as_bytes())
             }
-            Value::Integer(n) => {
-                w.write_u8(DataKind::Integer.into()).await?;
-                w.write_all(format!("{n}\r\n").as_bytes()).await?;
+            Value::Double(d) => put_line(buf, DataKind::Double, format_double(*d)),
+            Value::BigNumber(n) if resp2 => put_bulk(buf, DataKind::BulkString, n.as_bytes()),
+            Value::BigNumber(n) => put_line(buf, DataKind::BigNumber, n),
+            // Simple errors can't span lines
+            Value::BulkError(e) if resp2 => {
+                put_line(buf, DataKind::SimpleError, e.replace(['\r', '\n'], " "))
             }
-            Value::BulkString(s) => {
-                w.write_u8(DataKind::BulkString.into()).await?;
-                w.write_all(format!("{}\r\n", s.len()).as_bytes()).await?;
-                w.write_all(s.as_bytes()).await?;
-                w.write_all(b"\r\n").await?;
+            Value::BulkError(e) => put_bulk(buf, DataKind::BulkError, e.as_bytes()),
+            Value::VerbatimString { data, .. } if resp2 => {
+                put_bulk(buf, DataKind::BulkString, data)
             }
-            Value::Rdb(s) => {
-                w.write_u8(DataKind::BulkString.into()).await?;
-                w.write_all(format!("{}\r\n", s.len()).as_bytes()).await?;
-                w.write_all(s).await?;
+            Value::VerbatimString { encoding, data } => {
+                // The length covers the encoding and the `:` after it
+                put_line(buf, DataKind::VerbatimString, data.len() + 4);
+                buf.put_slice(encoding);
+                buf.put_u8(b':');
+                buf.put_slice(data);
+                buf.put_slice(b"\r\n");
             }
-            Value::Null => w
-                .write_all(b"$-1\r\n")
-                .await
-                .context("writing null json value")?,
-            Value::Array(a) => {
-                w.write_u8(DataKind::Array.into()).await?;
-                w.write_all(format!("{}\r\n", a.len()).as_bytes()).await?;
+            Value::Array(a) | Value::Set(a) | Value::Push(a) => {
+                put_header(buf, self.kind(), a.len(), protocol);
                 for (i, v) in a.iter().enumerate() {
-                    Box::pin(v.write_to(w))
+                    v.encode_into(buf, protocol)
+                        .with_context(|| format!("encoding value at index {i}"))?;
+                }
+            }
+            Value::Map(m) => {
+                put_header(buf, DataKind::Map, m.len(), protocol);
+                for (k, v) in m {
+                    k.encode_into(buf, protocol)?;
+                    v.encode_into(buf, protocol)?;
+                }
+            }
+            Value::Attribute { value, .. } if resp2 => value.encode_into(buf, protocol)?,
+            Value::Attribute { attributes, value } => {
+                put_header(buf, DataKind::Attribute, attributes.len(), protocol);
+                for (k, v) in attributes {
+                    k.encode_into(buf, protocol)?;
+                    v.encode_into(buf, protocol)?;
+                }
+                value.encode_into(buf, protocol)?;
+            }
+            Value::Streamed { .. } => bail!("Streamed values must be written with write_as"),
+            Value::Encoded {
+                kind,
+                len,
+                elements,
+            } => {
+                put_header(buf, *kind, *len, protocol);
+                buf.put_slice(elements);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The kind of this value when it's sent to a RESP3 client
+    fn kind(&self) -> DataKind {
+        match self {
+            Value::SimpleString(_) => DataKind::SimpleString,
+            Value::SimpleError(_) => DataKind::SimpleError,
+            Value::Integer(_) => DataKind::Integer,
+            Value::BulkString(_) | Value::Rdb(_) => DataKind::BulkString,
+            Value::Null => DataKind::Null,
+            Value::Array(_) => DataKind::Array,
+            Value::Boolean(_) => DataKind::Boolean,
+            Value::Double(_) => DataKind::Double,
+            Value::BigNumber(_) => DataKind::BigNumber,
+            Value::BulkError(_) => DataKind::BulkError,
+            Value::VerbatimString { .. } => DataKind::VerbatimString,
+            Value::Map(_) => DataKind::Map,
+            Value::Attribute { .. } => DataKind::Attribute,
+            Value::Set(_) => DataKind::Set,
+            Value::Push(_) => DataKind::Push,
+            Value::Streamed { kind, .. } | Value::Encoded { kind, .. } => *kind,
+        }
+    }
+
+    /// Whether this is or contains a streamed value, which can't be encoded up front
+    pub fn is_streamed(&self) -> bool {
+        match self {
+            Value::Streamed { .. } => true,
+            Value::Array(a) | Value::Set(a) | Value::Push(a) => a.iter().any(Value::is_streamed),
+            Value::Map(m) => m.iter().any(|(k, v)| k.is_streamed() || v.is_streamed()),
+            Value::Attribute { value, .. } => value.is_streamed(),
+            _ => false,
+        }
+    }
+
+    /// Write this value for a client which speaks RESP version `protocol`, as it's encoded by
+    /// [`Self::encode_into`].  Only streamed values are written a part at a time; anything else
+    /// is encoded and written all at once.
+    pub async fn write_as<W>(&self, w: &mut W, protocol: u8) -> anyhow::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        if !self.is_streamed() {
+            let mut buf = BytesMut::new();
+            self.encode_into(&mut buf, protocol)?;
+            w.write_all(&buf).await?;
+            return Ok(());
+        }
+
+        let resp2 = protocol < 3;
+        let mut buf = BytesMut::new();
+        match self {
+            Value::Array(a) | Value::Set(a) | Value::Push(a) => {
+                put_header(&mut buf, self.kind(), a.len(), protocol);
+                w.write_all(&buf).await?;
+                for (i, v) in a.iter().enumerate() {
+                    Box::pin(v.write_as(w, protocol))
                         .await
-                        .with_context(|| format!("writing value at index {i} in array"))?;
+                        .with_context(|| format!("writing value at index {i}"))?;
+                }
+            }
+            Value::Map(m) => {
+                put_header(&mut buf, DataKind::Map, m.len(), protocol);
+                w.write_all(&buf).await?;
+                for (k, v) in m {
+                    Box::pin(k.write_as(w, protocol)).await?;
+                    Box::pin(v.write_as(w, protocol)).await?;
+                }
+            }
+            Value::Attribute { attributes, value } => {
+                if !resp2 {
+                    put_header(&mut buf, DataKind::Attribute, attributes.len(), protocol);
+                    for (k, v) in attributes {
+                        k.encode_into(&mut buf, protocol)?;
+                        v.encode_into(&mut buf, protocol)?;
+                    }
+                    w.write_all(&buf).await?;
                 }
+                Box::pin(value.write_as(w, protocol)).await?
             }
-            Value::Boolean(_) => todo!(),
-            Value::Double(_) => todo!(),
-            Value::BigNumber(_) => todo!(),
-            Value::BulkError(_) => todo!(),
-            Value::VerbatimString { .. } => todo!(),
-            Value::Map(_) => todo!(),
-            Value::Attribute(_) => todo!(),
-            Value::Set(_) => todo!(),
-            Value::Push(_) => todo!(),
+            Value::Streamed { kind, parts } if resp2 => {
+                let mut parts = parts.lock().await;
+                let mut collected = Vec::new();
+                while let Some(part) = parts.recv().await {
+                    collected.push(part);
+                }
+                let value = match kind {
+                    DataKind::BulkString => Value::BulkString(
+                        collected
+                            .iter()
+                            .filter_map(|part| match part {
+                                Value::BulkString(chunk) => Some(chunk.as_bytes()),
+                                _ => None,
+                            })
+                            .collect::<Vec<_>>()
+                            .concat()
+                            .into(),
+                    ),
+                    DataKind::Map => Value::Map(into_pairs(collected)),
+                    _ => Value::Array(collected),
+                };
+                Box::pin(value.write_as(w, protocol)).await?
+            }
+            Value::Streamed { kind, parts } => {
+                let mut parts = parts.lock().await;
+                buf.put_u8((*kind).into());
+                buf.put_slice(b"?\r\n");
+                w.write_all(&buf).await?;
+                while let Some(part) = parts.recv().await {
+                    match (kind, part) {
+                        (DataKind::BulkString, Value::BulkString(chunk)) => {
+                            // An empty chunk would end the string
+                            if !chunk.is_empty() {
+                                buf.clear();
+                                write!(buf, ";{}\r\n", chunk.len())
+                                    .expect("writing to a BytesMut can't fail");
+                                buf.put_slice(chunk.as_bytes());
+                                buf.put_slice(b"\r\n");
+                                w.write_all(&buf).await?;
+                            }
+                        }
+                        (DataKind::BulkString, part) => {
+                            bail!("Expected streamed string chunk, got {part:?}")
+                        }
+                        (_, part) => Box::pin(part.write_as(w, protocol))
+                            .await
+                            .with_context(|| format!("writing element of streamed {kind:?}"))?,
+                    }
+                }
+                w.write_all(if *kind == DataKind::BulkString {
+                    b";0\r\n"
+                } else {
+                    b".\r\n"
+                })
+                .await?;
+            }
+            value => unreachable!("{value:?} can't contain a streamed value"),
         }
 
         Ok(())
     }
 
+    /// The contents of a simple string, or of a bulk string which is valid UTF-8
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::SimpleString(s) => Some(s),
+            Value::BulkString(s) => s.to_str(),
+            _ => None,
+        }
+    }
+
+    /// The arguments of a command sent as an array of bulk strings
+    pub fn into_args(self) -> anyhow::Result<Vec<Bytes>> {
+        let value = self.without_attributes();
+        // Like Redis, only the type is named, since the value itself could be huge
+        let Value::Array(values) = value else {
+            bail!(ProtocolError::new(format!(
+                "expected '*', got '{}'",
+                char::from(u8::from(value.kind()))
+            )));
+        };
+        values
+            .into_iter()
+            .map(|value| match value.without_attributes() {
+                Value::BulkString(s) => Ok(s),
+                Value::SimpleString(s) => Ok(s.into()),
+                value => bail!(ProtocolError::new(format!(
+                    "expected '$', got '{}'",
+                    char::from(u8::from(value.kind()))
+                ))),
+            })
+            .collect()
+    }
+
+    /// This value without any attributes attached to it
+    pub fn without_attributes(self) -> Value {
+        match self {
+            Value::Attribute { value, .. } => value.without_attributes(),
+            value => value,
+        }
+    }
+
     pub fn empty_array() -> Value {
         Value::Array(Vec::new())
     }
@@ -300,10 +1054,12 @@ impl Hash for Value {
             Value::BigNumber(x) => x.hash(state),
             Value::BulkError(x) => x.hash(state),
             Value::VerbatimString { encoding, data } => (encoding, data).hash(state),
-            Value::Map(_) => todo!("hash a hash map"),
-            Value::Attribute(_) => todo!("hash a hash map"),
-            Value::Set(_) => todo!("hash a hash set"),
+            Value::Map(x) => x.hash(state),
+            Value::Attribute { attributes, value } => (attributes, value).hash(state),
+            Value::Set(x) => x.hash(state),
             Value::Push(x) => x.hash(state),
+            Value::Streamed { parts, .. } => Arc::as_ptr(parts).hash(state),
+            Value::Encoded { elements, .. } => elements.hash(state),
         }
     }
 }
@@ -331,7 +1087,7 @@ impl From<Vec<Value>> for Value {
 
 impl From<String> for Value {
     fn from(value: String) -> Self {
-        Value::BulkString(value)
+        Value::BulkString(value.into())
     }
 }
 
@@ -343,10 +1099,28 @@ impl From<&str> for Value {
 
 impl From<&String> for Value {
     fn from(value: &String) -> Self {
+        Value::BulkString(value.into())
+    }
+}
+
+impl From<Bytes> for Value {
+    fn from(value: Bytes) -> Self {
+        Value::BulkString(value)
+    }
+}
+
+impl From<&Bytes> for Value {
+    fn from(value: &Bytes) -> Self {
         Value::BulkString(value.clone())
     }
 }
 
+impl From<Vec<u8>> for Value {
+    fn from(value: Vec<u8>) -> Self {
+        Value::BulkString(value.into())
+    }
+}
+
 impl<U> FromIterator<U> for Value
 where
     U: Into<Value>,
@@ -355,3 +1129,205 @@ where
         Value::Array(iter.into_iter().map(Into::into).collect())
     }
 }
+
+/// Format a double the way Redis does when sending it as a string: the shortest representation
+/// which round-trips, switching to scientific notation for very large or small magnitudes.
+pub fn format_double(x: f64) -> String {
+    if x.is_nan() {
+        return "nan".into();
+    }
+    if x.is_infinite() {
+        return if x > 0. { "inf" } else { "-inf" }.into();
+    }
+    if x == 0. {
+        return if x.is_sign_negative() { "-0" } else { "0" }.into();
+    }
+
+    // `{:e}` gives the shortest digits which round-trip, e.g. `-1.25e-7`
+    let sci = format!("{:e}", x.abs());
+    let (mantissa, exp) = sci.split_once('e').expect("`{:e}` always has an exponent");
+    let exp: i32 = exp.parse().expect("`{:e}` exponent is an integer");
+    let digits = mantissa.replace('.', "");
+    let ndigits = digits.len() as i32;
+    // The power of ten that the digits, taken as an integer, need to be multiplied by
+    let k = exp - (ndigits - 1);
+
+    let sign = if x < 0. { "-" } else { "" };
+    if k >= 0 && exp < ndigits + 7 {
+        format!("{sign}{digits}{}", "0".repeat(k as usize))
+    } else if k < 0 && (k > -7 || exp.abs() < 4) {
+        if exp >= 0 {
+            let (int, frac) = digits.split_at(exp as usize + 1);
+            format!("{sign}{int}.{frac}")
+        } else {
+            format!("{sign}0.{}{digits}", "0".repeat((-exp - 1) as usize))
+        }
+    } else {
+        let exp_sign = if exp < 0 { '-' } else { '+' };
+        format!("{sign}{mantissa}e{exp_sign}{}", exp.abs())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write `value` as RESP version `protocol` and parse it back, checking that nothing is left
+    /// over
+    async fn round_trip(value: &Value, protocol: u8) -> Value {
+        let mut buf = Vec::new();
+        value.write_as(&mut buf, protocol).await.unwrap();
+        let (parsed, len) = Reader::new(&buf[..]).parse().await.unwrap();
+        assert_eq!(len, buf.len(), "trailing bytes in {:?}", buf.escape_ascii());
+        parsed
+    }
+
+    /// `Value` has no `PartialEq`, since doubles and streamed values don't compare sensibly, but
+    /// the debug output of what's parsed covers everything that's sent
+    async fn assert_round_trip(value: Value, protocol: u8, expected: Value) {
+        let parsed = round_trip(&value, protocol).await;
+        assert_eq!(
+            format!("{parsed:?}"),
+            format!("{expected:?}"),
+            "{value:?} as RESP{protocol}"
+        );
+    }
+
+    fn bulk(s: &str) -> Value {
+        Value::bulk_string(s)
+    }
+
+    fn nested() -> Value {
+        Value::Array(vec![
+            Value::Integer(1),
+            Value::Array(vec![bulk("a"), Value::Array(Vec::new()), Value::Null]),
+            Value::Map(vec![(
+                bulk("key"),
+                Value::Map(vec![(Value::Integer(2), Value::Array(vec![bulk("b")]))]),
+            )]),
+        ])
+    }
+
+    /// Every kind of value, which RESP3 sends as it is
+    fn values() -> Vec<Value> {
+        vec![
+            Value::simple_string("OK"),
+            Value::simple_error("ERR bad"),
+            Value::Integer(i64::MIN),
+            bulk(""),
+            bulk("with\r\nnewline"),
+            Value::Null,
+            Value::Boolean(true),
+            Value::Boolean(false),
+            Value::Double(1.5),
+            Value::Double(-1.25e-7),
+            Value::Double(f64::INFINITY),
+            Value::BigNumber("3492890328409238509324850943850943825024385".into()),
+            Value::BigNumber("-3492890328409238509324850943850943825024385".into()),
+            Value::bulk_error("SYNTAX invalid\r\nsyntax"),
+            Value::VerbatimString {
+                encoding: *b"txt",
+                data: b"Some string".to_vec(),
+            },
+            Value::empty_array(),
+            nested(),
+            Value::Map(vec![(bulk("a"), Value::Integer(1)), (bulk("b"), nested())]),
+            Value::Set(vec![bulk("x"), Value::Integer(1)]),
+            Value::Push(vec![bulk("message"), bulk("channel"), bulk("hi")]),
+            bulk("value").with_attributes(vec![(bulk("ttl"), Value::Integer(10))]),
+        ]
+    }
+
+    #[tokio::test]
+    async fn resp3_round_trips() {
+        for value in values() {
+            assert_round_trip(value.clone(), 3, value).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn resp2_round_trips_as_closest_equivalent() {
+        let cases = [
+            (Value::simple_string("OK"), Value::simple_string("OK")),
+            (
+                Value::simple_error("ERR bad"),
+                Value::simple_error("ERR bad"),
+            ),
+            (Value::Integer(-42), Value::Integer(-42)),
+            (bulk("with\r\nnewline"), bulk("with\r\nnewline")),
+            (Value::Null, Value::Null),
+            (Value::Boolean(true), Value::Integer(1)),
+            (Value::Boolean(false), Value::Integer(0)),
+            (Value::Double(1.5), bulk("1.5")),
+            (Value::Double(f64::NEG_INFINITY), bulk("-inf")),
+            (
+                Value::BigNumber("-3492890328409238509324850943850943825024385".into()),
+                bulk("-3492890328409238509324850943850943825024385"),
+            ),
+            (
+                Value::bulk_error("SYNTAX invalid\r\nsyntax"),
+                Value::simple_error("SYNTAX invalid  syntax"),
+            ),
+            (
+                Value::VerbatimString {
+                    encoding: *b"txt",
+                    data: b"Some string".to_vec(),
+                },
+                bulk("Some string"),
+            ),
+            (
+                nested(),
+                Value::Array(vec![
+                    Value::Integer(1),
+                    Value::Array(vec![bulk("a"), Value::empty_array(), Value::Null]),
+                    Value::Array(vec![
+                        bulk("key"),
+                        Value::Array(vec![Value::Integer(2), Value::Array(vec![bulk("b")])]),
+                    ]),
+                ]),
+            ),
+            (
+                Value::Map(vec![(bulk("a"), Value::Integer(1))]),
+                Value::Array(vec![bulk("a"), Value::Integer(1)]),
+            ),
+            (Value::Set(vec![bulk("x")]), Value::Array(vec![bulk("x")])),
+            (
+                Value::Push(vec![bulk("message")]),
+                Value::Array(vec![bulk("message")]),
+            ),
+            (
+                bulk("value").with_attributes(vec![(bulk("ttl"), Value::Integer(10))]),
+                bulk("value"),
+            ),
+        ];
+        for (value, expected) in cases {
+            assert_round_trip(value, 2, expected).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn streamed_values_round_trip() {
+        for protocol in [2, 3] {
+            let (tx, value) = Value::streamed(DataKind::BulkString);
+            tx.send(bulk("hello ")).unwrap();
+            tx.send(bulk("world")).unwrap();
+            drop(tx);
+            assert_round_trip(value, protocol, bulk("hello world")).await;
+
+            let (tx, value) = Value::streamed(DataKind::Array);
+            tx.send(Value::Integer(1)).unwrap();
+            tx.send(nested()).unwrap();
+            drop(tx);
+            let expected = Value::Array(vec![Value::Integer(1), nested()]);
+            let expected = round_trip(&expected, protocol).await;
+            assert_round_trip(value, protocol, expected).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn encoded_push_round_trips() {
+        let values = [bulk("message"), bulk("channel"), bulk("hi")];
+        let value = Value::encoded_push(&values).await.unwrap();
+        assert_round_trip(value, 3, Value::Push(values.to_vec())).await;
+    }
+}
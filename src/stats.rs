@@ -0,0 +1,44 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Server-wide counters reported by `INFO stats` and cleared by `CONFIG RESETSTAT`
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub total_connections_received: AtomicU64,
+    pub total_commands_processed: AtomicU64,
+    /// Keys removed because they expired, whether lazily or by the active expiry cycle
+    pub expired_keys: AtomicU64,
+    /// Always 0, since nothing is evicted to stay under `maxmemory`
+    pub evicted_keys: AtomicU64,
+    /// Lookups of keys to read which found them
+    pub keyspace_hits: AtomicU64,
+    /// Lookups of keys to read which didn't
+    pub keyspace_misses: AtomicU64,
+}
+
+impl Stats {
+    pub fn incr(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn reset(&self) {
+        let Self {
+            total_connections_received,
+            total_commands_processed,
+            expired_keys,
+            evicted_keys,
+            keyspace_hits,
+            keyspace_misses,
+        } = self;
+
+        for counter in [
+            total_connections_received,
+            total_commands_processed,
+            expired_keys,
+            evicted_keys,
+            keyspace_hits,
+            keyspace_misses,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
@@ -0,0 +1,271 @@
+//! The representation used for streams.
+
+use std::{
+    collections::BTreeMap,
+    ops::{Bound, RangeBounds},
+};
+
+use crate::{
+    bytes::Bytes,
+    listpack::{read_varint, write_varint},
+};
+
+/// The ID of a stream entry: a unix time in milliseconds and a sequence number
+pub type StreamId = (u64, u64);
+
+/// How many entries are stored together in a node, like Redis' `stream-node-max-entries`.
+/// Trimming with `~` only ever removes whole nodes, which saves re-packing the one at the edge.
+pub const NODE_MAX_ENTRIES: usize = 100;
+
+/// An entry which has been delivered to a consumer but not yet acknowledged
+#[derive(Debug, Clone)]
+pub struct PendingEntry {
+    pub consumer: String,
+    /// When the entry was last delivered, in unix millis
+    pub delivered_at: i64,
+    pub delivery_count: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Consumer {
+    /// When the consumer last read or claimed anything, in unix millis
+    pub seen_at: i64,
+}
+
+/// A group of consumers which share the entries of a stream between them
+#[derive(Debug, Clone, Default)]
+pub struct ConsumerGroup {
+    /// The ID of the last entry delivered to any consumer in the group
+    pub last_delivered: StreamId,
+    /// The entries delivered to consumers in the group which haven't been acknowledged yet
+    pub pending: BTreeMap<StreamId, PendingEntry>,
+    pub consumers: BTreeMap<String, Consumer>,
+}
+
+impl ConsumerGroup {
+    pub fn new(last_delivered: StreamId) -> Self {
+        Self {
+            last_delivered,
+            ..Default::default()
+        }
+    }
+
+    /// The consumer called `name`, which is created if it doesn't exist yet
+    pub fn consumer(&mut self, name: &str, now: i64) -> &mut Consumer {
+        self.consumers
+            .entry(name.to_string())
+            .or_insert(Consumer { seen_at: now })
+    }
+
+    /// The number of entries pending for each consumer which has any
+    pub fn pending_counts(&self) -> BTreeMap<&str, usize> {
+        let mut counts = BTreeMap::new();
+        for entry in self.pending.values() {
+            *counts.entry(entry.consumer.as_str()).or_default() += 1;
+        }
+        counts
+    }
+}
+
+/// Up to [`NODE_MAX_ENTRIES`] consecutive entries, with their fields packed into one buffer
+/// rather than each being a separate allocation
+#[derive(Debug, Clone, Default)]
+struct Node {
+    ids: Vec<StreamId>,
+    /// Where each entry starts in `data`
+    offsets: Vec<u32>,
+    /// For each entry, the number of strings followed by each string's length and bytes
+    data: Vec<u8>,
+}
+
+impl Node {
+    fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    fn first_id(&self) -> StreamId {
+        self.ids[0]
+    }
+
+    fn push(&mut self, id: StreamId, fields: &[Bytes]) {
+        self.ids.push(id);
+        self.offsets.push(self.data.len() as u32);
+        write_varint(&mut self.data, fields.len());
+        for field in fields {
+            write_varint(&mut self.data, field.len());
+            self.data.extend_from_slice(field.as_bytes());
+        }
+    }
+
+    /// The fields of the entry at `index`
+    fn fields(&self, index: usize) -> Vec<Bytes> {
+        let mut data = &self.data[self.offsets[index] as usize..];
+        let (len, used) = read_varint(data);
+        data = &data[used..];
+        (0..len)
+            .map(|_| {
+                let (len, used) = read_varint(data);
+                let (field, rest) = data[used..].split_at(len);
+                data = rest;
+                Bytes::from(field)
+            })
+            .collect()
+    }
+
+    fn entries(&self) -> impl DoubleEndedIterator<Item = (StreamId, Vec<Bytes>)> + '_ {
+        (0..self.len()).map(|i| (self.ids[i], self.fields(i)))
+    }
+
+    /// Remove the first `count` entries, which must leave at least one
+    fn remove_first(&mut self, count: usize) {
+        let cut = self.offsets[count];
+        self.ids.drain(..count);
+        self.offsets.drain(..count);
+        self.data.drain(..cut as usize);
+        for offset in &mut self.offsets {
+            *offset -= cut;
+        }
+    }
+}
+
+/// The entries of a stream, ordered by ID, along with its consumer groups
+///
+/// Entries are kept in [`Node`]s of up to [`NODE_MAX_ENTRIES`], keyed by the ID of their first
+/// entry, which keeps the overhead per entry low for large streams.
+#[derive(Debug, Clone, Default)]
+pub struct Stream {
+    nodes: BTreeMap<StreamId, Node>,
+    len: usize,
+    /// The ID of the last entry added.  New entries must have a greater ID than this, even once
+    /// it's been trimmed away.
+    last_id: StreamId,
+    pub groups: BTreeMap<String, ConsumerGroup>,
+}
+
+impl Stream {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn last_id(&self) -> StreamId {
+        self.last_id
+    }
+
+    /// Set the ID that new entries must be greater than, which must be at least the ID of the
+    /// last entry still in the stream
+    pub fn set_last_id(&mut self, id: StreamId) {
+        debug_assert!(
+            self.nodes
+                .last_key_value()
+                .is_none_or(|(_, node)| node.ids.last() <= Some(&id)),
+            "last ID must be at least the last entry's"
+        );
+        self.last_id = id;
+    }
+
+    /// Add an entry, whose ID must be greater than [`Self::last_id`]
+    pub fn insert(&mut self, id: StreamId, fields: &[Bytes]) {
+        debug_assert!(id > self.last_id, "stream IDs must increase");
+        match self.nodes.last_entry() {
+            Some(mut node) if node.get().len() < NODE_MAX_ENTRIES => {
+                node.get_mut().push(id, fields)
+            }
+            _ => {
+                let mut node = Node::default();
+                node.push(id, fields);
+                self.nodes.insert(id, node);
+            }
+        }
+        self.len += 1;
+        self.last_id = id;
+    }
+
+    pub fn get(&self, id: StreamId) -> Option<Vec<Bytes>> {
+        let (_, node) = self.nodes.range(..=id).next_back()?;
+        let index = node.ids.binary_search(&id).ok()?;
+        Some(node.fields(index))
+    }
+
+    pub fn range(
+        &self,
+        range: impl RangeBounds<StreamId>,
+    ) -> impl DoubleEndedIterator<Item = (StreamId, Vec<Bytes>)> + '_ {
+        let start = range.start_bound().cloned();
+        let end = range.end_bound().cloned();
+
+        // The node that `start` falls in, which may start before it
+        let first_node = match start {
+            Bound::Included(id) | Bound::Excluded(id) => self
+                .nodes
+                .range(..=id)
+                .next_back()
+                .map_or(Bound::Unbounded, |(first, _)| Bound::Included(*first)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        // Nodes which start after `end` can't have anything in the range
+        let last_node = match end {
+            Bound::Excluded((0, 0)) => return None.into_iter().flatten(),
+            Bound::Excluded(id) | Bound::Included(id) => Bound::Included(id),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        if let (Bound::Included(first), Bound::Included(last)) = (first_node, last_node) {
+            if first > last {
+                return None.into_iter().flatten();
+            }
+        }
+
+        Some(
+            self.nodes
+                .range((first_node, last_node))
+                .flat_map(|(_, node)| node.entries())
+                .filter(move |(id, _)| (start, end).contains(id)),
+        )
+        .into_iter()
+        .flatten()
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (StreamId, Vec<Bytes>)> + '_ {
+        self.range(..)
+    }
+
+    /// Remove the oldest `count` entries, or fewer if `approx` is set (so that only whole nodes
+    /// are removed) or if that's more than `limit`.  Returns the number of entries removed.
+    fn remove_oldest(&mut self, count: usize, approx: bool, limit: Option<usize>) -> usize {
+        let count = count.min(limit.unwrap_or(usize::MAX));
+
+        let mut removed = 0;
+        while let Some(node) = self.nodes.first_entry() {
+            let left = count - removed;
+            if node.get().len() <= left {
+                removed += node.remove().len();
+            } else {
+                if !approx && left > 0 {
+                    // Part of the node goes, so it needs keying by its new first entry
+                    let mut node = node.remove();
+                    node.remove_first(left);
+                    self.nodes.insert(node.first_id(), node);
+                    removed += left;
+                }
+                break;
+            }
+        }
+
+        self.len -= removed;
+        removed
+    }
+
+    /// Trim the stream down to `maxlen` entries, returning the number removed
+    pub fn trim_to_len(&mut self, maxlen: usize, approx: bool, limit: Option<usize>) -> usize {
+        self.remove_oldest(self.len().saturating_sub(maxlen), approx, limit)
+    }
+
+    /// Remove the entries with IDs below `min_id`, returning the number removed
+    pub fn trim_before(&mut self, min_id: StreamId, approx: bool, limit: Option<usize>) -> usize {
+        let count = self.range(..min_id).count();
+        self.remove_oldest(count, approx, limit)
+    }
+}
@@ -0,0 +1,74 @@
+//! Letting systemd know when we're ready and when we're stopping, for units with `Type=notify`,
+//! as configured by `supervised`.
+
+use std::{fmt, io, str::FromStr};
+
+use anyhow::bail;
+
+use crate::config::Config;
+
+/// Whether we're run by a supervisor which wants to hear from us
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Supervised {
+    No,
+    Systemd,
+    /// Systemd, if it looks like we're run by it
+    Auto,
+}
+
+impl FromStr for Supervised {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.to_lowercase() {
+            "no" => Ok(Self::No),
+            "systemd" => Ok(Self::Systemd),
+            "auto" => Ok(Self::Auto),
+            _ => bail!("argument must be 'no', 'systemd' or 'auto'"),
+        }
+    }
+}
+
+impl fmt::Display for Supervised {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::No => "no",
+            Self::Systemd => "systemd",
+            Self::Auto => "auto",
+        })
+    }
+}
+
+/// Send `state` (e.g. `READY=1`) to systemd, if it's supervising us.  Like `sd_notify`, it's not
+/// an error for there to be nobody listening.
+#[cfg(target_os = "linux")]
+pub fn notify(config: &Config, state: &str) -> io::Result<()> {
+    use std::os::{
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            net::{SocketAddr, UnixDatagram},
+        },
+    };
+
+    if config.supervised == Supervised::No {
+        return Ok(());
+    }
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+
+    // Sockets starting with `@` are in the abstract namespace
+    let addr = match path.as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(&path)?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+/// There's no systemd to notify off Linux
+#[cfg(not(target_os = "linux"))]
+pub fn notify(_: &Config, _: &str) -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,130 @@
+//! TLS, for clients connecting to `tls-port` and, with `tls-replication`, for our connection to
+//! our master.
+//!
+//! Both sides present `tls-cert-file` and check the other side against `tls-ca-cert-file`, so with
+//! `tls-auth-clients` (the default) clients need a certificate signed by that CA too.
+
+use std::{fmt, path::Path, str::FromStr, sync::Arc};
+
+use anyhow::{bail, Context};
+use tokio_rustls::{
+    rustls::{
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        server::WebPkiClientVerifier,
+        ClientConfig, RootCertStore, ServerConfig,
+    },
+    TlsAcceptor, TlsConnector,
+};
+
+use crate::config::Config;
+
+/// Whether clients connecting over TLS must present a certificate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthClients {
+    Yes,
+    No,
+    /// Clients may present one, but if they do, it must be valid
+    Optional,
+}
+
+impl FromStr for AuthClients {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.to_lowercase() {
+            "yes" => Ok(Self::Yes),
+            "no" => Ok(Self::No),
+            "optional" => Ok(Self::Optional),
+            _ => bail!("argument must be 'yes', 'no' or 'optional'"),
+        }
+    }
+}
+
+impl fmt::Display for AuthClients {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Yes => "yes",
+            Self::No => "no",
+            Self::Optional => "optional",
+        })
+    }
+}
+
+/// What accepts TLS connections on `tls-port`, or `None` if it isn't set
+pub fn acceptor(config: &Config) -> anyhow::Result<Option<TlsAcceptor>> {
+    if config.tls_port == 0 {
+        return Ok(None);
+    }
+
+    let builder = ServerConfig::builder();
+    let builder = match config.tls_auth_clients {
+        AuthClients::No => builder.with_no_client_auth(),
+        auth => {
+            let roots = roots(config).context("tls-auth-clients needs tls-ca-cert-file")?;
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+            let verifier = match auth {
+                AuthClients::Optional => verifier.allow_unauthenticated(),
+                _ => verifier,
+            };
+            builder.with_client_cert_verifier(verifier.build()?)
+        }
+    };
+
+    let (certs, key) =
+        cert_and_key(config)?.context("tls-port needs tls-cert-file and tls-key-file")?;
+    let server = builder
+        .with_single_cert(certs, key)
+        .context("setting up TLS certificate")?;
+    Ok(Some(TlsAcceptor::from(Arc::new(server))))
+}
+
+/// What connects to our master over TLS, for `tls-replication`
+pub fn connector(config: &Config) -> anyhow::Result<TlsConnector> {
+    let roots = roots(config).context("tls-replication needs tls-ca-cert-file")?;
+    let builder = ClientConfig::builder().with_root_certificates(roots);
+    // Masters with `tls-auth-clients` want a certificate from us too
+    let client = match cert_and_key(config)? {
+        Some((certs, key)) => builder
+            .with_client_auth_cert(certs, key)
+            .context("setting up TLS certificate")?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(TlsConnector::from(Arc::new(client)))
+}
+
+/// The certificates in `tls-ca-cert-file`, which the other side's certificate must be signed by
+fn roots(config: &Config) -> anyhow::Result<RootCertStore> {
+    let Some(ref path) = config.tls_ca_cert_file else {
+        bail!("tls-ca-cert-file isn't set");
+    };
+    let mut roots = RootCertStore::empty();
+    for cert in certs(path)? {
+        roots
+            .add(cert)
+            .with_context(|| format!("adding CA certificate from {}", path.display()))?;
+    }
+    Ok(roots)
+}
+
+/// A certificate chain and its private key
+type CertAndKey = (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>);
+
+/// Our certificate chain and its private key, if both are set
+fn cert_and_key(config: &Config) -> anyhow::Result<Option<CertAndKey>> {
+    let (Some(cert), Some(key)) = (&config.tls_cert_file, &config.tls_key_file) else {
+        return Ok(None);
+    };
+    let key = PrivateKeyDer::from_pem_file(key)
+        .with_context(|| format!("reading private key from {}", key.display()))?;
+    Ok(Some((certs(cert)?, key)))
+}
+
+fn certs(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("reading certificates from {}", path.display()))?;
+    if certs.is_empty() {
+        bail!("no certificates in {}", path.display());
+    }
+    Ok(certs)
+}
@@ -0,0 +1,54 @@
+//! `protocol-trace`, which logs the raw RESP sent and received on every connection, for
+//! debugging clients and replication handshakes.
+
+use std::sync::Arc;
+
+use tracing::info;
+
+use crate::State;
+
+/// How much of a frame is logged, since values (and RDB files) can be large
+const MAX_TRACE_LEN: usize = 1024;
+
+/// Logs what a single connection sends and receives, while `protocol-trace` is on
+#[derive(Debug, Clone)]
+pub struct Tracer {
+    state: Arc<State>,
+    client_id: u64,
+}
+
+impl Tracer {
+    pub fn new(state: Arc<State>, client_id: u64) -> Self {
+        Self { state, client_id }
+    }
+
+    pub fn received(&self, frame: &[u8]) {
+        self.log("<-", frame);
+    }
+
+    pub fn sent(&self, frame: &[u8]) {
+        self.log("->", frame);
+    }
+
+    /// Streamed replies are written as their parts arrive, so only their start is logged
+    pub fn sent_streamed(&self) {
+        self.log("->", b"(streamed reply)");
+    }
+
+    fn log(&self, direction: &str, frame: &[u8]) {
+        if !self.state.config().protocol_trace {
+            return;
+        }
+
+        let shown = &frame[..frame.len().min(MAX_TRACE_LEN)];
+        let cut = match frame.len() - shown.len() {
+            0 => String::new(),
+            rest => format!(" ... ({rest} more bytes)"),
+        };
+        info!(
+            "client {} {direction} \"{}\"{cut}",
+            self.client_id,
+            shown.escape_ascii()
+        );
+    }
+}
@@ -0,0 +1,400 @@
+//! The representation used for sorted sets.
+//!
+//! Like Redis, members are kept both in a hash map, for constant-time score lookups, and in a
+//! skiplist ordered by score (then member), for logarithmic-time rank and range queries.  The
+//! skiplist tracks how many nodes each link skips over, which is what makes ranks cheap.
+
+use std::collections::HashMap;
+
+use crate::SetEntry;
+
+const MAX_LEVEL: usize = 32;
+
+/// Whether `entry` sorts before the entry for `member` with `score`
+fn sorts_before(entry: &SetEntry, score: f64, member: &str) -> bool {
+    entry.score < score || (entry.score == score && entry.value.as_str() < member)
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Link {
+    next: Option<usize>,
+    /// The number of nodes this link moves forward by
+    span: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    entry: SetEntry,
+    links: Vec<Link>,
+    prev: Option<usize>,
+}
+
+/// A skiplist of entries, with nodes stored in a slab and referred to by index.  `None` refers
+/// to the head of the list where a node is expected.
+#[derive(Debug, Clone)]
+struct SkipList {
+    nodes: Vec<Option<Node>>,
+    free: Vec<usize>,
+    head: Box<[Link; MAX_LEVEL]>,
+    level: usize,
+    tail: Option<usize>,
+    len: usize,
+}
+
+impl Default for SkipList {
+    fn default() -> Self {
+        Self {
+            nodes: Vec::new(),
+            free: Vec::new(),
+            head: Box::new([Link::default(); MAX_LEVEL]),
+            level: 1,
+            tail: None,
+            len: 0,
+        }
+    }
+}
+
+impl SkipList {
+    fn node(&self, index: usize) -> &Node {
+        self.nodes[index].as_ref().expect("skiplist node is live")
+    }
+
+    fn node_mut(&mut self, index: usize) -> &mut Node {
+        self.nodes[index].as_mut().expect("skiplist node is live")
+    }
+
+    fn link(&self, at: Option<usize>, level: usize) -> Link {
+        match at {
+            Some(index) => self.node(index).links[level],
+            None => self.head[level],
+        }
+    }
+
+    fn link_mut(&mut self, at: Option<usize>, level: usize) -> &mut Link {
+        match at {
+            Some(index) => &mut self.node_mut(index).links[level],
+            None => &mut self.head[level],
+        }
+    }
+
+    /// A level for a new node, where each level is a quarter as likely as the one below
+    fn random_level() -> usize {
+        let mut level = 1;
+        while level < MAX_LEVEL && rand::random::<u32>().is_multiple_of(4) {
+            level += 1;
+        }
+        level
+    }
+
+    /// The last node at each level which sorts before `score`/`member`, along with the rank
+    /// (counting from 1, with the head at 0) of each
+    fn predecessors(
+        &self,
+        score: f64,
+        member: &str,
+    ) -> ([Option<usize>; MAX_LEVEL], [usize; MAX_LEVEL]) {
+        let mut update = [None; MAX_LEVEL];
+        let mut rank = [0; MAX_LEVEL];
+        let mut at = None;
+        for level in (0..self.level).rev() {
+            rank[level] = rank.get(level + 1).copied().unwrap_or(0);
+            loop {
+                let link = self.link(at, level);
+                match link.next {
+                    Some(next) if sorts_before(&self.node(next).entry, score, member) => {
+                        rank[level] += link.span;
+                        at = Some(next);
+                    }
+                    _ => break,
+                }
+            }
+            update[level] = at;
+        }
+        (update, rank)
+    }
+
+    /// Insert `entry`, whose member must not already be in the list
+    fn insert(&mut self, entry: SetEntry) {
+        let (mut update, mut rank) = self.predecessors(entry.score, &entry.value);
+
+        let level = Self::random_level();
+        if level > self.level {
+            for l in self.level..level {
+                rank[l] = 0;
+                update[l] = None;
+                self.head[l].span = self.len;
+            }
+            self.level = level;
+        }
+
+        let index = self.free.pop().unwrap_or_else(|| {
+            self.nodes.push(None);
+            self.nodes.len() - 1
+        });
+        let mut links = vec![Link::default(); level];
+        for (l, link) in links.iter_mut().enumerate() {
+            let before = self.link_mut(update[l], l);
+            link.next = before.next;
+            link.span = before.span - (rank[0] - rank[l]);
+            before.next = Some(index);
+            before.span = rank[0] - rank[l] + 1;
+        }
+        for (l, &before) in update.iter().enumerate().take(self.level).skip(level) {
+            self.link_mut(before, l).span += 1;
+        }
+
+        let next = links[0].next;
+        self.nodes[index] = Some(Node {
+            entry,
+            links,
+            prev: update[0],
+        });
+        match next {
+            Some(next) => self.node_mut(next).prev = Some(index),
+            None => self.tail = Some(index),
+        }
+        self.len += 1;
+    }
+
+    /// Remove the entry for `member` with `score`, if there is one
+    fn remove(&mut self, score: f64, member: &str) -> Option<SetEntry> {
+        let (update, _) = self.predecessors(score, member);
+        let index = self.link(update[0], 0).next?;
+        let entry = &self.node(index).entry;
+        if entry.score != score || entry.value != member {
+            return None;
+        }
+
+        let node = self.nodes[index].take().expect("skiplist node is live");
+        for (l, &before) in update.iter().enumerate().take(self.level) {
+            let link = self.link_mut(before, l);
+            if link.next == Some(index) {
+                link.span += node.links[l].span;
+                link.span -= 1;
+                link.next = node.links[l].next;
+            } else {
+                link.span -= 1;
+            }
+        }
+        match node.links[0].next {
+            Some(next) => self.node_mut(next).prev = node.prev,
+            None => self.tail = node.prev,
+        }
+        while self.level > 1 && self.head[self.level - 1].next.is_none() {
+            self.level -= 1;
+        }
+
+        self.free.push(index);
+        self.len -= 1;
+        Some(node.entry)
+    }
+
+    /// The rank of the entry for `member` with `score`, counting from 0
+    fn rank(&self, score: f64, member: &str) -> Option<usize> {
+        let mut rank = 0;
+        let mut at = None;
+        for level in (0..self.level).rev() {
+            loop {
+                let link = self.link(at, level);
+                match link.next {
+                    Some(next)
+                        if sorts_before(&self.node(next).entry, score, member)
+                            || self.node(next).entry.value == member =>
+                    {
+                        rank += link.span;
+                        at = Some(next);
+                    }
+                    _ => break,
+                }
+            }
+            if let Some(index) = at {
+                if self.node(index).entry.value == member {
+                    return Some(rank - 1);
+                }
+            }
+        }
+        None
+    }
+
+    /// The node with `rank`, counting from 0
+    fn by_rank(&self, rank: usize) -> Option<usize> {
+        let target = rank + 1;
+        let mut traversed = 0;
+        let mut at = None;
+        for level in (0..self.level).rev() {
+            loop {
+                let link = self.link(at, level);
+                match link.next {
+                    Some(next) if traversed + link.span <= target => {
+                        traversed += link.span;
+                        at = Some(next);
+                    }
+                    _ => break,
+                }
+            }
+            if traversed == target {
+                return at;
+            }
+        }
+        None
+    }
+
+    /// The first node for which `before` returns `false`, where `before` must be `true` for some
+    /// prefix of the list and `false` for the rest
+    fn partition_point(&self, before: impl Fn(&SetEntry) -> bool) -> Option<usize> {
+        let mut at = None;
+        for level in (0..self.level).rev() {
+            while let Some(next) = self.link(at, level).next {
+                if !before(&self.node(next).entry) {
+                    break;
+                }
+                at = Some(next);
+            }
+        }
+        self.link(at, 0).next
+    }
+}
+
+/// A walk through a sorted set in either direction, starting from some entry
+pub(crate) struct Walk<'a> {
+    list: &'a SkipList,
+    at: Option<usize>,
+    rev: bool,
+}
+
+impl<'a> Iterator for Walk<'a> {
+    type Item = &'a SetEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.list.node(self.at?);
+        self.at = if self.rev {
+            node.prev
+        } else {
+            node.links[0].next
+        };
+        Some(&node.entry)
+    }
+}
+
+/// Members with scores, ordered by score and then by member
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SortedSet {
+    scores: HashMap<String, f64>,
+    list: SkipList,
+}
+
+impl SortedSet {
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn score(&self, member: &str) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// Add `member` with `score`, or update its score if it's already in the set, returning its
+    /// previous score
+    pub fn insert(&mut self, member: String, score: f64) -> Option<f64> {
+        let old = self.scores.insert(member.clone(), score);
+        if let Some(old) = old {
+            if old == score {
+                return Some(old);
+            }
+            self.list.remove(old, &member);
+        }
+        self.list.insert(SetEntry {
+            score,
+            value: member,
+        });
+        old
+    }
+
+    /// Remove `member`, returning its score
+    pub fn remove(&mut self, member: &str) -> Option<f64> {
+        let score = self.scores.remove(member)?;
+        self.list.remove(score, member);
+        Some(score)
+    }
+
+    /// The position of `member` in score order, counting from 0
+    pub fn rank(&self, member: &str) -> Option<usize> {
+        let score = self.score(member)?;
+        self.list.rank(score, member)
+    }
+
+    /// Every entry, from the lowest score to the highest
+    pub fn iter(&self) -> Walk<'_> {
+        self.walk_from_rank(0, false)
+    }
+
+    /// Walk from the entry at `rank`, counting from the lowest score, or from the highest if
+    /// `rev` is set
+    pub fn walk_from_rank(&self, rank: usize, rev: bool) -> Walk<'_> {
+        let rank = if rev {
+            self.len().checked_sub(rank + 1)
+        } else {
+            Some(rank)
+        };
+        Walk {
+            list: &self.list,
+            at: rank.and_then(|rank| self.list.by_rank(rank)),
+            rev,
+        }
+    }
+
+    /// Walk forwards from the first entry for which `before` returns `false`, or backwards from
+    /// the last entry for which it returns `true` if `rev` is set.  `before` must be `true` for
+    /// some prefix of the set and `false` for the rest.
+    pub fn walk_from(&self, before: impl Fn(&SetEntry) -> bool, rev: bool) -> Walk<'_> {
+        let first = self.list.partition_point(before);
+        let at = if rev {
+            match first {
+                Some(first) => self.list.node(first).prev,
+                None => self.list.tail,
+            }
+        } else {
+            first
+        };
+        Walk {
+            list: &self.list,
+            at,
+            rev,
+        }
+    }
+
+    /// Remove and return the entry with the lowest score, or the highest if `max` is set
+    pub fn pop(&mut self, max: bool) -> Option<SetEntry> {
+        let entry = self.walk_from_rank(0, max).next()?.clone();
+        self.remove(&entry.value);
+        Some(entry)
+    }
+}
+
+impl Extend<SetEntry> for SortedSet {
+    fn extend<T: IntoIterator<Item = SetEntry>>(&mut self, iter: T) {
+        for entry in iter {
+            self.insert(entry.value, entry.score);
+        }
+    }
+}
+
+impl FromIterator<SetEntry> for SortedSet {
+    fn from_iter<T: IntoIterator<Item = SetEntry>>(iter: T) -> Self {
+        let mut set = Self::default();
+        set.extend(iter);
+        set
+    }
+}
+
+impl<'a> IntoIterator for &'a SortedSet {
+    type Item = &'a SetEntry;
+    type IntoIter = Walk<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}